@@ -0,0 +1,305 @@
+//! Zoom animations. Keyframes pin the view and palette at chosen frames, and
+//! every frame in between is interpolated from the two around it.
+
+use std::{fs, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bignum::BigFixed,
+    error::{Error, Result},
+    floatexp::FloatExp,
+    palette::Easing,
+    perturbation::GUARD_BITS,
+    scene::{Scene, SceneFormat},
+    viewport::{Mapping, Viewport},
+};
+
+/// The view and palette at one frame of the animation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Keyframe {
+    /// Frame number the keyframe is shown at, counting from 0
+    pub frame: usize,
+    pub x: BigFixed,
+    pub y: BigFixed,
+    /// Interpolated on a log scale, so every frame zooms by the same factor
+    pub zoom: FloatExp,
+    /// Turn of the view in degrees, counterclockwise
+    #[serde(default)]
+    pub rotation: f64,
+    /// Shift of the palette colors as a fraction of the ramp
+    #[serde(default)]
+    pub palette_offset: f64,
+    /// Pace of the move from this keyframe to the next
+    #[serde(default = "Keyframe::default_easing")]
+    pub easing: Easing,
+}
+
+impl Keyframe {
+    fn default_easing() -> Easing {
+        Easing::Linear
+    }
+}
+
+/// Keyframes in the order they are shown, as read from a `[[keyframe]]`
+/// list in TOML or a `"keyframe"` array in JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Animation {
+    #[serde(rename = "keyframe")]
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl Animation {
+    pub fn load(path: &Path) -> Result<Self> {
+        let format = SceneFormat::from_path(path)?;
+        let text = fs::read_to_string(path)?;
+        let animation: Animation = format
+            .parse(&text)
+            .map_err(|err| animation_error(path, err))?;
+        animation
+            .validate()
+            .map_err(|err| animation_error(path, err.to_string()))?;
+        Ok(animation)
+    }
+
+    /// The keyframes as they would be stored at `path`
+    pub fn to_text(&self, path: &Path, format: SceneFormat) -> Result<String> {
+        format.write(self).map_err(|err| animation_error(path, err))
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.keyframes.is_empty() {
+            return Err(invalid(
+                "an animation needs at least one keyframe".to_string(),
+            ));
+        }
+        for pair in self.keyframes.windows(2) {
+            if pair[1].frame <= pair[0].frame {
+                return Err(invalid(format!(
+                    "keyframes must be in order of their frames, got frame {} after {}",
+                    pair[1].frame, pair[0].frame
+                )));
+            }
+        }
+        for keyframe in &self.keyframes {
+            if keyframe.zoom <= FloatExp::ZERO {
+                return Err(invalid(format!(
+                    "keyframe {}: zoom must be a positive number, got {}",
+                    keyframe.frame, keyframe.zoom
+                )));
+            }
+            if !(keyframe.rotation.is_finite() && keyframe.palette_offset.is_finite()) {
+                return Err(invalid(format!(
+                    "keyframe {}: rotation and palette offset must be numbers",
+                    keyframe.frame
+                )));
+            }
+            if let Easing::Power(power) = keyframe.easing {
+                if !(power > 0.0 && power.is_finite()) {
+                    return Err(invalid(format!(
+                        "keyframe {}: easing power must be positive, got {}",
+                        keyframe.frame, power
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of frames up to and including the last keyframe
+    pub fn frame_count(&self) -> usize {
+        self.keyframes
+            .last()
+            .map_or(0, |keyframe| keyframe.frame + 1)
+    }
+
+    /// Set the view and palette of `scene` to those of the frame. Frames
+    /// before the first keyframe show the first one.
+    pub fn apply(&self, frame: usize, scene: &mut Scene) {
+        let next = self
+            .keyframes
+            .iter()
+            .position(|keyframe| keyframe.frame > frame);
+        let (mut viewport, offset) = match next {
+            Some(0) => still(&self.keyframes[0]),
+            Some(next) => {
+                let (from, to) = (&self.keyframes[next - 1], &self.keyframes[next]);
+                let t = (frame - from.frame) as f64 / (to.frame - from.frame) as f64;
+                interpolate(from, to, from.easing.apply_f64(t))
+            }
+            None => still(self.keyframes.last().expect("keyframes are validated")),
+        };
+        // The keyframes move the view, the scene decides how it is laid out
+        viewport.mapping = scene.viewport.mapping;
+        scene.viewport = viewport;
+        scene.palette.offset = offset;
+    }
+}
+
+fn still(keyframe: &Keyframe) -> (Viewport, f64) {
+    (
+        Viewport {
+            center_x: keyframe.x.clone(),
+            center_y: keyframe.y.clone(),
+            zoom: keyframe.zoom,
+            rotation: keyframe.rotation,
+            mapping: Mapping::Cartesian,
+        },
+        keyframe.palette_offset,
+    )
+}
+
+/// View and palette offset `t` of the way from one keyframe to the next.
+/// The zoom moves at a steady rate on a log scale, and the center moves in
+/// step with the width of the view, so that the camera dives straight
+/// towards the one point that keeps its place on screen.
+fn interpolate(from: &Keyframe, to: &Keyframe, t: f64) -> (Viewport, f64) {
+    let (log_from, log_to) = (from.zoom.log2(), to.zoom.log2());
+    let zoom = FloatExp::exp2(log_from + (log_to - log_from) * t);
+
+    let limbs = BigFixed::limbs_for_step(
+        FloatExp::from_f64(Viewport::BASE_SPAN).div(zoom),
+        GUARD_BITS,
+    )
+    .max(from.x.frac_limbs())
+    .max(from.y.frac_limbs())
+    .max(to.x.frac_limbs())
+    .max(to.y.frac_limbs());
+    let width = |zoom: FloatExp| FloatExp::from_f64(1.0).div(zoom);
+    // Measure the way left from the deeper keyframe, so that the fraction
+    // shrinks along with the view and keeps its precision
+    let (deep, shallow, fraction) = if log_to == log_from {
+        (from, to, t)
+    } else if log_to > log_from {
+        let fraction = width(zoom)
+            .sub(width(to.zoom))
+            .div(width(from.zoom).sub(width(to.zoom)));
+        (to, from, fraction.to_f64())
+    } else {
+        let fraction = width(zoom)
+            .sub(width(from.zoom))
+            .div(width(to.zoom).sub(width(from.zoom)));
+        (from, to, fraction.to_f64())
+    };
+    let lerp = |deep: &BigFixed, shallow: &BigFixed| {
+        let deep = deep.with_frac_limbs(limbs);
+        let way = shallow.with_frac_limbs(limbs).sub(&deep);
+        deep.add(&way.mul(&BigFixed::from_f64(fraction.clamp(0.0, 1.0), limbs)))
+    };
+
+    (
+        Viewport {
+            center_x: lerp(&deep.x, &shallow.x),
+            center_y: lerp(&deep.y, &shallow.y),
+            zoom,
+            rotation: from.rotation + (to.rotation - from.rotation) * t,
+            mapping: Mapping::Cartesian,
+        },
+        from.palette_offset + (to.palette_offset - from.palette_offset) * t,
+    )
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidArgument(message)
+}
+
+fn animation_error(path: &Path, message: String) -> Error {
+    Error::Scene(format!("{}: {}", path.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyframe(frame: usize, x: &str, y: &str, zoom: f64) -> Keyframe {
+        Keyframe {
+            frame,
+            x: x.parse().unwrap(),
+            y: y.parse().unwrap(),
+            zoom: FloatExp::from_f64(zoom),
+            rotation: 0.0,
+            palette_offset: 0.0,
+            easing: Easing::Linear,
+        }
+    }
+
+    #[test]
+    fn zoom_dives_towards_a_fixed_point() {
+        let animation = Animation {
+            keyframes: vec![
+                keyframe(0, "-0.75", "0", 1.0),
+                keyframe(40, "-0.743643887037151", "0.13182590420533", 1e12),
+            ],
+        };
+        let mut scene = Scene::default();
+        let mut views = Vec::new();
+        for frame in 0..animation.frame_count() {
+            animation.apply(frame, &mut scene);
+            views.push(scene.viewport.clone());
+        }
+        assert_eq!(views[0].center_x.to_string(), "-0.75");
+        assert_eq!(views[40].center_x, animation.keyframes[1].x);
+        assert_eq!(views[40].zoom, animation.keyframes[1].zoom);
+
+        // Each frame zooms by the same factor
+        for pair in views.windows(2) {
+            let ratio = pair[1].zoom.div(pair[0].zoom).to_f64();
+            assert!(
+                (ratio - 1e12_f64.powf(1.0 / 40.0)).abs() < 1e-9,
+                "{}",
+                ratio
+            );
+        }
+        // The point the camera dives towards sits at the same offset from
+        // the center in every frame, measured in view widths
+        let screen = |view: &Viewport, x: f64, y: f64| {
+            (
+                (x - view.center_x.to_f64()) * view.zoom.to_f64(),
+                (y - view.center_y.to_f64()) * view.zoom.to_f64(),
+            )
+        };
+        let (first, second) = (&views[0], &views[1]);
+        let k = first.zoom.div(second.zoom).to_f64();
+        // Solve (p - c0) z0 = (p - c1) z1 for the fixed point p
+        let fixed = |c0: f64, c1: f64| (c1 - k * c0) / (1.0 - k);
+        let p = (
+            fixed(first.center_x.to_f64(), second.center_x.to_f64()),
+            fixed(first.center_y.to_f64(), second.center_y.to_f64()),
+        );
+        let expected = screen(first, p.0, p.1);
+        for view in &views[..20] {
+            let (sx, sy) = screen(view, p.0, p.1);
+            assert!(
+                (sx - expected.0).abs() < 1e-6 && (sy - expected.1).abs() < 1e-6,
+                "{:?} vs {:?}",
+                (sx, sy),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn easing_and_offsets_between_keyframes() {
+        let mut from = keyframe(10, "0", "0", 2.0);
+        from.easing = Easing::Smooth;
+        from.rotation = 90.0;
+        let mut to = keyframe(20, "0", "0", 2.0);
+        to.palette_offset = 1.0;
+        let animation = Animation {
+            keyframes: vec![from, to],
+        };
+        let mut scene = Scene::default();
+
+        animation.apply(3, &mut scene);
+        assert_eq!(scene.viewport.rotation, 90.0);
+        animation.apply(12, &mut scene);
+        // Smoothstep of 0.2
+        assert!((scene.palette.offset - 0.104).abs() < 1e-6);
+        assert!((scene.viewport.rotation - 90.0 * 0.896).abs() < 1e-4);
+        animation.apply(25, &mut scene);
+        assert_eq!(scene.viewport.rotation, 0.0);
+        assert_eq!(scene.palette.offset, 1.0);
+    }
+}
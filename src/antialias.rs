@@ -0,0 +1,222 @@
+//! Supersampling. A supersampled pixel is the average of several samples
+//! spread over the square of one pixel around the point a plain render
+//! would use, so the two line up. Samples are averaged in linear light,
+//! which keeps thin bright filaments from darkening as they blend in.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{decode, encode, Color},
+    rng::Rng,
+};
+
+/// Where the samples of a pixel go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SamplePattern {
+    /// Centers of an n x n grid of cells
+    #[default]
+    Grid,
+    /// A random point in each cell of the grid, trading aliasing for noise
+    Jittered,
+    /// A grid turned so that no two samples share a row or column, which
+    /// resolves near-horizontal and near-vertical edges n² ways rather than
+    /// n
+    RotatedGrid,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AntialiasSpec {
+    /// Samples along each side of a pixel, n² in all
+    pub grid: usize,
+    #[serde(default)]
+    pub pattern: SamplePattern,
+    /// Only supersample pixels with a neighbour whose color differs by more
+    /// than this fraction of the full range in some channel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adaptive: Option<f64>,
+}
+
+impl AntialiasSpec {
+    pub const DEFAULT_GRID: usize = 3;
+
+    pub fn new(grid: usize) -> Self {
+        Self {
+            grid,
+            pattern: SamplePattern::Grid,
+            adaptive: None,
+        }
+    }
+
+    /// Samples taken for each supersampled pixel
+    pub fn samples(&self) -> usize {
+        self.grid * self.grid
+    }
+
+    /// Offsets of the samples of a pixel from the point a plain render
+    /// samples, in pixels. Jittered samples are seeded by the pixel index,
+    /// so a render comes out the same every time.
+    pub fn offsets(&self, pixel: usize) -> Vec<(f64, f64)> {
+        let n = self.grid;
+        let cell = 1.0 / n as f64;
+        let mut rng = Rng::new(pixel as u64);
+        (0..n)
+            .flat_map(|j| (0..n).map(move |i| (i, j)))
+            .map(|(i, j)| {
+                let (x, y) = match self.pattern {
+                    SamplePattern::Grid => (i as f64 + 0.5, j as f64 + 0.5),
+                    SamplePattern::Jittered => {
+                        (i as f64 + rng.next_f64(), j as f64 + rng.next_f64())
+                    }
+                    // Each sample takes its own one of the n² columns and
+                    // rows, stepping n columns per row of cells
+                    SamplePattern::RotatedGrid => (
+                        i as f64 + (j as f64 + 0.5) * cell,
+                        j as f64 + ((n - 1 - i) as f64 + 0.5) * cell,
+                    ),
+                };
+                (x * cell - 0.5, y * cell - 0.5)
+            })
+            .collect()
+    }
+}
+
+/// Counts of samples taken, for reporting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SampleStats {
+    /// Samples over the whole image
+    pub samples: u64,
+    /// Pixels that were supersampled
+    pub supersampled: u64,
+    /// Samples of each supersampled pixel
+    pub per_pixel: u64,
+}
+
+impl SampleStats {
+    pub fn add(self, other: Self) -> Self {
+        Self {
+            samples: self.samples + other.samples,
+            supersampled: self.supersampled + other.supersampled,
+            per_pixel: self.per_pixel.max(other.per_pixel),
+        }
+    }
+}
+
+/// Pixels that differ from a neighbour by more than `threshold` in some
+/// channel. Both pixels of such a pair are marked.
+pub fn edges(colors: &[Color], width: usize, height: usize, threshold: f64) -> Vec<bool> {
+    let limit = threshold * 255.0;
+    let differs = |a: &Color, b: &Color| {
+        let channels = [(a.red, b.red), (a.green, b.green), (a.blue, b.blue)];
+        channels
+            .iter()
+            .any(|&(a, b)| (a as f64 - b as f64).abs() > limit)
+    };
+    let mut marked = vec![false; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            if x + 1 < width && differs(&colors[index], &colors[index + 1]) {
+                marked[index] = true;
+                marked[index + 1] = true;
+            }
+            if y + 1 < height && differs(&colors[index], &colors[index + width]) {
+                marked[index] = true;
+                marked[index + width] = true;
+            }
+        }
+    }
+    marked
+}
+
+/// Mean of the samples in linear light
+pub fn average(samples: &[Color]) -> Color {
+    let mut sum = [0.0; 3];
+    for sample in samples {
+        let rgb = sample.to_rgb();
+        for i in 0..3 {
+            sum[i] += decode(rgb[i]);
+        }
+    }
+    Color::from_rgb(sum.map(|total| encode(total / samples.len() as f64)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_cover_the_pixel_evenly() {
+        for pattern in [
+            SamplePattern::Grid,
+            SamplePattern::Jittered,
+            SamplePattern::RotatedGrid,
+        ] {
+            let spec = AntialiasSpec {
+                grid: 4,
+                pattern,
+                adaptive: None,
+            };
+            let offsets = spec.offsets(1234);
+            assert_eq!(offsets.len(), 16);
+            // One sample in each cell of the grid
+            let mut cells: Vec<(i64, i64)> = offsets
+                .iter()
+                .map(|&(x, y)| {
+                    assert!((-0.5..0.5).contains(&x) && (-0.5..0.5).contains(&y));
+                    (((x + 0.5) * 4.0) as i64, ((y + 0.5) * 4.0) as i64)
+                })
+                .collect();
+            cells.sort_unstable();
+            cells.dedup();
+            assert_eq!(cells.len(), 16, "{:?}", pattern);
+            assert_eq!(
+                spec.offsets(1234),
+                offsets,
+                "{:?} is not repeatable",
+                pattern
+            );
+        }
+
+        // No two rotated grid samples share a column or row of the n² x n²
+        // grid
+        let spec = AntialiasSpec {
+            grid: 3,
+            pattern: SamplePattern::RotatedGrid,
+            adaptive: None,
+        };
+        let offsets = spec.offsets(0);
+        for axis in [0, 1] {
+            let mut lines: Vec<i64> = offsets
+                .iter()
+                .map(|&(x, y)| ((if axis == 0 { x } else { y } + 0.5) * 9.0) as i64)
+                .collect();
+            lines.sort_unstable();
+            lines.dedup();
+            assert_eq!(lines.len(), 9);
+        }
+    }
+
+    #[test]
+    fn average_in_linear_light() {
+        let black = Color::new(0, 0, 0);
+        let white = Color::new(255, 255, 255);
+        // Half of full intensity, sRGB encoded
+        assert_eq!(average(&[black, white]), Color::new(188, 188, 188));
+        assert_eq!(average(&[white; 9]), white);
+    }
+
+    #[test]
+    fn edges_mark_both_sides() {
+        let (dark, light) = (Color::new(10, 10, 10), Color::new(200, 10, 10));
+        let mut colors = vec![dark; 16];
+        colors[5] = light;
+        let marked = edges(&colors, 4, 4, 0.1);
+        let expected: Vec<usize> = vec![1, 4, 5, 6, 9];
+        let got: Vec<usize> = (0..16).filter(|&i| marked[i]).collect();
+        assert_eq!(got, expected);
+        assert!(edges(&colors, 4, 4, 0.9).iter().all(|&marked| !marked));
+    }
+}
@@ -0,0 +1,207 @@
+//! Averaging colorings. A statistic of every point of an escaping orbit is
+//! averaged, and the averages with and without the final point are blended
+//! by how far past the bailout the orbit escaped. The blend moves in step
+//! with the smooth iteration count, so the result has no bands.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::Color,
+    fractal::{self, Fractal, Visitor},
+    kernel::{nu, EscapeParams},
+    palette::Palette,
+    scene::Formula,
+};
+
+/// Statistic averaged over the orbit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AverageMethod {
+    /// Stripe average: a sine of the angle of z
+    Stripe,
+    /// Triangle inequality average: where |z| falls between the bounds the
+    /// triangle inequality puts on it
+    Triangle,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AverageSpec {
+    pub method: AverageMethod,
+    /// Stripes per turn around the origin, for the stripe average
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub density: Option<f64>,
+}
+
+impl AverageSpec {
+    pub const DEFAULT_DENSITY: f64 = 5.0;
+
+    pub fn new(method: AverageMethod) -> Self {
+        Self {
+            method,
+            density: None,
+        }
+    }
+}
+
+/// Running sums of the orbit statistic
+#[derive(Default)]
+struct Sums {
+    total: f64,
+    last: f64,
+    count: usize,
+    final_z: (f64, f64),
+}
+
+impl Sums {
+    fn add(&mut self, term: f64) {
+        self.total += term;
+        self.last = term;
+        self.count += 1;
+    }
+
+    /// Average blended between leaving out and keeping the final term
+    fn blend(&self, fraction: f64) -> f64 {
+        let average = self.total / self.count as f64;
+        if self.count < 2 {
+            return average;
+        }
+        let previous = (self.total - self.last) / (self.count - 1) as f64;
+        previous + (average - previous) * fraction
+    }
+}
+
+/// Smooth iteration count of the point and the averaged statistic of its
+/// orbit, which is `None` if it never escapes
+fn average_point<F: Fractal>(
+    fractal: &F,
+    spec: &AverageSpec,
+    x0: f64,
+    y0: f64,
+    params: &EscapeParams,
+) -> (f64, Option<f64>) {
+    let density = spec.density.unwrap_or(AverageSpec::DEFAULT_DENSITY);
+    let (cx, cy) = params.julia.unwrap_or((x0, y0));
+    let c_abs = cx.hypot(cy);
+    let mut sums = Sums::default();
+    let iteration = fractal::calculate_orbit(fractal, x0, y0, params, |previous, z| {
+        sums.final_z = z;
+        match spec.method {
+            AverageMethod::Stripe => sums.add(0.5 * (density * z.1.atan2(z.0)).sin() + 0.5),
+            AverageMethod::Triangle => {
+                // |z| lies between ||f(z')| - |c|| and |f(z')| + |c|
+                let (fx, fy) = fractal.step(previous, (0.0, 0.0));
+                let f_abs = fx.hypot(fy);
+                let low = (f_abs - c_abs).abs();
+                let high = f_abs + c_abs;
+                if high > low {
+                    sums.add((z.0.hypot(z.1) - low) / (high - low));
+                }
+            }
+        }
+    });
+    if iteration >= params.max_iterations || sums.count == 0 {
+        return (iteration, None);
+    }
+
+    // Fraction of an iteration by which the final z is short of having
+    // escaped one step earlier, 1 right at the bailout
+    let degree = fractal.degree();
+    let at_bailout = (params.bailout_sqr.log2() / 2.0).log2() / degree.log2();
+    let (zx, zy) = sums.final_z;
+    let fraction = (1.0 - nu(zx, zy, degree) + at_bailout).clamp(0.0, 1.0);
+    (iteration, Some(sums.blend(fraction)))
+}
+
+/// Color a run of points by the averaged statistic of their orbits, writing
+/// their smooth iteration counts to `iterations`. Points that never escape
+/// get the interior color.
+#[allow(clippy::too_many_arguments)]
+pub fn color_points(
+    formula: Formula,
+    power: f64,
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    spec: &AverageSpec,
+    palette: &Palette,
+    iterations: &mut [f64],
+    out: &mut Vec<Color>,
+) {
+    struct Run<'a> {
+        x0: &'a [f64],
+        y0: &'a [f64],
+        params: &'a EscapeParams,
+        spec: &'a AverageSpec,
+        palette: &'a Palette,
+        iterations: &'a mut [f64],
+        out: &'a mut Vec<Color>,
+    }
+
+    impl Visitor for Run<'_> {
+        type Output = ();
+
+        fn visit<F: Fractal + Sync>(self, fractal: &F) {
+            let Run {
+                x0,
+                y0,
+                params,
+                spec,
+                palette,
+                iterations,
+                out,
+            } = self;
+            for ((&x0, &y0), iteration) in x0.iter().zip(y0).zip(iterations) {
+                let (count, value) = average_point(fractal, spec, x0, y0, params);
+                *iteration = count;
+                out.push(match value {
+                    Some(value) => palette.sample(value.clamp(0.0, 1.0) as f32),
+                    None => palette.max_color,
+                });
+            }
+        }
+    }
+
+    fractal::visit(
+        formula,
+        power,
+        Run {
+            x0,
+            y0,
+            params,
+            spec,
+            palette,
+            iterations,
+            out,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fractal::Mandelbrot;
+
+    #[test]
+    fn averages_have_no_bands() {
+        let params = EscapeParams {
+            max_iterations: 1000.0,
+            bailout_sqr: 1e6,
+            julia: None,
+        };
+        for method in [AverageMethod::Stripe, AverageMethod::Triangle] {
+            let spec = AverageSpec::new(method);
+            // A line crossing many escape count bands
+            let line = (0..=2000).map(|i| (-2.4 + 0.0002 * i as f64, 0.35));
+            let values: Vec<f64> = line
+                .map(|(x, y)| average_point(&Mandelbrot, &spec, x, y, &params).1.unwrap())
+                .collect();
+            let jump = values
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]).abs())
+                .fold(0.0, f64::max);
+            assert!(jump < 0.01, "{:?}: jump of {}", method, jump);
+        }
+    }
+}
@@ -0,0 +1,644 @@
+use std::{cmp::Ordering, convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::floatexp::FloatExp;
+
+/// Signed binary fixed-point number with one 64-bit integer limb and a
+/// configurable number of 64-bit fraction limbs. Used where `f64` runs out of
+/// precision, such as the reference orbit of a deep zoom.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Coordinate", into = "String")]
+pub struct BigFixed {
+    negative: bool,
+    /// Magnitude, least significant limb first. The last limb holds the
+    /// integer part.
+    limbs: Vec<u64>,
+}
+
+impl BigFixed {
+    pub fn zero(frac_limbs: usize) -> Self {
+        Self {
+            negative: false,
+            limbs: vec![0; frac_limbs + 1],
+        }
+    }
+
+    /// Number of fraction limbs needed to resolve steps of `step` with
+    /// `guard_bits` to spare
+    pub fn limbs_for_step(step: FloatExp, guard_bits: u32) -> usize {
+        let bits = (-step.log2()).max(0.0).ceil() as usize + guard_bits as usize;
+        bits.div_ceil(64)
+    }
+
+    pub fn frac_limbs(&self) -> usize {
+        self.limbs.len() - 1
+    }
+
+    /// Exact conversion, panics if `value` does not fit the integer limb
+    pub fn from_f64(value: f64, frac_limbs: usize) -> Self {
+        assert!(
+            value.is_finite() && value.abs() < 2.0_f64.powi(63),
+            "{} does not fit in a fixed-point number",
+            value
+        );
+        let mut result = Self::zero(frac_limbs);
+        result.negative = value < 0.0;
+
+        // Peel off 32 bits of the magnitude at a time, starting from the
+        // integer part, each step is exact in f64
+        let mut remainder = value.abs();
+        let integer = remainder.trunc();
+        result.limbs[frac_limbs] = integer as u64;
+        remainder -= integer;
+
+        for limb in (0..frac_limbs).rev() {
+            if remainder == 0.0 {
+                break;
+            }
+            let high = (remainder * 2.0_f64.powi(32)).trunc();
+            remainder = remainder * 2.0_f64.powi(32) - high;
+            let low = (remainder * 2.0_f64.powi(32)).trunc();
+            remainder = remainder * 2.0_f64.powi(32) - low;
+            result.limbs[limb] = ((high as u64) << 32) | low as u64;
+        }
+        result.normalize_zero();
+        result
+    }
+
+    /// Conversion from an extended-exponent float, truncating bits below the
+    /// precision. Panics if `value` does not fit the integer limb.
+    pub fn from_floatexp(value: FloatExp, frac_limbs: usize) -> Self {
+        let mut result = Self::zero(frac_limbs);
+        if value.is_zero() {
+            return result;
+        }
+        assert!(
+            value.exponent() <= 63,
+            "{} does not fit in a fixed-point number",
+            value
+        );
+
+        // The 53 mantissa bits as an integer, placed bit by bit relative to
+        // the lowest fraction bit
+        let bits = (value.mantissa().abs() * 2.0_f64.powi(53)) as u64;
+        let lowest = 64 * frac_limbs as i64 + value.exponent() - 53;
+        for bit in 0..53 {
+            let position = lowest + bit;
+            if position >= 0 && bits >> bit & 1 == 1 {
+                result.limbs[(position / 64) as usize] |= 1 << (position % 64);
+            }
+        }
+        result.negative = value.is_sign_negative();
+        result.normalize_zero();
+        result
+    }
+
+    /// Nearest `f64`, rounding half to even while the result is normal
+    pub fn to_f64(&self) -> f64 {
+        // The top two limbs hold far more than the 53 bits an f64 can keep.
+        // Any lower bits are folded into the lowest one so that the
+        // conversion still sees them when breaking ties. The result is then
+        // scaled, 64 bits at a time, so that nothing underflows before the
+        // final step.
+        let top = match self.limbs.iter().rposition(|&limb| limb != 0) {
+            Some(top) => top,
+            None => return 0.0,
+        };
+        let below = top.checked_sub(1).map_or(0, |i| self.limbs[i]);
+        let sticky = top >= 2 && self.limbs[..top - 1].iter().any(|&limb| limb != 0);
+        let mut value = ((self.limbs[top] as u128) << 64 | below as u128 | sticky as u128) as f64;
+
+        let exponent = top as i64 - 1 - self.frac_limbs() as i64;
+        for _ in 0..exponent.unsigned_abs() {
+            value *= 2.0_f64.powi(if exponent < 0 { -64 } else { 64 });
+        }
+        if self.negative {
+            -value
+        } else {
+            value
+        }
+    }
+
+    /// Change the number of fraction limbs, truncating towards zero
+    pub fn with_frac_limbs(&self, frac_limbs: usize) -> Self {
+        let current = self.frac_limbs();
+        let limbs = if frac_limbs >= current {
+            let mut limbs = vec![0; frac_limbs - current];
+            limbs.extend_from_slice(&self.limbs);
+            limbs
+        } else {
+            self.limbs[current - frac_limbs..].to_vec()
+        };
+        let mut result = Self {
+            negative: self.negative,
+            limbs,
+        };
+        result.normalize_zero();
+        result
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.limbs.iter().all(|&limb| limb == 0)
+    }
+
+    pub fn neg(&self) -> Self {
+        let mut result = self.clone();
+        result.negative = !result.negative;
+        result.normalize_zero();
+        result
+    }
+
+    pub fn add(&self, other: &Self) -> Self {
+        debug_assert_eq!(self.limbs.len(), other.limbs.len());
+        if self.negative == other.negative {
+            return Self {
+                negative: self.negative,
+                limbs: add_magnitudes(&self.limbs, &other.limbs),
+            };
+        }
+
+        let mut result = match compare_magnitudes(&self.limbs, &other.limbs) {
+            Ordering::Less => Self {
+                negative: other.negative,
+                limbs: sub_magnitudes(&other.limbs, &self.limbs),
+            },
+            _ => Self {
+                negative: self.negative,
+                limbs: sub_magnitudes(&self.limbs, &other.limbs),
+            },
+        };
+        result.normalize_zero();
+        result
+    }
+
+    pub fn sub(&self, other: &Self) -> Self {
+        self.add(&other.neg())
+    }
+
+    /// Product truncated to the common precision
+    pub fn mul(&self, other: &Self) -> Self {
+        debug_assert_eq!(self.limbs.len(), other.limbs.len());
+        let n = self.limbs.len();
+        let frac_limbs = n - 1;
+        let mut product = vec![0u64; 2 * n];
+
+        for (i, &a) in self.limbs.iter().enumerate() {
+            if a == 0 {
+                continue;
+            }
+            let mut carry: u128 = 0;
+            for (j, &b) in other.limbs.iter().enumerate() {
+                let sum = a as u128 * b as u128 + product[i + j] as u128 + carry;
+                product[i + j] = sum as u64;
+                carry = sum >> 64;
+            }
+            product[i + n] = carry as u64;
+        }
+
+        let mut result = Self {
+            negative: self.negative != other.negative,
+            limbs: product[frac_limbs..frac_limbs + n].to_vec(),
+        };
+        result.normalize_zero();
+        result
+    }
+
+    pub fn square(&self) -> Self {
+        self.mul(self)
+    }
+
+    /// Multiply by two without touching the precision
+    pub fn double(&self) -> Self {
+        Self {
+            negative: self.negative,
+            limbs: add_magnitudes(&self.limbs, &self.limbs),
+        }
+    }
+
+    /// Divide the magnitude by a small integer, truncating
+    fn div_small(&mut self, divisor: u64) {
+        let mut remainder: u128 = 0;
+        for limb in self.limbs.iter_mut().rev() {
+            let current = (remainder << 64) | *limb as u128;
+            *limb = (current / divisor as u128) as u64;
+            remainder = current % divisor as u128;
+        }
+    }
+
+    /// Multiply the magnitude by a small integer, returning the overflow out
+    /// of the integer limb
+    fn mul_small(&mut self, factor: u64) -> u64 {
+        let mut carry: u128 = 0;
+        for limb in self.limbs.iter_mut() {
+            let current = *limb as u128 * factor as u128 + carry;
+            *limb = current as u64;
+            carry = current >> 64;
+        }
+        carry as u64
+    }
+
+    fn normalize_zero(&mut self) {
+        if self.is_zero() {
+            self.negative = false;
+        }
+    }
+}
+
+fn compare_magnitudes(a: &[u64], b: &[u64]) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+fn add_magnitudes(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut carry = false;
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| {
+            let (sum, overflow_a) = a.overflowing_add(b);
+            let (sum, overflow_b) = sum.overflowing_add(carry as u64);
+            carry = overflow_a || overflow_b;
+            sum
+        })
+        .collect()
+}
+
+/// a - b, where |a| >= |b|
+fn sub_magnitudes(a: &[u64], b: &[u64]) -> Vec<u64> {
+    let mut borrow = false;
+    a.iter()
+        .zip(b)
+        .map(|(&a, &b)| {
+            let (diff, underflow_a) = a.overflowing_sub(b);
+            let (diff, underflow_b) = diff.overflowing_sub(borrow as u64);
+            borrow = underflow_a || underflow_b;
+            diff
+        })
+        .collect()
+}
+
+/// Longest fraction accepted when parsing, about 66000 bits
+const MAX_DIGITS: usize = 20_000;
+
+/// Bits of fraction represented by one decimal digit
+const BITS_PER_DIGIT: f64 = std::f64::consts::LOG2_10;
+
+impl BigFixed {
+    /// Parse a decimal number, rounding to `frac_limbs` fraction limbs or to
+    /// enough limbs for every digit given
+    fn parse_decimal(s: &str, frac_limbs: Option<usize>) -> Result<Self, String> {
+        let invalid = || format!("invalid number {:?}", s);
+        let text = s.trim();
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (mantissa, exponent) = match text.find(['e', 'E']) {
+            Some(pos) => (
+                &text[..pos],
+                text[pos + 1..].parse::<i64>().map_err(|_| invalid())?,
+            ),
+            None => (text, 0),
+        };
+        let (integer, fraction) = match mantissa.find('.') {
+            Some(pos) => (&mantissa[..pos], &mantissa[pos + 1..]),
+            None => (mantissa, ""),
+        };
+        if integer.is_empty() && fraction.is_empty()
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        // Move the decimal point according to the exponent, leading zeros
+        // don't count towards the integer part
+        let digits: Vec<u8> = integer
+            .bytes()
+            .chain(fraction.bytes())
+            .map(|b| b - b'0')
+            .collect();
+        let leading_zeros = digits.iter().take_while(|&&d| d == 0).count();
+        if leading_zeros == digits.len() {
+            return Ok(BigFixed::zero(frac_limbs.unwrap_or(1)));
+        }
+        let point = (integer.len() as i64).saturating_add(exponent);
+        if point - leading_zeros as i64 > 19 {
+            return Err(format!(
+                "{} is too large, coordinates must be below 2^63",
+                s
+            ));
+        }
+
+        let fraction_digits = (digits.len() as i64 - point).max(0) as usize;
+        if fraction_digits > MAX_DIGITS {
+            return Err(format!(
+                "{} has more than {} fraction digits",
+                s, MAX_DIGITS
+            ));
+        }
+        let frac_limbs = frac_limbs.unwrap_or_else(|| {
+            ((fraction_digits as f64 * BITS_PER_DIGIT) as usize).div_ceil(64) + 1
+        });
+        let digit_at = |i: i64| {
+            if i >= 0 && (i as usize) < digits.len() {
+                digits[i as usize] as u64
+            } else {
+                0
+            }
+        };
+
+        // Accumulate the fraction from its last digit, dividing by ten each
+        // time, with one spare limb for rounding
+        let mut result = BigFixed::zero(frac_limbs + 1);
+        for i in (point..point + fraction_digits as i64).rev() {
+            result.limbs[frac_limbs + 1] = digit_at(i);
+            result.div_small(10);
+        }
+
+        let mut integer_part: u64 = 0;
+        for i in 0..point {
+            integer_part = integer_part
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(digit_at(i)))
+                .filter(|&v| v < 1 << 63)
+                .ok_or_else(|| format!("{} is too large, coordinates must be below 2^63", s))?;
+        }
+        result.limbs[frac_limbs + 1] = integer_part;
+        result.negative = negative;
+
+        let mut rounded = result.with_frac_limbs(frac_limbs);
+        if result.limbs[0] >> 63 == 1 {
+            let mut ulp = BigFixed::zero(frac_limbs);
+            ulp.limbs[0] = 1;
+            ulp.negative = negative;
+            rounded = rounded.add(&ulp);
+        }
+        Ok(rounded)
+    }
+
+    /// Decimal representation with the fraction rounded to `digit_count`
+    /// digits, from the precomputed fraction digits
+    fn format_digits(&self, fraction_digits: &[u8], digit_count: usize) -> String {
+        let mut digits = fraction_digits[..digit_count].to_vec();
+        let mut integer = self.limbs[self.frac_limbs()] as u128;
+
+        // Round half up on the first dropped digit
+        let mut carry = fraction_digits[digit_count] >= 5;
+        for digit in digits.iter_mut().rev() {
+            if !carry {
+                break;
+            }
+            *digit += 1;
+            carry = *digit == 10;
+            if carry {
+                *digit = 0;
+            }
+        }
+        if carry {
+            integer += 1;
+        }
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+
+        let mut text = String::new();
+        if self.negative && (integer != 0 || !digits.is_empty()) {
+            text.push('-');
+        }
+        text.push_str(&integer.to_string());
+        if !digits.is_empty() {
+            text.push('.');
+            text.extend(digits.iter().map(|&digit| (b'0' + digit) as char));
+        }
+        text
+    }
+}
+
+impl FromStr for BigFixed {
+    type Err = String;
+
+    /// Parse a decimal number such as `-0.7436438870371587522`, optionally
+    /// with an exponent. The precision grows with the number of digits given.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse_decimal(s, None)
+    }
+}
+
+impl fmt::Display for BigFixed {
+    /// Shortest decimal that parses back to the same value at this precision
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frac_limbs = self.frac_limbs();
+        // Enough digits to always round trip, plus one for rounding
+        let max_digits = (frac_limbs as f64 * 64.0 / BITS_PER_DIGIT).floor() as usize + 1;
+
+        let mut fraction = self.clone();
+        fraction.limbs[frac_limbs] = 0;
+        let fraction_digits: Vec<u8> = (0..=max_digits)
+            .map(|_| {
+                fraction.mul_small(10);
+                let digit = fraction.limbs[frac_limbs] as u8;
+                fraction.limbs[frac_limbs] = 0;
+                digit
+            })
+            .collect();
+
+        let round_trips = |digit_count: usize| {
+            let text = self.format_digits(&fraction_digits, digit_count);
+            Self::parse_decimal(&text, Some(frac_limbs)).as_ref() == Ok(self)
+        };
+
+        // Longer representations keep round tripping once a shorter one does
+        let (mut low, mut high) = (0, max_digits);
+        while low < high {
+            let mid = (low + high) / 2;
+            if round_trips(mid) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        write!(f, "{}", self.format_digits(&fraction_digits, low))
+    }
+}
+
+/// A coordinate as written in a scene file, either a plain number or a
+/// string of arbitrarily many digits
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Coordinate {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<Coordinate> for BigFixed {
+    type Error = String;
+
+    fn try_from(coordinate: Coordinate) -> Result<Self, Self::Error> {
+        match coordinate {
+            // Go through the shortest decimal form, so that 0.1 means the
+            // decimal 0.1 rather than the binary value closest to it
+            Coordinate::Number(value) if value.is_finite() => value.to_string().parse(),
+            Coordinate::Number(value) => Err(format!("{} is not a valid coordinate", value)),
+            Coordinate::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<BigFixed> for String {
+    fn from(value: BigFixed) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1 in the lowest place of a number with `frac_limbs` fraction limbs
+    fn ulp(frac_limbs: usize) -> BigFixed {
+        let mut ulp = BigFixed::zero(frac_limbs);
+        ulp.limbs[0] = 1;
+        ulp
+    }
+
+    /// Position of the highest set bit relative to the binary point
+    fn magnitude_bits(value: &BigFixed) -> i64 {
+        let top = value.limbs.iter().rposition(|&limb| limb != 0).unwrap();
+        let bit = 63 - value.limbs[top].leading_zeros() as i64;
+        64 * (top as i64 - value.frac_limbs() as i64) + bit
+    }
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        let long = format!("-0.{}7", "1234567890".repeat(30));
+        let deep = format!("0.{}1", "0".repeat(400));
+        for text in [
+            long.as_str(),
+            deep.as_str(),
+            "-1.75",
+            "0",
+            "4611686018427387904.5",
+        ] {
+            let value: BigFixed = text.parse().unwrap();
+            assert_eq!(value.to_string(), text);
+        }
+        for (text, shown) in [
+            (".5", "0.5"),
+            ("1e-3", "0.001"),
+            ("+0.25", "0.25"),
+            ("-0", "0"),
+        ] {
+            assert_eq!(text.parse::<BigFixed>().unwrap().to_string(), shown);
+        }
+        for text in ["", ".", "1.2.3", "--1", "1e", "9223372036854775808", "0x10"] {
+            assert!(text.parse::<BigFixed>().is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn carries_and_borrows_cross_limbs() {
+        let one: BigFixed = "1".parse::<BigFixed>().unwrap().with_frac_limbs(3);
+        let almost_one = one.sub(&ulp(3));
+        assert_eq!(almost_one.limbs, [u64::MAX, u64::MAX, u64::MAX, 0]);
+        assert_eq!(almost_one.add(&ulp(3)), one);
+
+        // Differences of opposite signs take the sign of the larger one
+        let quarter = "0.25".parse::<BigFixed>().unwrap().with_frac_limbs(3);
+        assert_eq!(
+            quarter.sub(&almost_one).to_string(),
+            format!("-{}", almost_one.sub(&quarter))
+        );
+        assert_eq!(almost_one.neg().add(&almost_one), BigFixed::zero(3));
+        assert!(!almost_one.neg().add(&almost_one).negative);
+    }
+
+    #[test]
+    fn products_take_the_sign_of_their_factors() {
+        let a = "-1.5".parse::<BigFixed>().unwrap().with_frac_limbs(2);
+        let b = "2.25".parse::<BigFixed>().unwrap().with_frac_limbs(2);
+        assert_eq!(a.mul(&b).to_string(), "-3.375");
+        assert_eq!(b.mul(&a).to_string(), "-3.375");
+        assert_eq!(a.mul(&b.neg()).to_string(), "3.375");
+        assert_eq!(a.square().to_string(), "2.25");
+        let zero = BigFixed::zero(2).mul(&a);
+        assert!(zero.is_zero() && !zero.negative);
+
+        // (1 + 2^-64)^2 = 1 + 2^-63 + 2^-128, carried across both limbs
+        let mut x = BigFixed::zero(2);
+        x.limbs = vec![0, 1, 1];
+        let mut square = BigFixed::zero(2);
+        square.limbs = vec![1, 2, 1];
+        assert_eq!(x.square(), square);
+        assert_eq!(x.neg().mul(&x), square.neg());
+    }
+
+    #[test]
+    fn extended_floats_convert_past_f64_range() {
+        let frac_limbs = BigFixed::limbs_for_step("1e-500".parse().unwrap(), 64);
+        // 3 · 2^-1700, two bits in the middle of a limb
+        let value = BigFixed::from_floatexp(FloatExp::new(-0.75, -1698), frac_limbs);
+        assert!(value.negative);
+        assert_eq!(magnitude_bits(&value), -1699);
+        let lowest = 64 * frac_limbs as i64 - 1700;
+        let set: Vec<i64> = (0..64 * frac_limbs as i64)
+            .filter(|&bit| value.limbs[(bit / 64) as usize] >> (bit % 64) & 1 == 1)
+            .collect();
+        assert_eq!(set, [lowest, lowest + 1]);
+
+        // Every bit of the mantissa of 1e-500 lands in place
+        let float: FloatExp = "1e-500".parse().unwrap();
+        let converted = BigFixed::from_floatexp(float, frac_limbs);
+        let lowest = 64 * frac_limbs as i64 + float.exponent() - 53;
+        let bits = (0..53).fold(0u64, |bits, bit| {
+            let position = lowest + bit;
+            bits | (converted.limbs[(position / 64) as usize] >> (position % 64) & 1) << bit
+        });
+        assert_eq!(bits, (float.mantissa() * 2.0_f64.powi(53)) as u64);
+        let exact: BigFixed = "1e-500"
+            .parse::<BigFixed>()
+            .unwrap()
+            .with_frac_limbs(frac_limbs);
+        assert_eq!(magnitude_bits(&converted), magnitude_bits(&exact));
+        assert!(magnitude_bits(&converted.sub(&exact)) < magnitude_bits(&exact) - 40);
+    }
+
+    #[test]
+    fn to_f64_rounds_to_nearest() {
+        for text in [
+            "0.1",
+            "-2.5e-7",
+            "0.7436438870371587522",
+            "123456789.123456789",
+        ] {
+            let value: BigFixed = text.parse().unwrap();
+            assert_eq!(value.to_f64(), text.parse::<f64>().unwrap(), "{}", text);
+        }
+        for value in [1.0, -0.75, 1e-300, 2.0_f64.powi(62) + 2048.0] {
+            assert_eq!(BigFixed::from_f64(value, 20).to_f64(), value);
+        }
+
+        // Half way between 1 and the next f64 goes to the even 1, anything
+        // above it, however far down the limbs, rounds up
+        let half_ulp = format!(
+            "1.{}",
+            "00000000000000011102230246251565404236316680908203125"
+        );
+        let half: BigFixed = half_ulp.parse::<BigFixed>().unwrap().with_frac_limbs(4);
+        assert_eq!(half.to_f64(), 1.0);
+        assert_eq!(half.add(&ulp(4)).to_f64(), 1.0 + f64::EPSILON);
+        assert_eq!(half.sub(&ulp(4)).to_f64(), 1.0);
+    }
+
+    #[test]
+    fn precision_changes_truncate_towards_zero() {
+        let value: BigFixed = "-1.1".parse::<BigFixed>().unwrap().with_frac_limbs(3);
+        let coarse = value.with_frac_limbs(1);
+        assert_eq!(coarse.frac_limbs(), 1);
+        assert!(coarse.to_f64() >= value.to_f64() && coarse.to_f64() < -1.0999999);
+        assert_eq!(coarse.with_frac_limbs(3).with_frac_limbs(1), coarse);
+        let tiny = ulp(3).with_frac_limbs(1);
+        assert!(tiny.is_zero() && !tiny.neg().negative);
+    }
+}
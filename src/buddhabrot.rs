@@ -0,0 +1,473 @@
+//! Buddhabrot density rendering. Random points c are iterated and the
+//! orbits of those that escape are accumulated into a histogram of the
+//! viewport, which is then tone-mapped into colors. The Nebulabrot runs
+//! three iteration limits at once and maps them to red, green and blue.
+//!
+//! For zoomed views most random orbits miss the viewport entirely, so c can
+//! instead be sampled by Metropolis-Hastings, where the chain prefers points
+//! whose orbits cross the view. Each visit is weighted by the inverse of its
+//! contribution, so that both methods converge on the same image.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::Color,
+    fractal::{self, Fractal, Visitor},
+    palette::Palette,
+    rng::Rng,
+    scene::{Formula, Scene},
+    viewport::Bounds,
+};
+
+/// Independent sample streams, each with its own generator, so that the
+/// result does not depend on how many threads run them
+const CHAINS: u64 = 64;
+
+/// c is drawn from the square [-SAMPLE_RADIUS, SAMPLE_RADIUS]², which holds
+/// every set of the escape-time formulas
+const SAMPLE_RADIUS: f64 = 2.0;
+
+/// Fixed-point scale of the Metropolis weights, which keeps the histogram
+/// in integers and so independent of the order chains finish in
+const WEIGHT_SCALE: u64 = 1 << 24;
+
+/// Chance that a Metropolis step jumps to a fresh random point instead of
+/// mutating the current one
+const JUMP_PROBABILITY: f64 = 0.2;
+
+/// Fraction of the lit pixels that stay below full brightness, the rest
+/// clip so that a few hot spots do not darken the whole image
+const PEAK_PERCENTILE: f64 = 0.999;
+
+/// How c is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Sampling {
+    /// Uniformly over the whole set, best for views of all of it
+    Uniform,
+    /// Metropolis-Hastings, concentrating on orbits that cross the view
+    Metropolis,
+}
+
+/// Settings of a Buddhabrot render as stored in scene files. The iteration
+/// limit and bailout come from the scene's iteration settings.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BuddhabrotSpec {
+    /// Number of c values iterated
+    #[serde(default = "BuddhabrotSpec::default_samples")]
+    pub samples: u64,
+    #[serde(default)]
+    pub seed: u32,
+    #[serde(default = "BuddhabrotSpec::default_sampling")]
+    pub sampling: Sampling,
+    /// Iteration limits of the red, green and blue channels. Without them
+    /// the single density channel is colored through the palette.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nebulabrot: Option<[usize; 3]>,
+    /// Exponent applied to the normalized density, below 1 brings out faint
+    /// orbits
+    #[serde(default = "BuddhabrotSpec::default_gamma")]
+    pub gamma: f64,
+}
+
+impl BuddhabrotSpec {
+    fn default_samples() -> u64 {
+        10_000_000
+    }
+
+    fn default_sampling() -> Sampling {
+        Sampling::Uniform
+    }
+
+    fn default_gamma() -> f64 {
+        0.5
+    }
+}
+
+impl Default for BuddhabrotSpec {
+    fn default() -> Self {
+        Self {
+            samples: Self::default_samples(),
+            seed: 0,
+            sampling: Self::default_sampling(),
+            nebulabrot: None,
+            gamma: Self::default_gamma(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BuddhabrotStats {
+    pub samples: u64,
+    /// Escaping orbits that crossed the view
+    pub orbits: u64,
+    /// Accepted Metropolis proposals
+    pub accepted: u64,
+}
+
+impl BuddhabrotStats {
+    fn add(self, other: Self) -> Self {
+        Self {
+            samples: self.samples + other.samples,
+            orbits: self.orbits + other.orbits,
+            accepted: self.accepted + other.accepted,
+        }
+    }
+}
+
+/// Everything a chain needs to trace and record orbits
+struct Tracer<'a> {
+    spec: &'a BuddhabrotSpec,
+    bounds: Bounds,
+    width: usize,
+    height: usize,
+    /// Iteration limit of each histogram channel
+    limits: Vec<usize>,
+    max_limit: usize,
+    bailout_sqr: f64,
+    /// Skip points that are known never to escape, only valid for the
+    /// Mandelbrot formula
+    skip_interior: bool,
+    /// Pixel-major histogram with one counter per channel
+    histogram: Vec<AtomicU64>,
+}
+
+impl Tracer<'_> {
+    /// Image pixel that contains z
+    fn pixel(&self, (x, y): (f64, f64)) -> Option<usize> {
+        let (px, py) = self.bounds.locate(x, y);
+        let (px, py) = (px.floor(), py.floor());
+        if px >= 0.0 && py >= 0.0 && px < self.width as f64 && py < self.height as f64 {
+            Some(py as usize * self.width + px as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Number of iterations until c escapes, if it does within the largest
+    /// limit
+    fn escape<F: Fractal>(&self, fractal: &F, c: (f64, f64)) -> Option<usize> {
+        if self.skip_interior && fractal::in_cardioid_or_bulb(c.0, c.1) {
+            return None;
+        }
+        let mut z = fractal.initial_z(c);
+        for n in 1..=self.max_limit {
+            z = fractal.step(z, c);
+            if fractal.escaped(z, self.bailout_sqr) {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    /// Orbit points of c, escaping after n iterations, that land in the view
+    fn hits<F: Fractal>(&self, fractal: &F, c: (f64, f64), n: usize) -> u64 {
+        let mut z = fractal.initial_z(c);
+        let mut hits = 0;
+        for _ in 0..n {
+            z = fractal.step(z, c);
+            if self.pixel(z).is_some() {
+                hits += 1;
+            }
+        }
+        hits
+    }
+
+    /// Add `weight` for every orbit point of c to the channels whose limit
+    /// the orbit escaped within
+    fn record<F: Fractal>(&self, fractal: &F, c: (f64, f64), n: usize, weight: u64) {
+        let channels = self.limits.len();
+        let mut z = fractal.initial_z(c);
+        for _ in 0..n {
+            z = fractal.step(z, c);
+            if let Some(pixel) = self.pixel(z) {
+                for (channel, &limit) in self.limits.iter().enumerate() {
+                    if n <= limit {
+                        self.histogram[pixel * channels + channel]
+                            .fetch_add(weight, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    fn random_c(rng: &mut Rng) -> (f64, f64) {
+        (
+            rng.range(-SAMPLE_RADIUS, SAMPLE_RADIUS),
+            rng.range(-SAMPLE_RADIUS, SAMPLE_RADIUS),
+        )
+    }
+
+    /// Contribution of c: its escape iteration and the orbit points it puts
+    /// in the view, zero for points that never escape
+    fn evaluate<F: Fractal>(&self, fractal: &F, c: (f64, f64)) -> (usize, u64) {
+        if c.0.abs() > SAMPLE_RADIUS || c.1.abs() > SAMPLE_RADIUS {
+            return (0, 0);
+        }
+        match self.escape(fractal, c) {
+            Some(n) => (n, self.hits(fractal, c, n)),
+            None => (0, 0),
+        }
+    }
+
+    fn uniform<F: Fractal>(&self, fractal: &F, rng: &mut Rng, samples: u64) -> BuddhabrotStats {
+        let mut stats = BuddhabrotStats {
+            samples,
+            ..Default::default()
+        };
+        for _ in 0..samples {
+            let c = Self::random_c(rng);
+            if let Some(n) = self.escape(fractal, c) {
+                if self.hits(fractal, c, n) > 0 {
+                    stats.orbits += 1;
+                    self.record(fractal, c, n, 1);
+                }
+            }
+        }
+        stats
+    }
+
+    fn metropolis<F: Fractal>(&self, fractal: &F, rng: &mut Rng, samples: u64) -> BuddhabrotStats {
+        let mut stats = BuddhabrotStats {
+            samples,
+            ..Default::default()
+        };
+
+        // Mutations range from a tiny nudge up to a tenth of the view
+        let span = self.bounds.step * self.width.max(self.height) as f64;
+        let (min_radius, max_radius) = (1e-4 * span, 0.1 * span);
+
+        let mut remaining = samples;
+        let mut current = None;
+        while remaining > 0 && current.is_none() {
+            remaining -= 1;
+            let c = Self::random_c(rng);
+            let (n, hits) = self.evaluate(fractal, c);
+            if hits > 0 {
+                current = Some((c, n, hits));
+            }
+        }
+        let (mut c, mut n, mut hits) = match current {
+            Some(state) => state,
+            None => return stats,
+        };
+        stats.orbits += 1;
+
+        // Steps spent on the current state, recorded in one go once the
+        // chain moves on
+        let mut visits = 1;
+        for _ in 0..remaining {
+            let proposal = if rng.next_f64() < JUMP_PROBABILITY {
+                Self::random_c(rng)
+            } else {
+                let radius = max_radius * (-(max_radius / min_radius).ln() * rng.next_f64()).exp();
+                let angle = rng.range(0.0, std::f64::consts::TAU);
+                (c.0 + radius * angle.cos(), c.1 + radius * angle.sin())
+            };
+
+            let (proposed_n, proposed_hits) = self.evaluate(fractal, proposal);
+            if proposed_hits > 0 && rng.next_f64() * (hits as f64) < proposed_hits as f64 {
+                self.record(fractal, c, n, visits * WEIGHT_SCALE / hits);
+                c = proposal;
+                n = proposed_n;
+                hits = proposed_hits;
+                visits = 1;
+                stats.accepted += 1;
+                stats.orbits += 1;
+            } else {
+                visits += 1;
+            }
+        }
+        self.record(fractal, c, n, visits * WEIGHT_SCALE / hits);
+        stats
+    }
+
+    /// Run every chain on the current rayon pool
+    fn run<F: Fractal + Sync>(&self, fractal: &F) -> BuddhabrotStats {
+        let base = self.spec.samples / CHAINS;
+        let extra = self.spec.samples % CHAINS;
+        (0..CHAINS)
+            .into_par_iter()
+            .map(|chain| {
+                let mut rng = Rng::new((self.spec.seed as u64) << 32 | chain);
+                let samples = base + (chain < extra) as u64;
+                match self.spec.sampling {
+                    Sampling::Uniform => self.uniform(fractal, &mut rng, samples),
+                    Sampling::Metropolis => self.metropolis(fractal, &mut rng, samples),
+                }
+            })
+            .reduce(BuddhabrotStats::default, BuddhabrotStats::add)
+    }
+}
+
+impl<'a> Visitor for &Tracer<'a> {
+    type Output = BuddhabrotStats;
+
+    fn visit<F: Fractal + Sync>(self, fractal: &F) -> BuddhabrotStats {
+        self.run(fractal)
+    }
+}
+
+/// Render the density of the orbits through the scene's view, on the
+/// current rayon pool
+pub fn render(
+    spec: &BuddhabrotSpec,
+    scene: &Scene,
+    palette: &Palette,
+) -> (Vec<Color>, BuddhabrotStats) {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let (histogram, stats) = trace(spec, scene);
+    let channels = histogram.len() / (width * height);
+    let peaks: Vec<f64> = (0..channels)
+        .map(|channel| peak(histogram.iter().skip(channel).step_by(channels)))
+        .collect();
+    let brightness = |pixel: usize, channel: usize| -> f64 {
+        let density = histogram[pixel * channels + channel] as f64;
+        (density / peaks[channel]).min(1.0).powf(spec.gamma)
+    };
+
+    let colors = (0..width * height)
+        .map(|pixel| {
+            if channels == 3 {
+                let channel = |channel| (brightness(pixel, channel) * 255.0).round() as u8;
+                Color::new(channel(0), channel(1), channel(2))
+            } else {
+                palette.sample(brightness(pixel, 0) as f32)
+            }
+        })
+        .collect();
+    (colors, stats)
+}
+
+/// The pixel-major histogram of the orbits through the scene's view, with
+/// one channel per iteration limit
+fn trace(spec: &BuddhabrotSpec, scene: &Scene) -> (Vec<u64>, BuddhabrotStats) {
+    let (width, height) = (scene.image.width, scene.image.height);
+    let limits = match spec.nebulabrot {
+        Some(limits) => limits.to_vec(),
+        None => vec![scene.iteration.max_iterations],
+    };
+    let channels = limits.len();
+    let tracer = Tracer {
+        spec,
+        bounds: scene.viewport.bounds(width, height),
+        width,
+        height,
+        max_limit: limits.iter().copied().max().unwrap_or(0),
+        limits,
+        bailout_sqr: scene.iteration.bailout * scene.iteration.bailout,
+        skip_interior: scene.formula == Formula::Mandelbrot,
+        histogram: (0..width * height * channels)
+            .map(|_| AtomicU64::new(0))
+            .collect(),
+    };
+
+    let power = scene.power.unwrap_or(2.0);
+    let stats = fractal::visit(scene.formula, power, &tracer);
+
+    let histogram = tracer
+        .histogram
+        .into_iter()
+        .map(AtomicU64::into_inner)
+        .collect();
+    (histogram, stats)
+}
+
+/// Density that maps to full brightness
+fn peak<'a>(densities: impl Iterator<Item = &'a u64>) -> f64 {
+    let mut lit: Vec<u64> = densities.copied().filter(|&density| density > 0).collect();
+    if lit.is_empty() {
+        return 1.0;
+    }
+    let index = ((lit.len() - 1) as f64 * PEAK_PERCENTILE) as usize;
+    let (_, peak, _) = lit.select_nth_unstable(index);
+    *peak as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{floatexp::FloatExp, viewport::Viewport};
+
+    fn render_with_threads(spec: &BuddhabrotSpec, threads: usize) -> Vec<Color> {
+        let mut scene = Scene::default();
+        scene.image.width = 48;
+        scene.image.height = 32;
+        scene.viewport = Viewport::centered();
+        scene.iteration.max_iterations = 200;
+        scene.iteration.bailout = 2.0;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let palette = Palette::generate(&scene.palette, 1).unwrap();
+        pool.install(|| render(spec, &scene, &palette).0)
+    }
+
+    #[test]
+    fn seeded_renders_are_reproducible() {
+        for sampling in [Sampling::Uniform, Sampling::Metropolis] {
+            let spec = BuddhabrotSpec {
+                samples: 20_000,
+                seed: 7,
+                sampling,
+                nebulabrot: Some([20, 50, 200]),
+                gamma: 0.5,
+            };
+            let serial = render_with_threads(&spec, 1);
+            assert_eq!(serial, render_with_threads(&spec, 4), "{:?}", sampling);
+            assert!(serial.iter().any(|&color| color != Color::new(0, 0, 0)));
+
+            let reseeded = BuddhabrotSpec { seed: 8, ..spec };
+            assert_ne!(serial, render_with_threads(&reseeded, 1), "{:?}", sampling);
+        }
+    }
+
+    #[test]
+    fn metropolis_and_uniform_sampling_agree() {
+        // A view of part of the set that most orbits miss
+        let mut scene = Scene::default();
+        scene.image.width = 16;
+        scene.image.height = 12;
+        scene.viewport.center_x = "-0.5".parse().unwrap();
+        scene.viewport.center_y = "0.5".parse().unwrap();
+        scene.viewport.zoom = FloatExp::from_f64(3.0);
+        scene.iteration.max_iterations = 50;
+        scene.iteration.bailout = 2.0;
+
+        // Densities as fractions of the whole
+        let density = |sampling, samples| {
+            let spec = BuddhabrotSpec {
+                samples,
+                seed: 1,
+                sampling,
+                ..BuddhabrotSpec::default()
+            };
+            let (histogram, _) = trace(&spec, &scene);
+            let total: u64 = histogram.iter().sum();
+            histogram
+                .iter()
+                .map(|&count| count as f64 / total as f64)
+                .collect::<Vec<f64>>()
+        };
+        let uniform = density(Sampling::Uniform, 400_000);
+        let metropolis = density(Sampling::Metropolis, 400_000);
+
+        let lit = |density: &[f64]| density.iter().map(|&d| d > 0.0).collect::<Vec<_>>();
+        assert_eq!(lit(&uniform), lit(&metropolis));
+        // Half the summed difference is the share of the density that
+        // would have to move for the two to match
+        let moved: f64 = uniform
+            .iter()
+            .zip(&metropolis)
+            .map(|(a, b)| (a - b).abs())
+            .sum::<f64>()
+            / 2.0;
+        assert!(moved < 0.06, "{}", moved);
+    }
+}
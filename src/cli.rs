@@ -0,0 +1,1097 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    antialias::{AntialiasSpec, SamplePattern},
+    average::{AverageMethod, AverageSpec},
+    bignum::BigFixed,
+    buddhabrot::{BuddhabrotSpec, Sampling},
+    color::{Color, ColorSpace},
+    distance::{DistanceMode, DistanceSpec},
+    error::{Error, Result},
+    fill::Strategy,
+    floatexp::FloatExp,
+    gradient::GradientFile,
+    interior::{InteriorMode, InteriorSpec},
+    kernel::{Kernel, KernelChoice},
+    newton::{NewtonSpec, Polynomial},
+    output::FrameOutput,
+    palette::PalettePreset,
+    pyramid::{Layout, Pyramid, MAX_TILES, MAX_ZOOM},
+    render::RenderOptions,
+    scene::{Deltas, Formula, JuliaSpec, OutputFormat, Perturbation, Scene, SceneFormat},
+    stream,
+    trap::{TrapShape, TrapSpec},
+    video::{VideoEncoder, VideoFormat, VideoSpec},
+    viewport::{Mapping, Viewport},
+};
+
+#[derive(Parser)]
+#[command(
+    name = "mandelbrot",
+    version,
+    about = "Render images of the Mandelbrot set"
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Render the set to an image file
+    Render(RenderArgs),
+    /// Print the resolved render parameters without rendering
+    Info(RenderArgs),
+    /// Render a zoom animation between keyframes to numbered PNG frames
+    Animate(AnimateArgs),
+    /// Resample a strip rendered with `--mapping exponential` into the
+    /// frames of a zoom into its center
+    Assemble(AssembleArgs),
+    /// Render a tile pyramid for Deep Zoom or slippy map viewers
+    Export(ExportArgs),
+}
+
+/// Tile pyramid parameters. The render flags describe the full image, which
+/// the finest Deep Zoom level shows pixel for pixel.
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Directory the pyramid is written to
+    pub dir: PathBuf,
+
+    /// How the tiles are laid out on disk
+    #[arg(long, value_enum, default_value_t = Layout::Dzi)]
+    pub layout: Layout,
+
+    /// Edge of a tile in pixels, not counting the overlap [default: 254 for
+    /// dzi, 256 for xyz]
+    #[arg(long, value_name = "PIXELS")]
+    pub tile_pixels: Option<usize>,
+
+    /// Pixels each Deep Zoom tile shares with its neighbours [default: 1]
+    #[arg(long)]
+    pub overlap: Option<usize>,
+
+    /// Finest XYZ zoom level [default: the first whose pixels are no larger
+    /// than the image's]
+    #[arg(long)]
+    pub max_zoom: Option<u32>,
+
+    /// Name of the .dzi descriptor and its _files directory
+    #[arg(long, default_value = "mandelbrot")]
+    pub name: String,
+
+    /// Render every tile again instead of resuming after the ones already
+    /// in the directory
+    #[arg(long)]
+    pub restart: bool,
+
+    #[command(flatten)]
+    pub render: RenderArgs,
+}
+
+impl ExportArgs {
+    pub fn pyramid(&self, width: usize, height: usize) -> Result<Pyramid> {
+        let tile_size = self
+            .tile_pixels
+            .unwrap_or_else(|| self.layout.default_tile_size());
+        if tile_size == 0 {
+            return Err(Error::InvalidArgument(
+                "tiles must be at least 1 pixel wide".to_string(),
+            ));
+        }
+        let pyramid = match self.layout {
+            Layout::Dzi => {
+                if self.max_zoom.is_some() {
+                    return Err(Error::InvalidArgument(
+                        "--max-zoom only applies to the xyz layout, Deep Zoom levels follow the image size"
+                            .to_string(),
+                    ));
+                }
+                let overlap = self.overlap.unwrap_or(1);
+                if overlap >= tile_size {
+                    return Err(Error::InvalidArgument(format!(
+                        "overlap must be smaller than the {} pixel tiles, got {}",
+                        tile_size, overlap
+                    )));
+                }
+                Pyramid::dzi(width, height, tile_size, overlap)
+            }
+            Layout::Xyz => {
+                if self.overlap.is_some() {
+                    return Err(Error::InvalidArgument(
+                        "slippy map tiles do not overlap, --overlap only applies to the dzi layout"
+                            .to_string(),
+                    ));
+                }
+                if let Some(zoom) = self.max_zoom.filter(|&zoom| zoom > MAX_ZOOM) {
+                    return Err(Error::InvalidArgument(format!(
+                        "zoom levels go up to {}, got {}",
+                        MAX_ZOOM, zoom
+                    )));
+                }
+                Pyramid::xyz(width, height, tile_size, self.max_zoom)
+            }
+        };
+        match pyramid.tile_count() {
+            Some(count) if count <= MAX_TILES => Ok(pyramid),
+            count => Err(Error::InvalidArgument(format!(
+                "the pyramid would have {} tiles, more than the {} an export writes, use larger tiles or fewer levels",
+                count.map_or_else(|| "too many".to_string(), |count| count.to_string()),
+                MAX_TILES
+            ))),
+        }
+    }
+}
+
+/// Frame assembly parameters
+#[derive(Args, Debug)]
+pub struct AssembleArgs {
+    /// Exponential map image to resample
+    pub strip: PathBuf,
+
+    /// Scene file the strip was rendered from [default: the strip's path
+    /// with a .toml extension]
+    #[arg(short, long)]
+    pub scene: Option<PathBuf>,
+
+    /// Frame width in pixels. Strips about three times as wide keep the
+    /// frame edges sharp.
+    #[arg(short = 'W', long, default_value_t = 1920)]
+    pub width: usize,
+
+    /// Frame height in pixels
+    #[arg(short = 'H', long, default_value_t = 1080)]
+    pub height: usize,
+
+    /// Number of frames, spread evenly over the zoom the strip covers
+    /// [default: 30 per doubling of the zoom]
+    #[arg(long)]
+    pub count: Option<usize>,
+
+    /// Directory the frames are written to [default: frames]
+    #[arg(long, conflicts_with = "video")]
+    pub frames: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub video: VideoArgs,
+}
+
+/// Animation parameters. The render flags set everything the keyframes do
+/// not, which are the view and the palette offset.
+#[derive(Args, Debug)]
+pub struct AnimateArgs {
+    /// Keyframe file (.toml or .json) with a list of `keyframe` entries
+    pub keyframes: PathBuf,
+
+    /// Directory the frames are written to [default: frames]
+    #[arg(long, conflicts_with = "video")]
+    pub frames: Option<PathBuf>,
+
+    /// Render every frame again instead of resuming after the ones already
+    /// in the frames directory
+    #[arg(long, conflicts_with = "video")]
+    pub restart: bool,
+
+    #[command(flatten)]
+    pub video: VideoArgs,
+
+    #[command(flatten)]
+    pub render: RenderArgs,
+}
+
+/// Encoding of an animation into a single file or stream
+#[derive(Args, Debug)]
+pub struct VideoArgs {
+    /// Encode the frames into this file as they are made instead of writing
+    /// numbered PNGs, or stream them to stdout with `-`
+    #[arg(long, value_name = "FILE")]
+    pub video: Option<PathBuf>,
+
+    /// Video encoding, guessed from the --video extension when omitted
+    #[arg(long, value_enum, requires = "video")]
+    pub video_format: Option<VideoFormat>,
+
+    /// Frames per second of the video
+    #[arg(long, default_value_t = 30)]
+    pub fps: u32,
+
+    /// Map GIF frames to their palette without dithering
+    #[arg(long)]
+    pub no_dither: bool,
+}
+
+/// Render parameters. Flags override the values from `--scene`, which in
+/// turn override the built-in defaults.
+#[derive(Args, Debug)]
+#[command(allow_negative_numbers = true)]
+pub struct RenderArgs {
+    /// Scene file (.toml or .json) to start from
+    #[arg(short, long)]
+    pub scene: Option<PathBuf>,
+
+    /// Image width in pixels [default: 2560]
+    #[arg(short = 'W', long)]
+    pub width: Option<usize>,
+
+    /// Image height in pixels [default: 1440]
+    #[arg(short = 'H', long)]
+    pub height: Option<usize>,
+
+    /// Maximum number of iterations per point [default: 1000]
+    #[arg(short = 'i', long)]
+    pub max_iterations: Option<usize>,
+
+    /// Escape radius [default: 256]
+    #[arg(short, long)]
+    pub bailout: Option<f64>,
+
+    /// Real coordinate of the image center, as many digits as needed
+    #[arg(short = 'x', long)]
+    pub center_x: Option<BigFixed>,
+
+    /// Imaginary coordinate of the image center, as many digits as needed
+    #[arg(short = 'y', long)]
+    pub center_y: Option<BigFixed>,
+
+    /// Magnification, where zoom 1 shows 3.5 units of the real axis. Any
+    /// exponent is accepted, such as 1e500 [default: 7]
+    #[arg(short, long)]
+    pub zoom: Option<FloatExp>,
+
+    /// Turn of the view about its center in degrees, counterclockwise
+    /// [default: 0]
+    #[arg(long)]
+    pub rotation: Option<f64>,
+
+    /// How pixels are laid over the plane. An exponential map is a strip
+    /// as wide as one turn round the center, for `assemble` to turn into
+    /// zoom frames [default: cartesian]
+    #[arg(long, value_enum)]
+    pub mapping: Option<Mapping>,
+
+    /// Iteration formula [default: mandelbrot]
+    #[arg(short = 'F', long, value_enum)]
+    pub formula: Option<Formula>,
+
+    /// Exponent n of the multibrot formula, any real number above 1
+    #[arg(short = 'n', long)]
+    pub power: Option<f64>,
+
+    /// Polynomial for the newton and nova formulas, such as "z^3 - 1" or
+    /// "(1+2i)z^4 - z" [default: z^3 - 1]
+    #[arg(long)]
+    pub polynomial: Option<Polynomial>,
+
+    /// Step size of the newton and nova formulas, 1 is plain Newton's method
+    /// [default: 1]
+    #[arg(long)]
+    pub relaxation: Option<f64>,
+
+    /// Render the Julia set for the constant c = X + Y i. Without --scene the
+    /// view starts out showing the whole Julia set.
+    #[arg(long, value_name = "X,Y", conflicts_with = "julia_pixel")]
+    pub julia: Option<JuliaSpec>,
+
+    /// Render the Julia set for the point under pixel (X, Y) of the Mandelbrot
+    /// image described by the other options. The view then changes to show
+    /// the whole Julia set at the same image size.
+    #[arg(long, value_name = "X,Y", value_parser = parse_pixel)]
+    pub julia_pixel: Option<(usize, usize)>,
+
+    /// Render the density of escaping orbits (the Buddhabrot) instead of
+    /// escape times. Implied by the other buddhabrot options.
+    #[arg(long)]
+    pub buddhabrot: bool,
+
+    /// Number of random points iterated for the buddhabrot [default: 10000000]
+    #[arg(long)]
+    pub samples: Option<u64>,
+
+    /// Seed of the buddhabrot's random points [default: 0]
+    #[arg(long)]
+    pub seed: Option<u32>,
+
+    /// How the buddhabrot picks points, metropolis suits zoomed views
+    /// [default: uniform]
+    #[arg(long, value_enum)]
+    pub sampling: Option<Sampling>,
+
+    /// Render a nebulabrot with these red, green and blue iteration limits
+    #[arg(long, value_name = "R,G,B", value_parser = parse_limits)]
+    pub nebulabrot: Option<[usize; 3]>,
+
+    /// Color by the estimated distance to the set, implied by the other
+    /// distance options [default: boundary]
+    #[arg(long, value_enum)]
+    pub distance: Option<DistanceMode>,
+
+    /// Boundary width, or distance at which shading is half way, in pixels
+    /// [default: 1]
+    #[arg(long, value_name = "PIXELS")]
+    pub thickness: Option<f64>,
+
+    /// Color of the distance-estimated boundary [default: #ffffff]
+    #[arg(long, value_name = "COLOR")]
+    pub boundary_color: Option<Color>,
+
+    /// Color by how close orbits come to this shape, implied by the other
+    /// trap options [default: point, or image with --trap-image]
+    #[arg(long, value_enum)]
+    pub trap: Option<TrapShape>,
+
+    /// Center of the trap shape [default: 0,0]
+    #[arg(long, value_name = "X,Y", value_parser = parse_point)]
+    pub trap_center: Option<(f64, f64)>,
+
+    /// Orbit distance at which the trap's palette runs out, or the width of
+    /// an image trap [default: 0.25]
+    #[arg(long)]
+    pub trap_size: Option<f64>,
+
+    /// Angle of a line trap in degrees [default: 0]
+    #[arg(long)]
+    pub trap_angle: Option<f64>,
+
+    /// Radius of a circle trap [default: 1]
+    #[arg(long)]
+    pub trap_radius: Option<f64>,
+
+    /// Texture of an image trap
+    #[arg(long, value_name = "FILE")]
+    pub trap_image: Option<PathBuf>,
+
+    /// Color by a statistic averaged over the orbit
+    #[arg(long, value_enum)]
+    pub average: Option<AverageMethod>,
+
+    /// Color points that never escape by their attracting cycle
+    #[arg(long, value_enum)]
+    pub interior: Option<InteriorMode>,
+
+    /// Interior distance in pixels at which the palette is half way,
+    /// implies --interior distance [default: 16]
+    #[arg(long, value_name = "PIXELS")]
+    pub interior_falloff: Option<f64>,
+
+    /// Stripes per turn of the stripe average, implies --average stripe
+    /// [default: 5]
+    #[arg(long)]
+    pub stripe_density: Option<f64>,
+
+    /// Average an N x N grid of samples per pixel
+    #[arg(long, value_name = "N")]
+    pub supersample: Option<usize>,
+
+    /// Placement of the supersamples, implies --supersample 3 [default: grid]
+    #[arg(long, value_enum)]
+    pub sample_pattern: Option<SamplePattern>,
+
+    /// Only supersample pixels whose color differs from a neighbour's by
+    /// more than this fraction of the full range, implies --supersample 3
+    /// [default: 0.1]
+    #[arg(long, value_name = "THRESHOLD", num_args = 0..=1, default_missing_value = "0.1")]
+    pub adaptive: Option<f64>,
+
+    /// Iterate pixels against a high-precision reference orbit [default: auto]
+    #[arg(long, value_enum)]
+    pub perturbation: Option<Perturbation>,
+
+    /// Number type of perturbation deltas [default: auto]
+    #[arg(long, value_enum)]
+    pub deltas: Option<Deltas>,
+
+    /// Color ramp used for escaping points [default: ocean]
+    #[arg(short, long, value_enum, conflicts_with = "gradient")]
+    pub palette: Option<PalettePreset>,
+
+    /// Shift of the palette colors as a fraction of the ramp [default: 0]
+    #[arg(long)]
+    pub palette_offset: Option<f64>,
+
+    /// Space the palette stops are blended in [default: srgb]
+    #[arg(long, value_enum)]
+    pub color_space: Option<ColorSpace>,
+
+    /// Color escaping points from a GIMP .ggr, Fractint .map or UltraFractal .ugr/.gradient file
+    #[arg(long, value_name = "FILE")]
+    pub gradient: Option<PathBuf>,
+
+    /// Gradient to use from a file holding several
+    #[arg(long, value_name = "NAME", requires = "gradient")]
+    pub gradient_name: Option<String>,
+
+    /// Image encoding, guessed from the output extension when omitted [default: png]
+    #[arg(short, long, value_enum)]
+    pub format: Option<OutputFormat>,
+
+    /// Output image path [default: mandelbrot.<format extension>]
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Render a band of rows at a time and write each to the PNG or TIFF
+    /// output as it is done, for images too large to hold in memory
+    #[arg(long)]
+    pub stream: bool,
+
+    /// Rows per band of a streamed render [default: about 4 million
+    /// pixels' worth]
+    #[arg(long, value_name = "ROWS", requires = "stream")]
+    pub band_rows: Option<usize>,
+
+    /// Worker threads, 0 uses every core and 1 renders serially without tiles
+    #[arg(short = 'j', long, default_value_t = RenderOptions::default().threads)]
+    pub threads: usize,
+
+    /// Edge length in pixels of the tiles handed to worker threads
+    #[arg(long, default_value_t = RenderOptions::default().tile_size)]
+    pub tile_size: usize,
+
+    /// Escape-time kernel, auto picks the widest SIMD kernel the CPU supports
+    #[arg(long, value_enum, default_value_t = KernelChoice::Auto)]
+    pub kernel: KernelChoice,
+
+    /// Iterate points in the main cardioid and period-2 bulb instead of
+    /// skipping them
+    #[arg(long)]
+    pub no_cardioid: bool,
+
+    /// Iterate orbits caught in a cycle all the way to the iteration limit
+    #[arg(long)]
+    pub no_periodicity: bool,
+
+    /// Which pixels of a tile are iterated, the rest are filled in from
+    /// the pixels around them
+    #[arg(long, value_enum, default_value_t = RenderOptions::default().strategy)]
+    pub strategy: Strategy,
+}
+
+/// A fully resolved render: the scene plus where to put the results
+#[derive(Debug, Clone)]
+pub struct RenderJob {
+    pub scene: Scene,
+    pub output: PathBuf,
+    /// Set to render in bands of this many rows, each written to the output
+    /// as soon as it is done
+    pub band_rows: Option<usize>,
+    /// Encoding used for the scene file written next to the image
+    pub scene_format: SceneFormat,
+    pub options: RenderOptions,
+}
+
+impl RenderArgs {
+    pub fn job(&self) -> Result<RenderJob> {
+        let (mut scene, scene_format) = match &self.scene {
+            Some(path) => (Scene::load(path)?, SceneFormat::from_path(path)?),
+            None => (Scene::default(), SceneFormat::Toml),
+        };
+
+        if let Some(julia) = &self.julia {
+            if self.scene.is_none() {
+                scene.viewport = Viewport::centered();
+            }
+            scene.julia = Some(julia.clone());
+        }
+
+        if let Some(formula) = self.formula {
+            scene.formula = formula;
+            if formula != Formula::Multibrot {
+                scene.power = None;
+            }
+            if matches!(formula, Formula::Newton | Formula::Nova) {
+                if self.scene.is_none() {
+                    scene.viewport = Viewport::centered();
+                }
+                scene.newton.get_or_insert_with(NewtonSpec::default);
+            } else {
+                scene.newton = None;
+            }
+        }
+        if let Some(power) = self.power {
+            scene.power = Some(power);
+        }
+        if let Some(polynomial) = &self.polynomial {
+            match &mut scene.newton {
+                Some(newton) => newton.polynomial = polynomial.clone(),
+                None => scene.newton = Some(NewtonSpec::new(polynomial.clone())),
+            }
+        }
+        if let Some(relaxation) = self.relaxation {
+            scene
+                .newton
+                .get_or_insert_with(NewtonSpec::default)
+                .relaxation = relaxation;
+        }
+        if self.buddhabrot
+            || self.samples.is_some()
+            || self.seed.is_some()
+            || self.sampling.is_some()
+            || self.nebulabrot.is_some()
+        {
+            let buddhabrot = scene.buddhabrot.get_or_insert_with(BuddhabrotSpec::default);
+            if let Some(samples) = self.samples {
+                buddhabrot.samples = samples;
+            }
+            if let Some(seed) = self.seed {
+                buddhabrot.seed = seed;
+            }
+            if let Some(sampling) = self.sampling {
+                buddhabrot.sampling = sampling;
+            }
+            if let Some(limits) = self.nebulabrot {
+                buddhabrot.nebulabrot = Some(limits);
+            }
+        }
+        if self.distance.is_some() || self.thickness.is_some() || self.boundary_color.is_some() {
+            let distance = scene.distance.get_or_insert_with(DistanceSpec::default);
+            if let Some(mode) = self.distance {
+                distance.mode = mode;
+            }
+            if let Some(thickness) = self.thickness {
+                distance.thickness = thickness;
+            }
+            if let Some(color) = self.boundary_color {
+                distance.color = color;
+            }
+        }
+        if self.trap.is_some()
+            || self.trap_center.is_some()
+            || self.trap_size.is_some()
+            || self.trap_angle.is_some()
+            || self.trap_radius.is_some()
+            || self.trap_image.is_some()
+        {
+            let default_shape = match self.trap_image {
+                Some(_) => TrapShape::Image,
+                None => TrapShape::Point,
+            };
+            let trap = scene
+                .trap
+                .get_or_insert_with(|| TrapSpec::new(default_shape));
+            if let Some(shape) = self.trap {
+                trap.shape = shape;
+            }
+            if let Some((x, y)) = self.trap_center {
+                trap.x = x;
+                trap.y = y;
+            }
+            if let Some(size) = self.trap_size {
+                trap.size = size;
+            }
+            if self.trap_angle.is_some() {
+                trap.angle = self.trap_angle;
+            }
+            if self.trap_radius.is_some() {
+                trap.radius = self.trap_radius;
+            }
+            if self.trap_image.is_some() {
+                trap.image = self.trap_image.clone();
+            }
+        }
+        if self.average.is_some() || self.stripe_density.is_some() {
+            let method = self.average.unwrap_or(AverageMethod::Stripe);
+            let average = scene
+                .average
+                .get_or_insert_with(|| AverageSpec::new(method));
+            average.method = method;
+            if self.stripe_density.is_some() {
+                average.density = self.stripe_density;
+            }
+        }
+        if self.interior.is_some() || self.interior_falloff.is_some() {
+            let mode = self.interior.unwrap_or(InteriorMode::Distance);
+            let interior = scene
+                .interior
+                .get_or_insert_with(|| InteriorSpec::new(mode));
+            interior.mode = mode;
+            if self.interior_falloff.is_some() {
+                interior.falloff = self.interior_falloff;
+            }
+        }
+        if self.supersample.is_some() || self.sample_pattern.is_some() || self.adaptive.is_some() {
+            let antialias = scene
+                .antialias
+                .get_or_insert_with(|| AntialiasSpec::new(AntialiasSpec::DEFAULT_GRID));
+            if let Some(grid) = self.supersample {
+                antialias.grid = grid;
+            }
+            if let Some(pattern) = self.sample_pattern {
+                antialias.pattern = pattern;
+            }
+            if self.adaptive.is_some() {
+                antialias.adaptive = self.adaptive;
+            }
+        }
+        if let Some(width) = self.width {
+            scene.image.width = width;
+        }
+        if let Some(height) = self.height {
+            scene.image.height = height;
+        }
+        if let Some(max_iterations) = self.max_iterations {
+            scene.iteration.max_iterations = max_iterations;
+        }
+        if let Some(bailout) = self.bailout {
+            scene.iteration.bailout = bailout;
+        }
+        if let Some(perturbation) = self.perturbation {
+            scene.iteration.perturbation = perturbation;
+        }
+        if let Some(deltas) = self.deltas {
+            scene.iteration.deltas = deltas;
+        }
+        if let Some(center_x) = &self.center_x {
+            scene.viewport.center_x = center_x.clone();
+        }
+        if let Some(center_y) = &self.center_y {
+            scene.viewport.center_y = center_y.clone();
+        }
+        if let Some(zoom) = self.zoom {
+            scene.viewport.zoom = zoom;
+        }
+        if let Some(rotation) = self.rotation {
+            scene.viewport.rotation = rotation;
+        }
+        if let Some(mapping) = self.mapping {
+            scene.viewport.mapping = mapping;
+        }
+        if let Some(palette) = self.palette {
+            scene.palette = palette.into();
+        }
+        if let Some(offset) = self.palette_offset {
+            scene.palette.offset = offset;
+        }
+        if let Some(space) = self.color_space {
+            scene.palette.color_space = space;
+        }
+        if let Some(file) = &self.gradient {
+            scene.palette.stops.clear();
+            scene.palette.gradient = Some(GradientFile {
+                file: file.clone(),
+                name: self.gradient_name.clone(),
+            });
+        }
+        if let Some((x, y)) = self.julia_pixel {
+            pick_julia(&mut scene, x, y)?;
+        }
+
+        match (self.format, &self.output) {
+            (Some(format), Some(output)) => {
+                if OutputFormat::from_path(output).is_some_and(|guess| guess != format) {
+                    return Err(Error::InvalidArgument(format!(
+                        "output {} does not match the {:?} format",
+                        output.display(),
+                        format
+                    )));
+                }
+                scene.image.format = format;
+            }
+            (Some(format), None) => scene.image.format = format,
+            (None, Some(output)) => {
+                scene.image.format = OutputFormat::from_path(output).ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "cannot tell the image format of {} from its extension (try .png or --format)",
+                        output.display()
+                    ))
+                })?;
+            }
+            (None, None) => {}
+        }
+
+        scene.validate()?;
+        if self.tile_size == 0 {
+            return Err(Error::InvalidArgument(
+                "tile size must be at least 1".to_string(),
+            ));
+        }
+
+        let output = self.output.clone().unwrap_or_else(|| {
+            PathBuf::from("mandelbrot").with_extension(scene.image.format.extension())
+        });
+        if self.band_rows == Some(0) {
+            return Err(Error::InvalidArgument(
+                "bands must be at least 1 row high".to_string(),
+            ));
+        }
+        let band_rows = self.stream.then(|| {
+            self.band_rows
+                .unwrap_or_else(|| stream::default_band_rows(scene.image.width))
+        });
+        if band_rows.is_some()
+            && !matches!(scene.image.format, OutputFormat::Png | OutputFormat::Tiff)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "streamed images are written as PNG or TIFF, not {:?}",
+                scene.image.format
+            )));
+        }
+        if band_rows.is_some() && scene.buddhabrot.is_some() {
+            return Err(Error::InvalidArgument(
+                "the buddhabrot accumulates orbits over the whole image and cannot be streamed"
+                    .to_string(),
+            ));
+        }
+
+        let options = RenderOptions {
+            threads: self.threads,
+            tile_size: self.tile_size,
+            kernel: Kernel::select(self.kernel)?,
+            cardioid: !self.no_cardioid,
+            periodicity: !self.no_periodicity,
+            strategy: self.strategy,
+        };
+        options.check(&scene)?;
+
+        Ok(RenderJob {
+            scene,
+            output,
+            band_rows,
+            scene_format,
+            options,
+        })
+    }
+}
+
+/// Turn a Mandelbrot scene into the Julia set for the point under pixel
+/// (x, y)
+fn pick_julia(scene: &mut Scene, x: usize, y: usize) -> Result<()> {
+    if scene.julia.is_some() {
+        return Err(Error::InvalidArgument(
+            "--julia-pixel picks from a Mandelbrot image, but the scene already renders a Julia set"
+                .to_string(),
+        ));
+    }
+    let (width, height) = (scene.image.width, scene.image.height);
+    if x >= width || y >= height {
+        return Err(Error::InvalidArgument(format!(
+            "pixel ({}, {}) is outside the {} x {} image",
+            x, y, width, height
+        )));
+    }
+
+    let (cx, cy) = scene.viewport.pixel(width, height, x, y);
+    scene.julia = Some(JuliaSpec { x: cx, y: cy });
+    scene.viewport = Viewport::centered();
+    Ok(())
+}
+
+fn parse_pixel(s: &str) -> std::result::Result<(usize, usize), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected a pixel as \"x,y\", got \"{}\"", s))?;
+    let x = x.trim().parse().map_err(|err| format!("{}: {}", x, err))?;
+    let y = y.trim().parse().map_err(|err| format!("{}: {}", y, err))?;
+    Ok((x, y))
+}
+
+fn parse_point(s: &str) -> std::result::Result<(f64, f64), String> {
+    let (x, y) = s
+        .split_once(',')
+        .ok_or_else(|| format!("expected a point as \"x,y\", got \"{}\"", s))?;
+    let x = x.trim().parse().map_err(|err| format!("{}: {}", x, err))?;
+    let y = y.trim().parse().map_err(|err| format!("{}: {}", y, err))?;
+    Ok((x, y))
+}
+
+fn parse_limits(s: &str) -> std::result::Result<[usize; 3], String> {
+    let limits: Vec<&str> = s.split(',').collect();
+    match limits[..] {
+        [red, green, blue] => {
+            let parse = |limit: &str| {
+                limit
+                    .trim()
+                    .parse()
+                    .map_err(|err| format!("{}: {}", limit, err))
+            };
+            Ok([parse(red)?, parse(green)?, parse(blue)?])
+        }
+        _ => Err(format!("expected three limits as \"r,g,b\", got \"{}\"", s)),
+    }
+}
+
+impl AnimateArgs {
+    pub fn job(&self) -> Result<RenderJob> {
+        if self.render.output.is_some() || self.render.format.is_some() {
+            return Err(Error::InvalidArgument(
+                "frames are written as PNG to the frames directory, use --frames to move them or --video to encode them"
+                    .to_string(),
+            ));
+        }
+        self.render.job()
+    }
+}
+
+impl VideoArgs {
+    /// Open the video if one was asked for, otherwise the directory for
+    /// numbered frames
+    pub fn output(
+        &self,
+        frames_dir: &Option<PathBuf>,
+        width: usize,
+        height: usize,
+        frames: usize,
+    ) -> Result<FrameOutput> {
+        let path = match &self.video {
+            Some(path) => path,
+            None => {
+                let dir = frames_dir
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from("frames"));
+                fs::create_dir_all(&dir)?;
+                return Ok(FrameOutput::Files(dir));
+            }
+        };
+        let format = match (self.video_format, VideoFormat::from_path(path)) {
+            (Some(format), Some(guess)) if format != guess => {
+                return Err(Error::InvalidArgument(format!(
+                    "video {} does not match the {:?} format",
+                    path.display(),
+                    format
+                )))
+            }
+            (Some(format), _) | (None, Some(format)) => format,
+            (None, None) => {
+                return Err(Error::InvalidArgument(format!(
+                    "cannot tell the video format of {} from its extension (try .gif or --video-format)",
+                    path.display()
+                )))
+            }
+        };
+        let spec = VideoSpec {
+            format,
+            width,
+            height,
+            frames,
+            fps: self.fps,
+            dither: !self.no_dither,
+        };
+        let encoder = VideoEncoder::create(path, spec)?;
+        Ok(FrameOutput::Video(encoder, path.clone()))
+    }
+
+    /// Whether the video goes to stdout, which leaves progress messages to
+    /// stderr
+    pub fn to_stdout(&self) -> bool {
+        self.video.as_deref() == Some(Path::new("-"))
+    }
+}
+
+impl RenderJob {
+    /// Path of the scene file written next to the image
+    pub fn scene_path(&self) -> PathBuf {
+        self.output.with_extension(self.scene_format.extension())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch;
+    use clap::{error::ErrorKind, Parser};
+
+    fn parse(args: &[&str]) -> std::result::Result<Command, clap::Error> {
+        Cli::try_parse_from(std::iter::once("mandelbrot").chain(args.iter().copied()))
+            .map(|cli| cli.command)
+    }
+
+    fn render_job(args: &[&str]) -> Result<RenderJob> {
+        match parse(&[&["render"], args].concat()).unwrap() {
+            Command::Render(args) => args.job(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn export_args(args: &[&str]) -> ExportArgs {
+        match parse(&[&["export", "tiles"], args].concat()).unwrap() {
+            Command::Export(args) => args,
+            _ => unreachable!(),
+        }
+    }
+
+    fn is_invalid<T>(result: Result<T>) -> bool {
+        matches!(result, Err(Error::InvalidArgument(_)))
+    }
+
+    #[test]
+    fn flags_override_the_scene_over_the_defaults() {
+        // Without a scene every value not given is the default
+        let job = render_job(&["-W", "320", "-z", "1e3"]).unwrap();
+        let mut expected = Scene::default();
+        expected.image.width = 320;
+        expected.viewport.zoom = FloatExp::from_f64(1e3);
+        assert_eq!(job.scene, expected);
+        assert_eq!(job.output, PathBuf::from("mandelbrot.png"));
+        assert_eq!(job.scene_format, SceneFormat::Toml);
+
+        let dir = scratch("cli-merge");
+        let path = dir.join("scene.json");
+        let mut saved = Scene::default();
+        saved.image.width = 300;
+        saved.image.height = 200;
+        saved.iteration.max_iterations = 500;
+        saved.viewport.center_x = "-1.25".parse().unwrap();
+        saved.save(&path, SceneFormat::Json).unwrap();
+        let scene = path.to_str().unwrap();
+
+        // Flags win over the scene, which wins over the defaults
+        let job = render_job(&["--scene", scene, "-W", "640", "-i", "2000"]).unwrap();
+        assert_eq!(job.scene.image.width, 640);
+        assert_eq!(job.scene.image.height, 200);
+        assert_eq!(job.scene.iteration.max_iterations, 2000);
+        assert_eq!(job.scene.viewport.center_x, saved.viewport.center_x);
+        assert_eq!(
+            job.scene.iteration.bailout,
+            Scene::default().iteration.bailout
+        );
+        assert_eq!(job.scene_format, SceneFormat::Json);
+
+        // A Newton formula shows the whole plane unless a scene sets the view
+        let job = render_job(&["--formula", "newton"]).unwrap();
+        assert_eq!(job.scene.viewport, Viewport::centered());
+        assert_eq!(job.scene.newton, Some(NewtonSpec::default()));
+        let job = render_job(&["--scene", scene, "--formula", "newton"]).unwrap();
+        assert_eq!(job.scene.viewport, saved.viewport);
+        fs::remove_dir_all(dir).unwrap();
+
+        // Options that imply their mode switch it on with its defaults
+        let job = render_job(&["--thickness", "2"]).unwrap();
+        let distance = job.scene.distance.unwrap();
+        assert_eq!(distance.thickness, 2.0);
+        assert_eq!(distance.mode, DistanceSpec::default().mode);
+        let job = render_job(&["--adaptive"]).unwrap();
+        let antialias = job.scene.antialias.unwrap();
+        assert_eq!(antialias.grid, AntialiasSpec::DEFAULT_GRID);
+        assert_eq!(antialias.adaptive, Some(0.1));
+    }
+
+    #[test]
+    fn render_arguments_are_checked() {
+        assert!(is_invalid(render_job(&["-W", "0"])));
+        assert!(is_invalid(render_job(&["--tile-size", "0"])));
+        assert!(is_invalid(render_job(&[
+            "-o",
+            "image.png",
+            "--format",
+            "jpeg"
+        ])));
+        assert!(is_invalid(render_job(&["-o", "image.unknown"])));
+        assert!(is_invalid(render_job(&["--stream", "-o", "image.bmp"])));
+        assert!(is_invalid(render_job(&["--julia-pixel", "5000,10"])));
+        assert_eq!(
+            render_job(&["-o", "image.tif"]).unwrap().scene.image.format,
+            OutputFormat::Tiff
+        );
+
+        let kind = |args: &[&str]| parse(args).err().map(|err| err.kind());
+        assert_eq!(
+            kind(&["render", "--julia", "0,1", "--julia-pixel", "1,1"]),
+            Some(ErrorKind::ArgumentConflict)
+        );
+        assert_eq!(
+            kind(&["render", "--band-rows", "8"]),
+            Some(ErrorKind::MissingRequiredArgument)
+        );
+        assert_eq!(
+            kind(&["render", "--julia-pixel", "1;1"]),
+            Some(ErrorKind::ValueValidation)
+        );
+        assert_eq!(kind(&["render", "-x", "-0.5"]), None);
+    }
+
+    #[test]
+    fn animations_only_write_frames_or_video() {
+        for flags in [&["-o", "frame.png"][..], &["--format", "png"]] {
+            let command = parse(&[&["animate", "keys.toml"], flags].concat()).unwrap();
+            match command {
+                Command::Animate(args) => assert!(is_invalid(args.job())),
+                _ => unreachable!(),
+            }
+        }
+        assert_eq!(
+            parse(&[
+                "animate",
+                "keys.toml",
+                "--video",
+                "a.gif",
+                "--frames",
+                "dir"
+            ])
+            .err()
+            .map(|err| err.kind()),
+            Some(ErrorKind::ArgumentConflict)
+        );
+        assert_eq!(
+            parse(&["animate", "keys.toml", "--video-format", "gif"])
+                .err()
+                .map(|err| err.kind()),
+            Some(ErrorKind::MissingRequiredArgument)
+        );
+    }
+
+    #[test]
+    fn videos_take_their_format_from_the_flag_or_the_extension() {
+        let dir = scratch("cli-video");
+        let video = |name: &str, format: Option<&str>| {
+            let path = dir.join(name);
+            let mut args = vec!["animate", "keys.toml", "--video", path.to_str().unwrap()];
+            if let Some(format) = format {
+                args.extend(["--video-format", format]);
+            }
+            let args = match parse(&args).unwrap() {
+                Command::Animate(args) => args,
+                _ => unreachable!(),
+            };
+            let output = args.video.output(&None, 4, 2, 1);
+            // Dropping the encoder flushes what it wrote so far
+            output.map(|output| {
+                drop(output);
+                fs::read(&path).unwrap()
+            })
+        };
+
+        assert!(video("a.gif", None).unwrap().starts_with(b"GIF89a"));
+        assert!(video("a.png", None).unwrap().starts_with(b"\x89PNG"));
+        assert!(video("a.y4m", None).unwrap().starts_with(b"YUV4MPEG2"));
+        assert!(video("a.bin", Some("gif")).unwrap().starts_with(b"GIF89a"));
+        assert!(is_invalid(video("b.gif", Some("apng"))));
+        assert!(is_invalid(video("b.bin", None)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pyramid_arguments_are_checked() {
+        let pyramid = |args: &[&str]| export_args(args).pyramid(1000, 600);
+        let dzi = pyramid(&[]).unwrap();
+        assert_eq!(dzi.layout, Layout::Dzi);
+        assert_eq!(dzi.levels().len(), 11);
+        let xyz = pyramid(&["--layout", "xyz", "--max-zoom", "4"]).unwrap();
+        assert_eq!(xyz.levels().len(), 5);
+
+        assert!(is_invalid(pyramid(&["--tile-pixels", "0"])));
+        assert!(is_invalid(pyramid(&["--max-zoom", "3"])));
+        assert!(is_invalid(pyramid(&[
+            "--tile-pixels",
+            "8",
+            "--overlap",
+            "8"
+        ])));
+        assert!(is_invalid(pyramid(&["--layout", "xyz", "--overlap", "1"])));
+        assert!(is_invalid(pyramid(&[
+            "--layout",
+            "xyz",
+            "--max-zoom",
+            "31"
+        ])));
+        // Within the zoom limit, but with far too many tiles to write
+        assert!(is_invalid(pyramid(&[
+            "--layout",
+            "xyz",
+            "--max-zoom",
+            "30"
+        ])));
+    }
+}
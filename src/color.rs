@@ -0,0 +1,355 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use clap::ValueEnum;
+use interpolation::lerp;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color {
+    pub red: u8,
+    pub blue: u8,
+    pub green: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self {
+            red: r,
+            blue: b,
+            green: g,
+        }
+    }
+
+    pub fn as_slice(&self) -> [u8; 4] {
+        [self.red, self.green, self.blue, 0xFF]
+    }
+
+    pub fn interpolate(&self, other_color: &Color, t: f32) -> Self {
+        Self {
+            red: lerp(&self.red, &other_color.red, &t),
+            green: lerp(&self.green, &other_color.green, &t),
+            blue: lerp(&self.blue, &other_color.blue, &t),
+        }
+    }
+}
+
+impl FromStr for Color {
+    type Err = String;
+
+    /// Parse a `#rrggbb` hex color, the leading `#` is optional
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid color {:?}, expected #rrggbb", s));
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(Color::new(channel(0), channel(2), channel(4)))
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Color> for String {
+    fn from(color: Color) -> Self {
+        color.to_string()
+    }
+}
+
+/// Space in which colors are mixed when blending between palette stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorSpace {
+    /// Directly on the encoded channels
+    Srgb,
+    /// On light intensity, with the sRGB transfer curve removed
+    Linear,
+    /// Hue, saturation and value, turning the short way round the hue circle
+    Hsv,
+    /// Hue, saturation and lightness, turning the short way round
+    Hsl,
+    /// CIE L*a*b* with a D65 white point
+    Lab,
+    Oklab,
+    /// OKLab as lightness, chroma and hue, turning the short way round
+    Oklch,
+}
+
+impl Color {
+    /// Blend towards `other` in the given color space
+    pub fn interpolate_in(&self, other: &Color, t: f32, space: ColorSpace) -> Self {
+        let t = t as f64;
+        let (a, b) = (self.to_rgb(), other.to_rgb());
+        let rgb = match space {
+            ColorSpace::Srgb => return self.interpolate(other, t as f32),
+            ColorSpace::Linear => {
+                let mixed = mix(a.map(decode), b.map(decode), t);
+                mixed.map(encode)
+            }
+            ColorSpace::Hsv => hsv_to_rgb(mix_polar(rgb_to_hsv(a), rgb_to_hsv(b), t, 1)),
+            ColorSpace::Hsl => hsl_to_rgb(mix_polar(rgb_to_hsl(a), rgb_to_hsl(b), t, 1)),
+            ColorSpace::Lab => lab_to_rgb(mix(rgb_to_lab(a), rgb_to_lab(b), t)),
+            ColorSpace::Oklab => oklab_to_rgb(mix(rgb_to_oklab(a), rgb_to_oklab(b), t)),
+            ColorSpace::Oklch => {
+                let (a, b) = (to_lch(rgb_to_oklab(a)), to_lch(rgb_to_oklab(b)));
+                oklab_to_rgb(from_lch(mix_polar(a, b, t, 1)))
+            }
+        };
+        Self::from_rgb(rgb)
+    }
+
+    /// Channels from 0 to 1, still sRGB encoded
+    pub fn to_rgb(self) -> [f64; 3] {
+        [self.red, self.green, self.blue].map(|channel| channel as f64 / 255.0)
+    }
+
+    /// Round channels from 0 to 1, clamping anything out of gamut
+    pub fn from_rgb([r, g, b]: [f64; 3]) -> Self {
+        let channel = |value: f64| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        Color::new(channel(r), channel(g), channel(b))
+    }
+}
+
+/// Below this saturation or chroma a color's hue is meaningless, and the
+/// other color's hue is used for both
+const ACHROMATIC: f64 = 1e-6;
+
+fn mix(a: [f64; 3], b: [f64; 3], t: f64) -> [f64; 3] {
+    [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t)
+}
+
+/// Mix colors whose first component is a hue in turns and whose component
+/// `chroma` says how colorful they are
+fn mix_polar(mut a: [f64; 3], mut b: [f64; 3], t: f64, chroma: usize) -> [f64; 3] {
+    if a[chroma] < ACHROMATIC {
+        a[0] = b[0];
+    } else if b[chroma] < ACHROMATIC {
+        b[0] = a[0];
+    }
+    let mut turn = (b[0] - a[0]).rem_euclid(1.0);
+    if turn > 0.5 {
+        turn -= 1.0;
+    }
+    let mut mixed = mix(a, b, t);
+    mixed[0] = (a[0] + turn * t).rem_euclid(1.0);
+    mixed
+}
+
+/// Remove the sRGB transfer curve
+pub fn decode(channel: f64) -> f64 {
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Apply the sRGB transfer curve
+pub fn encode(linear: f64) -> f64 {
+    if linear <= 0.0031308 {
+        linear * 12.92
+    } else {
+        1.055 * linear.max(0.0).powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Hue in turns, saturation and value, all from 0 to 1
+pub fn rgb_to_hsv([r, g, b]: [f64; 3]) -> [f64; 3] {
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / delta).rem_euclid(6.0) / 6.0
+    } else if max == g {
+        ((b - r) / delta + 2.0) / 6.0
+    } else {
+        ((r - g) / delta + 4.0) / 6.0
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    [hue, saturation, max]
+}
+
+pub fn hsv_to_rgb([h, s, v]: [f64; 3]) -> [f64; 3] {
+    let sector = h.rem_euclid(1.0) * 6.0;
+    let f = sector.fract();
+    let (p, q, t) = (v * (1.0 - s), v * (1.0 - s * f), v * (1.0 - s * (1.0 - f)));
+    match sector as usize % 6 {
+        0 => [v, t, p],
+        1 => [q, v, p],
+        2 => [p, v, t],
+        3 => [p, q, v],
+        4 => [t, p, v],
+        _ => [v, p, q],
+    }
+}
+
+fn rgb_to_hsl(rgb: [f64; 3]) -> [f64; 3] {
+    let [h, s, v] = rgb_to_hsv(rgb);
+    let l = v * (1.0 - s / 2.0);
+    let s = if l <= 0.0 || l >= 1.0 {
+        0.0
+    } else {
+        (v - l) / l.min(1.0 - l)
+    };
+    [h, s, l]
+}
+
+fn hsl_to_rgb([h, s, l]: [f64; 3]) -> [f64; 3] {
+    let v = l + s * l.min(1.0 - l);
+    let s = if v == 0.0 { 0.0 } else { 2.0 * (1.0 - l / v) };
+    hsv_to_rgb([h, s, v])
+}
+
+/// Reference white of the D65 illuminant
+const D65: [f64; 3] = [0.95047, 1.0, 1.08883];
+const LAB_EPSILON: f64 = 6.0 / 29.0;
+
+fn rgb_to_lab(rgb: [f64; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(decode);
+    let xyz = [
+        0.4124564 * r + 0.3575761 * g + 0.1804375 * b,
+        0.2126729 * r + 0.7151522 * g + 0.0721750 * b,
+        0.0193339 * r + 0.1191920 * g + 0.9503041 * b,
+    ];
+    let [fx, fy, fz] = [0, 1, 2].map(|i| {
+        let t = xyz[i] / D65[i];
+        if t > LAB_EPSILON.powi(3) {
+            t.cbrt()
+        } else {
+            t / (3.0 * LAB_EPSILON * LAB_EPSILON) + 4.0 / 29.0
+        }
+    });
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn lab_to_rgb([l, a, b]: [f64; 3]) -> [f64; 3] {
+    let fy = (l + 16.0) / 116.0;
+    let f = [fy + a / 500.0, fy, fy - b / 200.0];
+    let [x, y, z] = [0, 1, 2].map(|i| {
+        let t = f[i];
+        let t = if t > LAB_EPSILON {
+            t * t * t
+        } else {
+            3.0 * LAB_EPSILON * LAB_EPSILON * (t - 4.0 / 29.0)
+        };
+        t * D65[i]
+    });
+    [
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    ]
+    .map(encode)
+}
+
+fn rgb_to_oklab(rgb: [f64; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(decode);
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_rgb([lightness, a, b]: [f64; 3]) -> [f64; 3] {
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+    .map(encode)
+}
+
+/// OKLab to hue in turns, chroma and lightness, ordered for `mix_polar`
+fn to_lch([l, a, b]: [f64; 3]) -> [f64; 3] {
+    [
+        b.atan2(a).rem_euclid(std::f64::consts::TAU) / std::f64::consts::TAU,
+        a.hypot(b),
+        l,
+    ]
+}
+
+fn from_lch([h, c, l]: [f64; 3]) -> [f64; 3] {
+    let angle = h * std::f64::consts::TAU;
+    [l, c * angle.cos(), c * angle.sin()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPACES: [ColorSpace; 7] = [
+        ColorSpace::Srgb,
+        ColorSpace::Linear,
+        ColorSpace::Hsv,
+        ColorSpace::Hsl,
+        ColorSpace::Lab,
+        ColorSpace::Oklab,
+        ColorSpace::Oklch,
+    ];
+
+    #[test]
+    fn ends_are_exact_in_every_space() {
+        let a = Color::new(0x12, 0x80, 0xf0);
+        let b = Color::new(0xff, 0xd0, 0x00);
+        for space in SPACES {
+            assert_eq!(a.interpolate_in(&b, 0.0, space), a, "{:?}", space);
+            assert_eq!(a.interpolate_in(&b, 1.0, space), b, "{:?}", space);
+        }
+    }
+
+    #[test]
+    fn midpoints() {
+        let black = Color::new(0, 0, 0);
+        let white = Color::new(255, 255, 255);
+        // Half the light of white, not half the encoded value
+        assert_eq!(
+            black.interpolate_in(&white, 0.5, ColorSpace::Linear),
+            Color::new(188, 188, 188)
+        );
+        // Gray has no hue to turn through
+        let gray = Color::new(128, 128, 128);
+        let red = Color::new(255, 0, 0);
+        for space in [ColorSpace::Hsv, ColorSpace::Hsl, ColorSpace::Oklch] {
+            let mid = gray.interpolate_in(&red, 0.5, space);
+            assert!(
+                mid.red > mid.green.max(mid.blue) + 64,
+                "{:?}: {:?}",
+                space,
+                mid
+            );
+        }
+        // Red to blue the short way is through magenta, not green
+        let blue = Color::new(0, 0, 255);
+        for space in [ColorSpace::Hsv, ColorSpace::Hsl] {
+            assert_eq!(
+                red.interpolate_in(&blue, 0.5, space),
+                Color::new(255, 0, 255),
+                "{:?}",
+                space
+            );
+        }
+        let mid = red.interpolate_in(&blue, 0.5, ColorSpace::Oklch);
+        assert!(mid.green < mid.red.min(mid.blue), "{:?}", mid);
+    }
+}
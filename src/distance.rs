@@ -0,0 +1,72 @@
+//! Coloring by the estimated distance to the set rather than by iteration
+//! count. Distances are measured in pixels, so that a boundary looks the
+//! same at any image size or zoom.
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{color::Color, palette::Palette};
+
+/// What the distance estimate is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DistanceMode {
+    /// Draw the boundary of the set over the usual coloring
+    Boundary,
+    /// Color escaping points through the palette by their distance alone
+    Shade,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DistanceSpec {
+    #[serde(default = "DistanceSpec::default_mode")]
+    pub mode: DistanceMode,
+    /// Width of the boundary in pixels. When shading, the distance in pixels
+    /// at which the palette is half way through.
+    #[serde(default = "DistanceSpec::default_thickness")]
+    pub thickness: f64,
+    /// Color of the boundary
+    #[serde(default = "DistanceSpec::default_color")]
+    pub color: Color,
+}
+
+impl DistanceSpec {
+    fn default_mode() -> DistanceMode {
+        DistanceMode::Boundary
+    }
+
+    fn default_thickness() -> f64 {
+        1.0
+    }
+
+    fn default_color() -> Color {
+        Color::new(0xFF, 0xFF, 0xFF)
+    }
+
+    /// Color of a pixel from its smooth iteration count and its distance to
+    /// the set in pixels
+    pub fn color(&self, palette: &Palette, iteration: f64, pixels: f64) -> Color {
+        if iteration >= palette.len() as f64 {
+            return palette.max_color;
+        }
+        match self.mode {
+            DistanceMode::Boundary => {
+                let coverage = (pixels / self.thickness).min(1.0);
+                self.color
+                    .interpolate(&palette.color(iteration), coverage as f32)
+            }
+            DistanceMode::Shade => palette.ramp(1.0 / (1.0 + pixels / self.thickness) as f32),
+        }
+    }
+}
+
+impl Default for DistanceSpec {
+    fn default() -> Self {
+        Self {
+            mode: Self::default_mode(),
+            thickness: Self::default_thickness(),
+            color: Self::default_color(),
+        }
+    }
+}
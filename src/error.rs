@@ -0,0 +1,52 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A command-line or scene parameter failed validation
+    InvalidArgument(String),
+    /// A scene file could not be read or written
+    Scene(String),
+    /// A gradient file could not be read
+    Gradient(String),
+    /// An animation frame could not be encoded
+    Video(String),
+    Io(io::Error),
+    Image(image::ImageError),
+    ThreadPool(rayon::ThreadPoolBuildError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidArgument(message) => write!(f, "{}", message),
+            Error::Scene(message) => write!(f, "{}", message),
+            Error::Gradient(message) => write!(f, "{}", message),
+            Error::Video(message) => write!(f, "{}", message),
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Image(err) => write!(f, "{}", err),
+            Error::ThreadPool(err) => write!(f, "failed to start render threads: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(err: image::ImageError) -> Self {
+        Error::Image(err)
+    }
+}
+
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(err: rayon::ThreadPoolBuildError) -> Self {
+        Error::ThreadPool(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
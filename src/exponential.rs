@@ -0,0 +1,188 @@
+//! Zoom frames resampled from an exponential map. The strip's columns go
+//! once round the center and its rows step inwards by a constant factor, so
+//! every frame of a zoom into the center is a lookup into the strip at some
+//! offset down its rows.
+
+use std::f64::consts::{LN_2, TAU};
+
+use rayon::prelude::*;
+
+use crate::{
+    color::{decode, encode},
+    floatexp::FloatExp,
+    viewport::Viewport,
+};
+
+/// An exponential map rendered to an image, with its channels in linear
+/// light for blending
+pub struct Strip {
+    width: usize,
+    height: usize,
+    pixels: Vec<[f64; 3]>,
+    /// Zoom of the view the strip was rendered from
+    zoom: FloatExp,
+    /// Angle of the first column in radians
+    angle: f64,
+}
+
+impl Strip {
+    /// Read an RGBA image rendered with the exponential mapping of
+    /// `viewport`
+    pub fn new(viewport: &Viewport, width: usize, height: usize, rgba: &[u8]) -> Self {
+        let pixels = rgba
+            .chunks_exact(4)
+            .map(|pixel| [0, 1, 2].map(|i| decode(pixel[i] as f64 / 255.0)))
+            .collect();
+        Self {
+            width,
+            height,
+            pixels,
+            zoom: viewport.zoom,
+            angle: viewport.rotation.to_radians(),
+        }
+    }
+
+    /// Angle between columns, and the log of the radius ratio between rows
+    fn turn(&self) -> f64 {
+        TAU / self.width as f64
+    }
+
+    /// Depths of the first and last frames of the given size the strip can
+    /// fill. A frame's depth is the log2 of the strip's top radius over the
+    /// frame's pixel size. The first frame's corners reach the top row, and
+    /// the last frame's center pixel covers what is left below the bottom
+    /// row.
+    pub fn depths(&self, width: usize, height: usize) -> (f64, f64) {
+        let first = (((width * width + height * height) as f64).sqrt() / 2.0).log2();
+        let last = self.turn() * self.height.saturating_sub(1) as f64 / LN_2 - 1.0;
+        (first, last)
+    }
+
+    /// Zoom of a frame `width` pixels wide at the given depth, in the terms
+    /// of `Viewport::zoom`
+    pub fn zoom(&self, depth: f64, width: usize) -> FloatExp {
+        // The top radius is the width of the strip's own view
+        self.zoom
+            .mul(FloatExp::exp2(depth))
+            .mul_f64(1.0 / width as f64)
+    }
+
+    /// RGBA frame of the zoom at the given depth, with the same center and
+    /// no rotation
+    pub fn frame(&self, depth: f64, width: usize, height: usize) -> Vec<u8> {
+        let mut buffer = vec![0; width * height * 4];
+        let turn = self.turn();
+        buffer
+            .par_chunks_mut(width * 4)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, pixel) in row.chunks_exact_mut(4).enumerate() {
+                    // Offset of the pixel's top-left corner from the center,
+                    // in frame pixels, the way `Bounds::point` places it
+                    let (dx, dy) = (
+                        x as f64 - width as f64 / 2.0,
+                        y as f64 - height as f64 / 2.0,
+                    );
+                    let column = (dy.atan2(dx) - self.angle).rem_euclid(TAU) / turn;
+                    let row = (depth * LN_2 - (dx * dx + dy * dy).sqrt().ln()) / turn;
+                    let rgb = self.sample(column, row);
+                    for i in 0..3 {
+                        pixel[i] = (encode(rgb[i]).clamp(0.0, 1.0) * 255.0).round() as u8;
+                    }
+                    pixel[3] = 0xFF;
+                }
+            });
+        buffer
+    }
+
+    /// Bilinear blend of the four pixels around a point of the strip. The
+    /// columns wrap round, the rows stop at the edges.
+    fn sample(&self, column: f64, row: f64) -> [f64; 3] {
+        let row = row.clamp(0.0, (self.height - 1) as f64);
+        let (left, top) = (column.floor(), row.floor());
+        let (tx, ty) = (column - left, row - top);
+        let left = left as usize % self.width;
+        let right = (left + 1) % self.width;
+        let top = top as usize;
+        let bottom = (top + 1).min(self.height - 1);
+        let at = |x: usize, y: usize| self.pixels[y * self.width + x];
+        let (a, b, c, d) = (
+            at(left, top),
+            at(right, top),
+            at(left, bottom),
+            at(right, bottom),
+        );
+        [0, 1, 2].map(|i| {
+            let upper = a[i] + (b[i] - a[i]) * tx;
+            let lower = c[i] + (d[i] - c[i]) * tx;
+            upper + (lower - upper) * ty
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::viewport::Mapping;
+
+    /// A pattern that is smooth in the log-polar coordinates around the
+    /// center, so bilinear sampling of the strip stays close to it
+    fn shade(dx: f64, dy: f64) -> f64 {
+        let radius = (dx * dx + dy * dy).sqrt();
+        0.5 + 0.25 * (3.0 * dy.atan2(dx)).sin() + 0.2 * (1.5 * radius.ln()).cos()
+    }
+
+    #[test]
+    fn frames_match_the_cartesian_view() {
+        let mut viewport = Viewport {
+            zoom: FloatExp::from_f64(3.0),
+            rotation: 30.0,
+            mapping: Mapping::Exponential,
+            ..Viewport::default()
+        };
+        let (center_x, center_y) = (viewport.center_x.to_f64(), viewport.center_y.to_f64());
+        let (width, height) = (720, 2000);
+        let bounds = viewport.bounds(width, height);
+        let mut rgba = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let (px, py) = bounds.point(x, y);
+                let value = shade(px - center_x, py - center_y);
+                let channel = (encode(value) * 255.0).round() as u8;
+                rgba.extend_from_slice(&[channel, channel, channel, 0xFF]);
+            }
+        }
+        let strip = Strip::new(&viewport, width, height, &rgba);
+
+        let (frame_width, frame_height) = (64, 48);
+        let (first, last) = strip.depths(frame_width, frame_height);
+        assert!(last > first + 10.0);
+        for depth in [first, (first + last) / 2.0, last] {
+            let frame = strip.frame(depth, frame_width, frame_height);
+            viewport.zoom = strip.zoom(depth, frame_width);
+            viewport.rotation = 0.0;
+            viewport.mapping = Mapping::Cartesian;
+            let bounds = viewport.bounds(frame_width, frame_height);
+            for y in 0..frame_height {
+                for x in 0..frame_width {
+                    if (x, y) == (frame_width / 2, frame_height / 2) {
+                        continue;
+                    }
+                    let (px, py) = bounds.point(x, y);
+                    let expected = shade(px - center_x, py - center_y);
+                    let index = (y * frame_width + x) * 4;
+                    let red = decode(frame[index] as f64 / 255.0);
+                    assert!(
+                        (red - expected).abs() < 0.02,
+                        "depth {} pixel ({}, {}): {} vs {}",
+                        depth,
+                        x,
+                        y,
+                        red,
+                        expected
+                    );
+                }
+            }
+        }
+    }
+}
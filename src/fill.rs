@@ -0,0 +1,293 @@
+//! Rendering strategies that iterate only part of a block of pixels and fill
+//! in the rest. Both rely on regions of one iteration value having no holes,
+//! which holds for the interior of the Mandelbrot set and of connected Julia
+//! sets, so the result matches iterating every pixel. The exception is where
+//! the set pinches to less than a pixel, like the necks where bulbs join the
+//! main cardioid: the interior pixels on both sides touch, and can close off
+//! a few escaping pixels that neither strategy looks at.
+
+use std::collections::VecDeque;
+
+use clap::ValueEnum;
+
+/// Which pixels of a tile are iterated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+    /// Iterate every pixel
+    Pixels,
+    /// Mariani–Silver: iterate the border of a rectangle and fill it when
+    /// the border is one value, otherwise split it in two and recurse
+    Subdivide,
+    /// Trace the boundaries between regions of one value and fill in the
+    /// pixels they enclose
+    Trace,
+}
+
+/// Rectangles this narrow are iterated in full rather than split further
+const MIN_SPLIT: usize = 4;
+
+/// Iteration values of a `width` x `height` block of pixels in row-major
+/// order, along with the number of pixels filled in without iterating them.
+/// `evaluate` writes the values of the pixels at the given indices.
+pub fn fill(
+    strategy: Strategy,
+    width: usize,
+    height: usize,
+    mut evaluate: impl FnMut(&[usize], &mut [f64]),
+) -> (Vec<f64>, u64) {
+    let mut block = Block {
+        width,
+        height,
+        values: vec![0.0; width * height],
+        known: vec![false; width * height],
+        evaluate: &mut evaluate,
+    };
+    match strategy {
+        Strategy::Pixels => {
+            let all: Vec<usize> = (0..width * height).collect();
+            block.load(&all);
+            (block.values, 0)
+        }
+        Strategy::Subdivide => {
+            let filled = block.subdivide();
+            (block.values, filled)
+        }
+        Strategy::Trace => {
+            let filled = block.trace();
+            (block.values, filled)
+        }
+    }
+}
+
+struct Block<'a> {
+    width: usize,
+    height: usize,
+    values: Vec<f64>,
+    /// Whether each value has been iterated or filled in
+    known: Vec<bool>,
+    evaluate: &'a mut dyn FnMut(&[usize], &mut [f64]),
+}
+
+impl Block<'_> {
+    /// Iterate the pixels among `indices` that are not known yet
+    fn load(&mut self, indices: &[usize]) {
+        let mut missing: Vec<usize> = indices
+            .iter()
+            .copied()
+            .filter(|&index| !self.known[index])
+            .collect();
+        // Rectangle corners are on two sides of the border
+        missing.sort_unstable();
+        missing.dedup();
+        if missing.is_empty() {
+            return;
+        }
+        let mut out = vec![0.0; missing.len()];
+        (self.evaluate)(&missing, &mut out);
+        for (&index, value) in missing.iter().zip(out) {
+            self.values[index] = value;
+            self.known[index] = true;
+        }
+    }
+
+    /// Mariani–Silver over the whole block. Neighbouring rectangles share
+    /// their common edge, so it is only iterated once.
+    fn subdivide(&mut self) -> u64 {
+        if self.width == 0 || self.height == 0 {
+            return 0;
+        }
+        let mut filled = 0;
+        // Inclusive pixel bounds: left, top, right, bottom
+        let mut rectangles = vec![(0, 0, self.width - 1, self.height - 1)];
+        while let Some((left, top, right, bottom)) = rectangles.pop() {
+            let mut border: Vec<usize> = Vec::new();
+            for x in left..=right {
+                border.push(top * self.width + x);
+                border.push(bottom * self.width + x);
+            }
+            for y in top..=bottom {
+                border.push(y * self.width + left);
+                border.push(y * self.width + right);
+            }
+            self.load(&border);
+
+            let inside = |x, y| x > left && x < right && y > top && y < bottom;
+            let first = self.values[border[0]];
+            if border
+                .iter()
+                .all(|&index| self.values[index].to_bits() == first.to_bits())
+            {
+                for y in top + 1..bottom {
+                    for x in left + 1..right {
+                        let index = y * self.width + x;
+                        if !self.known[index] {
+                            self.values[index] = first;
+                            self.known[index] = true;
+                            filled += 1;
+                        }
+                    }
+                }
+            } else if right - left < MIN_SPLIT && bottom - top < MIN_SPLIT {
+                let rest: Vec<usize> = (top..=bottom)
+                    .flat_map(|y| (left..=right).map(move |x| (x, y)))
+                    .filter(|&(x, y)| inside(x, y))
+                    .map(|(x, y)| y * self.width + x)
+                    .collect();
+                self.load(&rest);
+            } else if right - left >= bottom - top {
+                let middle = (left + right) / 2;
+                rectangles.push((left, top, middle, bottom));
+                rectangles.push((middle, top, right, bottom));
+            } else {
+                let middle = (top + bottom) / 2;
+                rectangles.push((left, top, right, middle));
+                rectangles.push((left, middle, right, bottom));
+            }
+        }
+        filled
+    }
+
+    /// Boundary tracing over the whole block. Starting from the edges, every
+    /// pixel that differs from a neighbour has its neighbours iterated too,
+    /// which walks the boundaries of each region of one value. The pixels
+    /// left over lie inside a region, and take the value of the pixel to
+    /// their left.
+    fn trace(&mut self) -> u64 {
+        let (width, height) = (self.width, self.height);
+        if width == 0 || height == 0 {
+            return 0;
+        }
+        let mut queued = vec![false; width * height];
+        let mut queue = VecDeque::new();
+        let mut push = |index: usize, queue: &mut VecDeque<usize>| {
+            if !queued[index] {
+                queued[index] = true;
+                queue.push_back(index);
+            }
+        };
+
+        let mut edges = Vec::new();
+        for x in 0..width {
+            edges.push(x);
+            edges.push((height - 1) * width + x);
+        }
+        for y in 0..height {
+            edges.push(y * width);
+            edges.push(y * width + width - 1);
+        }
+        self.load(&edges);
+        for index in edges {
+            push(index, &mut queue);
+        }
+
+        while !queue.is_empty() {
+            // Iterate the neighbours of the whole queue at once, to keep the
+            // SIMD lanes full
+            let wave: Vec<usize> = queue.drain(..).collect();
+            let neighbours: Vec<usize> = wave
+                .iter()
+                .flat_map(|&index| {
+                    let (x, y) = (index % width, index / width);
+                    [
+                        (x > 0).then(|| index - 1),
+                        (x + 1 < width).then(|| index + 1),
+                        (y > 0).then(|| index - width),
+                        (y + 1 < height).then(|| index + width),
+                    ]
+                })
+                .flatten()
+                .chain(wave.iter().copied())
+                .collect();
+            self.load(&neighbours);
+
+            for index in wave {
+                let (x, y) = (index % width, index / width);
+                let center = self.values[index].to_bits();
+                let differs = |index: usize| self.values[index].to_bits() != center;
+                let left = x > 0 && differs(index - 1);
+                let right = x + 1 < width && differs(index + 1);
+                let up = y > 0 && differs(index - width);
+                let down = y + 1 < height && differs(index + width);
+
+                if left {
+                    push(index - 1, &mut queue);
+                }
+                if right {
+                    push(index + 1, &mut queue);
+                }
+                if up {
+                    push(index - width, &mut queue);
+                }
+                if down {
+                    push(index + width, &mut queue);
+                }
+                // Diagonal neighbours keep the boundary connected around
+                // corners
+                if x > 0 && y > 0 && (left || up) {
+                    push(index - width - 1, &mut queue);
+                }
+                if x + 1 < width && y > 0 && (right || up) {
+                    push(index - width + 1, &mut queue);
+                }
+                if x > 0 && y + 1 < height && (left || down) {
+                    push(index + width - 1, &mut queue);
+                }
+                if x + 1 < width && y + 1 < height && (right || down) {
+                    push(index + width + 1, &mut queue);
+                }
+            }
+        }
+
+        let mut filled = 0;
+        for y in 0..height {
+            for x in 1..width {
+                let index = y * width + x;
+                if !self.known[index] {
+                    self.values[index] = self.values[index - 1];
+                    self.known[index] = true;
+                    filled += 1;
+                }
+            }
+        }
+        filled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fractal::{calculate_point, Mandelbrot},
+        kernel::EscapeParams,
+    };
+
+    #[test]
+    fn strategies_match_every_pixel() {
+        let params = EscapeParams {
+            max_iterations: 200.0,
+            bailout_sqr: 4.0,
+            // Inside the main cardioid, where the filled Julia set is a
+            // single wrinkled disk without any necks
+            julia: Some((-0.4, 0.3)),
+        };
+        let (width, height) = (97, 73);
+        let evaluate = |indices: &[usize], out: &mut [f64]| {
+            for (&index, out) in indices.iter().zip(out) {
+                let x0 = -1.6 + 3.2 * (index % width) as f64 / width as f64;
+                let y0 = -1.2 + 2.4 * (index / width) as f64 / height as f64;
+                *out = calculate_point(&Mandelbrot, x0, y0, &params);
+            }
+        };
+        let (expected, _) = fill(Strategy::Pixels, width, height, evaluate);
+        for strategy in [Strategy::Subdivide, Strategy::Trace] {
+            let mut evaluations = 0;
+            let (values, filled) = fill(strategy, width, height, |indices, out| {
+                evaluations += indices.len();
+                evaluate(indices, out)
+            });
+            assert_eq!(values, expected, "{:?}", strategy);
+            assert_eq!(evaluations as u64 + filled, (width * height) as u64);
+            assert!(filled > 1000, "{:?} only filled {}", strategy, filled);
+        }
+    }
+}
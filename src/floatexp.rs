@@ -0,0 +1,361 @@
+use std::{cmp::Ordering, convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Floating point number with an `f64` mantissa and a separate 64-bit
+/// binary exponent, for magnitudes far outside the range of `f64`. The
+/// mantissa is either zero or normalized to 0.5 <= |mantissa| < 1.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Scalar", into = "String")]
+pub struct FloatExp {
+    mantissa: f64,
+    exponent: i64,
+}
+
+impl FloatExp {
+    pub const ZERO: FloatExp = FloatExp {
+        mantissa: 0.0,
+        exponent: 0,
+    };
+
+    pub fn new(mantissa: f64, exponent: i64) -> Self {
+        let (mantissa, shift) = frexp(mantissa);
+        if mantissa == 0.0 {
+            return Self::ZERO;
+        }
+        Self {
+            mantissa,
+            exponent: exponent + shift,
+        }
+    }
+
+    pub fn from_f64(value: f64) -> Self {
+        Self::new(value, 0)
+    }
+
+    /// Nearest `f64`, saturating to infinity or flushing to zero when out of
+    /// range
+    pub fn to_f64(self) -> f64 {
+        ldexp(self.mantissa, self.exponent)
+    }
+
+    pub fn mantissa(self) -> f64 {
+        self.mantissa
+    }
+
+    pub fn exponent(self) -> i64 {
+        self.exponent
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.mantissa == 0.0
+    }
+
+    pub fn is_sign_negative(self) -> bool {
+        self.mantissa < 0.0
+    }
+
+    pub fn abs(self) -> Self {
+        Self {
+            mantissa: self.mantissa.abs(),
+            exponent: self.exponent,
+        }
+    }
+
+    pub fn neg(self) -> Self {
+        Self {
+            mantissa: -self.mantissa,
+            exponent: self.exponent,
+        }
+    }
+
+    pub fn add(self, other: Self) -> Self {
+        if self.is_zero() {
+            return other;
+        }
+        if other.is_zero() {
+            return self;
+        }
+        let (large, small) = if self.exponent >= other.exponent {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        let shift = large.exponent - small.exponent;
+        if shift > 64 {
+            return large;
+        }
+        Self::new(
+            large.mantissa + ldexp(small.mantissa, -shift),
+            large.exponent,
+        )
+    }
+
+    pub fn sub(self, other: Self) -> Self {
+        self.add(other.neg())
+    }
+
+    pub fn mul(self, other: Self) -> Self {
+        Self::new(
+            self.mantissa * other.mantissa,
+            self.exponent + other.exponent,
+        )
+    }
+
+    pub fn mul_f64(self, other: f64) -> Self {
+        self.mul(Self::from_f64(other))
+    }
+
+    pub fn div(self, other: Self) -> Self {
+        Self::new(
+            self.mantissa / other.mantissa,
+            self.exponent - other.exponent,
+        )
+    }
+
+    pub fn square(self) -> Self {
+        self.mul(self)
+    }
+
+    /// Multiply by 2^exponent exactly
+    pub fn mul_pow2(self, exponent: i64) -> Self {
+        if self.is_zero() {
+            return self;
+        }
+        Self {
+            mantissa: self.mantissa,
+            exponent: self.exponent + exponent,
+        }
+    }
+
+    /// Base 2 logarithm of the magnitude
+    pub fn log2(self) -> f64 {
+        self.mantissa.abs().log2() + self.exponent as f64
+    }
+
+    /// 2^x for any finite x
+    pub fn exp2(x: f64) -> Self {
+        let whole = x.floor();
+        Self::new((x - whole).exp2(), whole as i64)
+    }
+
+    /// 10^exponent by repeated squaring
+    pub fn pow10(exponent: i64) -> Self {
+        let mut result = Self::from_f64(1.0);
+        let mut base = Self::from_f64(10.0);
+        let mut remaining = exponent.unsigned_abs();
+        while remaining > 0 {
+            if remaining & 1 == 1 {
+                result = result.mul(base);
+            }
+            base = base.square();
+            remaining >>= 1;
+        }
+        if exponent < 0 {
+            Self::from_f64(1.0).div(result)
+        } else {
+            result
+        }
+    }
+}
+
+impl PartialOrd for FloatExp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self.mantissa.is_nan() || other.mantissa.is_nan() {
+            return None;
+        }
+        let sign = |v: &FloatExp| v.mantissa.partial_cmp(&0.0).unwrap();
+        let by_sign = sign(self).cmp(&sign(other));
+        if by_sign != Ordering::Equal || self.is_zero() {
+            return Some(by_sign);
+        }
+
+        let by_magnitude = self.exponent.cmp(&other.exponent).then(
+            self.mantissa
+                .abs()
+                .partial_cmp(&other.mantissa.abs())
+                .unwrap(),
+        );
+        Some(if self.mantissa < 0.0 {
+            by_magnitude.reverse()
+        } else {
+            by_magnitude
+        })
+    }
+}
+
+impl From<f64> for FloatExp {
+    fn from(value: f64) -> Self {
+        Self::from_f64(value)
+    }
+}
+
+/// Split a finite value into a mantissa with 0.5 <= |mantissa| < 1 and a
+/// power of two
+fn frexp(value: f64) -> (f64, i64) {
+    if value == 0.0 || !value.is_finite() {
+        return (value, 0);
+    }
+    let bits = value.to_bits();
+    let biased = ((bits >> 52) & 0x7ff) as i64;
+    if biased == 0 {
+        // Subnormal, scale into the normal range first
+        let (mantissa, exponent) = frexp(value * 2.0_f64.powi(64));
+        return (mantissa, exponent - 64);
+    }
+    let mantissa = f64::from_bits((bits & !(0x7ff << 52)) | (1022 << 52));
+    (mantissa, biased - 1022)
+}
+
+/// mantissa * 2^exponent, in steps so that no intermediate leaves the f64
+/// range before the final product
+fn ldexp(mut mantissa: f64, mut exponent: i64) -> f64 {
+    if mantissa == 0.0 {
+        return mantissa;
+    }
+    if exponent > 2100 {
+        return mantissa * f64::INFINITY;
+    }
+    if exponent < -2200 {
+        return mantissa * 0.0;
+    }
+    while exponent > 1000 {
+        mantissa *= pow2(1000);
+        exponent -= 1000;
+    }
+    while exponent < -1000 {
+        mantissa *= pow2(-1000);
+        exponent += 1000;
+    }
+    mantissa * pow2(exponent)
+}
+
+/// Exact power of two for -1022 <= exponent <= 1023
+fn pow2(exponent: i64) -> f64 {
+    f64::from_bits(((exponent + 1023) as u64) << 52)
+}
+
+impl FromStr for FloatExp {
+    type Err = String;
+
+    /// Parse a decimal number whose exponent may lie far beyond the range of
+    /// `f64`, such as `2.5e-1200`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid number {:?}", s);
+        let text = s.trim();
+        // Within the normal range of f64 the parse is correctly rounded, so
+        // whatever `Display` wrote comes back exactly
+        match text.parse::<f64>() {
+            Ok(value) if value.is_normal() => return Ok(Self::from_f64(value)),
+            _ => {}
+        }
+        let (mantissa, exponent) = match text.find(['e', 'E']) {
+            Some(pos) => (
+                &text[..pos],
+                text[pos + 1..].parse::<i64>().map_err(|_| invalid())?,
+            ),
+            None => (text, 0),
+        };
+        let mantissa: f64 = mantissa.parse().map_err(|_| invalid())?;
+        if !mantissa.is_finite() || exponent.unsigned_abs() > 1 << 60 {
+            return Err(invalid());
+        }
+        Ok(Self::from_f64(mantissa).mul(Self::pow10(exponent)))
+    }
+}
+
+impl fmt::Display for FloatExp {
+    /// Plain `f64` formatting within its comfortable range and the shortest
+    /// scientific notation that reads back exactly up to the limits of
+    /// `f64`. Beyond them, scientific notation with 15 significant digits.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let log10 = self.log2() * std::f64::consts::LOG10_2;
+        if self.is_zero() || log10.abs() < 16.0 {
+            return write!(f, "{}", self.to_f64());
+        }
+        if self.to_f64().is_normal() {
+            return write!(f, "{:e}", self.to_f64());
+        }
+
+        let mut decimal_exponent = log10.floor() as i64;
+        let mut digits = self.abs().div(Self::pow10(decimal_exponent)).to_f64();
+        digits = (digits * 1e14).round() / 1e14;
+        if digits >= 10.0 {
+            digits /= 10.0;
+            decimal_exponent += 1;
+        }
+        let sign = if self.is_sign_negative() { "-" } else { "" };
+        write!(f, "{}{}e{}", sign, digits, decimal_exponent)
+    }
+}
+
+/// A value as written in a scene file, either a plain number or a string for
+/// magnitudes `f64` cannot hold
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scalar {
+    Number(f64),
+    Text(String),
+}
+
+impl TryFrom<Scalar> for FloatExp {
+    type Error = String;
+
+    fn try_from(scalar: Scalar) -> Result<Self, Self::Error> {
+        match scalar {
+            Scalar::Number(value) if value.is_finite() => Ok(Self::from_f64(value)),
+            Scalar::Number(value) => Err(format!("{} is not a finite number", value)),
+            Scalar::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<FloatExp> for String {
+    fn from(value: FloatExp) -> Self {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_beyond_f64_range() {
+        let tiny: FloatExp = "3e-500".parse().unwrap();
+        let huge: FloatExp = "2e500".parse().unwrap();
+
+        assert_eq!(tiny.to_f64(), 0.0);
+        assert!((tiny.mul(huge).to_f64() - 6.0).abs() < 1e-12);
+        assert!((huge.div(tiny).log2() - 1000.0 * 10.0_f64.log2() + 1.5_f64.log2()).abs() < 1e-9);
+
+        let sum = tiny.add(tiny).sub(tiny.mul_f64(0.5));
+        assert!((sum.div(tiny).to_f64() - 1.5).abs() < 1e-15);
+        assert!(tiny.neg() < tiny && tiny < huge && tiny.neg() < FloatExp::ZERO);
+    }
+
+    #[test]
+    fn f64_round_trip() {
+        for &value in &[1.0, -0.75, 1e-310, 3.5e300, f64::MIN_POSITIVE, 0.0] {
+            assert_eq!(FloatExp::from_f64(value).to_f64(), value);
+        }
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for text in &["1e500", "-2.5e-1200", "7", "0.001", "3.1e40", "-1.25e-300"] {
+            let value: FloatExp = text.parse().unwrap();
+            assert_eq!(value.to_string(), *text);
+        }
+
+        // Anything f64 can hold reads back exactly
+        for &value in &[
+            0.7117250952244318 * 2f64.powi(135),
+            1.0 / 3.0 * 1e-200,
+            5e300,
+        ] {
+            let value = FloatExp::from_f64(value);
+            assert_eq!(value.to_string().parse::<FloatExp>().unwrap(), value);
+        }
+    }
+}
@@ -0,0 +1,633 @@
+//! Escape-time formulas. Each one only describes a single step of its
+//! iteration; `calculate_point` runs the shared escape loop around it, so
+//! every formula gets Julia mode and smoothing for free.
+
+use crate::{
+    kernel::{smooth, EscapeParams},
+    scene::Formula,
+};
+
+/// One escape-time iteration z -> f(z, c)
+pub trait Fractal {
+    /// Starting z for the point c
+    fn initial_z(&self, _c: (f64, f64)) -> (f64, f64) {
+        (0.0, 0.0)
+    }
+
+    fn step(&self, z: (f64, f64), c: (f64, f64)) -> (f64, f64);
+
+    /// Whether z has left the escape radius for good
+    fn escaped(&self, (x, y): (f64, f64), bailout_sqr: f64) -> bool {
+        x * x + y * y > bailout_sqr
+    }
+
+    /// Power at which |z| grows once it is large, so that smoothing can
+    /// interpolate between iterations
+    fn degree(&self) -> f64;
+}
+
+/// z -> z² + c
+pub struct Mandelbrot;
+
+impl Fractal for Mandelbrot {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        (x * x - y * y + cx, 2.0 * x * y + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        2.0
+    }
+}
+
+/// z -> zⁿ + c for a whole number n, by repeated multiplication
+pub struct Multibrot {
+    pub power: u32,
+}
+
+impl Fractal for Multibrot {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        let (mut zx, mut zy) = (x, y);
+        for _ in 1..self.power {
+            let xtemp = zx * x - zy * y;
+            zy = zx * y + zy * x;
+            zx = xtemp;
+        }
+        (zx + cx, zy + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        self.power as f64
+    }
+}
+
+/// z -> zⁿ + c for any real n > 1, in polar form on the principal branch
+pub struct RealMultibrot {
+    pub power: f64,
+}
+
+impl Fractal for RealMultibrot {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        let r = (x * x + y * y).powf(self.power / 2.0);
+        let theta = y.atan2(x) * self.power;
+        (r * theta.cos() + cx, r * theta.sin() + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        self.power
+    }
+}
+
+/// A formula analytic in z, so that its orbits can carry a derivative for
+/// distance estimation. The folding formulas are not.
+pub trait Holomorphic: Fractal {
+    /// f'(z), which the derivative is multiplied by at every step
+    fn slope(&self, z: (f64, f64)) -> (f64, f64);
+
+    /// f''(z), for second derivatives
+    fn curvature(&self, z: (f64, f64)) -> (f64, f64);
+}
+
+impl Holomorphic for Mandelbrot {
+    fn slope(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        (2.0 * x, 2.0 * y)
+    }
+
+    fn curvature(&self, _z: (f64, f64)) -> (f64, f64) {
+        (2.0, 0.0)
+    }
+}
+
+impl Holomorphic for Multibrot {
+    fn slope(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (mut zx, mut zy) = (1.0, 0.0);
+        for _ in 1..self.power {
+            let xtemp = zx * x - zy * y;
+            zy = zx * y + zy * x;
+            zx = xtemp;
+        }
+        let n = self.power as f64;
+        (n * zx, n * zy)
+    }
+
+    fn curvature(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let (mut zx, mut zy) = (1.0, 0.0);
+        for _ in 2..self.power {
+            let xtemp = zx * x - zy * y;
+            zy = zx * y + zy * x;
+            zx = xtemp;
+        }
+        let n = self.power as f64;
+        (n * (n - 1.0) * zx, n * (n - 1.0) * zy)
+    }
+}
+
+impl Holomorphic for RealMultibrot {
+    fn slope(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let n = self.power;
+        let r = n * (x * x + y * y).powf((n - 1.0) / 2.0);
+        let theta = y.atan2(x) * (n - 1.0);
+        (r * theta.cos(), r * theta.sin())
+    }
+
+    fn curvature(&self, (x, y): (f64, f64)) -> (f64, f64) {
+        let n = self.power;
+        let r = n * (n - 1.0) * (x * x + y * y).powf((n - 2.0) / 2.0);
+        let theta = y.atan2(x) * (n - 2.0);
+        (r * theta.cos(), r * theta.sin())
+    }
+}
+
+/// z -> (|Re z| + |Im z| i)² + c
+pub struct BurningShip;
+
+impl Fractal for BurningShip {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        (x * x - y * y + cx, 2.0 * (x * y).abs() + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        2.0
+    }
+}
+
+/// The Mandelbar: z -> conj(z)² + c
+pub struct Tricorn;
+
+impl Fractal for Tricorn {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        (x * x - y * y + cx, -2.0 * x * y + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        2.0
+    }
+}
+
+/// z -> |Re z²| + Im z² i + c
+pub struct Celtic;
+
+impl Fractal for Celtic {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        ((x * x - y * y).abs() + cx, 2.0 * x * y + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        2.0
+    }
+}
+
+/// z -> |Re z²| + |Im z²| i + c
+pub struct Buffalo;
+
+impl Fractal for Buffalo {
+    fn step(&self, (x, y): (f64, f64), (cx, cy): (f64, f64)) -> (f64, f64) {
+        ((x * x - y * y).abs() + cx, 2.0 * (x * y).abs() + cy)
+    }
+
+    fn degree(&self) -> f64 {
+        2.0
+    }
+}
+
+/// Smooth iteration count of the point (x0, y0), which is c, or the
+/// starting z when rendering a Julia set. Points that never escape return
+/// exactly `max_iterations`.
+pub fn calculate_point<F: Fractal>(fractal: &F, x0: f64, y0: f64, params: &EscapeParams) -> f64 {
+    let (mut z, c) = match params.julia {
+        Some(c) => ((x0, y0), c),
+        None => (fractal.initial_z((x0, y0)), (x0, y0)),
+    };
+    let mut iteration: f64 = 0.0;
+
+    while !fractal.escaped(z, params.bailout_sqr) && iteration < params.max_iterations {
+        z = fractal.step(z, c);
+        iteration += 1.0;
+    }
+
+    smooth(iteration, z.0, z.1, fractal.degree(), params)
+}
+
+/// Smooth iteration count of the point, as `calculate_point`, giving up on
+/// the orbit once it comes back within `tolerance_sqr` of itself. Orbits
+/// caught in a cycle never escape, so they count `max_iterations` and are
+/// flagged with `true`.
+pub fn calculate_point_periodic<F: Fractal>(
+    fractal: &F,
+    x0: f64,
+    y0: f64,
+    params: &EscapeParams,
+    tolerance_sqr: f64,
+) -> (f64, bool) {
+    let (mut z, c) = match params.julia {
+        Some(c) => ((x0, y0), c),
+        None => (fractal.initial_z((x0, y0)), (x0, y0)),
+    };
+    let mut iteration: f64 = 0.0;
+    let mut periodicity = Periodicity::new(z, tolerance_sqr);
+
+    while !fractal.escaped(z, params.bailout_sqr) && iteration < params.max_iterations {
+        z = fractal.step(z, c);
+        iteration += 1.0;
+        if !fractal.escaped(z, params.bailout_sqr) && periodicity.check(z).is_some() {
+            return (params.max_iterations, true);
+        }
+    }
+
+    (smooth(iteration, z.0, z.1, fractal.degree(), params), false)
+}
+
+/// Smooth iteration count of the point, as `calculate_point`, handing
+/// `visit` the previous and new z after every step, up to and including
+/// the step that escapes
+pub fn calculate_orbit<F: Fractal>(
+    fractal: &F,
+    x0: f64,
+    y0: f64,
+    params: &EscapeParams,
+    mut visit: impl FnMut((f64, f64), (f64, f64)),
+) -> f64 {
+    let (mut z, c) = match params.julia {
+        Some(c) => ((x0, y0), c),
+        None => (fractal.initial_z((x0, y0)), (x0, y0)),
+    };
+    let mut iteration: f64 = 0.0;
+
+    while !fractal.escaped(z, params.bailout_sqr) && iteration < params.max_iterations {
+        let previous = z;
+        z = fractal.step(z, c);
+        iteration += 1.0;
+        visit(previous, z);
+    }
+
+    smooth(iteration, z.0, z.1, fractal.degree(), params)
+}
+
+/// Smooth iteration count of the point, as `calculate_point`, along with an
+/// estimate of its distance to the set. The derivative of z with respect to
+/// c (or to the starting z for Julia sets) is iterated next to z. Points
+/// that never escape are at distance 0.
+pub fn estimate_distance<F: Holomorphic>(
+    fractal: &F,
+    x0: f64,
+    y0: f64,
+    params: &EscapeParams,
+) -> (f64, f64) {
+    let (mut z, c, mut dz, dc) = match params.julia {
+        Some(c) => ((x0, y0), c, (1.0, 0.0), 0.0),
+        None => (fractal.initial_z((x0, y0)), (x0, y0), (0.0, 0.0), 1.0),
+    };
+    let mut iteration: f64 = 0.0;
+
+    while !fractal.escaped(z, params.bailout_sqr) && iteration < params.max_iterations {
+        let (sx, sy) = fractal.slope(z);
+        dz = (sx * dz.0 - sy * dz.1 + dc, sx * dz.1 + sy * dz.0);
+        z = fractal.step(z, c);
+        iteration += 1.0;
+    }
+
+    let smoothed = smooth(iteration, z.0, z.1, fractal.degree(), params);
+    if iteration >= params.max_iterations {
+        return (smoothed, 0.0);
+    }
+    // Half of |z| log|z| / |dz|, the Hubbard-Douady potential over its
+    // gradient
+    let (z_abs, dz_abs) = (z.0.hypot(z.1), dz.0.hypot(dz.1));
+    (smoothed, 0.5 * z_abs * z_abs.ln() / dz_abs)
+}
+
+/// Smooth iteration counts and distance estimates for a run of points,
+/// for the formulas that are `Holomorphic`
+pub fn estimate_distances(
+    formula: Formula,
+    power: f64,
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    iterations: &mut [f64],
+    distances: &mut [f64],
+) {
+    fn run<F: Holomorphic>(
+        fractal: &F,
+        x0: &[f64],
+        y0: &[f64],
+        params: &EscapeParams,
+        iterations: &mut [f64],
+        distances: &mut [f64],
+    ) {
+        let outputs = iterations.iter_mut().zip(distances.iter_mut());
+        for ((x0, y0), (iteration, distance)) in x0.iter().zip(y0).zip(outputs) {
+            (*iteration, *distance) = estimate_distance(fractal, *x0, *y0, params);
+        }
+    }
+
+    match formula {
+        Formula::Mandelbrot => run(&Mandelbrot, x0, y0, params, iterations, distances),
+        Formula::Multibrot if power.fract() == 0.0 && power <= u32::MAX as f64 => run(
+            &Multibrot {
+                power: power as u32,
+            },
+            x0,
+            y0,
+            params,
+            iterations,
+            distances,
+        ),
+        Formula::Multibrot => run(
+            &RealMultibrot { power },
+            x0,
+            y0,
+            params,
+            iterations,
+            distances,
+        ),
+        _ => unreachable!("distance estimation needs a holomorphic formula"),
+    }
+}
+
+/// Tolerance of the periodicity check as a fraction of a pixel. Orbits of
+/// points just outside the set linger near the cycle they narrowly miss,
+/// but move on by about the point's distance to the set every step.
+pub const PERIODICITY_TOLERANCE: f64 = 1e-6;
+
+/// Brent's cycle detection along an orbit. Orbits only approach their
+/// cycle, so a point counts as revisited once it comes within a tolerance.
+pub struct Periodicity {
+    saved: (f64, f64),
+    tolerance_sqr: f64,
+    steps: u64,
+    window: u64,
+}
+
+impl Periodicity {
+    pub fn new(z: (f64, f64), tolerance_sqr: f64) -> Self {
+        Self {
+            saved: z,
+            tolerance_sqr,
+            steps: 0,
+            window: 1,
+        }
+    }
+
+    /// Record the next z of the orbit, returning the period once it comes
+    /// back to the saved point. The saved point moves on after windows of
+    /// doubling length.
+    pub fn check(&mut self, z: (f64, f64)) -> Option<u64> {
+        self.steps += 1;
+        let (dx, dy) = (z.0 - self.saved.0, z.1 - self.saved.1);
+        if dx * dx + dy * dy < self.tolerance_sqr {
+            return Some(self.steps);
+        }
+        if self.steps == self.window {
+            self.saved = z;
+            self.steps = 0;
+            self.window *= 2;
+        }
+        None
+    }
+}
+
+/// Code to run against a formula's concrete type, so that the escape loop
+/// is compiled separately for every formula
+pub trait Visitor {
+    type Output;
+
+    fn visit<F: Fractal + Sync>(self, fractal: &F) -> Self::Output;
+}
+
+/// Hand the formula to the visitor. `power` is only read by the multibrot
+/// formula.
+pub fn visit<V: Visitor>(formula: Formula, power: f64, visitor: V) -> V::Output {
+    match formula {
+        Formula::Mandelbrot => visitor.visit(&Mandelbrot),
+        Formula::Multibrot if power.fract() == 0.0 && power <= u32::MAX as f64 => {
+            visitor.visit(&Multibrot {
+                power: power as u32,
+            })
+        }
+        Formula::Multibrot => visitor.visit(&RealMultibrot { power }),
+        Formula::BurningShip => visitor.visit(&BurningShip),
+        Formula::Tricorn => visitor.visit(&Tricorn),
+        Formula::Celtic => visitor.visit(&Celtic),
+        Formula::Buffalo => visitor.visit(&Buffalo),
+        Formula::Newton | Formula::Nova => {
+            unreachable!("root-finding formulas are iterated by the newton module")
+        }
+    }
+}
+
+/// Smooth iteration counts for a run of points with the given formula,
+/// written to `out`. With a periodicity tolerance, orbits caught in a cycle
+/// stop early, and the number of them is returned.
+pub fn calculate_points(
+    formula: Formula,
+    power: f64,
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    periodicity: Option<f64>,
+    out: &mut [f64],
+) -> u64 {
+    struct Run<'a> {
+        x0: &'a [f64],
+        y0: &'a [f64],
+        params: &'a EscapeParams,
+        periodicity: Option<f64>,
+        out: &'a mut [f64],
+    }
+
+    impl Visitor for Run<'_> {
+        type Output = u64;
+
+        fn visit<F: Fractal + Sync>(self, fractal: &F) -> u64 {
+            let mut periodic = 0;
+            for ((x0, y0), out) in self.x0.iter().zip(self.y0).zip(self.out) {
+                *out = match self.periodicity {
+                    Some(tolerance_sqr) => {
+                        let (iteration, cycled) =
+                            calculate_point_periodic(fractal, *x0, *y0, self.params, tolerance_sqr);
+                        periodic += cycled as u64;
+                        iteration
+                    }
+                    None => calculate_point(fractal, *x0, *y0, self.params),
+                };
+            }
+            periodic
+        }
+    }
+
+    visit(
+        formula,
+        power,
+        Run {
+            x0,
+            y0,
+            params,
+            periodicity,
+            out,
+        },
+    )
+}
+
+/// Whether c lies in the main cardioid or the period-2 bulb of the
+/// Mandelbrot set, where the orbit never escapes
+pub fn in_cardioid_or_bulb(x: f64, y: f64) -> bool {
+    let y2 = y * y;
+    let q = (x - 0.25) * (x - 0.25) + y2;
+    q * (q + (x - 0.25)) <= 0.25 * y2 || (x + 1.0) * (x + 1.0) + y2 <= 0.0625
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: EscapeParams = EscapeParams {
+        max_iterations: 500.0,
+        bailout_sqr: 256.0 * 256.0,
+        julia: None,
+    };
+
+    fn grid() -> impl Iterator<Item = (f64, f64)> {
+        (0..41).flat_map(|j| (0..53).map(move |i| (-2.1 + 0.05 * i as f64, -1.2 + 0.06 * j as f64)))
+    }
+
+    #[test]
+    fn multibrot_of_power_two_is_mandelbrot() {
+        for (x0, y0) in grid() {
+            let expected = calculate_point(&Mandelbrot, x0, y0, &PARAMS);
+            let multibrot = calculate_point(&Multibrot { power: 2 }, x0, y0, &PARAMS);
+            assert_eq!(
+                multibrot.to_bits(),
+                expected.to_bits(),
+                "at ({}, {})",
+                x0,
+                y0
+            );
+        }
+    }
+
+    #[test]
+    fn early_exits_match_brute_force() {
+        let tolerance_sqr = (0.05 * PERIODICITY_TOLERANCE).powi(2);
+        let mut caught = 0;
+        for (x0, y0) in grid() {
+            let expected = calculate_point(&Mandelbrot, x0, y0, &PARAMS);
+            if in_cardioid_or_bulb(x0, y0) {
+                assert_eq!(expected, PARAMS.max_iterations, "at ({}, {})", x0, y0);
+            }
+            let (iteration, cycled) =
+                calculate_point_periodic(&Mandelbrot, x0, y0, &PARAMS, tolerance_sqr);
+            assert_eq!(
+                iteration.to_bits(),
+                expected.to_bits(),
+                "at ({}, {})",
+                x0,
+                y0
+            );
+            caught += cycled as u64;
+
+            let expected = calculate_point(&BurningShip, x0, y0, &PARAMS);
+            let (iteration, _) =
+                calculate_point_periodic(&BurningShip, x0, y0, &PARAMS, tolerance_sqr);
+            assert_eq!(
+                iteration.to_bits(),
+                expected.to_bits(),
+                "at ({}, {})",
+                x0,
+                y0
+            );
+        }
+        assert!(caught > 100, "only {} orbits caught in a cycle", caught);
+    }
+
+    #[test]
+    fn distance_estimate_brackets_the_true_distance() {
+        // The Mandelbrot set reaches the real axis at -2 and 1/4
+        for (x0, distance) in [(-2.5, 0.5), (-2.01, 0.01), (-2.0001, 0.0001), (1.25, 1.0)] {
+            let (iteration, estimate) = estimate_distance(&Mandelbrot, x0, 0.0, &PARAMS);
+            assert!(iteration < PARAMS.max_iterations);
+            // By Koebe's quarter theorem the true distance is between the
+            // estimate and four times it
+            assert!(
+                estimate > distance / 4.0 && estimate <= distance,
+                "at {}: {} vs {}",
+                x0,
+                estimate,
+                distance
+            );
+        }
+        let (_, inside) = estimate_distance(&Mandelbrot, -0.5, 0.0, &PARAMS);
+        assert_eq!(inside, 0.0);
+
+        for (x0, y0) in grid() {
+            let expected = calculate_point(&Mandelbrot, x0, y0, &PARAMS);
+            let (iteration, _) = estimate_distance(&Multibrot { power: 2 }, x0, y0, &PARAMS);
+            assert_eq!(iteration.to_bits(), expected.to_bits());
+        }
+    }
+
+    #[test]
+    fn real_multibrot_matches_integer_powers() {
+        for power in [3, 5] {
+            for (x0, y0) in grid() {
+                let expected = calculate_point(&Multibrot { power }, x0, y0, &PARAMS);
+                let real = calculate_point(
+                    &RealMultibrot {
+                        power: power as f64,
+                    },
+                    x0,
+                    y0,
+                    &PARAMS,
+                );
+                // Rounding differs between the two, but never by enough to
+                // change the escape count on this grid
+                assert!(
+                    (real - expected).abs() < 1e-6,
+                    "power {} at ({}, {}): {} != {}",
+                    power,
+                    x0,
+                    y0,
+                    real,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_multibrot_counts_have_no_bands() {
+        /// Cubic iteration smoothed as if it were quadratic
+        struct SquareSmoothed(Multibrot);
+
+        impl Fractal for SquareSmoothed {
+            fn step(&self, z: (f64, f64), c: (f64, f64)) -> (f64, f64) {
+                self.0.step(z, c)
+            }
+
+            fn degree(&self) -> f64 {
+                2.0
+            }
+        }
+
+        fn largest_jump<F: Fractal>(fractal: &F) -> f64 {
+            let params = EscapeParams {
+                max_iterations: 1000.0,
+                bailout_sqr: 1e6,
+                julia: None,
+            };
+            // A line towards the set crossing several escape count bands
+            let values: Vec<f64> = (0..=2000)
+                .map(|i| calculate_point(fractal, 0.85 - 0.0001 * i as f64, 0.3, &params))
+                .collect();
+            values
+                .windows(2)
+                .map(|pair| (pair[1] - pair[0]).abs())
+                .fold(0.0, f64::max)
+        }
+
+        let cubic = Multibrot { power: 3 };
+        let smooth = largest_jump(&cubic);
+        let banded = largest_jump(&SquareSmoothed(cubic));
+        assert!(smooth < 0.05, "jump of {}", smooth);
+        assert!(banded > 0.2, "jump of {}", banded);
+    }
+}
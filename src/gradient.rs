@@ -0,0 +1,453 @@
+//! Gradient files made by other programs: GIMP `.ggr`, Fractint `.map` and
+//! UltraFractal `.ugr`/`.gradient`. Every format is read into segments
+//! blended the way GIMP blends them, and sampled by position from 0 to 1.
+//! Opacity is not supported and is ignored.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{hsv_to_rgb, rgb_to_hsv, Color},
+    error::{Error, Result},
+};
+
+/// UltraFractal places colors at indices from 0 to this
+const UGR_INDICES: f64 = 400.0;
+
+/// Segments shorter than this are treated as a hard edge
+const EPSILON: f64 = 1e-10;
+
+/// How the color moves from one end of a segment to the other, named after
+/// GIMP's blending functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blend {
+    Linear,
+    /// A power curve through the midpoint
+    Curved,
+    Sine,
+    SphereIncreasing,
+    SphereDecreasing,
+    /// Jumps from one color to the other at the midpoint
+    Step,
+}
+
+/// Space in which the endpoint colors are mixed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorModel {
+    Rgb,
+    /// Hue turning counter-clockwise
+    HsvCcw,
+    /// Hue turning clockwise
+    HsvCw,
+}
+
+type Rgb = [f64; 3];
+
+/// Gradient file named by a scene's palette
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GradientFile {
+    /// Relative paths are resolved against the directory of the scene file
+    pub file: PathBuf,
+    /// Gradient to use from an UltraFractal file holding several
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl GradientFile {
+    pub fn load(&self) -> Result<Gradient> {
+        Gradient::load(&self.file, self.name.as_deref())
+    }
+
+    /// Whether the file is in a format that can hold several gradients
+    pub fn is_collection(&self) -> bool {
+        self.file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("ugr") || ext.eq_ignore_ascii_case("gradient")
+            })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    left: f64,
+    middle: f64,
+    right: f64,
+    left_color: Rgb,
+    right_color: Rgb,
+    blend: Blend,
+    model: ColorModel,
+}
+
+impl Segment {
+    /// A plain linear segment between two colors
+    fn linear(left: f64, left_color: Rgb, right: f64, right_color: Rgb) -> Self {
+        Self {
+            left,
+            middle: (left + right) / 2.0,
+            right,
+            left_color,
+            right_color,
+            blend: Blend::Linear,
+            model: ColorModel::Rgb,
+        }
+    }
+
+    /// Blend factor from 0 at the left end to 1 at the right, as GIMP
+    /// computes it
+    fn factor(&self, t: f64) -> f64 {
+        let length = self.right - self.left;
+        let (position, middle) = if length < EPSILON {
+            (0.5, 0.5)
+        } else {
+            ((t - self.left) / length, (self.middle - self.left) / length)
+        };
+
+        match self.blend {
+            Blend::Linear => linear_factor(middle, position),
+            Blend::Curved => position.powf(0.5_f64.ln() / middle.max(EPSILON).ln()),
+            Blend::Sine => {
+                let factor = linear_factor(middle, position);
+                ((std::f64::consts::PI * factor - std::f64::consts::FRAC_PI_2).sin() + 1.0) / 2.0
+            }
+            Blend::SphereIncreasing => {
+                let factor = linear_factor(middle, position) - 1.0;
+                (1.0 - factor * factor).sqrt()
+            }
+            Blend::SphereDecreasing => {
+                let factor = linear_factor(middle, position);
+                1.0 - (1.0 - factor * factor).sqrt()
+            }
+            Blend::Step => {
+                if position >= middle {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+
+    fn color(&self, t: f64) -> Rgb {
+        let factor = self.factor(t).clamp(0.0, 1.0);
+        let (a, b) = (self.left_color, self.right_color);
+        match self.model {
+            ColorModel::Rgb => [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * factor),
+            ColorModel::HsvCcw | ColorModel::HsvCw => {
+                let (a, b) = (rgb_to_hsv(a), rgb_to_hsv(b));
+                let hue = if self.model == ColorModel::HsvCcw {
+                    if a[0] < b[0] {
+                        a[0] + (b[0] - a[0]) * factor
+                    } else {
+                        a[0] + (1.0 - (a[0] - b[0])) * factor
+                    }
+                } else if b[0] < a[0] {
+                    a[0] - (a[0] - b[0]) * factor
+                } else {
+                    a[0] - (1.0 - (b[0] - a[0])) * factor
+                };
+                hsv_to_rgb([
+                    hue.rem_euclid(1.0),
+                    a[1] + (b[1] - a[1]) * factor,
+                    a[2] + (b[2] - a[2]) * factor,
+                ])
+            }
+        }
+    }
+}
+
+fn linear_factor(middle: f64, position: f64) -> f64 {
+    if position <= middle {
+        if middle < EPSILON {
+            0.0
+        } else {
+            0.5 * position / middle
+        }
+    } else {
+        let middle = 1.0 - middle;
+        if middle < EPSILON {
+            1.0
+        } else {
+            0.5 + 0.5 * (position - (1.0 - middle)) / middle
+        }
+    }
+}
+
+/// A color ramp read from a gradient file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradient {
+    segments: Vec<Segment>,
+}
+
+impl Gradient {
+    /// Read a gradient file, picking the format from its extension. `name`
+    /// selects one gradient from UltraFractal files holding several, the
+    /// first is used when it is omitted.
+    pub fn load(path: &Path, name: Option<&str>) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| Error::Gradient(format!("{}: {}", path.display(), err)))?;
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        let gradient = match extension.as_str() {
+            "ggr" => Self::parse_ggr(&text),
+            "map" => Self::parse_map(&text),
+            "ugr" | "gradient" => Self::parse_ugr(&text, name),
+            _ => Err("gradient files must end in .ggr, .map, .ugr or .gradient".to_string()),
+        };
+        gradient.map_err(|err| Error::Gradient(format!("{}: {}", path.display(), err)))
+    }
+
+    /// GIMP gradient: a header, a segment count and one line per segment
+    /// with positions, endpoint colors, blending function and color model
+    pub fn parse_ggr(text: &str) -> std::result::Result<Self, String> {
+        let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty());
+        if lines.next() != Some("GIMP Gradient") {
+            return Err("missing \"GIMP Gradient\" header".to_string());
+        }
+        let mut line = lines.next().ok_or("missing segment count")?;
+        if line.starts_with("Name:") {
+            line = lines.next().ok_or("missing segment count")?;
+        }
+        let count: usize = line
+            .parse()
+            .map_err(|_| format!("invalid segment count {:?}", line))?;
+
+        let mut segments = Vec::with_capacity(count);
+        for index in 0..count {
+            let line = lines
+                .next()
+                .ok_or_else(|| format!("expected {} segments, found {}", count, index))?;
+            let fields = line
+                .split_whitespace()
+                .map(|field| field.parse::<f64>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|_| format!("invalid segment {:?}", line))?;
+            if fields.len() < 11 {
+                return Err(format!("segment {:?} has too few fields", line));
+            }
+
+            let blend = match fields.get(11).copied().unwrap_or(0.0) as u32 {
+                0 => Blend::Linear,
+                1 => Blend::Curved,
+                2 => Blend::Sine,
+                3 => Blend::SphereIncreasing,
+                4 => Blend::SphereDecreasing,
+                5 => Blend::Step,
+                other => return Err(format!("unknown blending function {}", other)),
+            };
+            let model = match fields.get(12).copied().unwrap_or(0.0) as u32 {
+                0 => ColorModel::Rgb,
+                1 => ColorModel::HsvCcw,
+                2 => ColorModel::HsvCw,
+                other => return Err(format!("unknown color model {}", other)),
+            };
+            segments.push(Segment {
+                left: fields[0],
+                middle: fields[1],
+                right: fields[2],
+                left_color: [fields[3], fields[4], fields[5]],
+                right_color: [fields[7], fields[8], fields[9]],
+                blend,
+                model,
+            });
+        }
+        Self::new(segments)
+    }
+
+    /// Fractint map: one "red green blue" line per color, anything after
+    /// the three numbers is a comment
+    pub fn parse_map(text: &str) -> std::result::Result<Self, String> {
+        let mut colors = Vec::new();
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            let channels: Vec<&str> = fields.by_ref().take(3).collect();
+            if channels.is_empty() {
+                continue;
+            }
+            let channels = channels
+                .iter()
+                .map(|channel| channel.parse::<u8>())
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .ok()
+                .filter(|channels| channels.len() == 3)
+                .ok_or_else(|| format!("invalid color line {:?}", line.trim()))?;
+            colors.push([0, 1, 2].map(|i| channels[i] as f64 / 255.0));
+        }
+        if colors.len() < 2 {
+            return Err("a map needs at least 2 colors".to_string());
+        }
+
+        let last = (colors.len() - 1) as f64;
+        let segments = colors
+            .windows(2)
+            .enumerate()
+            .map(|(i, pair)| {
+                Segment::linear(i as f64 / last, pair[0], (i + 1) as f64 / last, pair[1])
+            })
+            .collect();
+        Self::new(segments)
+    }
+
+    /// UltraFractal gradient collection: entries of the form
+    /// `name { gradient: ... index=0 color=16777215 ... }`, with colors as
+    /// 0xBBGGRR and indices from 0 to 399 that wrap around
+    pub fn parse_ugr(text: &str, name: Option<&str>) -> std::result::Result<Self, String> {
+        let entries = ugr_entries(text);
+        let body = match name {
+            Some(name) => entries
+                .iter()
+                .find(|(title, _)| title == name)
+                .map(|(_, body)| *body)
+                .ok_or_else(|| {
+                    let titles: Vec<&str> =
+                        entries.iter().map(|(title, _)| title.as_str()).collect();
+                    format!("no gradient named {:?}, found {:?}", name, titles)
+                })?,
+            None => entries.first().map(|(_, body)| *body).unwrap_or(text),
+        };
+
+        let mut stops: Vec<(f64, Rgb)> = Vec::new();
+        let mut index = None;
+        for token in body.split_whitespace() {
+            if let Some(value) = token.strip_prefix("index=") {
+                index = Some(
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| format!("invalid index {:?}", value))?,
+                );
+            } else if let Some(value) = token.strip_prefix("color=") {
+                let color: u32 = value
+                    .parse()
+                    .map_err(|_| format!("invalid color {:?}", value))?;
+                let index = index.take().ok_or("color without an index")?;
+                let channel = |shift: u32| ((color >> shift) & 0xFF) as f64 / 255.0;
+                stops.push((index / UGR_INDICES, [channel(0), channel(8), channel(16)]));
+            }
+        }
+        if stops.is_empty() {
+            return Err("no index=... color=... pairs found".to_string());
+        }
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        // Wrap the ends around so that every position from 0 to 1 falls in
+        // a segment
+        let (first, last) = (stops[0], stops[stops.len() - 1]);
+        stops.insert(0, (last.0 - 1.0, last.1));
+        stops.push((first.0 + 1.0, first.1));
+        let segments = stops
+            .windows(2)
+            .map(|pair| Segment::linear(pair[0].0, pair[0].1, pair[1].0, pair[1].1))
+            .collect();
+        Self::new(segments)
+    }
+
+    fn new(segments: Vec<Segment>) -> std::result::Result<Self, String> {
+        if segments.is_empty() {
+            return Err("gradient has no segments".to_string());
+        }
+        if segments.iter().any(|segment| {
+            !(segment.left <= segment.middle && segment.middle <= segment.right)
+                || segment
+                    .left_color
+                    .iter()
+                    .chain(&segment.right_color)
+                    .any(|c| !c.is_finite())
+        }) {
+            return Err("segment positions must be ordered left <= middle <= right".to_string());
+        }
+        Ok(Self { segments })
+    }
+
+    /// Color at position t from 0 to 1
+    pub fn color(&self, t: f64) -> Color {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| t <= segment.right)
+            .unwrap_or(&self.segments[self.segments.len() - 1]);
+        Color::from_rgb(segment.color(t))
+    }
+}
+
+/// Split an UltraFractal file into (title, body) pairs
+fn ugr_entries(text: &str) -> Vec<(String, &str)> {
+    let mut entries = Vec::new();
+    let mut rest = text;
+    while let Some(open) = rest.find('{') {
+        let title = rest[..open].lines().last().unwrap_or("").trim().to_string();
+        let close = match rest[open..].find('}') {
+            Some(close) => open + close,
+            None => break,
+        };
+        entries.push((title, &rest[open + 1..close]));
+        rest = &rest[close + 1..];
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ggr_blend_modes() {
+        let text = "GIMP Gradient\nName: Test\n3\n\
+            0.0 0.25 0.5 0 0 0 1 1 1 1 1 0 0\n\
+            0.5 0.75 1.0 1 0 0 1 0 0 1 1 5 0\n";
+        assert!(Gradient::parse_ggr(text).is_err(), "segment count is 3");
+
+        let text = text.replace("\n3\n", "\n2\n");
+        let gradient = Gradient::parse_ggr(&text).unwrap();
+        // Linear with the midpoint at a quarter: half way at 0.25
+        assert_eq!(gradient.color(0.0), Color::new(0, 0, 0));
+        assert_eq!(gradient.color(0.25), Color::new(128, 128, 128));
+        assert_eq!(gradient.color(0.5), Color::new(255, 255, 255));
+        // Step from red to blue at 0.75
+        assert_eq!(gradient.color(0.7), Color::new(255, 0, 0));
+        assert_eq!(gradient.color(0.8), Color::new(0, 0, 255));
+    }
+
+    #[test]
+    fn ggr_hsv_takes_the_requested_direction() {
+        // Red to blue through magenta (clockwise) or green (counter-clockwise)
+        let segment = |model| format!("GIMP Gradient\n1\n0 0.5 1 1 0 0 1 0 0 1 1 0 {}\n", model);
+        let ccw = Gradient::parse_ggr(&segment(1)).unwrap();
+        let cw = Gradient::parse_ggr(&segment(2)).unwrap();
+        assert_eq!(ccw.color(0.5), Color::new(0, 255, 0));
+        assert_eq!(cw.color(0.5), Color::new(255, 0, 255));
+    }
+
+    #[test]
+    fn map_colors_are_evenly_spaced() {
+        let gradient = Gradient::parse_map("0 0 0 black\n255 0 0\n\n255 255 255 white\n").unwrap();
+        assert_eq!(gradient.color(0.0), Color::new(0, 0, 0));
+        assert_eq!(gradient.color(0.5), Color::new(255, 0, 0));
+        assert_eq!(gradient.color(1.0), Color::new(255, 255, 255));
+        assert!(Gradient::parse_map("0 0\n").is_err());
+    }
+
+    #[test]
+    fn ugr_picks_entry_and_wraps() {
+        let text = "first {\ngradient:\n  title=\"First\" smooth=no\n  \
+            index=0 color=255\n  index=200 color=16711680\nopacity:\n  smooth=no\n}\n\
+            second {\ngradient:\n  index=100 color=65280\n}\n";
+        let first = Gradient::parse_ugr(text, None).unwrap();
+        assert_eq!(first.color(0.0), Color::new(255, 0, 0));
+        assert_eq!(first.color(0.5), Color::new(0, 0, 255));
+        // Three quarters of the way round, half way back to red
+        assert_eq!(first.color(0.75), Color::new(128, 0, 128));
+
+        let second = Gradient::parse_ugr(text, Some("second")).unwrap();
+        assert_eq!(second.color(0.9), Color::new(0, 255, 0));
+        assert!(Gradient::parse_ugr(text, Some("third")).is_err());
+    }
+}
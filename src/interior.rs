@@ -0,0 +1,351 @@
+//! Coloring points that never escape. Their orbits settle on an attracting
+//! cycle, whose period, multiplier and distance to the boundary show the
+//! hyperbolic components the point lies in.
+
+use std::f64::consts::TAU;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::Color,
+    fractal::{Fractal, Holomorphic, Mandelbrot, Multibrot, Periodicity, RealMultibrot},
+    kernel::EscapeParams,
+    palette::Palette,
+    scene::Formula,
+};
+
+/// Points of a cycle are told apart from the orbit merely passing close
+/// below this squared distance
+const PERIOD_TOLERANCE_SQR: f64 = 1e-20;
+
+/// Newton steps refining the cycle point
+const REFINE_STEPS: usize = 16;
+
+/// Spreads successive periods over the palette
+const GOLDEN_RATIO_CONJUGATE: f64 = 0.618_033_988_749_895;
+
+/// What interior points are colored by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InteriorMode {
+    /// Period of the attracting cycle, one palette color per period
+    Period,
+    /// Magnitude of the cycle's multiplier, 0 at the center of a component
+    /// and 1 on its edge
+    Magnitude,
+    /// Argument of the cycle's multiplier, one turn around each component
+    Argument,
+    /// Angle of z after the last iteration
+    Angle,
+    /// Estimated distance to the boundary of the set
+    Distance,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InteriorSpec {
+    pub mode: InteriorMode,
+    /// Interior distance in pixels at which the palette is half way
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub falloff: Option<f64>,
+}
+
+impl InteriorSpec {
+    pub const DEFAULT_FALLOFF: f64 = 16.0;
+
+    pub fn new(mode: InteriorMode) -> Self {
+        Self {
+            mode,
+            falloff: None,
+        }
+    }
+}
+
+/// Interior coloring set up for one render
+pub struct Interior {
+    spec: InteriorSpec,
+    formula: Formula,
+    power: f64,
+}
+
+impl Interior {
+    pub fn new(spec: &InteriorSpec, formula: Formula, power: f64) -> Self {
+        Self {
+            spec: spec.clone(),
+            formula,
+            power,
+        }
+    }
+
+    /// Color of a point that did not escape within the iteration limit,
+    /// with distances measured in pixels of the given size. Points whose
+    /// cycle cannot be found keep the interior color.
+    pub fn color(
+        &self,
+        x0: f64,
+        y0: f64,
+        pixel_size: f64,
+        params: &EscapeParams,
+        palette: &Palette,
+    ) -> Color {
+        let progress = match self.formula {
+            Formula::Mandelbrot => self.progress(&Mandelbrot, x0, y0, pixel_size, params),
+            Formula::Multibrot if self.power.fract() == 0.0 && self.power <= u32::MAX as f64 => {
+                self.progress(
+                    &Multibrot {
+                        power: self.power as u32,
+                    },
+                    x0,
+                    y0,
+                    pixel_size,
+                    params,
+                )
+            }
+            Formula::Multibrot => self.progress(
+                &RealMultibrot { power: self.power },
+                x0,
+                y0,
+                pixel_size,
+                params,
+            ),
+            _ => unreachable!("interior coloring needs a holomorphic formula"),
+        };
+        match progress {
+            Some(progress) => palette.sample(progress.clamp(0.0, 1.0) as f32),
+            None => palette.max_color,
+        }
+    }
+
+    /// Position along the palette for the point
+    fn progress<F: Holomorphic>(
+        &self,
+        fractal: &F,
+        x0: f64,
+        y0: f64,
+        pixel_size: f64,
+        params: &EscapeParams,
+    ) -> Option<f64> {
+        let (mut z, c) = match params.julia {
+            Some(c) => ((x0, y0), c),
+            None => (fractal.initial_z((x0, y0)), (x0, y0)),
+        };
+        for _ in 0..params.max_iterations as u64 {
+            z = fractal.step(z, c);
+        }
+        if fractal.escaped(z, params.bailout_sqr) || !(z.0.is_finite() && z.1.is_finite()) {
+            return None;
+        }
+        if self.spec.mode == InteriorMode::Angle {
+            return Some(z.1.atan2(z.0) / TAU + 0.5);
+        }
+
+        let period = find_period(fractal, z, c, params.max_iterations as u64)?;
+        if self.spec.mode == InteriorMode::Period {
+            return Some(((period - 1) as f64 * GOLDEN_RATIO_CONJUGATE).fract());
+        }
+
+        let z = refine(fractal, z, c, period);
+        let cycle = Derivatives::around_cycle(fractal, z, c, period);
+        let multiplier = cycle.dz;
+        let magnitude = multiplier.0.hypot(multiplier.1);
+        match self.spec.mode {
+            InteriorMode::Magnitude => Some(magnitude),
+            InteriorMode::Argument => Some(multiplier.1.atan2(multiplier.0) / TAU + 0.5),
+            InteriorMode::Distance => {
+                // Only defined for the parameter plane, where c varies
+                if params.julia.is_some() || magnitude >= 1.0 {
+                    return None;
+                }
+                let pixels = cycle.interior_distance() / pixel_size;
+                let falloff = self.spec.falloff.unwrap_or(InteriorSpec::DEFAULT_FALLOFF);
+                Some(1.0 / (1.0 + pixels / falloff))
+            }
+            InteriorMode::Period | InteriorMode::Angle => unreachable!(),
+        }
+    }
+}
+
+/// Length of the cycle the orbit has settled on from z, if it shows within
+/// `limit` steps
+fn find_period<F: Fractal>(
+    fractal: &F,
+    mut z: (f64, f64),
+    c: (f64, f64),
+    limit: u64,
+) -> Option<u64> {
+    let mut periodicity = Periodicity::new(z, PERIOD_TOLERANCE_SQR);
+    for _ in 0..limit {
+        z = fractal.step(z, c);
+        if let Some(period) = periodicity.check(z) {
+            return Some(period);
+        }
+    }
+    None
+}
+
+/// Move z onto the cycle with Newton's method on f^p(z) - z
+fn refine<F: Holomorphic>(
+    fractal: &F,
+    mut z: (f64, f64),
+    c: (f64, f64),
+    period: u64,
+) -> (f64, f64) {
+    for _ in 0..REFINE_STEPS {
+        let cycle = Derivatives::around_cycle(fractal, z, c, period);
+        let (w, dw) = (cycle.z, cycle.dz);
+        let step = div((w.0 - z.0, w.1 - z.1), (dw.0 - 1.0, dw.1));
+        if !(step.0.is_finite() && step.1.is_finite()) {
+            break;
+        }
+        z = (z.0 - step.0, z.1 - step.1);
+        if step.0 * step.0 + step.1 * step.1 < PERIOD_TOLERANCE_SQR * 1e-4 {
+            break;
+        }
+    }
+    z
+}
+
+/// f^p at a point and its derivatives with respect to z and c
+struct Derivatives {
+    z: (f64, f64),
+    dz: (f64, f64),
+    dc: (f64, f64),
+    dzdz: (f64, f64),
+    dcdz: (f64, f64),
+}
+
+impl Derivatives {
+    fn around_cycle<F: Holomorphic>(
+        fractal: &F,
+        z: (f64, f64),
+        c: (f64, f64),
+        period: u64,
+    ) -> Self {
+        let mut d = Derivatives {
+            z,
+            dz: (1.0, 0.0),
+            dc: (0.0, 0.0),
+            dzdz: (0.0, 0.0),
+            dcdz: (0.0, 0.0),
+        };
+        for _ in 0..period {
+            let slope = fractal.slope(d.z);
+            let curvature = fractal.curvature(d.z);
+            d = Derivatives {
+                z: fractal.step(d.z, c),
+                dz: mul(slope, d.dz),
+                dc: add(mul(slope, d.dc), (1.0, 0.0)),
+                dzdz: add(mul(slope, d.dzdz), mul(curvature, mul(d.dz, d.dz))),
+                dcdz: add(mul(slope, d.dcdz), mul(curvature, mul(d.dc, d.dz))),
+            };
+        }
+        d
+    }
+
+    /// Distance from c to the boundary of its hyperbolic component, within
+    /// a factor of four
+    fn interior_distance(&self) -> f64 {
+        let dz_abs_sqr = self.dz.0 * self.dz.0 + self.dz.1 * self.dz.1;
+        let one_minus_dz = (1.0 - self.dz.0, -self.dz.1);
+        let denominator = add(self.dcdz, div(mul(self.dzdz, self.dc), one_minus_dz));
+        (1.0 - dz_abs_sqr) / denominator.0.hypot(denominator.1)
+    }
+}
+
+fn add(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 + b.0, a.1 + b.1)
+}
+
+fn mul(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+fn div(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    let norm = b.0 * b.0 + b.1 * b.1;
+    (
+        (a.0 * b.0 + a.1 * b.1) / norm,
+        (a.1 * b.0 - a.0 * b.1) / norm,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAMS: EscapeParams = EscapeParams {
+        max_iterations: 1000.0,
+        bailout_sqr: 4.0,
+        julia: None,
+    };
+
+    fn progress(mode: InteriorMode, x0: f64, y0: f64) -> Option<f64> {
+        Interior::new(&InteriorSpec::new(mode), Formula::Mandelbrot, 2.0).progress(
+            &Mandelbrot,
+            x0,
+            y0,
+            1.0,
+            &PARAMS,
+        )
+    }
+
+    #[test]
+    fn periods_of_known_components() {
+        // Main cardioid, period 2 bulb, the period 3 bulb on top and the
+        // real period 3 minibrot
+        for ((x0, y0), period) in [
+            ((0.0, 0.0), 1),
+            ((-1.0, 0.0), 2),
+            ((-0.12, 0.75), 3),
+            ((-1.755, 0.0), 3),
+        ] {
+            let progress = progress(InteriorMode::Period, x0, y0).unwrap();
+            let expected = ((period - 1) as f64 * GOLDEN_RATIO_CONJUGATE).fract();
+            assert!((progress - expected).abs() < 1e-12, "at ({}, {})", x0, y0);
+        }
+    }
+
+    #[test]
+    fn multiplier_and_distance_at_component_centers() {
+        // Superattracting centers have multiplier 0
+        for (x0, y0) in [(0.0, 0.0), (-1.0, 0.0)] {
+            let magnitude = progress(InteriorMode::Magnitude, x0, y0).unwrap();
+            assert!(magnitude < 1e-9, "at ({}, {}): {}", x0, y0, magnitude);
+        }
+        // The period 2 bulb is the disk of radius 1/4 around -1
+        let z = refine(&Mandelbrot, (0.5, 0.0), (-1.0, 0.0), 2);
+        let distance =
+            Derivatives::around_cycle(&Mandelbrot, z, (-1.0, 0.0), 2).interior_distance();
+        assert!(
+            distance > 0.25 / 4.0 && distance <= 0.25 * 1.0001,
+            "{}",
+            distance
+        );
+    }
+
+    #[test]
+    fn interior_distance_is_counted_in_pixels() {
+        let interior = Interior::new(
+            &InteriorSpec::new(InteriorMode::Distance),
+            Formula::Mandelbrot,
+            2.0,
+        );
+        // Falloff pixels from the boundary is half way along the palette
+        let distance = |pixel_size| {
+            let progress = interior
+                .progress(&Mandelbrot, -1.0, 0.1, pixel_size, &PARAMS)
+                .unwrap();
+            InteriorSpec::DEFAULT_FALLOFF * (1.0 / progress - 1.0) * pixel_size
+        };
+        let (coarse, fine) = (distance(1e-3), distance(1e-5));
+        assert!(
+            (coarse - fine).abs() < 1e-12 * coarse,
+            "{} != {}",
+            coarse,
+            fine
+        );
+        // 0.15 inside the period 2 bulb, which the estimate overshoots by
+        // less than four times
+        assert!((0.15..0.6).contains(&coarse), "{}", coarse);
+    }
+}
@@ -0,0 +1,162 @@
+use clap::ValueEnum;
+
+use crate::{
+    error::{Error, Result},
+    fractal::{self, Mandelbrot},
+};
+
+#[cfg(target_arch = "x86_64")]
+use crate::simd;
+
+/// Per-render constants of the escape-time iteration
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EscapeParams {
+    pub max_iterations: f64,
+    /// Squared escape radius
+    pub bailout_sqr: f64,
+    /// Constant c of a Julia set. When set, the pixel is the starting z
+    /// instead of c.
+    pub julia: Option<(f64, f64)>,
+}
+
+/// Calculate the smooth iteration count of a single point on the mandelbrot set.
+/// Points that never escape return exactly `max_iterations`.
+/// x0: scaled x coordinate of pixel
+/// y0: scaled y coordinate of pixel
+pub fn mandelbrot_calculate_point(x0: f64, y0: f64, params: &EscapeParams) -> f64 {
+    fractal::calculate_point(&Mandelbrot, x0, y0, params)
+}
+
+/// Turn the integer escape count and final z into a fractional count, for a
+/// formula where |z| grows with the given power
+pub fn smooth(iteration: f64, x: f64, y: f64, degree: f64, params: &EscapeParams) -> f64 {
+    if iteration < params.max_iterations {
+        iteration + 1.0 - nu(x, y, degree)
+    } else {
+        iteration
+    }
+}
+
+/// How far past escaping the final z is, in iterations of a formula of the
+/// given degree, to be taken off the escape count
+pub fn nu(x: f64, y: f64, degree: f64) -> f64 {
+    let log_zn = (x * x + y * y).log2() / 2.0;
+    (log_zn / 2.0_f64.log2()).log2() / degree.log2()
+}
+
+/// Which escape-time kernel to run, as requested on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KernelChoice {
+    /// Fastest kernel the CPU supports
+    Auto,
+    /// One point at a time
+    Scalar,
+    /// 4 points at a time with AVX2
+    Avx2,
+    /// 8 points at a time with AVX-512
+    Avx512,
+}
+
+/// An escape-time kernel the current CPU is able to run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kernel {
+    Scalar,
+    Avx2,
+    Avx512,
+}
+
+impl Kernel {
+    /// Pick the widest kernel supported by the CPU
+    pub fn detect() -> Self {
+        if Kernel::Avx512.is_supported() {
+            Kernel::Avx512
+        } else if Kernel::Avx2.is_supported() {
+            Kernel::Avx2
+        } else {
+            Kernel::Scalar
+        }
+    }
+
+    pub fn select(choice: KernelChoice) -> Result<Self> {
+        let kernel = match choice {
+            KernelChoice::Auto => return Ok(Kernel::detect()),
+            KernelChoice::Scalar => Kernel::Scalar,
+            KernelChoice::Avx2 => Kernel::Avx2,
+            KernelChoice::Avx512 => Kernel::Avx512,
+        };
+
+        if kernel.is_supported() {
+            Ok(kernel)
+        } else {
+            Err(Error::InvalidArgument(format!(
+                "the {:?} kernel is not supported by this CPU",
+                kernel
+            )))
+        }
+    }
+
+    pub fn is_supported(self) -> bool {
+        match self {
+            Kernel::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+
+    /// Number of points iterated together
+    pub fn lanes(self) -> usize {
+        match self {
+            Kernel::Scalar => 1,
+            Kernel::Avx2 => 4,
+            Kernel::Avx512 => 8,
+        }
+    }
+
+    /// Smooth iteration counts for a run of points, written to `out`. With a
+    /// periodicity tolerance, orbits caught in a cycle stop early, and the
+    /// number of them is returned.
+    pub fn calculate_points(
+        self,
+        x0: &[f64],
+        y0: &[f64],
+        params: &EscapeParams,
+        periodicity: Option<f64>,
+        out: &mut [f64],
+    ) -> u64 {
+        match self {
+            Kernel::Scalar => match periodicity {
+                Some(tolerance_sqr) => {
+                    let mut periodic = 0;
+                    for ((x0, y0), out) in x0.iter().zip(y0).zip(out) {
+                        let (iteration, cycled) = fractal::calculate_point_periodic(
+                            &Mandelbrot,
+                            *x0,
+                            *y0,
+                            params,
+                            tolerance_sqr,
+                        );
+                        *out = iteration;
+                        periodic += cycled as u64;
+                    }
+                    periodic
+                }
+                None => {
+                    for ((x0, y0), out) in x0.iter().zip(y0).zip(out) {
+                        *out = mandelbrot_calculate_point(*x0, *y0, params);
+                    }
+                    0
+                }
+            },
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx2 => simd::calculate_points_avx2(x0, y0, params, periodicity, out),
+            #[cfg(target_arch = "x86_64")]
+            Kernel::Avx512 => simd::calculate_points_avx512(x0, y0, params, periodicity, out),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => unreachable!("SIMD kernels are only selected on x86_64"),
+        }
+    }
+}
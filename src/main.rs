@@ -1,149 +1,685 @@
-use std::{path::Path, time::SystemTime};
-use interpolation::lerp;
+use std::{
+    f64::consts::TAU,
+    fs,
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
+};
 
-const WIDTH: usize = 256 * 10;
-const HEIGHT: usize = 144 * 10;
-const MAX_ITERATIONS: f64 = 1000.0;
+use clap::{Parser, ValueEnum};
 
-const MIN_X: f64 = -2.5;
-const MAX_X: f64 = 1.0;
-const MIN_Y: f64 = -1.0;
-const MAX_Y: f64 = 1.0;
+mod animation;
+mod antialias;
+mod average;
+mod bignum;
+mod buddhabrot;
+mod cli;
+mod color;
+mod distance;
+mod error;
+mod exponential;
+mod fill;
+mod floatexp;
+mod fractal;
+mod gradient;
+mod interior;
+mod kernel;
+mod newton;
+mod output;
+mod palette;
+mod perturbation;
+mod png;
+mod pyramid;
+mod render;
+mod rng;
+mod scene;
+#[cfg(target_arch = "x86_64")]
+mod simd;
+mod stream;
+#[cfg(test)]
+mod test_util;
+mod trap;
+mod video;
+mod viewport;
 
-const ZOOM_FACTOR: f64 = 7.0;
-const OFFSET_X: f64 = -3.5;
-const OFFSET_Y: f64 = -2.5;
+use animation::Animation;
+use cli::{AnimateArgs, AssembleArgs, Cli, Command, ExportArgs, RenderJob};
+use color::Color;
+use error::{Error, Result};
+use exponential::Strip;
+use fill::Strategy;
+use output::FrameOutput;
+use pyramid::Layout;
+use render::{delta_kind, uses_perturbation, Renderer};
+use scene::{OutputFormat, Scene, SceneFormat};
+use stream::ImageStream;
+use trap::TrapShape;
+use viewport::Mapping;
+
+/// Print progress to stdout, or to stderr while stdout carries a video
+macro_rules! progress {
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if $to_stderr {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
 
 fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Render(args) => args.job().and_then(|job| render(&job)),
+        Command::Info(args) => args.job().map(|job| info(&job)),
+        Command::Animate(args) => args.job().and_then(|job| animate(&args, &job)),
+        Command::Assemble(args) => assemble(&args),
+        Command::Export(args) => args.render.job().and_then(|job| export(&args, &job)),
+    };
+
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
 
-    let palette = Palette::generate(MAX_ITERATIONS as usize);
-    let mut set: Vec<Color> = Vec::with_capacity(WIDTH * HEIGHT);
+fn render(job: &RenderJob) -> Result<()> {
+    let scene = &job.scene;
+    let width = scene.image.width;
+    let height = scene.image.height;
+    let max_iterations = scene.iteration.max_iterations;
+    let mut renderer = Renderer::new(scene)?;
 
-    println!("Calculating mandelbrot set...");
+    let formula = scene
+        .formula
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string());
+    let name = if scene.julia.is_some() {
+        format!("{} julia", formula)
+    } else if scene.buddhabrot.is_some() {
+        format!("{} buddhabrot", formula)
+    } else {
+        formula
+    };
+    println!("Calculating {} set...", name);
     let start = SystemTime::now();
 
-    for index in 0..(WIDTH * HEIGHT) {
-        let x = index % WIDTH;
-        let y = index / WIDTH;
+    let mut streamed = None;
+    let (set, stats) = match job.band_rows {
+        Some(band_rows) => {
+            let mut stream = ImageStream::create(&job.output, scene.image.format, width, height)?;
+            let stats =
+                renderer.render_bands(&job.options, band_rows, |rows| stream.write_rows(rows))?;
+            streamed = Some(stream.finish()?);
+            (Vec::new(), stats)
+        }
+        None => renderer.render(&job.options)?,
+    };
 
-        let x0 = (((((MAX_X + OFFSET_X) / ZOOM_FACTOR) - ((MIN_X + OFFSET_X) / ZOOM_FACTOR)) * x as f64) / WIDTH as f64) + ((MIN_X + OFFSET_X) / ZOOM_FACTOR);
-        let y0 = (((((MAX_Y + OFFSET_Y) / ZOOM_FACTOR) - ((MIN_Y + OFFSET_Y) / ZOOM_FACTOR)) * y as f64) / HEIGHT as f64) + ((MIN_Y + OFFSET_Y) / ZOOM_FACTOR);
+    let calc_time = SystemTime::now().duration_since(start).unwrap_or_default();
 
-        set.push(mandelbrot_calculate_point(x0, y0, &palette));
+    println!(
+        "Calculated {} set at {} x {} with {} iterations in {:.2} seconds",
+        name,
+        width,
+        height,
+        max_iterations,
+        calc_time.as_secs_f32()
+    );
+    if let Some(buddhabrot) = stats.buddhabrot {
+        println!(
+            "Traced {} sample(s), {} orbit(s) crossed the view, {} proposal(s) accepted",
+            buddhabrot.samples, buddhabrot.orbits, buddhabrot.accepted
+        );
+    }
+    if let Some(perturbation) = stats.perturbation {
+        println!(
+            "Used {} reference orbit(s), {} pixel(s) left glitched",
+            perturbation.references, perturbation.glitched_pixels
+        );
+    }
+    if let Some(samples) = stats.samples {
+        println!(
+            "Took {} sample(s), {:.2} per pixel, {} of {} pixel(s) supersampled with {} each",
+            samples.samples,
+            samples.samples as f64 / (width * height) as f64,
+            samples.supersampled,
+            width * height,
+            samples.per_pixel
+        );
+    }
+    if let Some(exits) = stats.exits {
+        if job.options.cardioid || job.options.periodicity {
+            println!(
+                "Skipped {} pixel(s) in the main cardioid or period-2 bulb, stopped {} pixel(s) caught in a cycle",
+                exits.cardioid, exits.periodic
+            );
+        }
+        if job.options.strategy != Strategy::Pixels {
+            println!(
+                "Filled {} of {} pixel(s) without iterating them",
+                exits.filled,
+                width * height
+            );
+        }
     }
 
-    let calc_time = SystemTime::now().duration_since(start).unwrap();
+    match streamed {
+        Some(format) => println!("Saved {} as {}", job.output.display(), format),
+        None => {
+            output::save_image(
+                &job.output,
+                scene.image.format,
+                width,
+                height,
+                rgba_buffer(&set),
+            )?;
+            println!("Saved {}", job.output.display());
+        }
+    }
+    if let Some(peak) = stream::peak_memory() {
+        println!("Peak memory {:.1} MiB", peak as f64 / (1 << 20) as f64);
+    }
 
-    println!("Calculated mandelbrot set at {} x {} with {} iterations in {:.2} seconds", WIDTH, HEIGHT, MAX_ITERATIONS as usize, calc_time.as_secs_f32());
+    let scene_path = job.scene_path();
+    scene.save(&scene_path, job.scene_format)?;
+    println!("Saved {}", scene_path.display());
+
+    Ok(())
+}
 
-    let mut buffer = vec![0; WIDTH * HEIGHT * 4];
+fn rgba_buffer(set: &[Color]) -> Vec<u8> {
+    let mut buffer = vec![0; set.len() * 4];
 
     for (i, pixel) in buffer.chunks_exact_mut(4).enumerate() {
         pixel.copy_from_slice(&set[i].as_slice());
     }
-
-    image::save_buffer(&Path::new("mandelbrot.png"), &buffer, WIDTH as u32, HEIGHT as u32, image::ColorType::Rgba8).unwrap();
+    buffer
 }
 
-/// Calculate the value of a single colored point on the mandelbrot set
-/// x0: scaled x coordinate of pixel (scaled to lie in the Mandelbrot X scale (-2.5, 1))
-/// y0: scaled y coordinate of pixel (scaled to lie in the Mandelbrot Y scale (-1, 1))
-fn mandelbrot_calculate_point(x0: f64, y0: f64, palette: &Palette) -> Color {
-    let mut x: f64 = 0.0;
-    let mut y: f64 = 0.0;
-    let mut iteration: f64 = 0.0;
+fn animate(args: &AnimateArgs, job: &RenderJob) -> Result<()> {
+    let animation = Animation::load(&args.keyframes)?;
+    let (width, height) = (job.scene.image.width, job.scene.image.height);
+    let frames = animation.frame_count();
+    let mut output = args.video.output(&args.frames, width, height, frames)?;
+    let to_stderr = args.video.to_stdout();
 
-    while (x*x + y*y) as u64 <= 2^32 && iteration < MAX_ITERATIONS {
-        let xtemp = x*x - y*y + x0;
-        y = 2.0*x*y + y0;
-        x = xtemp;
-        iteration += 1.0;
+    // Frames of another animation would not join up with the new ones
+    if let FrameOutput::Files(dir) = &output {
+        let scene_path = dir
+            .join("scene")
+            .with_extension(job.scene_format.extension());
+        let keyframes_format = SceneFormat::from_path(&args.keyframes)?;
+        let keyframes_path = dir
+            .join("keyframes")
+            .with_extension(keyframes_format.extension());
+        let records = [
+            (
+                job.scene.to_text(&scene_path, job.scene_format)?,
+                scene_path,
+            ),
+            (
+                animation.to_text(&keyframes_path, keyframes_format)?,
+                keyframes_path,
+            ),
+        ];
+        record_job(
+            dir,
+            &records,
+            args.restart,
+            "frames of a different animation",
+        )?;
     }
 
-    if iteration < MAX_ITERATIONS {
-        let log_zn = (x*x + y*y).log2() / 2.0;
-        let nu = (log_zn / (2.0 as f64).log2()).log2() / (2.0 as f64).log2();
-        iteration = iteration + 1.0 - nu;
+    // Frame files are renamed into place once written, so any that exist
+    // are complete
+    let done: Vec<bool> = (0..frames)
+        .map(|frame| match &output {
+            FrameOutput::Files(dir) => {
+                !args.restart && FrameOutput::frame_path(dir, frame).exists()
+            }
+            FrameOutput::Video(..) => false,
+        })
+        .collect();
+    let skipped = done.iter().filter(|&&done| done).count();
+    if skipped > 0 {
+        progress!(
+            to_stderr,
+            "Resuming with {} of {} frame(s) already in {}",
+            skipped,
+            frames,
+            output
+        );
     }
 
-    let c1 = if iteration >= MAX_ITERATIONS {
-        &palette.max_color
-    } else {
-        palette.get_color(((iteration as usize)).min((MAX_ITERATIONS as usize) - 1))
-    };
-    let c2 = palette.get_color(((iteration as usize) + 1).min((MAX_ITERATIONS as usize) - 1));
-    
-    c1.interpolate(c2, iteration.fract() as f32)
-}
+    let start = SystemTime::now();
+    for (frame, &done) in done.iter().enumerate() {
+        if done {
+            continue;
+        }
+        let frame_start = SystemTime::now();
+        let mut scene = job.scene.clone();
+        animation.apply(frame, &mut scene);
+        scene.validate()?;
+        job.options.check(&scene)?;
+        let (set, _) = Renderer::new(&scene)?.render(&job.options)?;
 
-#[derive(Debug)]
-struct Color {
-    red: u8,
-    blue: u8,
-    green: u8,
+        output.write(frame, width, height, rgba_buffer(&set))?;
+        progress!(
+            to_stderr,
+            "Frame {}/{} in {:.2} seconds",
+            frame + 1,
+            frames,
+            SystemTime::now()
+                .duration_since(frame_start)
+                .unwrap_or_default()
+                .as_secs_f32()
+        );
+    }
+
+    let target = output.to_string();
+    output.finish()?;
+    progress!(
+        to_stderr,
+        "Rendered {} frame(s) to {} in {:.2} seconds",
+        frames - skipped,
+        target,
+        SystemTime::now()
+            .duration_since(start)
+            .unwrap_or_default()
+            .as_secs_f32()
+    );
+    Ok(())
 }
 
-impl Color {
-    fn new(r: u8, g: u8, b: u8) -> Self {
-        Self {
-            red: r,
-            blue: b,
-            green: g,
-        }
+fn assemble(args: &AssembleArgs) -> Result<()> {
+    let scene_path = args
+        .scene
+        .clone()
+        .unwrap_or_else(|| args.strip.with_extension("toml"));
+    let scene = Scene::load(&scene_path)?;
+    if scene.viewport.mapping != Mapping::Exponential {
+        return Err(Error::InvalidArgument(format!(
+            "{} is not an exponential map, render the strip with --mapping exponential",
+            scene_path.display()
+        )));
+    }
+    let image = image::open(&args.strip)?.to_rgba8();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    if (width, height) != (scene.image.width, scene.image.height) {
+        return Err(Error::InvalidArgument(format!(
+            "{} is {} x {}, but {} renders at {} x {}",
+            args.strip.display(),
+            width,
+            height,
+            scene_path.display(),
+            scene.image.width,
+            scene.image.height
+        )));
+    }
+    if args.width == 0 || args.height == 0 {
+        return Err(Error::InvalidArgument(
+            "frames must be at least 1 x 1 pixels".to_string(),
+        ));
     }
 
-    fn as_slice(&self) -> [u8; 4] {
-        [self.red.clone(), self.green.clone(), self.blue.clone(), 0xFF]
+    let strip = Strip::new(&scene.viewport, width, height, image.as_raw());
+    let (first, last) = strip.depths(args.width, args.height);
+    if last < first {
+        return Err(Error::InvalidArgument(format!(
+            "{} is too short to fill a {} x {} frame, render it taller",
+            args.strip.display(),
+            args.width,
+            args.height
+        )));
     }
+    let count = args
+        .count
+        .unwrap_or_else(|| ((last - first) * 30.0).ceil() as usize + 1);
+    let to_stderr = args.video.to_stdout();
+    progress!(
+        to_stderr,
+        "Assembling {} frame(s) from zoom {} to {}",
+        count,
+        strip.zoom(first, args.width),
+        strip.zoom(last, args.width)
+    );
+    let mut output = args
+        .video
+        .output(&args.frames, args.width, args.height, count)?;
 
-    fn interpolate(&self, other_color: &Color, t: f32) -> Self {
-        Self {
-            red: lerp(&self.red, &other_color.red, &t),
-            green: lerp(&self.green, &other_color.green, &t),
-            blue: lerp(&self.blue, &other_color.blue, &t),
-        }
+    let start = SystemTime::now();
+    for frame in 0..count {
+        let depth = if count > 1 {
+            first + (last - first) * frame as f64 / (count - 1) as f64
+        } else {
+            first
+        };
+        output.write(
+            frame,
+            args.width,
+            args.height,
+            strip.frame(depth, args.width, args.height),
+        )?;
     }
-}
 
-struct Palette {
-    colors: Vec<Color>,
-    max_color: Color,
+    let target = output.to_string();
+    output.finish()?;
+    progress!(
+        to_stderr,
+        "Saved {} frame(s) to {} in {:.2} seconds",
+        count,
+        target,
+        SystemTime::now()
+            .duration_since(start)
+            .unwrap_or_default()
+            .as_secs_f32()
+    );
+    Ok(())
 }
 
-impl Palette {
-    fn generate(size: usize) -> Self {
-        let mut colors = Vec::with_capacity(size);
-        for index in 0..size {
-            let progress = index as f32 / size as f32;
-            let quartic = root(progress, 3);
-            colors.push(Color::new(
-                lerp(&(0x0 as u8), &(0xFF as u8), &quartic),
-                lerp(&(0x0 as u8), &(0xFF as u8), &quartic),
-                lerp(&(0x55 as u8), &(0xFF as u8), &quartic),
-            ));
+/// Write the files that describe a job rendering into `dir`, given as
+/// their text and path. Unless restarting, refuse to resume when they differ
+/// from the ones already there, which means that the files in `dir` hold
+/// `what`.
+fn record_job(dir: &Path, records: &[(String, PathBuf)], restart: bool, what: &str) -> Result<()> {
+    if !restart {
+        for (text, path) in records {
+            if path.exists() && fs::read_to_string(path)? != *text {
+                return Err(Error::InvalidArgument(format!(
+                    "{} holds {}, pass --restart to render them again",
+                    dir.display(),
+                    what
+                )));
+            }
         }
+    }
+    for (text, path) in records {
+        fs::write(path, text)?;
+    }
+    Ok(())
+}
 
-        Self {
-            colors,
-            max_color: Color::new(0, 0, 0)
+fn export(args: &ExportArgs, job: &RenderJob) -> Result<()> {
+    let scene = &job.scene;
+    if scene.viewport.mapping != Mapping::Cartesian {
+        return Err(Error::InvalidArgument(
+            "tile pyramids need the cartesian mapping".to_string(),
+        ));
+    }
+    if scene.buddhabrot.is_some() {
+        return Err(Error::InvalidArgument(
+            "the buddhabrot accumulates orbits over the whole image and cannot be tiled"
+                .to_string(),
+        ));
+    }
+    let format = scene.image.format;
+    if !matches!(format, OutputFormat::Png | OutputFormat::Jpeg) {
+        return Err(Error::InvalidArgument(format!(
+            "tiles are written as PNG or JPEG, not {:?}",
+            format
+        )));
+    }
+    let extension = format.extension();
+    let (width, height) = (scene.image.width, scene.image.height);
+    let pyramid = args.pyramid(width, height)?;
+
+    // Tiles from another scene, or cut from another grid, would not fit
+    // together with the new ones
+    fs::create_dir_all(&args.dir)?;
+    let scene_path = args
+        .dir
+        .join("scene")
+        .with_extension(job.scene_format.extension());
+    let pyramid_path = args
+        .dir
+        .join("pyramid")
+        .with_extension(job.scene_format.extension());
+    let pyramid_text = job
+        .scene_format
+        .write(&pyramid.record(&args.name, extension))
+        .map_err(|err| Error::Scene(format!("{}: {}", pyramid_path.display(), err)))?;
+    let records = [
+        (scene.to_text(&scene_path, job.scene_format)?, scene_path),
+        (pyramid_text, pyramid_path),
+    ];
+    record_job(
+        &args.dir,
+        &records,
+        args.restart,
+        "tiles of a different scene or pyramid",
+    )?;
+    if pyramid.layout == Layout::Dzi {
+        let descriptor = args.dir.join(format!("{}.dzi", args.name));
+        fs::write(descriptor, pyramid.descriptor(extension))?;
+    }
+
+    // Every tile is iterated against the reference orbit of the full image,
+    // on one pool of threads
+    let full = Renderer::new(scene)?;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(job.options.threads)
+        .build()?;
+
+    let start = SystemTime::now();
+    let (mut rendered, mut skipped) = (0, 0);
+    for level in pyramid.levels() {
+        let level_start = SystemTime::now();
+        let mut level_rendered = 0;
+        for tile in pyramid.tiles(level) {
+            // Tile files are renamed into place once written, so any that
+            // exist are complete
+            let path = pyramid.tile_path(&args.dir, &args.name, level, &tile, extension);
+            if !args.restart && path.exists() {
+                skipped += 1;
+                continue;
+            }
+            let mut tile_scene = scene.clone();
+            tile_scene.viewport = pyramid.viewport(&scene.viewport, level, &tile);
+            tile_scene.image.width = tile.width;
+            tile_scene.image.height = tile.height;
+            tile_scene.validate()?;
+            job.options.check(&tile_scene)?;
+            let mut renderer = Renderer::new(&tile_scene)?;
+            renderer.share_reference(&full, pyramid.center_offset(&scene.viewport, level, &tile));
+            let (set, _) = pool.install(|| renderer.render_in_pool(&job.options))?;
+
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            output::save_image_whole(&path, format, tile.width, tile.height, rgba_buffer(&set))?;
+            level_rendered += 1;
+        }
+        if level_rendered > 0 {
+            println!(
+                "Level {}/{}: {} tile(s) at {} x {} in {:.2} seconds",
+                level.index,
+                pyramid.levels().len() - 1,
+                level_rendered,
+                level.width,
+                level.height,
+                SystemTime::now()
+                    .duration_since(level_start)
+                    .unwrap_or_default()
+                    .as_secs_f32()
+            );
         }
+        rendered += level_rendered;
     }
 
-    fn get_color(&self, index: usize) -> &Color {
-        &self.colors[index]
+    if skipped > 0 {
+        println!("Kept {} tile(s) already in {}", skipped, args.dir.display());
     }
+    println!(
+        "Rendered {} tile(s) to {} in {:.2} seconds",
+        rendered,
+        args.dir.display(),
+        SystemTime::now()
+            .duration_since(start)
+            .unwrap_or_default()
+            .as_secs_f32()
+    );
+    Ok(())
 }
 
-fn root(x: f32, n: u32) -> f32 {
-    let exp = 1.0 / n as f32;
-    if (n & 1) == 0 {
-        x.powf(exp)
+fn info(job: &RenderJob) {
+    let scene = &job.scene;
+    let (width, height) = (scene.image.width, scene.image.height);
+    let bounds = scene.viewport.bounds(width, height);
+    let max_x = bounds.min_x + bounds.step * width as f64;
+    let max_y = bounds.min_y + bounds.step * height as f64;
+
+    match scene.power {
+        Some(power) => println!("Formula:         {:?} (power {})", scene.formula, power),
+        None => println!("Formula:         {:?}", scene.formula),
+    }
+    if let Some(buddhabrot) = &scene.buddhabrot {
+        println!(
+            "Buddhabrot:      {} samples, {:?} sampling, seed {}",
+            buddhabrot.samples, buddhabrot.sampling, buddhabrot.seed
+        );
+        if let Some([red, green, blue]) = buddhabrot.nebulabrot {
+            println!("Nebulabrot:      {} / {} / {} iterations", red, green, blue);
+        }
+    }
+    if let Some(newton) = &scene.newton {
+        println!(
+            "Polynomial:      {} (relaxation {})",
+            newton.polynomial, newton.relaxation
+        );
+    }
+    if let Some(julia) = &scene.julia {
+        println!("Julia constant:  {}, {}", julia.x, julia.y);
+    }
+    if let Some(distance) = &scene.distance {
+        println!(
+            "Distance:        {:?}, {} px ({})",
+            distance.mode, distance.thickness, distance.color
+        );
+    }
+    if let Some(average) = &scene.average {
+        match average.density {
+            Some(density) => println!(
+                "Average:         {:?} ({} stripes)",
+                average.method, density
+            ),
+            None => println!("Average:         {:?}", average.method),
+        }
+    }
+    if let Some(interior) = &scene.interior {
+        match interior.falloff {
+            Some(falloff) => println!("Interior:        {:?} ({} px)", interior.mode, falloff),
+            None => println!("Interior:        {:?}", interior.mode),
+        }
+    }
+    if let Some(antialias) = &scene.antialias {
+        let (n, pattern) = (antialias.grid, antialias.pattern);
+        match antialias.adaptive {
+            Some(threshold) => println!(
+                "Supersampling:   {} x {} {:?}, adaptive at {}",
+                n, n, pattern, threshold
+            ),
+            None => println!("Supersampling:   {} x {} {:?}", n, n, pattern),
+        }
+    }
+    if let Some(trap) = &scene.trap {
+        let detail = match trap.shape {
+            TrapShape::Line => format!(" at {} degrees", trap.angle.unwrap_or(0.0)),
+            TrapShape::Circle => format!(" of radius {}", trap.radius.unwrap_or(1.0)),
+            TrapShape::Image => format!(
+                " from {}",
+                trap.image
+                    .as_ref()
+                    .map_or_else(String::new, |image| image.display().to_string())
+            ),
+            TrapShape::Point | TrapShape::Cross => String::new(),
+        };
+        println!(
+            "Orbit trap:      {:?}{} around {}, {} (size {})",
+            trap.shape, detail, trap.x, trap.y, trap.size
+        );
+    }
+    println!("Image size:      {} x {}", width, height);
+    if let Some(band_rows) = job.band_rows {
+        println!(
+            "Streaming:       {} band(s) of {} row(s)",
+            height.div_ceil(band_rows),
+            band_rows
+        );
+    }
+    println!("Iterations:      {}", scene.iteration.max_iterations);
+    println!("Bailout radius:  {}", scene.iteration.bailout);
+    if uses_perturbation(scene) {
+        println!(
+            "Perturbation:    {:?} (on, {:?} deltas)",
+            scene.iteration.perturbation,
+            delta_kind(scene)
+        );
     } else {
-        let absroot = x.abs().powf(exp);
-        if x < 0.0 {
-            -absroot
-        } else {
-            absroot
+        println!("Perturbation:    {:?} (off)", scene.iteration.perturbation);
+    }
+    println!("Center real:     {}", scene.viewport.center_x);
+    println!("Center imag:     {}", scene.viewport.center_y);
+    println!(
+        "Precision:       {} bits",
+        64 * scene.viewport.frac_limbs(width, height)
+    );
+    println!("Zoom:            {}", scene.viewport.zoom);
+    if scene.viewport.rotation != 0.0 {
+        println!("Rotation:        {} degrees", scene.viewport.rotation);
+    }
+    match scene.viewport.mapping {
+        Mapping::Cartesian => {
+            println!("Real range:      {} .. {}", bounds.min_x, max_x);
+            println!("Imaginary range: {} .. {}", bounds.min_y, max_y);
+            println!("Pixel size:      {}", scene.viewport.step(width));
         }
+        Mapping::Exponential => {
+            let finest = scene.viewport.finest_step(width, height);
+            let top = scene.viewport.radius().mul_f64(TAU / width as f64);
+            println!(
+                "Mapping:         Exponential ({:.1} octaves)",
+                top.log2() - finest.log2()
+            );
+            println!(
+                "Radius:          {} .. {}",
+                scene.viewport.radius(),
+                finest.mul_f64(width as f64 / TAU)
+            );
+            println!("Pixel size:      {} .. {}", top, finest);
+        }
+    }
+    match &scene.palette.gradient {
+        Some(gradient) => println!(
+            "Palette file:    {}{} (root {})",
+            gradient.file.display(),
+            gradient
+                .name
+                .as_ref()
+                .map_or_else(String::new, |name| format!(" [{}]", name)),
+            scene.palette.root
+        ),
+        None => println!(
+            "Palette stops:   {} in {:?} (root {})",
+            scene.palette.stops.len(),
+            scene.palette.color_space,
+            scene.palette.root
+        ),
     }
-}
\ No newline at end of file
+    if scene.palette.offset != 0.0 {
+        println!("Palette offset:  {}", scene.palette.offset);
+    }
+    println!(
+        "Output:          {} ({:?})",
+        job.output.display(),
+        scene.image.format
+    );
+    println!("Scene file:      {}", job.scene_path().display());
+    println!(
+        "Kernel:          {:?} ({} points per step)",
+        job.options.kernel,
+        job.options.kernel.lanes()
+    );
+    println!("Strategy:        {:?}", job.options.strategy);
+}
@@ -0,0 +1,549 @@
+//! Root-finding fractals. Newton's method is run on a polynomial p from
+//! every pixel:
+//!
+//! z -> z - a·p(z)/p'(z)
+//!
+//! and the pixel is colored by the root it lands on. The relaxation a is 1
+//! for the plain method. The Nova variant adds the pixel as c to every step
+//! and starts from a root of p, so that its basins form Mandelbrot-like
+//! shapes instead.
+
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::kernel::EscapeParams;
+
+/// Distance from a root, or between successive Nova iterates, at which a
+/// point counts as converged
+const TOLERANCE: f64 = 1e-6;
+
+/// Highest power of z accepted in a polynomial
+const MAX_DEGREE: usize = 64;
+
+/// Iteration limit when locating the roots of the polynomial itself
+const ROOT_ITERATIONS: usize = 1000;
+
+type Complex = (f64, f64);
+
+fn mul((a, b): Complex, (c, d): Complex) -> Complex {
+    (a * c - b * d, a * d + b * c)
+}
+
+fn div((a, b): Complex, (c, d): Complex) -> Complex {
+    let denominator = c * c + d * d;
+    ((a * c + b * d) / denominator, (b * c - a * d) / denominator)
+}
+
+fn norm_sqr((x, y): Complex) -> f64 {
+    x * x + y * y
+}
+
+/// A polynomial with complex coefficients, written like "z^3 - 1" or
+/// "(1+2i)z^2 + 0.5z - i" in scene files and on the command line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Polynomial {
+    /// Coefficient of z^k at index k, the last one is never zero
+    coefficients: Vec<Complex>,
+}
+
+impl Polynomial {
+    pub fn degree(&self) -> usize {
+        self.coefficients.len().saturating_sub(1)
+    }
+
+    /// p(z) and p'(z) by Horner's scheme
+    pub fn evaluate(&self, z: Complex) -> (Complex, Complex) {
+        let mut p = (0.0, 0.0);
+        let mut dp = (0.0, 0.0);
+        for &coefficient in self.coefficients.iter().rev() {
+            let dp_temp = mul(dp, z);
+            dp = (dp_temp.0 + p.0, dp_temp.1 + p.1);
+            let p_temp = mul(p, z);
+            p = (p_temp.0 + coefficient.0, p_temp.1 + coefficient.1);
+        }
+        (p, dp)
+    }
+
+    /// All complex roots by the Durand-Kerner method, sorted by angle
+    pub fn roots(&self) -> Vec<Complex> {
+        let degree = self.degree();
+        let leading = self.coefficients[degree];
+        let monic = |z: Complex| div(self.evaluate(z).0, leading);
+
+        // Start from distinct points that are neither real nor symmetric
+        let mut roots: Vec<Complex> = Vec::with_capacity(degree);
+        let mut guess = (1.0, 0.0);
+        for _ in 0..degree {
+            roots.push(guess);
+            guess = mul(guess, (0.4, 0.9));
+        }
+
+        for _ in 0..ROOT_ITERATIONS {
+            let mut change: f64 = 0.0;
+            for k in 0..degree {
+                let mut denominator = (1.0, 0.0);
+                for j in 0..degree {
+                    if j != k {
+                        denominator = mul(
+                            denominator,
+                            (roots[k].0 - roots[j].0, roots[k].1 - roots[j].1),
+                        );
+                    }
+                }
+                let delta = div(monic(roots[k]), denominator);
+                if delta.0.is_finite() && delta.1.is_finite() {
+                    roots[k] = (roots[k].0 - delta.0, roots[k].1 - delta.1);
+                    change = change.max(norm_sqr(delta) / (1.0 + norm_sqr(roots[k])));
+                }
+            }
+            if change < 1e-30 {
+                break;
+            }
+        }
+
+        roots.sort_by(|a, b| a.1.atan2(a.0).total_cmp(&b.1.atan2(b.0)));
+        roots
+    }
+}
+
+impl FromStr for Polynomial {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chars: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let mut parser = Parser { chars, pos: 0 };
+        let mut coefficients: Vec<Complex> = Vec::new();
+
+        loop {
+            let (degree, coefficient) = parser
+                .term()
+                .map_err(|err| format!("invalid polynomial {:?}: {}", s, err))?;
+            if coefficients.len() <= degree {
+                coefficients.resize(degree + 1, (0.0, 0.0));
+            }
+            coefficients[degree].0 += coefficient.0;
+            coefficients[degree].1 += coefficient.1;
+            match parser.peek() {
+                None => break,
+                Some('+') | Some('-') => {}
+                Some(c) => return Err(format!("invalid polynomial {:?}: unexpected {:?}", s, c)),
+            }
+        }
+
+        while coefficients.last() == Some(&(0.0, 0.0)) {
+            coefficients.pop();
+        }
+        if coefficients
+            .iter()
+            .any(|c| !c.0.is_finite() || !c.1.is_finite())
+        {
+            return Err(format!(
+                "invalid polynomial {:?}: coefficients must be finite",
+                s
+            ));
+        }
+        Ok(Polynomial { coefficients })
+    }
+}
+
+/// Recursive descent over a polynomial with the whitespace removed
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn sign(&mut self) -> f64 {
+        if self.eat('-') {
+            -1.0
+        } else {
+            self.eat('+');
+            1.0
+        }
+    }
+
+    /// A signed term such as "-2.5z^3", "+(1-i)z" or "i", as its degree and
+    /// coefficient
+    fn term(&mut self) -> Result<(usize, Complex), String> {
+        let start = self.pos;
+        let sign = self.sign();
+        let coefficient = match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let value = self.complex()?;
+                if !self.eat(')') {
+                    return Err("missing closing parenthesis".to_string());
+                }
+                Some(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' || c == 'i' => Some(self.imaginary()?),
+            _ => None,
+        };
+        if coefficient.is_some() {
+            self.eat('*');
+        }
+
+        let degree = if self.eat('z') {
+            if self.eat('^') {
+                let digits = self.take_while(|c| c.is_ascii_digit());
+                match digits.parse() {
+                    Ok(degree) if degree <= MAX_DEGREE => degree,
+                    Ok(_) => {
+                        return Err(format!("powers above z^{} are not supported", MAX_DEGREE))
+                    }
+                    Err(_) => return Err(format!("expected an exponent after ^ at {:?}", digits)),
+                }
+            } else {
+                1
+            }
+        } else if coefficient.is_some() {
+            0
+        } else {
+            let rest: String = self.chars[start..].iter().collect();
+            return Err(format!("expected a term at {:?}", rest));
+        };
+
+        let (x, y) = coefficient.unwrap_or((1.0, 0.0));
+        Ok((degree, (sign * x, sign * y)))
+    }
+
+    /// Sum of real and imaginary numbers inside parentheses
+    fn complex(&mut self) -> Result<Complex, String> {
+        let mut value = (0.0, 0.0);
+        loop {
+            let sign = self.sign();
+            let (x, y) = self.imaginary()?;
+            value = (value.0 + sign * x, value.1 + sign * y);
+            if !matches!(self.peek(), Some('+') | Some('-')) {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// A real number, optionally followed by i, or i on its own
+    fn imaginary(&mut self) -> Result<Complex, String> {
+        if self.eat('i') {
+            return Ok((0.0, 1.0));
+        }
+        let value = self.number()?;
+        if self.eat('i') {
+            Ok((0.0, value))
+        } else {
+            Ok((value, 0.0))
+        }
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let mut text = self.take_while(|c| c.is_ascii_digit() || c == '.');
+        // Only treat e as an exponent when digits follow, "2ez" is not a number
+        if matches!(self.peek(), Some('e') | Some('E')) {
+            let mark = self.pos;
+            self.pos += 1;
+            let sign = if self.eat('-') {
+                "-"
+            } else {
+                self.eat('+');
+                ""
+            };
+            let digits = self.take_while(|c| c.is_ascii_digit());
+            if digits.is_empty() {
+                self.pos = mark;
+            } else {
+                text = format!("{}e{}{}", text, sign, digits);
+            }
+        }
+        text.parse()
+            .map_err(|_| format!("expected a number at {:?}", text))
+    }
+
+    fn take_while(&mut self, accept: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(&accept) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+impl fmt::Display for Polynomial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for (degree, &(x, y)) in self.coefficients.iter().enumerate().rev() {
+            if (x, y) == (0.0, 0.0) {
+                continue;
+            }
+
+            // Pull the sign out of purely real or imaginary coefficients
+            let (negative, magnitude) = if y == 0.0 {
+                (x < 0.0, format_real(x.abs(), degree))
+            } else if x == 0.0 {
+                (y < 0.0, format!("{}i", format_real(y.abs(), 1)))
+            } else {
+                let sign = if y < 0.0 { '-' } else { '+' };
+                (false, format!("({}{}{}i)", x, sign, y.abs()))
+            };
+            match (first, negative) {
+                (true, true) => write!(f, "-")?,
+                (true, false) => {}
+                (false, true) => write!(f, " - ")?,
+                (false, false) => write!(f, " + ")?,
+            }
+            first = false;
+
+            write!(f, "{}", magnitude)?;
+            match degree {
+                0 => {}
+                1 => write!(f, "z")?,
+                _ => write!(f, "z^{}", degree)?,
+            }
+        }
+        if first {
+            write!(f, "0")?;
+        }
+        Ok(())
+    }
+}
+
+/// A real number, leaving out a 1 in front of z or i
+fn format_real(value: f64, degree: usize) -> String {
+    if value == 1.0 && degree > 0 {
+        String::new()
+    } else {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for Polynomial {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Polynomial> for String {
+    fn from(polynomial: Polynomial) -> Self {
+        polynomial.to_string()
+    }
+}
+
+/// Parameters of the Newton and Nova formulas as stored in scene files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NewtonSpec {
+    pub polynomial: Polynomial,
+    /// Step size a of z -> z - a·p(z)/p'(z). Values between 0 and 2
+    /// converge, away from 1 the basins grow more filaments.
+    #[serde(default = "NewtonSpec::default_relaxation")]
+    pub relaxation: f64,
+    /// How far each iteration darkens a root's color toward the interior
+    /// color, between 0 and 1
+    #[serde(default = "NewtonSpec::default_shading")]
+    pub shading: f64,
+}
+
+impl NewtonSpec {
+    fn default_relaxation() -> f64 {
+        1.0
+    }
+
+    fn default_shading() -> f64 {
+        0.05
+    }
+
+    pub fn new(polynomial: Polynomial) -> Self {
+        Self {
+            polynomial,
+            relaxation: Self::default_relaxation(),
+            shading: Self::default_shading(),
+        }
+    }
+}
+
+impl Default for NewtonSpec {
+    /// The classic z^3 - 1
+    fn default() -> Self {
+        Self::new("z^3 - 1".parse().unwrap())
+    }
+}
+
+/// Result of running Newton's method from one pixel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NewtonPoint {
+    /// Index into `Newton::roots` of the root that was reached
+    pub root: Option<usize>,
+    /// Smooth number of steps taken to get within the tolerance
+    pub iteration: f64,
+}
+
+/// A polynomial prepared for iterating, see `NewtonSpec`
+pub struct Newton {
+    polynomial: Polynomial,
+    pub roots: Vec<Complex>,
+    relaxation: f64,
+    /// Nova orbits start from the root with the largest real part, which is
+    /// the traditional z = 1 for z^3 - 1
+    start: Complex,
+}
+
+impl Newton {
+    pub fn new(spec: &NewtonSpec) -> Self {
+        let roots = spec.polynomial.roots();
+        let start = roots
+            .iter()
+            .copied()
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap_or((0.0, 0.0));
+        Self {
+            polynomial: spec.polynomial.clone(),
+            roots,
+            relaxation: spec.relaxation,
+            start,
+        }
+    }
+
+    /// One relaxed Newton step, or None where p'(z) vanishes
+    fn step(&self, z: Complex) -> Option<Complex> {
+        let (p, dp) = self.polynomial.evaluate(z);
+        if dp == (0.0, 0.0) {
+            return None;
+        }
+        let (dx, dy) = div(p, dp);
+        Some((z.0 - self.relaxation * dx, z.1 - self.relaxation * dy))
+    }
+
+    /// Run Newton's method from z = x0 + y0 i until it comes within the
+    /// tolerance of a root
+    pub fn calculate_point(&self, x0: f64, y0: f64, params: &EscapeParams) -> NewtonPoint {
+        let mut z = (x0, y0);
+        let mut iteration = 0.0;
+
+        while iteration < params.max_iterations {
+            z = match self.step(z) {
+                Some(z) => z,
+                None => break,
+            };
+            iteration += 1.0;
+
+            for (root, &(rx, ry)) in self.roots.iter().enumerate() {
+                let distance = norm_sqr((z.0 - rx, z.1 - ry)).sqrt();
+                if distance < TOLERANCE {
+                    return NewtonPoint {
+                        root: Some(root),
+                        iteration: smooth(iteration, distance),
+                    };
+                }
+            }
+        }
+
+        NewtonPoint {
+            root: None,
+            iteration: params.max_iterations,
+        }
+    }
+
+    /// Smooth iteration count of the Nova formula for the pixel x0 + y0 i,
+    /// counting steps until the orbit settles down or escapes. Orbits that
+    /// do neither return exactly `max_iterations`.
+    pub fn calculate_nova_point(&self, x0: f64, y0: f64, params: &EscapeParams) -> f64 {
+        let (mut z, (cx, cy)) = match params.julia {
+            Some(c) => ((x0, y0), c),
+            None => (self.start, (x0, y0)),
+        };
+        let mut iteration = 0.0;
+
+        while iteration < params.max_iterations {
+            let next = match self.step(z) {
+                Some((x, y)) => (x + cx, y + cy),
+                None => break,
+            };
+            iteration += 1.0;
+
+            if norm_sqr(next) > params.bailout_sqr {
+                return iteration;
+            }
+            let distance = norm_sqr((next.0 - z.0, next.1 - z.1)).sqrt();
+            if distance < TOLERANCE {
+                return smooth(iteration, distance);
+            }
+            z = next;
+        }
+
+        params.max_iterations
+    }
+}
+
+/// Fractional iteration count for an orbit that came within `distance` of
+/// its limit. Convergence is quadratic, so one step squares the distance
+/// and this runs from n - 1 to n between steps.
+fn smooth(iteration: f64, distance: f64) -> f64 {
+    let overshoot = (distance.ln() / TOLERANCE.ln()).log2();
+    iteration - overshoot.clamp(0.0, 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        for (text, display) in [
+            ("z^3 - 1", "z^3 - 1"),
+            ("z^3-1", "z^3 - 1"),
+            ("-2.5z^4 + z - 3", "-2.5z^4 + z - 3"),
+            ("(1+2i)z^2 + 0.5*z - i", "(1+2i)z^2 + 0.5z - i"),
+            ("z^2 + z^2 + 2iz", "2z^2 + 2iz"),
+            ("iz^2 - 1", "iz^2 - 1"),
+            ("1e-3z^2 + 1", "0.001z^2 + 1"),
+        ] {
+            let polynomial: Polynomial = text.parse().unwrap();
+            assert_eq!(polynomial.to_string(), display, "from {:?}", text);
+            assert_eq!(display.parse::<Polynomial>().unwrap(), polynomial);
+        }
+
+        for text in ["", "z^", "z^3 +", "(1+2i z", "q", "z^3 z"] {
+            assert!(text.parse::<Polynomial>().is_err(), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn roots_of_unity() {
+        let roots = "z^5 - 1".parse::<Polynomial>().unwrap().roots();
+        assert_eq!(roots.len(), 5);
+        for (x, y) in roots {
+            assert!((norm_sqr((x, y)) - 1.0).abs() < 1e-12);
+            let fifth = (0..4).fold((x, y), |z, _| mul(z, (x, y)));
+            assert!((fifth.0 - 1.0).abs() < 1e-12 && fifth.1.abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn pixels_converge_to_the_nearest_root_close_by() {
+        let newton = Newton::new(&NewtonSpec::default());
+        let params = EscapeParams {
+            max_iterations: 100.0,
+            bailout_sqr: 4.0,
+            julia: None,
+        };
+        for (k, &(rx, ry)) in newton.roots.iter().enumerate() {
+            let point = newton.calculate_point(rx * 1.1, ry * 1.1 + 0.01, &params);
+            assert_eq!(point.root, Some(k));
+            assert!(point.iteration > 0.0 && point.iteration < 10.0);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, RgbaImage};
+
+use crate::{error::Result, scene::OutputFormat, video::VideoEncoder};
+
+/// Encode an RGBA buffer to disk, dropping the alpha channel for formats
+/// that cannot store it
+pub fn save_image(
+    path: &Path,
+    format: OutputFormat,
+    width: usize,
+    height: usize,
+    buffer: Vec<u8>,
+) -> Result<()> {
+    let image = RgbaImage::from_raw(width as u32, height as u32, buffer)
+        .expect("buffer size matches the image dimensions");
+
+    match format {
+        OutputFormat::Png | OutputFormat::Tiff => {
+            image.save_with_format(path, format.image_format())?
+        }
+        OutputFormat::Jpeg | OutputFormat::Bmp => DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .save_with_format(path, format.image_format())?,
+    }
+
+    Ok(())
+}
+
+/// Like `save_image`, but written next to `path` and renamed into place
+/// once complete, so that any file found at `path` is whole
+pub fn save_image_whole(
+    path: &Path,
+    format: OutputFormat,
+    width: usize,
+    height: usize,
+    buffer: Vec<u8>,
+) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    save_image(&partial, format, width, height, buffer)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Where the frames of an animation go
+pub enum FrameOutput {
+    /// Numbered PNG files in a directory
+    Files(PathBuf),
+    /// One video, with the path it is written to
+    Video(VideoEncoder, PathBuf),
+}
+
+impl FrameOutput {
+    /// File a numbered frame is written to
+    pub fn frame_path(dir: &Path, frame: usize) -> PathBuf {
+        dir.join(format!("frame_{:05}.png", frame))
+    }
+
+    /// Write the next frame, which is frame number `frame` of the animation
+    pub fn write(
+        &mut self,
+        frame: usize,
+        width: usize,
+        height: usize,
+        rgba: Vec<u8>,
+    ) -> Result<()> {
+        match self {
+            FrameOutput::Files(dir) => {
+                let output = Self::frame_path(dir, frame);
+                save_image_whole(&output, OutputFormat::Png, width, height, rgba)
+            }
+            FrameOutput::Video(encoder, _) => encoder.write_frame(&rgba),
+        }
+    }
+
+    pub fn finish(self) -> Result<()> {
+        match self {
+            FrameOutput::Files(_) => Ok(()),
+            FrameOutput::Video(encoder, _) => encoder.finish(),
+        }
+    }
+}
+
+impl fmt::Display for FrameOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameOutput::Files(dir) => write!(f, "{}", dir.display()),
+            FrameOutput::Video(_, path) if path == Path::new("-") => write!(f, "stdout"),
+            FrameOutput::Video(_, path) => write!(f, "{}", path.display()),
+        }
+    }
+}
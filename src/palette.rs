@@ -0,0 +1,403 @@
+use std::convert::TryFrom;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::{Color, ColorSpace},
+    error::Result,
+    gradient::{Gradient, GradientFile},
+};
+
+/// Built-in color ramps selectable from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PalettePreset {
+    /// Dark blue to white
+    Ocean,
+    /// Black through red and yellow to white
+    Fire,
+    /// Black to white
+    Grayscale,
+}
+
+impl PalettePreset {
+    /// Evenly spaced color stops making up the ramp
+    pub fn stops(self) -> Vec<Color> {
+        match self {
+            PalettePreset::Ocean => {
+                vec![Color::new(0x00, 0x00, 0x55), Color::new(0xFF, 0xFF, 0xFF)]
+            }
+            PalettePreset::Fire => vec![
+                Color::new(0x00, 0x00, 0x00),
+                Color::new(0xC0, 0x10, 0x00),
+                Color::new(0xFF, 0xD0, 0x00),
+                Color::new(0xFF, 0xFF, 0xFF),
+            ],
+            PalettePreset::Grayscale => {
+                vec![Color::new(0x00, 0x00, 0x00), Color::new(0xFF, 0xFF, 0xFF)]
+            }
+        }
+    }
+}
+
+/// Shape of the blend from one stop to the next
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Easing {
+    Linear,
+    /// t^p, powers below 1 hurry towards the next color like the palette
+    /// root does
+    Power(f64),
+    /// Smoothstep, slow at both ends
+    Smooth,
+    /// Half a cosine wave, slow at both ends
+    Sine,
+    /// Hold the color until the next stop
+    Step,
+}
+
+impl Easing {
+    /// Map progress t from 0 to 1 through the segment
+    pub fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::Power(power) => t.powf(power as f32),
+            Easing::Smooth => t * t * (3.0 - 2.0 * t),
+            Easing::Sine => (1.0 - (t * std::f32::consts::PI).cos()) / 2.0,
+            Easing::Step => {
+                if t < 1.0 {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+
+    /// [`Easing::apply`] in double precision, for timing deep zooms where
+    /// the steps between `f32` values show as uneven frames
+    pub fn apply_f64(self, t: f64) -> f64 {
+        match self {
+            Easing::Linear => t,
+            Easing::Power(power) => t.powf(power),
+            Easing::Smooth => t * t * (3.0 - 2.0 * t),
+            Easing::Sine => (1.0 - (t * std::f64::consts::PI).cos()) / 2.0,
+            Easing::Step => {
+                if t < 1.0 {
+                    0.0
+                } else {
+                    1.0
+                }
+            }
+        }
+    }
+}
+
+/// A color the ramp passes through. Written as a bare `"#rrggbb"` string
+/// when it has no position or easing.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "StopEntry", into = "StopEntry")]
+pub struct Stop {
+    pub color: Color,
+    /// Place along the ramp from 0 to 1. Stops without one are spread
+    /// evenly between their neighbours, the first and last default to the
+    /// ends.
+    pub position: Option<f64>,
+    /// Blend from this stop to the next
+    pub easing: Easing,
+}
+
+impl From<Color> for Stop {
+    fn from(color: Color) -> Self {
+        Self {
+            color,
+            position: None,
+            easing: Easing::Linear,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StopEntry {
+    Color(String),
+    Table(StopTable),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StopTable {
+    color: Color,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<f64>,
+    #[serde(default = "StopTable::default_easing")]
+    easing: Easing,
+}
+
+impl StopTable {
+    fn default_easing() -> Easing {
+        Easing::Linear
+    }
+}
+
+impl TryFrom<StopEntry> for Stop {
+    type Error = String;
+
+    fn try_from(entry: StopEntry) -> std::result::Result<Self, Self::Error> {
+        Ok(match entry {
+            StopEntry::Color(color) => color.parse::<Color>()?.into(),
+            StopEntry::Table(table) => Self {
+                color: table.color,
+                position: table.position,
+                easing: table.easing,
+            },
+        })
+    }
+}
+
+impl From<Stop> for StopEntry {
+    fn from(stop: Stop) -> Self {
+        if stop.position.is_none() && stop.easing == Easing::Linear {
+            StopEntry::Color(stop.color.to_string())
+        } else {
+            StopEntry::Table(StopTable {
+                color: stop.color,
+                position: stop.position,
+                easing: stop.easing,
+            })
+        }
+    }
+}
+
+/// Position of every stop, filling in the ones left out
+pub fn stop_positions(stops: &[Stop]) -> Vec<f64> {
+    let mut positions: Vec<Option<f64>> = stops.iter().map(|stop| stop.position).collect();
+    let last = positions.len().saturating_sub(1);
+    if let Some(first) = positions.first_mut() {
+        first.get_or_insert(0.0);
+    }
+    if let Some(end) = positions.last_mut() {
+        end.get_or_insert(1.0);
+    }
+
+    let mut start = 0;
+    for index in 1..=last {
+        if let Some(end) = positions[index] {
+            let from = positions[start].unwrap_or(0.0);
+            let gaps = (index - start) as f64;
+            for (step, position) in positions[start + 1..index].iter_mut().enumerate() {
+                *position = Some(from + (end - from) * (step + 1) as f64 / gaps);
+            }
+            start = index;
+        }
+    }
+    positions.into_iter().map(|p| p.unwrap_or(0.0)).collect()
+}
+
+/// Serializable description of a palette as stored in scene files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PaletteSpec {
+    /// Colors the ramp passes through
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stops: Vec<Stop>,
+    /// Space the stops are blended in
+    #[serde(default = "PaletteSpec::default_color_space")]
+    pub color_space: ColorSpace,
+    /// Take the ramp from a gradient file instead of the stops
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gradient: Option<GradientFile>,
+    /// The ramp follows the n-th root of the escape progress
+    #[serde(default = "PaletteSpec::default_root")]
+    pub root: u32,
+    /// Color of points that never escape
+    #[serde(default = "PaletteSpec::default_interior")]
+    pub interior: Color,
+    /// Shift of the colors along the ramp as a fraction of it, wrapping
+    /// around at the end
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: f64,
+}
+
+fn is_zero(value: &f64) -> bool {
+    *value == 0.0
+}
+
+impl PaletteSpec {
+    fn default_color_space() -> ColorSpace {
+        ColorSpace::Srgb
+    }
+
+    fn default_root() -> u32 {
+        3
+    }
+
+    fn default_interior() -> Color {
+        Color::new(0, 0, 0)
+    }
+}
+
+impl From<PalettePreset> for PaletteSpec {
+    fn from(preset: PalettePreset) -> Self {
+        Self {
+            stops: preset.stops().into_iter().map(Stop::from).collect(),
+            color_space: Self::default_color_space(),
+            gradient: None,
+            root: Self::default_root(),
+            interior: Self::default_interior(),
+            offset: 0.0,
+        }
+    }
+}
+
+impl Default for PaletteSpec {
+    fn default() -> Self {
+        PalettePreset::Ocean.into()
+    }
+}
+
+/// The colors a palette passes through from start to end
+enum Ramp {
+    Stops {
+        stops: Vec<Stop>,
+        positions: Vec<f64>,
+        space: ColorSpace,
+    },
+    Gradient(Gradient),
+}
+
+pub struct Palette {
+    colors: Vec<Color>,
+    pub max_color: Color,
+    ramp: Ramp,
+    /// Easing of the whole ramp, from the spec's root
+    root: Easing,
+    /// Shift along the ramp, from 0 to 1
+    offset: f32,
+}
+
+impl Palette {
+    /// Build a palette with one color per iteration, following the stops or
+    /// gradient file along an n-th root ramp
+    pub fn generate(spec: &PaletteSpec, size: usize) -> Result<Self> {
+        let ramp = match &spec.gradient {
+            Some(gradient) => Ramp::Gradient(gradient.load()?),
+            None => Ramp::Stops {
+                stops: spec.stops.clone(),
+                positions: stop_positions(&spec.stops),
+                space: spec.color_space,
+            },
+        };
+        let mut palette = Self {
+            colors: Vec::with_capacity(size),
+            max_color: spec.interior,
+            ramp,
+            root: Easing::Power(1.0 / spec.root as f64),
+            offset: spec.offset.rem_euclid(1.0) as f32,
+        };
+        for index in 0..size {
+            let progress = index as f32 / size as f32;
+            let color = palette.ramp(progress);
+            palette.colors.push(color);
+        }
+        Ok(palette)
+    }
+
+    /// Color at `progress` from 0 to 1, following the root and offset as
+    /// the per-iteration colors do
+    pub fn ramp(&self, progress: f32) -> Color {
+        let progress = if self.offset == 0.0 {
+            progress
+        } else {
+            (progress + self.offset).rem_euclid(1.0)
+        };
+        self.sample(self.root.apply(progress))
+    }
+
+    /// Color at `progress` from 0 to 1 along the ramp, without the root
+    /// mapping
+    pub fn sample(&self, progress: f32) -> Color {
+        match &self.ramp {
+            Ramp::Stops {
+                stops,
+                positions,
+                space,
+            } => {
+                let progress = progress as f64;
+                let segment = positions[1..stops.len() - 1]
+                    .iter()
+                    .position(|&end| progress < end)
+                    .unwrap_or(stops.len() - 2);
+                let (start, end) = (positions[segment], positions[segment + 1]);
+                let t = if end > start {
+                    ((progress - start) / (end - start)).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                let (from, to) = (&stops[segment], &stops[segment + 1]);
+                from.color
+                    .interpolate_in(&to.color, from.easing.apply(t as f32), *space)
+            }
+            Ramp::Gradient(gradient) => gradient.color(progress as f64),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.colors.len()
+    }
+
+    pub fn get_color(&self, index: usize) -> &Color {
+        &self.colors[index]
+    }
+
+    /// Color for a smooth iteration count, blending between neighbouring
+    /// entries. Counts at or past the end of the palette never escaped.
+    pub fn color(&self, iteration: f64) -> Color {
+        let c1 = if iteration >= self.len() as f64 {
+            &self.max_color
+        } else {
+            self.get_color((iteration as usize).min(self.len() - 1))
+        };
+        let c2 = self.get_color(((iteration as usize) + 1).min(self.len() - 1));
+
+        c1.interpolate(c2, iteration.fract() as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_stop_positions_are_spread_between_neighbours() {
+        let stop = |position| Stop {
+            position,
+            ..Color::new(0, 0, 0).into()
+        };
+        let stops = [None, Some(0.6), None, None, None];
+        let positions = stop_positions(&stops.map(stop));
+        let expected = [0.0, 0.6, 0.7333, 0.8667, 1.0];
+        for (position, expected) in positions.iter().zip(expected) {
+            assert!((position - expected).abs() < 1e-4, "{:?}", positions);
+        }
+    }
+
+    #[test]
+    fn stops_hold_and_ease() {
+        let mut spec = PaletteSpec::from(PalettePreset::Grayscale);
+        spec.stops.insert(
+            1,
+            Stop {
+                color: Color::new(255, 0, 0),
+                position: Some(0.25),
+                easing: Easing::Step,
+            },
+        );
+        let palette = Palette::generate(&spec, 4).unwrap();
+        assert_eq!(palette.sample(0.0), Color::new(0, 0, 0));
+        assert_eq!(palette.sample(0.2), Color::new(204, 0, 0));
+        assert_eq!(palette.sample(0.25), Color::new(255, 0, 0));
+        assert_eq!(palette.sample(0.9), Color::new(255, 0, 0));
+        assert_eq!(palette.sample(1.0), Color::new(255, 255, 255));
+    }
+}
@@ -0,0 +1,606 @@
+//! Deep zoom rendering by perturbation theory. One reference orbit is
+//! iterated at full precision and every pixel only tracks its `f64`
+//! difference from it:
+//!
+//! δ(n+1) = 2·Z(n)·δ(n) + δ(n)² + δc
+//!
+//! For Mandelbrot images δ starts at zero and δc is the pixel's offset from
+//! the reference. Julia sets share c between all pixels, so there δc is zero
+//! and the offset is the starting δ instead.
+//!
+//! Pixels whose difference stops being representable relative to the
+//! reference are detected with Pauldelbrot's criterion and re-rendered
+//! against a new reference placed inside the glitch.
+//!
+//! Past a pixel step of about 1e-290 the differences themselves leave the
+//! range of `f64`. They are then carried either as `f64` scaled by a fixed
+//! power of two, or as `FloatExp` once even that runs out, and switch back to
+//! plain `f64` as soon as they have grown large enough.
+
+use rayon::prelude::*;
+
+use crate::{
+    bignum::BigFixed,
+    floatexp::FloatExp,
+    kernel::{smooth, EscapeParams},
+    viewport::Viewport,
+};
+
+/// Pixel steps smaller than this lose detail with plain `f64` iteration
+pub const DEEP_ZOOM_STEP: f64 = 1e-12;
+
+/// A pixel is glitched once |z|² drops below this fraction of |Z|²
+const GLITCH_TOLERANCE: f64 = 1e-6;
+
+/// Give up on the remaining glitches after this many reference orbits
+const MAX_REFERENCES: usize = 64;
+
+/// Extra bits of precision carried by the reference orbit below the pixel step
+pub const GUARD_BITS: u32 = 64;
+
+/// Deltas above 2^F64_MIN_EXPONENT (about 1e-289) are normal `f64` numbers
+/// with room to spare
+const F64_MIN_EXPONENT: i64 = -960;
+
+/// The rescaled kernel stores δ = 2^RESCALE_EXPONENT · w
+const RESCALE_EXPONENT: i64 = -1000;
+
+/// Below a pixel step of 2^RESCALED_MIN_EXPONENT (about 1e-599) the pixel
+/// offsets divided by the fixed scale would no longer be normal `f64`s
+const RESCALED_MIN_EXPONENT: i64 = -1990;
+
+/// Number type carrying the per-pixel deltas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaKind {
+    F64,
+    /// `f64` multiplied by a fixed power of two
+    Rescaled,
+    FloatExp,
+}
+
+impl DeltaKind {
+    /// The fastest kind that can hold the deltas of an image with this pixel
+    /// step
+    pub fn for_step(step: FloatExp) -> Self {
+        if step.exponent() > F64_MIN_EXPONENT {
+            DeltaKind::F64
+        } else if step.exponent() > RESCALED_MIN_EXPONENT {
+            DeltaKind::Rescaled
+        } else {
+            DeltaKind::FloatExp
+        }
+    }
+}
+
+/// Orbit of a single point computed at high precision and rounded to `f64`
+pub struct ReferenceOrbit {
+    orbit: Vec<(f64, f64)>,
+    /// Whether pixel offsets apply to the starting z rather than to c
+    julia: bool,
+}
+
+/// Result of iterating one pixel against a reference orbit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PerturbedPoint {
+    /// Smooth iteration count, only meaningful when not glitched
+    pub iteration: f64,
+    /// |z|² / |Z|² at the point the glitch was detected. Pixels with the
+    /// smallest ratio sit closest to the center of a glitch.
+    pub glitch: Option<f64>,
+}
+
+impl ReferenceOrbit {
+    /// Iterate the point px + py i until it escapes or runs out of
+    /// iterations. The point is c, or the starting z when `julia` holds the
+    /// constant c of a Julia set.
+    pub fn compute(
+        px: &BigFixed,
+        py: &BigFixed,
+        julia: Option<(&BigFixed, &BigFixed)>,
+        params: &EscapeParams,
+    ) -> Self {
+        let frac_limbs = px.frac_limbs();
+        let (mut x, mut y, cx, cy) = match julia {
+            Some((cx, cy)) => (
+                px.clone(),
+                py.clone(),
+                cx.with_frac_limbs(frac_limbs),
+                cy.with_frac_limbs(frac_limbs),
+            ),
+            None => (
+                BigFixed::zero(frac_limbs),
+                BigFixed::zero(frac_limbs),
+                px.clone(),
+                py.clone(),
+            ),
+        };
+        let mut orbit = vec![(x.to_f64(), y.to_f64())];
+
+        for _ in 0..params.max_iterations as usize {
+            let (zx, zy) = orbit[orbit.len() - 1];
+            if zx * zx + zy * zy > params.bailout_sqr {
+                break;
+            }
+
+            let xy = x.mul(&y);
+            x = x.square().sub(&y.square()).add(&cx);
+            y = xy.double().add(&cy);
+            orbit.push((x.to_f64(), y.to_f64()));
+        }
+
+        Self {
+            orbit,
+            julia: julia.is_some(),
+        }
+    }
+
+    /// Iterate the point at offset (dx, dy) from the reference, carrying
+    /// the deltas in the given number type
+    pub fn iterate(
+        &self,
+        dx: FloatExp,
+        dy: FloatExp,
+        kind: DeltaKind,
+        params: &EscapeParams,
+    ) -> PerturbedPoint {
+        let ((dx, dy), (dcx, dcy)) = if self.julia {
+            ((dx, dy), (FloatExp::ZERO, FloatExp::ZERO))
+        } else {
+            ((FloatExp::ZERO, FloatExp::ZERO), (dx, dy))
+        };
+        match kind {
+            DeltaKind::F64 => self.iterate_f64(
+                0,
+                dx.to_f64(),
+                dy.to_f64(),
+                dcx.to_f64(),
+                dcy.to_f64(),
+                params,
+            ),
+            DeltaKind::Rescaled => self.iterate_rescaled(dx, dy, dcx, dcy, params),
+            DeltaKind::FloatExp => self.iterate_floatexp(dx, dy, dcx, dcy, params),
+        }
+    }
+
+    /// Continue from iteration n with the delta (dx, dy) in plain `f64`
+    fn iterate_f64(
+        &self,
+        mut n: usize,
+        mut dx: f64,
+        mut dy: f64,
+        dcx: f64,
+        dcy: f64,
+        params: &EscapeParams,
+    ) -> PerturbedPoint {
+        loop {
+            let (zx, zy) = match self.orbit.get(n) {
+                Some(&z) => z,
+                None => return reference_escaped(n),
+            };
+            if let Some(point) = check(n, zx, zy, zx + dx, zy + dy, params) {
+                return point;
+            }
+
+            let dxtemp = 2.0 * (zx * dx - zy * dy) + dx * dx - dy * dy + dcx;
+            dy = 2.0 * (zx * dy + zy * dx) + 2.0 * dx * dy + dcy;
+            dx = dxtemp;
+            n += 1;
+        }
+    }
+
+    /// δ = S·w with the fixed scale S, so that w stays within `f64` while δ
+    /// is far below it:
+    ///
+    /// w(n+1) = 2·Z(n)·w(n) + S·w(n)² + δc/S
+    fn iterate_rescaled(
+        &self,
+        dx: FloatExp,
+        dy: FloatExp,
+        dcx: FloatExp,
+        dcy: FloatExp,
+        params: &EscapeParams,
+    ) -> PerturbedPoint {
+        let scale = FloatExp::new(1.0, RESCALE_EXPONENT).to_f64();
+        let ex = dcx.mul_pow2(-RESCALE_EXPONENT).to_f64();
+        let ey = dcy.mul_pow2(-RESCALE_EXPONENT).to_f64();
+        let switch = FloatExp::new(1.0, F64_MIN_EXPONENT - RESCALE_EXPONENT).to_f64();
+        let mut wx = dx.mul_pow2(-RESCALE_EXPONENT).to_f64();
+        let mut wy = dy.mul_pow2(-RESCALE_EXPONENT).to_f64();
+        let mut n = 0;
+
+        loop {
+            if wx.abs().max(wy.abs()) > switch {
+                return self.iterate_f64(
+                    n,
+                    wx * scale,
+                    wy * scale,
+                    dcx.to_f64(),
+                    dcy.to_f64(),
+                    params,
+                );
+            }
+
+            let (zx, zy) = match self.orbit.get(n) {
+                Some(&z) => z,
+                None => return reference_escaped(n),
+            };
+            if let Some(point) = check(n, zx, zy, zx + wx * scale, zy + wy * scale, params) {
+                return point;
+            }
+
+            let wxtemp = 2.0 * (zx * wx - zy * wy) + scale * (wx * wx - wy * wy) + ex;
+            wy = 2.0 * (zx * wy + zy * wx) + scale * (2.0 * wx * wy) + ey;
+            wx = wxtemp;
+            n += 1;
+        }
+    }
+
+    /// Iterate with the deltas in `FloatExp`, which works at any depth but
+    /// costs several times more per step
+    fn iterate_floatexp(
+        &self,
+        mut dx: FloatExp,
+        mut dy: FloatExp,
+        dcx: FloatExp,
+        dcy: FloatExp,
+        params: &EscapeParams,
+    ) -> PerturbedPoint {
+        let two = FloatExp::from_f64(2.0);
+        let mut n = 0;
+
+        loop {
+            if fits_f64(dx) || fits_f64(dy) {
+                return self.iterate_f64(
+                    n,
+                    dx.to_f64(),
+                    dy.to_f64(),
+                    dcx.to_f64(),
+                    dcy.to_f64(),
+                    params,
+                );
+            }
+
+            let (zx, zy) = match self.orbit.get(n) {
+                Some(&z) => z,
+                None => return reference_escaped(n),
+            };
+            if let Some(point) = check(n, zx, zy, zx + dx.to_f64(), zy + dy.to_f64(), params) {
+                return point;
+            }
+
+            let dxtemp = two
+                .mul(dx.mul_f64(zx).sub(dy.mul_f64(zy)))
+                .add(dx.square())
+                .sub(dy.square())
+                .add(dcx);
+            dy = two
+                .mul(dy.mul_f64(zx).add(dx.mul_f64(zy)))
+                .add(two.mul(dx).mul(dy))
+                .add(dcy);
+            dx = dxtemp;
+            n += 1;
+        }
+    }
+}
+
+/// Whether a delta is large enough to continue in plain `f64`
+fn fits_f64(delta: FloatExp) -> bool {
+    !delta.is_zero() && delta.exponent() > F64_MIN_EXPONENT
+}
+
+/// The reference escaped before this pixel did, so there is nothing left
+/// to perturb against
+fn reference_escaped(n: usize) -> PerturbedPoint {
+    PerturbedPoint {
+        iteration: n as f64,
+        glitch: Some(f64::INFINITY),
+    }
+}
+
+/// Escape and glitch tests for z = x + y i against the reference Z = zx + zy i
+/// at iteration n
+fn check(
+    n: usize,
+    zx: f64,
+    zy: f64,
+    x: f64,
+    y: f64,
+    params: &EscapeParams,
+) -> Option<PerturbedPoint> {
+    let magnitude = x * x + y * y;
+    if magnitude > params.bailout_sqr || n as f64 >= params.max_iterations {
+        return Some(PerturbedPoint {
+            iteration: smooth(n as f64, x, y, 2.0, params),
+            glitch: None,
+        });
+    }
+
+    let reference_magnitude = zx * zx + zy * zy;
+    if magnitude < GLITCH_TOLERANCE * reference_magnitude {
+        return Some(PerturbedPoint {
+            iteration: n as f64,
+            glitch: Some(magnitude / reference_magnitude),
+        });
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PerturbationStats {
+    pub references: usize,
+    /// Pixels still glitched once the reference budget ran out
+    pub glitched_pixels: usize,
+}
+
+impl PerturbationStats {
+    pub fn add(self, other: Self) -> Self {
+        Self {
+            references: self.references + other.references,
+            glitched_pixels: self.glitched_pixels + other.glitched_pixels,
+        }
+    }
+}
+
+/// The reference orbit at the center of a view, which every render of the
+/// view starts from, with the center kept at the precision that places any
+/// of its pixels exactly
+pub struct CentralReference {
+    center_x: BigFixed,
+    center_y: BigFixed,
+    julia: Option<(BigFixed, BigFixed)>,
+    orbit: ReferenceOrbit,
+}
+
+impl CentralReference {
+    pub fn new(
+        viewport: &Viewport,
+        julia: Option<(&BigFixed, &BigFixed)>,
+        frac_limbs: usize,
+        params: &EscapeParams,
+    ) -> Self {
+        let center_x = viewport.center_x.with_frac_limbs(frac_limbs);
+        let center_y = viewport.center_y.with_frac_limbs(frac_limbs);
+        let orbit = ReferenceOrbit::compute(&center_x, &center_y, julia, params);
+        Self {
+            center_x,
+            center_y,
+            julia: julia.map(|(x, y)| (x.clone(), y.clone())),
+            orbit,
+        }
+    }
+}
+
+/// Smooth iteration counts for `count` points placed by `offset` relative to
+/// the center of the view, or of the Julia set for the reference's constant.
+/// Runs on the current rayon pool.
+pub fn render_points(
+    central: &CentralReference,
+    count: usize,
+    offset: impl Fn(usize) -> (FloatExp, FloatExp) + Sync,
+    kind: DeltaKind,
+    params: &EscapeParams,
+) -> (Vec<f64>, PerturbationStats) {
+    let CentralReference {
+        center_x,
+        center_y,
+        orbit: reference,
+        ..
+    } = central;
+    let frac_limbs = center_x.frac_limbs();
+    let julia = central.julia.as_ref().map(|(x, y)| (x, y));
+
+    let points: Vec<PerturbedPoint> = (0..count)
+        .into_par_iter()
+        .map(|index| {
+            let (dcx, dcy) = offset(index);
+            reference.iterate(dcx, dcy, kind, params)
+        })
+        .collect();
+
+    let mut iterations: Vec<f64> = points.iter().map(|point| point.iteration).collect();
+    let mut glitched: Vec<(usize, f64)> = points
+        .iter()
+        .enumerate()
+        .filter_map(|(index, point)| point.glitch.map(|glitch| (index, glitch)))
+        .collect();
+    let mut references = 1;
+
+    while !glitched.is_empty() && references < MAX_REFERENCES {
+        // Place the new reference at the heart of the worst glitch. That
+        // pixel has no offset from its own reference, so it cannot glitch
+        // again and every pass makes progress.
+        let (reference_index, _) = glitched
+            .iter()
+            .copied()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        let (rx, ry) = offset(reference_index);
+        let reference = ReferenceOrbit::compute(
+            &center_x.add(&BigFixed::from_floatexp(rx, frac_limbs)),
+            &center_y.add(&BigFixed::from_floatexp(ry, frac_limbs)),
+            julia,
+            params,
+        );
+        references += 1;
+
+        let retried: Vec<(usize, PerturbedPoint)> = glitched
+            .par_iter()
+            .map(|&(index, _)| {
+                let (dcx, dcy) = offset(index);
+                (
+                    index,
+                    reference.iterate(dcx.sub(rx), dcy.sub(ry), kind, params),
+                )
+            })
+            .collect();
+
+        glitched.clear();
+        for (index, point) in retried {
+            iterations[index] = point.iteration;
+            if let Some(glitch) = point.glitch {
+                glitched.push((index, glitch));
+            }
+        }
+    }
+
+    let stats = PerturbationStats {
+        references,
+        glitched_pixels: glitched.len(),
+    };
+    (iterations, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fractal::{self, Mandelbrot};
+
+    const PARAMS: EscapeParams = EscapeParams {
+        max_iterations: 3000.0,
+        bailout_sqr: 256.0 * 256.0,
+        julia: None,
+    };
+
+    /// Reference at c = -2, the tip of the set, where Z settles on 2 and
+    /// offsets along the real axis grow fourfold per iteration. Points just
+    /// left of it escape after roughly log4(1/offset) iterations.
+    fn tip_reference(step: FloatExp) -> ReferenceOrbit {
+        let frac_limbs = BigFixed::limbs_for_step(step, GUARD_BITS);
+        let cx = "-2"
+            .parse::<BigFixed>()
+            .unwrap()
+            .with_frac_limbs(frac_limbs);
+        let cy = BigFixed::zero(frac_limbs);
+        ReferenceOrbit::compute(&cx, &cy, None, &PARAMS)
+    }
+
+    fn offsets(step: FloatExp) -> Vec<(FloatExp, FloatExp)> {
+        (1..=8)
+            .map(|k| (step.mul_f64(-(k as f64)), step.mul_f64(0.25 * k as f64)))
+            .collect()
+    }
+
+    #[test]
+    fn delta_kind_follows_depth() {
+        assert_eq!(
+            DeltaKind::for_step("1e-100".parse().unwrap()),
+            DeltaKind::F64
+        );
+        assert_eq!(
+            DeltaKind::for_step("1e-288".parse().unwrap()),
+            DeltaKind::F64
+        );
+        assert_eq!(
+            DeltaKind::for_step("1e-500".parse().unwrap()),
+            DeltaKind::Rescaled
+        );
+        assert_eq!(
+            DeltaKind::for_step("1e-700".parse().unwrap()),
+            DeltaKind::FloatExp
+        );
+    }
+
+    #[test]
+    fn rescaled_matches_floatexp_at_1e_minus_500() {
+        let step: FloatExp = "1e-500".parse().unwrap();
+        let reference = tip_reference(step);
+
+        let mut previous = f64::INFINITY;
+        for (dcx, dcy) in offsets(step) {
+            let rescaled = reference.iterate(dcx, dcy, DeltaKind::Rescaled, &PARAMS);
+            let floatexp = reference.iterate(dcx, dcy, DeltaKind::FloatExp, &PARAMS);
+
+            assert_eq!(rescaled.glitch, None);
+            assert_eq!(floatexp.glitch, None);
+            assert!(
+                (rescaled.iteration - floatexp.iteration).abs() < 1e-9,
+                "rescaled {} != floatexp {}",
+                rescaled.iteration,
+                floatexp.iteration
+            );
+
+            // Escapes after ~830 iterations, earlier the further out it lies
+            assert!(rescaled.iteration > 800.0 && rescaled.iteration < 900.0);
+            assert!(rescaled.iteration < previous);
+            previous = rescaled.iteration;
+        }
+    }
+
+    #[test]
+    fn f64_deltas_underflow_at_1e_minus_500() {
+        let step: FloatExp = "1e-500".parse().unwrap();
+        let reference = tip_reference(step);
+
+        // Every offset rounds to zero, so each pixel is mistaken for the
+        // reference itself, which never escapes
+        for (dcx, dcy) in offsets(step) {
+            let point = reference.iterate(dcx, dcy, DeltaKind::F64, &PARAMS);
+            assert_eq!(point.iteration, PARAMS.max_iterations);
+        }
+    }
+
+    #[test]
+    fn floatexp_matches_f64_where_both_work() {
+        let step: FloatExp = "1e-200".parse().unwrap();
+        let reference = tip_reference(step);
+
+        for (dcx, dcy) in offsets(step) {
+            let plain = reference.iterate(dcx, dcy, DeltaKind::F64, &PARAMS);
+            let rescaled = reference.iterate(dcx, dcy, DeltaKind::Rescaled, &PARAMS);
+            let floatexp = reference.iterate(dcx, dcy, DeltaKind::FloatExp, &PARAMS);
+            assert!((plain.iteration - floatexp.iteration).abs() < 1e-9);
+            assert!((plain.iteration - rescaled.iteration).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn glitches_are_rerendered_against_new_references() {
+        let params = EscapeParams {
+            max_iterations: 1000.0,
+            ..PARAMS
+        };
+        // Shallow enough for f64, and the orbits of some pixels pass far
+        // closer to 0 than the one from the center does
+        let viewport = Viewport {
+            center_x: "-0.1011".parse().unwrap(),
+            center_y: "0.9563".parse().unwrap(),
+            zoom: FloatExp::from_f64(20.0),
+            ..Viewport::default()
+        };
+        let (width, height) = (60, 40);
+        let central =
+            CentralReference::new(&viewport, None, viewport.frac_limbs(width, height), &params);
+        let offsets = viewport.offsets(width, height);
+        let offset = |index: usize| offsets.point(index % width, index / width);
+
+        let first_pass = (0..width * height)
+            .filter(|&index| {
+                let (dcx, dcy) = offset(index);
+                central
+                    .orbit
+                    .iterate(dcx, dcy, DeltaKind::F64, &params)
+                    .glitch
+                    .is_some()
+            })
+            .count();
+        assert!(first_pass > 0);
+
+        let (iterations, stats) =
+            render_points(&central, width * height, offset, DeltaKind::F64, &params);
+        assert!(stats.references > 1);
+        assert_eq!(stats.glitched_pixels, 0);
+
+        let bounds = viewport.bounds(width, height);
+        for (index, &iteration) in iterations.iter().enumerate() {
+            let (x, y) = bounds.point(index % width, index / width);
+            let expected = fractal::calculate_point(&Mandelbrot, x, y, &params);
+            assert!(
+                (iteration - expected).abs() < 1e-6,
+                "pixel {} took {} iterations, not {}",
+                index,
+                iteration,
+                expected
+            );
+        }
+    }
+}
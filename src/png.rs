@@ -0,0 +1,145 @@
+//! The parts of PNG encoding shared by the animated and streamed writers,
+//! which both put out 8-bit RGB images
+
+use std::io::{self, Write};
+
+const CHANNELS: usize = 3;
+
+/// Write the PNG signature and the header of an 8-bit RGB image
+pub fn write_header(writer: &mut dyn Write, width: usize, height: usize) -> io::Result<()> {
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8-bit RGB, default compression and filters, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &header)
+}
+
+/// Write a PNG chunk: length, type, data and the CRC of type and data
+pub fn write_chunk(writer: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc.finalize().to_be_bytes())
+}
+
+/// RGB scanlines of an RGBA image, each one filtered by `filter_row`
+pub fn filter_scanlines(rgba: &[u8], width: usize) -> Vec<u8> {
+    let stride = width * CHANNELS;
+    let mut out = Vec::with_capacity(rgba.len() / 4 * CHANNELS + rgba.len() / (width * 4));
+    let mut above = vec![0; stride];
+    let mut row = Vec::with_capacity(stride);
+    for line in rgba.chunks_exact(width * 4) {
+        row.clear();
+        row.extend(
+            line.chunks_exact(4)
+                .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]),
+        );
+        filter_row(&row, &above, &mut out);
+        std::mem::swap(&mut row, &mut above);
+    }
+    out
+}
+
+/// Append an RGB scanline prefixed by the PNG filter that leaves the
+/// smallest differences from the scanline above, the usual heuristic for
+/// true color images. The first scanline goes with zeros above it.
+pub fn filter_row(row: &[u8], above: &[u8], out: &mut Vec<u8>) {
+    let stride = row.len();
+    let mut candidate = vec![0; stride];
+    let mut best = vec![0; stride];
+    let mut best_filter = 0;
+    let mut best_cost = u64::MAX;
+    for filter in 0..5u8 {
+        for i in 0..stride {
+            let left = if i >= CHANNELS { row[i - CHANNELS] } else { 0 };
+            let up = above[i];
+            let up_left = if i >= CHANNELS {
+                above[i - CHANNELS]
+            } else {
+                0
+            };
+            let predicted = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                _ => paeth(left, up, up_left),
+            };
+            candidate[i] = row[i].wrapping_sub(predicted);
+        }
+        // Sum of the differences read as signed bytes
+        let cost = candidate
+            .iter()
+            .map(|&byte| (byte as i8).unsigned_abs() as u64)
+            .sum();
+        if cost < best_cost {
+            best_cost = cost;
+            best_filter = filter;
+            best.copy_from_slice(&candidate);
+        }
+    }
+    out.push(best_filter);
+    out.extend_from_slice(&best);
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (a, b, c) = (
+        (estimate - left as i16).abs(),
+        (estimate - up as i16).abs(),
+        (estimate - up_left as i16).abs(),
+    );
+    if a <= b && a <= c {
+        left
+    } else if b <= c {
+        up
+    } else {
+        up_left
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filtered_scanlines_decode_back() {
+        let (width, height) = (13, 7);
+        let rgba: Vec<u8> = (0..width * height * 4)
+            .map(|i| ((i * 37) ^ (i / 11)) as u8)
+            .collect();
+        let filtered = filter_scanlines(&rgba, width);
+        assert_eq!(filtered.len(), height * (width * 3 + 1));
+
+        // Undo the filters the way a PNG decoder does
+        let stride = width * 3;
+        let mut previous = vec![0u8; stride];
+        for (y, line) in filtered.chunks_exact(stride + 1).enumerate() {
+            let mut row = vec![0u8; stride];
+            for i in 0..stride {
+                let left = if i >= 3 { row[i - 3] } else { 0 };
+                let up = previous[i];
+                let up_left = if i >= 3 { previous[i - 3] } else { 0 };
+                let predicted = match line[0] {
+                    0 => 0,
+                    1 => left,
+                    2 => up,
+                    3 => ((left as u16 + up as u16) / 2) as u8,
+                    4 => paeth(left, up, up_left),
+                    filter => panic!("unknown filter {}", filter),
+                };
+                row[i] = line[i + 1].wrapping_add(predicted);
+            }
+            for x in 0..width {
+                let pixel = &rgba[(y * width + x) * 4..];
+                assert_eq!(&row[x * 3..x * 3 + 3], &pixel[..3], "row {}", y);
+            }
+            previous = row;
+        }
+    }
+}
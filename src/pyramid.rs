@@ -0,0 +1,427 @@
+//! Tile pyramids for web viewers. Each level of the pyramid shows the same
+//! view at its own resolution and is rendered at that resolution, one tile
+//! at a time, rather than scaled down from the level below it.
+
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{bignum::BigFixed, floatexp::FloatExp, viewport::Viewport};
+
+/// How the tiles of a pyramid are laid out on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Deep Zoom: a .dzi descriptor next to a folder per level, each level
+    /// half the size of the next, from a single pixel up to the full image
+    Dzi,
+    /// Slippy map z/x/y tiles: 2^z x 2^z tiles at zoom z, over a square
+    /// around the view that takes in the whole image
+    Xyz,
+}
+
+impl Layout {
+    pub fn default_tile_size(self) -> usize {
+        match self {
+            // 256 with the overlap on both sides
+            Layout::Dzi => 254,
+            Layout::Xyz => 256,
+        }
+    }
+}
+
+/// Largest XYZ zoom level, 2^30 tiles across
+pub const MAX_ZOOM: u32 = 30;
+
+/// Most tiles a pyramid may have, over all of its levels
+pub const MAX_TILES: usize = 1 << 30;
+
+/// One resolution of the pyramid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    pub index: u32,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels of the full image per pixel of the level
+    scale: f64,
+    /// Position of the level's top-left corner in pixels of the full image
+    origin: (f64, f64),
+}
+
+/// Pixels of a level that go into one tile file, overlap included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub column: usize,
+    pub row: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Everything that decides which pixels go into which tile file, saved
+/// next to the tiles so that an export only resumes after tiles that fit
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PyramidRecord<'a> {
+    pub layout: Layout,
+    pub name: &'a str,
+    pub format: &'a str,
+    pub width: usize,
+    pub height: usize,
+    pub tile_size: usize,
+    pub overlap: usize,
+    pub levels: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pyramid {
+    pub layout: Layout,
+    /// Size of the full image
+    width: usize,
+    height: usize,
+    /// Edge of a tile before the overlap is added
+    tile_size: usize,
+    /// Pixels each tile shares with its neighbours on either side
+    overlap: usize,
+    levels: Vec<Level>,
+}
+
+impl Pyramid {
+    /// A Deep Zoom pyramid of a `width` x `height` image. Level n is the
+    /// image scaled by 2^n over 2^max, rounded up, where 2^max is the first
+    /// power of two that covers both sides.
+    pub fn dzi(width: usize, height: usize, tile_size: usize, overlap: usize) -> Self {
+        let mut max = 0;
+        while 1usize << max < width.max(height) {
+            max += 1;
+        }
+        let levels = (0..=max)
+            .map(|index| {
+                let shift = max - index;
+                let scale = (1usize << shift) as f64;
+                Level {
+                    index,
+                    width: (width + (1 << shift) - 1) >> shift,
+                    height: (height + (1 << shift) - 1) >> shift,
+                    scale,
+                    origin: (0.0, 0.0),
+                }
+            })
+            .collect();
+        Self {
+            layout: Layout::Dzi,
+            width,
+            height,
+            tile_size,
+            overlap,
+            levels,
+        }
+    }
+
+    /// An XYZ pyramid over the square that has a `width` x `height` image
+    /// at its center and touches its longer sides. Without a `max_zoom` the
+    /// levels stop at the first whose pixels are no larger than the image's.
+    pub fn xyz(width: usize, height: usize, tile_size: usize, max_zoom: Option<u32>) -> Self {
+        let side = width.max(height);
+        let max_zoom = max_zoom.unwrap_or_else(|| {
+            let mut zoom = 0;
+            while tile_size << zoom < side && zoom < MAX_ZOOM {
+                zoom += 1;
+            }
+            zoom
+        });
+        let origin = (
+            (width as f64 - side as f64) / 2.0,
+            (height as f64 - side as f64) / 2.0,
+        );
+        let levels = (0..=max_zoom)
+            .map(|index| {
+                let size = tile_size << index;
+                Level {
+                    index,
+                    width: size,
+                    height: size,
+                    scale: side as f64 / size as f64,
+                    origin,
+                }
+            })
+            .collect();
+        Self {
+            layout: Layout::Xyz,
+            width,
+            height,
+            tile_size,
+            overlap: 0,
+            levels,
+        }
+    }
+
+    /// Levels from the coarsest to the finest
+    pub fn levels(&self) -> &[Level] {
+        &self.levels
+    }
+
+    /// Columns and rows of tiles in a level
+    fn grid(&self, level: &Level) -> (usize, usize) {
+        (
+            level.width.div_ceil(self.tile_size),
+            level.height.div_ceil(self.tile_size),
+        )
+    }
+
+    /// Number of tiles over all levels, or `None` past `usize`
+    pub fn tile_count(&self) -> Option<usize> {
+        self.levels.iter().try_fold(0usize, |total, level| {
+            let (columns, rows) = self.grid(level);
+            total.checked_add(columns.checked_mul(rows)?)
+        })
+    }
+
+    /// Tiles of a level in rows from the top left, made as they are asked
+    /// for. Tiles along the right and bottom edges are cropped to fit, and
+    /// the overlap only reaches into the level, never past its edges.
+    pub fn tiles<'a>(&'a self, level: &'a Level) -> impl Iterator<Item = Tile> + 'a {
+        let (columns, rows) = self.grid(level);
+        let span = move |index: usize, size: usize| {
+            let start = (index * self.tile_size).saturating_sub(self.overlap);
+            let end = ((index + 1) * self.tile_size + self.overlap).min(size);
+            (start, end - start)
+        };
+        (0..rows).flat_map(move |row| {
+            (0..columns).map(move |column| {
+                let (x, width) = span(column, level.width);
+                let (y, height) = span(row, level.height);
+                Tile {
+                    column,
+                    row,
+                    x,
+                    y,
+                    width,
+                    height,
+                }
+            })
+        })
+    }
+
+    /// What the tile files of the pyramid depend on, for one called `name`
+    /// with tiles in `format`
+    pub fn record<'a>(&self, name: &'a str, format: &'a str) -> PyramidRecord<'a> {
+        PyramidRecord {
+            layout: self.layout,
+            name,
+            format,
+            width: self.width,
+            height: self.height,
+            tile_size: self.tile_size,
+            overlap: self.overlap,
+            levels: self.levels.len(),
+        }
+    }
+
+    /// File of a tile in the pyramid written to `dir`, whose Deep Zoom
+    /// descriptor is called `name`
+    pub fn tile_path(
+        &self,
+        dir: &Path,
+        name: &str,
+        level: &Level,
+        tile: &Tile,
+        extension: &str,
+    ) -> PathBuf {
+        match self.layout {
+            Layout::Dzi => dir
+                .join(format!("{}_files", name))
+                .join(level.index.to_string())
+                .join(format!("{}_{}.{}", tile.column, tile.row, extension)),
+            Layout::Xyz => dir
+                .join(level.index.to_string())
+                .join(tile.column.to_string())
+                .join(format!("{}.{}", tile.row, extension)),
+        }
+    }
+
+    /// The Deep Zoom descriptor of the pyramid
+    pub fn descriptor(&self, extension: &str) -> String {
+        format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" ",
+                "Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n",
+                "  <Size Width=\"{}\" Height=\"{}\"/>\n",
+                "</Image>\n"
+            ),
+            extension, self.overlap, self.tile_size, self.width, self.height
+        )
+    }
+
+    /// View of a tile on its own, given the view of the full image. The
+    /// tile's pixels land where they would in the full level, and at the
+    /// finest level of a Deep Zoom pyramid on the pixels of the full image.
+    pub fn viewport(&self, viewport: &Viewport, level: &Level, tile: &Tile) -> Viewport {
+        let (dx, dy) = self.center_offset(viewport, level, tile);
+        let mut tiled = Viewport {
+            zoom: viewport
+                .zoom
+                .mul_f64(self.width as f64 / (tile.width as f64 * level.scale)),
+            ..viewport.clone()
+        };
+        let frac_limbs = tiled.frac_limbs(tile.width, tile.height);
+        tiled.center_x = viewport
+            .center_x
+            .with_frac_limbs(frac_limbs)
+            .add(&BigFixed::from_floatexp(dx, frac_limbs));
+        tiled.center_y = viewport
+            .center_y
+            .with_frac_limbs(frac_limbs)
+            .add(&BigFixed::from_floatexp(dy, frac_limbs));
+        tiled
+    }
+
+    /// Offset of the center of a tile from the center of the full image
+    pub fn center_offset(
+        &self,
+        viewport: &Viewport,
+        level: &Level,
+        tile: &Tile,
+    ) -> (FloatExp, FloatExp) {
+        // Center of the tile in pixels of the full image
+        let center = (
+            level.origin.0 + (tile.x as f64 + tile.width as f64 / 2.0) * level.scale,
+            level.origin.1 + (tile.y as f64 + tile.height as f64 / 2.0) * level.scale,
+        );
+        viewport
+            .offsets(self.width, self.height)
+            .point_at(0, 0, center)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        render::{RenderOptions, Renderer},
+        scene::{Perturbation, Scene},
+    };
+
+    #[test]
+    fn dzi_levels_halve_and_tiles_overlap() {
+        let pyramid = Pyramid::dzi(1000, 600, 254, 1);
+        let levels = pyramid.levels();
+        assert_eq!(levels.len(), 11);
+        assert_eq!((levels[10].width, levels[10].height), (1000, 600));
+        assert_eq!((levels[9].width, levels[9].height), (500, 300));
+        assert_eq!((levels[1].width, levels[1].height), (2, 2));
+        assert_eq!((levels[0].width, levels[0].height), (1, 1));
+
+        let tiles: Vec<Tile> = pyramid.tiles(&levels[10]).collect();
+        assert_eq!(tiles.len(), 4 * 3);
+        let spans: Vec<(usize, usize)> =
+            tiles[..4].iter().map(|tile| (tile.x, tile.width)).collect();
+        assert_eq!(spans, [(0, 255), (253, 256), (507, 256), (761, 239)]);
+        let last = tiles[11];
+        assert_eq!(
+            (last.column, last.row, last.y, last.height),
+            (3, 2, 507, 93)
+        );
+    }
+
+    #[test]
+    fn tiles_are_counted_without_being_made() {
+        assert_eq!(
+            Pyramid::dzi(1000, 600, 254, 1).tile_count(),
+            Some(12 + 4 + 9)
+        );
+        let deepest = Pyramid::xyz(1000, 600, 256, Some(MAX_ZOOM));
+        assert_eq!(deepest.tile_count(), Some(((1 << 62) - 1) / 3));
+        let level = deepest.levels().last().unwrap();
+        let last = deepest.tiles(level).nth(5).unwrap();
+        assert_eq!((last.column, last.row, last.x), (5, 0, 5 * 256));
+        assert_eq!(Pyramid::dzi(1 << 40, 1 << 40, 1, 0).tile_count(), None);
+    }
+
+    #[test]
+    fn tile_pixels_land_on_the_full_image() {
+        let viewport = Viewport {
+            center_x: "-0.743643887037158704752191506114774".parse().unwrap(),
+            center_y: "0.131825904205311970493132056385139".parse().unwrap(),
+            zoom: FloatExp::from_f64(1e5),
+            rotation: 30.0,
+            ..Viewport::default()
+        };
+        let (width, height) = (1000, 600);
+        let full = viewport.bounds(width, height);
+        let step = full.step;
+
+        // The finest Deep Zoom level is the full image
+        let pyramid = Pyramid::dzi(width, height, 254, 1);
+        let level = *pyramid.levels().last().unwrap();
+        let tile = pyramid.tiles(&level).nth(6).unwrap();
+        let bounds = pyramid
+            .viewport(&viewport, &level, &tile)
+            .bounds(tile.width, tile.height);
+        for (x, y) in [(0, 0), (17, 200), (tile.width - 1, tile.height - 1)] {
+            let (px, py) = bounds.point(x, y);
+            let (qx, qy) = full.point(tile.x + x, tile.y + y);
+            assert!((px - qx).abs() < step * 1e-6 && (py - qy).abs() < step * 1e-6);
+        }
+
+        // The single tile at XYZ zoom 0 takes in the image, centered
+        let pyramid = Pyramid::xyz(width, height, 256, None);
+        assert_eq!(pyramid.levels().len(), 3);
+        let level = pyramid.levels()[0];
+        let tile = pyramid.tiles(&level).next().unwrap();
+        let bounds = pyramid
+            .viewport(&viewport, &level, &tile)
+            .bounds(tile.width, tile.height);
+        let scale = 1000.0 / 256.0;
+        for (x, y) in [(0, 0), (100, 120), (255, 255)] {
+            let (px, py) = bounds.point(x, y);
+            let (qx, qy) = full.point_at(0, 0, (x as f64 * scale, y as f64 * scale - 200.0));
+            assert!((px - qx).abs() < step * 1e-6 && (py - qy).abs() < step * 1e-6);
+        }
+    }
+
+    #[test]
+    fn deep_tiles_share_the_full_images_reference() {
+        let mut scene = Scene::default();
+        scene.viewport.center_x = "-0.743643887037158704752191506114774".parse().unwrap();
+        scene.viewport.center_y = "0.131825904205311970493132056385139".parse().unwrap();
+        scene.viewport.zoom = FloatExp::from_f64(1e12);
+        scene.image.width = 60;
+        scene.image.height = 40;
+        scene.iteration.max_iterations = 3000;
+        scene.iteration.perturbation = Perturbation::Always;
+        let options = RenderOptions::default();
+        let full = Renderer::new(&scene).unwrap();
+        let (expected, _) = full.render(&options).unwrap();
+        assert!(expected.iter().any(|&color| color != expected[0]));
+
+        let pyramid = Pyramid::dzi(60, 40, 16, 1);
+        let level = *pyramid.levels().last().unwrap();
+        for tile in pyramid.tiles(&level) {
+            let mut tile_scene = scene.clone();
+            tile_scene.viewport = pyramid.viewport(&scene.viewport, &level, &tile);
+            tile_scene.image.width = tile.width;
+            tile_scene.image.height = tile.height;
+            let mut renderer = Renderer::new(&tile_scene).unwrap();
+            renderer.share_reference(&full, pyramid.center_offset(&scene.viewport, &level, &tile));
+            let (set, stats) = renderer.render(&options).unwrap();
+            assert!(stats.perturbation.is_some());
+            // The pixels sit on the same points up to rounding, which may
+            // move a smooth color by a step
+            for (row, line) in set.chunks_exact(tile.width).enumerate() {
+                let start = (tile.y + row) * 60 + tile.x;
+                for (a, b) in line.iter().zip(&expected[start..start + tile.width]) {
+                    let channels = [(a.red, b.red), (a.green, b.green), (a.blue, b.blue)];
+                    assert!(
+                        channels.iter().all(|&(a, b)| a.abs_diff(b) <= 1),
+                        "{:?} in {:?}",
+                        (a, b),
+                        tile
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,957 @@
+use std::sync::{Arc, OnceLock};
+
+use rayon::prelude::*;
+
+use crate::{
+    antialias::{self, AntialiasSpec, SampleStats},
+    average::{self, AverageSpec},
+    buddhabrot::{self, BuddhabrotStats},
+    color::Color,
+    distance::DistanceSpec,
+    error::{Error, Result},
+    fill::{self, Strategy},
+    floatexp::FloatExp,
+    fractal,
+    interior::Interior,
+    kernel::{EscapeParams, Kernel},
+    newton::{Newton, NewtonPoint},
+    palette::Palette,
+    perturbation::{self, CentralReference, DeltaKind, PerturbationStats, DEEP_ZOOM_STEP},
+    scene::{Deltas, Formula, JuliaSpec, Perturbation, Scene},
+    trap::{self, Trap},
+    viewport::{Bounds, Offsets, Viewport},
+};
+
+/// How the work of a render is spread over the CPU. None of these settings
+/// change the resulting image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RenderOptions {
+    /// Worker threads, 0 uses one per core and 1 renders serially
+    pub threads: usize,
+    /// Edge length of the square tiles handed to worker threads
+    pub tile_size: usize,
+    pub kernel: Kernel,
+    /// Skip the escape loop for points in the main cardioid or the period-2
+    /// bulb of the Mandelbrot set
+    pub cardioid: bool,
+    /// Stop iterating orbits that have fallen into a cycle
+    pub periodicity: bool,
+    /// Which pixels of a tile are iterated, the rest are filled in
+    pub strategy: Strategy,
+}
+
+impl RenderOptions {
+    /// Refuse options that would have no effect on `scene`, rather than
+    /// leave them out without a word
+    pub fn check(&self, scene: &Scene) -> Result<()> {
+        if self.strategy == Strategy::Pixels {
+            return Ok(());
+        }
+        if scene.buddhabrot.is_some() {
+            return Err(Error::InvalidArgument(
+                "--strategy fills in escape times, which the buddhabrot does not render"
+                    .to_string(),
+            ));
+        }
+        if !uses_escape_kernels(scene) {
+            return Err(Error::InvalidArgument(
+                "--strategy fills in escape times, which orbit traps, average coloring, distance estimation and Newton fractals do not color by"
+                    .to_string(),
+            ));
+        }
+        if uses_perturbation(scene) {
+            return Err(Error::InvalidArgument(
+                "--strategy cannot fill in pixels rendered by perturbation, which this zoom needs"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            threads: 0,
+            tile_size: 64,
+            kernel: Kernel::detect(),
+            cardioid: true,
+            periodicity: true,
+            strategy: Strategy::Pixels,
+        }
+    }
+}
+
+/// Rectangle of pixels rendered as one unit of work
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    /// Split an image into tiles in row-major order, the tiles along the right
+    /// and bottom edges are cropped to fit
+    pub fn split(width: usize, height: usize, tile_size: usize) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(tile_size) {
+            for x in (0..width).step_by(tile_size) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: tile_size.min(width - x),
+                    height: tile_size.min(height - y),
+                });
+            }
+        }
+        tiles
+    }
+}
+
+/// Pixels that took a shortcut out of the escape loop
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExitStats {
+    /// Skipped for lying in the main cardioid or the period-2 bulb
+    pub cardioid: u64,
+    /// Stopped once their orbit fell into a cycle
+    pub periodic: u64,
+    /// Filled in from the pixels around them without being iterated
+    pub filled: u64,
+}
+
+impl ExitStats {
+    pub fn add(self, other: Self) -> Self {
+        Self {
+            cardioid: self.cardioid + other.cardioid,
+            periodic: self.periodic + other.periodic,
+            filled: self.filled + other.filled,
+        }
+    }
+}
+
+/// Counters collected while rendering, for reporting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenderStats {
+    /// Set when the image was rendered by perturbation
+    pub perturbation: Option<PerturbationStats>,
+    pub buddhabrot: Option<BuddhabrotStats>,
+    /// Set when pixels were iterated by the escape-time kernels
+    pub exits: Option<ExitStats>,
+    /// Set when the image was supersampled
+    pub samples: Option<SampleStats>,
+}
+
+impl RenderStats {
+    /// Counts of two parts of the same image taken together
+    fn add(self, other: Self) -> Self {
+        fn either<T>(a: Option<T>, b: Option<T>, add: fn(T, T) -> T) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(add(a, b)),
+                (a, b) => a.or(b),
+            }
+        }
+        Self {
+            perturbation: either(
+                self.perturbation,
+                other.perturbation,
+                PerturbationStats::add,
+            ),
+            buddhabrot: self.buddhabrot.or(other.buddhabrot),
+            exits: either(self.exits, other.exits, ExitStats::add),
+            samples: either(self.samples, other.samples, SampleStats::add),
+        }
+    }
+}
+
+/// Everything the kernel needs that stays the same for every pixel
+pub struct Renderer {
+    width: usize,
+    /// Rows being rendered, all of the image's unless it is rendered in
+    /// bands
+    height: usize,
+    image_height: usize,
+    /// Row of the image the band starts at
+    top: usize,
+    formula: Formula,
+    /// Multibrot exponent
+    power: f64,
+    newton: Option<Newton>,
+    /// One color per root of the Newton polynomial
+    root_colors: Vec<Color>,
+    shading: f64,
+    params: EscapeParams,
+    viewport: Viewport,
+    bounds: Bounds,
+    palette: Palette,
+    julia: Option<JuliaSpec>,
+    distance: Option<DistanceSpec>,
+    /// Squared distance within which an orbit counts as having come back
+    /// to itself
+    periodicity_tolerance_sqr: f64,
+    /// Orbit trap and the distance at which it fades out
+    trap: Option<(Trap, f64)>,
+    average: Option<AverageSpec>,
+    interior: Option<Interior>,
+    antialias: Option<AntialiasSpec>,
+    perturbation: bool,
+    deltas: DeltaKind,
+    /// Computed by the first band that needs it and shared by the rest, or
+    /// by the renderer it was taken from
+    reference: Arc<OnceLock<CentralReference>>,
+    /// Offset of the view's center from the reference's
+    reference_offset: (FloatExp, FloatExp),
+    /// The whole scene, for render modes that are not per pixel
+    scene: Scene,
+}
+
+impl Renderer {
+    pub fn new(scene: &Scene) -> Result<Self> {
+        let width = scene.image.width;
+        let height = scene.image.height;
+        let palette = Palette::generate(&scene.palette, scene.iteration.max_iterations)?;
+        let newton = scene.newton.as_ref().map(Newton::new);
+        // Spread the roots evenly over the palette
+        let root_colors = newton.as_ref().map_or_else(Vec::new, |newton| {
+            let count = newton.roots.len();
+            (0..count)
+                .map(|root| palette.sample((root as f32 + 0.5) / count as f32))
+                .collect()
+        });
+        Ok(Self {
+            width,
+            height,
+            image_height: height,
+            top: 0,
+            formula: scene.formula,
+            power: scene.power.unwrap_or(2.0),
+            newton,
+            root_colors,
+            shading: scene.newton.as_ref().map_or(0.0, |newton| newton.shading),
+            params: EscapeParams {
+                max_iterations: scene.iteration.max_iterations as f64,
+                bailout_sqr: scene.iteration.bailout * scene.iteration.bailout,
+                julia: scene
+                    .julia
+                    .as_ref()
+                    .map(|julia| (julia.x.to_f64(), julia.y.to_f64())),
+            },
+            viewport: scene.viewport.clone(),
+            bounds: scene.viewport.bounds(width, height),
+            palette,
+            julia: scene.julia.clone(),
+            distance: scene.distance.clone(),
+            periodicity_tolerance_sqr: (scene.viewport.finest_step(width, height).to_f64()
+                * fractal::PERIODICITY_TOLERANCE)
+                .powi(2),
+            average: scene.average.clone(),
+            interior: scene
+                .interior
+                .as_ref()
+                .map(|spec| Interior::new(spec, scene.formula, scene.power.unwrap_or(2.0))),
+            trap: match &scene.trap {
+                Some(spec) => Some((Trap::new(spec)?, spec.size)),
+                None => None,
+            },
+            antialias: scene.antialias.clone(),
+            perturbation: uses_perturbation(scene),
+            deltas: delta_kind(scene),
+            reference: Arc::new(OnceLock::new()),
+            reference_offset: (FloatExp::ZERO, FloatExp::ZERO),
+            scene: scene.clone(),
+        })
+    }
+
+    /// Iterate pixels of a view inside this renderer's one, such as a tile
+    /// of it, against this renderer's reference orbit rather than one of
+    /// their own. `center` is the offset of the inner view's center from
+    /// this one's. The orbit is computed once for all of them, at the
+    /// precision of the finest pixels, which a view inside does not pass.
+    pub fn share_reference(&mut self, outer: &Renderer, center: (FloatExp, FloatExp)) {
+        self.reference = Arc::clone(&outer.reference);
+        self.reference_offset = center;
+    }
+
+    pub fn render(&self, options: &RenderOptions) -> Result<(Vec<Color>, RenderStats)> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(options.threads)
+            .build()?;
+        pool.install(|| self.render_in_pool(options))
+    }
+
+    /// Like `render`, on the current rayon pool instead of one of its own,
+    /// for callers that render many small images
+    pub fn render_in_pool(&self, options: &RenderOptions) -> Result<(Vec<Color>, RenderStats)> {
+        let mut stats = RenderStats::default();
+        if let Some(spec) = &self.scene.buddhabrot {
+            let (set, buddhabrot_stats) = buddhabrot::render(spec, &self.scene, &self.palette);
+            stats.buddhabrot = Some(buddhabrot_stats);
+            return Ok((set, stats));
+        }
+        Ok(match &self.antialias {
+            Some(spec) => self.render_supersampled(spec, options),
+            None => self.render_pixels(options),
+        })
+    }
+
+    /// Render the image `band_rows` rows at a time and hand each band to
+    /// `write` as soon as it is done, so that no more than one band is held
+    /// in memory. Adaptive supersampling looks one row past either edge of
+    /// a band to find the edges there, and those rows count twice in the
+    /// stats.
+    pub fn render_bands(
+        &mut self,
+        options: &RenderOptions,
+        band_rows: usize,
+        mut write: impl FnMut(&[Color]) -> Result<()>,
+    ) -> Result<RenderStats> {
+        if self.scene.buddhabrot.is_some() {
+            return Err(Error::InvalidArgument(
+                "the buddhabrot accumulates orbits over the whole image and cannot be rendered in bands"
+                    .to_string(),
+            ));
+        }
+        let margin = match &self.antialias {
+            Some(spec) if spec.adaptive.is_some() => 1,
+            _ => 0,
+        };
+
+        let height = self.image_height;
+        let mut stats = RenderStats::default();
+        let mut top = 0;
+        while top < height {
+            let rows = band_rows.min(height - top);
+            let first = top.saturating_sub(margin);
+            let last = (top + rows + margin).min(height);
+            self.set_band(first, last - first);
+            let (set, band_stats) = self.render(options)?;
+            stats = stats.add(band_stats);
+            let start = (top - first) * self.width;
+            write(&set[start..start + rows * self.width])?;
+            top += rows;
+        }
+        self.set_band(0, height);
+        Ok(stats)
+    }
+
+    /// Render rows `top..top + rows` of the image from now on
+    fn set_band(&mut self, top: usize, rows: usize) {
+        self.top = top;
+        self.height = rows;
+        self.bounds = self
+            .viewport
+            .bounds(self.width, self.image_height)
+            .rows_from(top);
+    }
+
+    /// Offsets from the reference's center of the pixels being rendered
+    fn offsets(&self) -> Offsets {
+        self.viewport
+            .offsets(self.width, self.image_height)
+            .rows_from(self.top)
+            .with_center_at(self.reference_offset)
+    }
+
+    fn central_reference(&self) -> &CentralReference {
+        self.reference.get_or_init(|| {
+            CentralReference::new(
+                &self.viewport,
+                self.julia.as_ref().map(|julia| (&julia.x, &julia.y)),
+                self.viewport.frac_limbs(self.width, self.image_height),
+                &self.params,
+            )
+        })
+    }
+
+    /// One sample per pixel
+    fn render_pixels(&self, options: &RenderOptions) -> (Vec<Color>, RenderStats) {
+        let mut stats = RenderStats::default();
+        let set = if self.perturbation {
+            let (set, perturbation_stats) = self.render_perturbed();
+            stats.perturbation = Some(perturbation_stats);
+            set
+        } else {
+            let (set, exits) = if options.threads == 1 {
+                self.render_serial(options)
+            } else {
+                self.render_tiled(options)
+            };
+            stats.exits = self.uses_escape_kernels().then_some(exits);
+            set
+        };
+        (set, stats)
+    }
+
+    /// Several samples per pixel, or in adaptive mode a plain render
+    /// followed by more samples for the pixels on edges
+    fn render_supersampled(
+        &self,
+        spec: &AntialiasSpec,
+        options: &RenderOptions,
+    ) -> (Vec<Color>, RenderStats) {
+        let pixels = self.width * self.height;
+        let (mut set, mut stats, marked) = match spec.adaptive {
+            Some(threshold) => {
+                let (set, stats) = self.render_pixels(options);
+                let marked = antialias::edges(&set, self.width, self.height, threshold);
+                (set, stats, marked)
+            }
+            None => (
+                vec![Color::new(0, 0, 0); pixels],
+                RenderStats::default(),
+                vec![true; pixels],
+            ),
+        };
+        let base_samples = if spec.adaptive.is_some() { pixels } else { 0 };
+
+        let supersampled: Vec<usize> = (0..pixels).filter(|&index| marked[index]).collect();
+        let colors = if self.perturbation {
+            let (colors, perturbation) = self.supersample_perturbed(spec, &supersampled);
+            stats.perturbation = Some(match stats.perturbation {
+                Some(base) => base.add(perturbation),
+                None => perturbation,
+            });
+            colors
+        } else {
+            let (colors, exits) = self.supersample_rows(spec, &supersampled, options);
+            stats.exits = self
+                .uses_escape_kernels()
+                .then(|| stats.exits.unwrap_or_default().add(exits));
+            colors
+        };
+        for (&index, color) in supersampled.iter().zip(colors) {
+            set[index] = color;
+        }
+
+        let per_pixel = spec.samples() as u64;
+        stats.samples = Some(SampleStats {
+            samples: base_samples as u64 + supersampled.len() as u64 * per_pixel,
+            supersampled: supersampled.len() as u64,
+            per_pixel,
+        });
+        (set, stats)
+    }
+
+    /// Supersampled colors of the given pixels, which are in order, a row
+    /// at a time on the current rayon pool
+    fn supersample_rows(
+        &self,
+        spec: &AntialiasSpec,
+        pixels: &[usize],
+        options: &RenderOptions,
+    ) -> (Vec<Color>, ExitStats) {
+        let samples = spec.samples();
+        let rows: Vec<&[usize]> = pixels
+            .chunk_by(|a, b| a / self.width == b / self.width)
+            .collect();
+        let rendered: Vec<(Vec<Color>, ExitStats)> = rows
+            .par_iter()
+            .map(|row| {
+                let y = row[0] / self.width;
+                let (mut x0, mut y0) = (Vec::new(), Vec::new());
+                for &index in row.iter() {
+                    let x = index % self.width;
+                    for offset in spec.offsets(self.top * self.width + index) {
+                        let (px, py) = self.bounds.point_at(x, y, offset);
+                        x0.push(px);
+                        y0.push(py);
+                    }
+                }
+                let mut colors = Vec::with_capacity(x0.len());
+                let exits =
+                    self.color_points(&x0, &y0, self.bounds.pixel_size(y), options, &mut colors);
+                let averaged = colors
+                    .chunks_exact(samples)
+                    .map(antialias::average)
+                    .collect();
+                (averaged, exits)
+            })
+            .collect();
+
+        let mut colors = Vec::with_capacity(pixels.len());
+        let mut exits = ExitStats::default();
+        for (row, row_exits) in rendered {
+            colors.extend(row);
+            exits = exits.add(row_exits);
+        }
+        (colors, exits)
+    }
+
+    /// Supersampled colors of the given pixels against reference orbits. The
+    /// samples go in batches, to bound the memory their iteration counts
+    /// take.
+    fn supersample_perturbed(
+        &self,
+        spec: &AntialiasSpec,
+        pixels: &[usize],
+    ) -> (Vec<Color>, PerturbationStats) {
+        const BATCH_SAMPLES: usize = 1 << 22;
+        let samples = spec.samples();
+        let offsets = self.offsets();
+
+        let mut colors = Vec::with_capacity(pixels.len());
+        let mut stats = PerturbationStats::default();
+        for batch in pixels.chunks((BATCH_SAMPLES / samples).max(1)) {
+            let positions: Vec<(usize, (f64, f64))> = batch
+                .iter()
+                .flat_map(|&index| {
+                    spec.offsets(self.top * self.width + index)
+                        .into_iter()
+                        .map(move |offset| (index, offset))
+                })
+                .collect();
+            let (iterations, batch_stats) = perturbation::render_points(
+                self.central_reference(),
+                positions.len(),
+                |sample| {
+                    let (index, offset) = positions[sample];
+                    offsets.point_at(index % self.width, index / self.width, offset)
+                },
+                self.deltas,
+                &self.params,
+            );
+            stats = stats.add(batch_stats);
+            let sampled: Vec<Color> = iterations
+                .par_iter()
+                .map(|&iteration| self.palette.color(iteration))
+                .collect();
+            colors.extend(sampled.chunks_exact(samples).map(antialias::average));
+        }
+        (colors, stats)
+    }
+
+    fn uses_escape_kernels(&self) -> bool {
+        uses_escape_kernels(&self.scene)
+    }
+
+    /// Render the whole image against high-precision reference orbits
+    pub fn render_perturbed(&self) -> (Vec<Color>, PerturbationStats) {
+        let offsets = self.offsets();
+        let (iterations, stats) = perturbation::render_points(
+            self.central_reference(),
+            self.width * self.height,
+            |index| offsets.point(index % self.width, index / self.width),
+            self.deltas,
+            &self.params,
+        );
+        let set = iterations
+            .par_iter()
+            .map(|&iteration| self.palette.color(iteration))
+            .collect();
+        (set, stats)
+    }
+
+    /// Walk every pixel in order on the calling thread. Fill strategies
+    /// go a tile at a time, as in `render_tiled`, since a fill can come out
+    /// differently over a different rectangle.
+    pub fn render_serial(&self, options: &RenderOptions) -> (Vec<Color>, ExitStats) {
+        if options.strategy != Strategy::Pixels {
+            let tiles = Tile::split(self.width, self.height, options.tile_size);
+            let rendered = tiles
+                .iter()
+                .map(|tile| self.render_tile(tile, options))
+                .collect();
+            return self.stitch(&tiles, rendered);
+        }
+        self.render_tile(
+            &Tile {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            },
+            options,
+        )
+    }
+
+    /// Render tiles in parallel on the current rayon pool and stitch them
+    /// back together
+    pub fn render_tiled(&self, options: &RenderOptions) -> (Vec<Color>, ExitStats) {
+        let tiles = Tile::split(self.width, self.height, options.tile_size);
+        let rendered = tiles
+            .par_iter()
+            .map(|tile| self.render_tile(tile, options))
+            .collect();
+        self.stitch(&tiles, rendered)
+    }
+
+    /// The image made up of rendered tiles
+    fn stitch(
+        &self,
+        tiles: &[Tile],
+        rendered: Vec<(Vec<Color>, ExitStats)>,
+    ) -> (Vec<Color>, ExitStats) {
+        let mut set = vec![Color::new(0, 0, 0); self.width * self.height];
+        let mut exits = ExitStats::default();
+        for (tile, (colors, tile_exits)) in tiles.iter().zip(rendered) {
+            exits = exits.add(tile_exits);
+            for (row, line) in colors.chunks_exact(tile.width).enumerate() {
+                let start = (tile.y + row) * self.width + tile.x;
+                set[start..start + tile.width].copy_from_slice(line);
+            }
+        }
+        (set, exits)
+    }
+
+    pub fn render_tile(&self, tile: &Tile, options: &RenderOptions) -> (Vec<Color>, ExitStats) {
+        if options.strategy != Strategy::Pixels && self.uses_escape_kernels() {
+            return self.render_tile_filled(tile, options);
+        }
+
+        let mut colors = Vec::with_capacity(tile.width * tile.height);
+        let mut x0 = vec![0.0; tile.width];
+        let mut y0 = vec![0.0; tile.width];
+        let mut exits = ExitStats::default();
+
+        for y in tile.y..tile.y + tile.height {
+            for (i, x) in (tile.x..tile.x + tile.width).enumerate() {
+                let (px, py) = self.bounds.point(x, y);
+                x0[i] = px;
+                y0[i] = py;
+            }
+            let row_exits =
+                self.color_points(&x0, &y0, self.bounds.pixel_size(y), options, &mut colors);
+            exits = exits.add(row_exits);
+        }
+        (colors, exits)
+    }
+
+    /// Append the colors of a run of points to `colors`, for every coloring
+    /// but the buddhabrot. Distances are measured in pixels of the given
+    /// size.
+    fn color_points(
+        &self,
+        x0: &[f64],
+        y0: &[f64],
+        pixel_size: f64,
+        options: &RenderOptions,
+        colors: &mut Vec<Color>,
+    ) -> ExitStats {
+        if let Some((trap, size)) = &self.trap {
+            trap::color_points(
+                self.formula,
+                self.power,
+                x0,
+                y0,
+                &self.params,
+                trap,
+                *size,
+                &self.palette,
+                colors,
+            );
+            return ExitStats::default();
+        }
+        let mut iterations = vec![0.0; x0.len()];
+        let mut exits = ExitStats::default();
+        let row = colors.len();
+        if let Some(average) = &self.average {
+            average::color_points(
+                self.formula,
+                self.power,
+                x0,
+                y0,
+                &self.params,
+                average,
+                &self.palette,
+                &mut iterations,
+                colors,
+            );
+        } else if let Some(distance) = &self.distance {
+            let mut distances = vec![0.0; x0.len()];
+            fractal::estimate_distances(
+                self.formula,
+                self.power,
+                x0,
+                y0,
+                &self.params,
+                &mut iterations,
+                &mut distances,
+            );
+            colors.extend(
+                iterations.iter().zip(&distances).map(|(&iteration, &d)| {
+                    distance.color(&self.palette, iteration, d / pixel_size)
+                }),
+            );
+        } else {
+            match (self.formula, &self.newton) {
+                (Formula::Newton, Some(newton)) => {
+                    colors.extend(x0.iter().zip(y0).map(|(&x, &y)| {
+                        self.root_color(newton.calculate_point(x, y, &self.params))
+                    }));
+                    return exits;
+                }
+                (Formula::Nova, Some(newton)) => {
+                    for ((x, y), iteration) in x0.iter().zip(y0).zip(&mut iterations) {
+                        *iteration = newton.calculate_nova_point(*x, *y, &self.params);
+                    }
+                }
+                _ => {
+                    exits = self.iterate_row(x0, y0, options, &mut iterations);
+                }
+            }
+            colors.extend(
+                iterations
+                    .iter()
+                    .map(|&iteration| self.palette.color(iteration)),
+            );
+        }
+
+        if let Some(interior) = &self.interior {
+            for (i, &iteration) in iterations.iter().enumerate() {
+                if iteration >= self.params.max_iterations {
+                    colors[row + i] =
+                        interior.color(x0[i], y0[i], pixel_size, &self.params, &self.palette);
+                }
+            }
+        }
+        exits
+    }
+
+    /// Render a tile by the options' strategy, which only iterates some of
+    /// its pixels
+    fn render_tile_filled(&self, tile: &Tile, options: &RenderOptions) -> (Vec<Color>, ExitStats) {
+        let mut exits = ExitStats::default();
+        let (iterations, filled) =
+            fill::fill(options.strategy, tile.width, tile.height, |indices, out| {
+                let (x0, y0): (Vec<f64>, Vec<f64>) = indices
+                    .iter()
+                    .map(|&index| {
+                        self.bounds
+                            .point(tile.x + index % tile.width, tile.y + index / tile.width)
+                    })
+                    .unzip();
+                exits = exits.add(self.iterate_row(&x0, &y0, options, out));
+            });
+        exits.filled = filled;
+
+        let mut colors: Vec<Color> = iterations
+            .iter()
+            .map(|&iteration| self.palette.color(iteration))
+            .collect();
+        if let Some(interior) = &self.interior {
+            for (index, &iteration) in iterations.iter().enumerate() {
+                if iteration >= self.params.max_iterations {
+                    let y = tile.y + index / tile.width;
+                    let (x0, y0) = self.bounds.point(tile.x + index % tile.width, y);
+                    colors[index] = interior.color(
+                        x0,
+                        y0,
+                        self.bounds.pixel_size(y),
+                        &self.params,
+                        &self.palette,
+                    );
+                }
+            }
+        }
+        (colors, exits)
+    }
+
+    /// Smooth iteration counts for a row of escape-time points, taking the
+    /// early exits the options allow
+    fn iterate_row(
+        &self,
+        x0: &[f64],
+        y0: &[f64],
+        options: &RenderOptions,
+        iterations: &mut [f64],
+    ) -> ExitStats {
+        let periodicity = options
+            .periodicity
+            .then_some(self.periodicity_tolerance_sqr);
+        let iterate = |x0: &[f64], y0: &[f64], out: &mut [f64]| match self.formula {
+            // The SIMD kernels only know the Mandelbrot formula
+            Formula::Mandelbrot => {
+                options
+                    .kernel
+                    .calculate_points(x0, y0, &self.params, periodicity, out)
+            }
+            formula => fractal::calculate_points(
+                formula,
+                self.power,
+                x0,
+                y0,
+                &self.params,
+                periodicity,
+                out,
+            ),
+        };
+        if !options.cardioid || self.formula != Formula::Mandelbrot || self.params.julia.is_some() {
+            return ExitStats {
+                periodic: iterate(x0, y0, iterations),
+                ..ExitStats::default()
+            };
+        }
+
+        // Pack the remaining points together so that the SIMD lanes stay full
+        let kept: Vec<usize> = (0..x0.len())
+            .filter(|&i| !fractal::in_cardioid_or_bulb(x0[i], y0[i]))
+            .collect();
+        let xs: Vec<f64> = kept.iter().map(|&i| x0[i]).collect();
+        let ys: Vec<f64> = kept.iter().map(|&i| y0[i]).collect();
+        let mut out = vec![0.0; kept.len()];
+        let periodic = iterate(&xs, &ys, &mut out);
+
+        iterations.fill(self.params.max_iterations);
+        for (&i, &iteration) in kept.iter().zip(&out) {
+            iterations[i] = iteration;
+        }
+        ExitStats {
+            cardioid: (x0.len() - kept.len()) as u64,
+            periodic,
+            filled: 0,
+        }
+    }
+
+    /// Color of the root a Newton orbit reached, darkened by the number of
+    /// steps it took
+    fn root_color(&self, point: NewtonPoint) -> Color {
+        match point.root {
+            Some(root) => {
+                let shade = 1.0 - (1.0 - self.shading).powf(point.iteration);
+                self.root_colors[root].interpolate(&self.palette.max_color, shade as f32)
+            }
+            None => self.palette.max_color,
+        }
+    }
+}
+
+/// Whether pixels go through the plain escape-time iteration, where the
+/// early exits and fill strategies apply
+pub fn uses_escape_kernels(scene: &Scene) -> bool {
+    scene.trap.is_none()
+        && scene.average.is_none()
+        && scene.distance.is_none()
+        && !matches!(scene.formula, Formula::Newton | Formula::Nova)
+}
+
+/// Whether the scene is rendered by perturbation rather than plain `f64`.
+/// Only the Mandelbrot formula has a perturbed iteration.
+pub fn uses_perturbation(scene: &Scene) -> bool {
+    if scene.formula != Formula::Mandelbrot
+        || scene.buddhabrot.is_some()
+        || scene.distance.is_some()
+        || scene.trap.is_some()
+        || scene.average.is_some()
+        || scene.interior.is_some()
+    {
+        return false;
+    }
+    match scene.iteration.perturbation {
+        Perturbation::Always => true,
+        Perturbation::Never => false,
+        Perturbation::Auto => {
+            scene
+                .viewport
+                .finest_step(scene.image.width, scene.image.height)
+                < FloatExp::from_f64(DEEP_ZOOM_STEP)
+        }
+    }
+}
+
+/// Number type for the deltas of a perturbation render
+pub fn delta_kind(scene: &Scene) -> DeltaKind {
+    match scene.iteration.deltas {
+        Deltas::Auto => DeltaKind::for_step(
+            scene
+                .viewport
+                .finest_step(scene.image.width, scene.image.height),
+        ),
+        Deltas::F64 => DeltaKind::F64,
+        Deltas::Rescaled => DeltaKind::Rescaled,
+        Deltas::Floatexp => DeltaKind::FloatExp,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trap::{TrapShape, TrapSpec};
+
+    fn small_scene(formula: Formula) -> Scene {
+        let mut scene = Scene {
+            formula,
+            ..Scene::default()
+        };
+        scene.image.width = 45;
+        scene.image.height = 31;
+        scene.iteration.max_iterations = 200;
+        scene
+    }
+
+    #[test]
+    fn tiled_renders_match_the_serial_render() {
+        let mut julia = small_scene(Formula::Mandelbrot);
+        julia.julia = Some("-0.8,0.156".parse().unwrap());
+        julia.viewport.zoom = FloatExp::from_f64(0.6);
+        let mut ship = small_scene(Formula::BurningShip);
+        ship.viewport.center_x = "-1.76".parse().unwrap();
+        ship.viewport.center_y = "-0.03".parse().unwrap();
+        ship.viewport.zoom = FloatExp::from_f64(12.0);
+
+        // Fills may differ over different rectangles, so the serial render
+        // splits them into the same tiles, while pixels may go in any split
+        let strategies = [Strategy::Pixels, Strategy::Subdivide, Strategy::Trace];
+        for (scene, strategy) in [small_scene(Formula::Mandelbrot), julia, ship]
+            .iter()
+            .flat_map(|scene| strategies.map(|strategy| (scene, strategy)))
+        {
+            let renderer = Renderer::new(scene).unwrap();
+            let (whole, _) = renderer
+                .render(&RenderOptions {
+                    threads: 1,
+                    ..RenderOptions::default()
+                })
+                .unwrap();
+            // Single pixels, an odd size, and one tile for the whole image
+            for tile_size in [1, 7, 64] {
+                let serial = RenderOptions {
+                    threads: 1,
+                    tile_size,
+                    strategy,
+                    ..RenderOptions::default()
+                };
+                let (expected, _) = renderer.render(&serial).unwrap();
+                if strategy == Strategy::Pixels {
+                    assert!(expected == whole, "{:?}", scene.formula);
+                }
+                for threads in [2, 3, 8] {
+                    let options = RenderOptions { threads, ..serial };
+                    let (set, _) = renderer.render(&options).unwrap();
+                    assert!(
+                        set == expected,
+                        "{:?} by {:?} with {} threads and {} pixel tiles",
+                        scene.formula,
+                        strategy,
+                        threads,
+                        tile_size
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fill_strategies_are_refused_where_they_do_not_apply() {
+        let subdivide = RenderOptions {
+            strategy: Strategy::Subdivide,
+            ..RenderOptions::default()
+        };
+        let plain = small_scene(Formula::Mandelbrot);
+        assert!(subdivide.check(&plain).is_ok());
+
+        let mut trapped = plain.clone();
+        trapped.trap = Some(TrapSpec::new(TrapShape::Point));
+        let mut distance = plain.clone();
+        distance.distance = Some(DistanceSpec::default());
+        let mut perturbed = plain.clone();
+        perturbed.iteration.perturbation = Perturbation::Always;
+        for scene in [trapped, distance, small_scene(Formula::Newton), perturbed] {
+            assert!(matches!(
+                subdivide.check(&scene),
+                Err(Error::InvalidArgument(_))
+            ));
+            assert!(RenderOptions::default().check(&scene).is_ok());
+        }
+    }
+}
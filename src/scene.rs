@@ -0,0 +1,948 @@
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+};
+
+use clap::ValueEnum;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    antialias::AntialiasSpec,
+    average::{AverageMethod, AverageSpec},
+    bignum::BigFixed,
+    buddhabrot::BuddhabrotSpec,
+    distance::DistanceSpec,
+    error::{Error, Result},
+    floatexp::FloatExp,
+    interior::{InteriorMode, InteriorSpec},
+    newton::NewtonSpec,
+    palette::{stop_positions, Easing, PaletteSpec},
+    trap::{TrapShape, TrapSpec},
+    viewport::Viewport,
+};
+
+/// Scene file format version written by this build
+pub const SCENE_VERSION: u32 = 1;
+
+/// Largest supersampling grid, 256 samples per pixel
+pub const MAX_SUPERSAMPLE_GRID: usize = 16;
+
+/// Iteration formula used to decide whether a point escapes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Formula {
+    /// z -> z^2 + c, starting from z = 0 (or from the pixel for Julia sets)
+    Mandelbrot,
+    /// z -> z^n + c for the scene's power n
+    Multibrot,
+    /// z -> (|Re z| + |Im z| i)^2 + c
+    #[value(name = "burning-ship")]
+    #[serde(rename = "burning-ship")]
+    BurningShip,
+    /// z -> conj(z)^2 + c, also known as the Mandelbar
+    Tricorn,
+    /// z -> |Re z^2| + Im z^2 i + c
+    Celtic,
+    /// z -> |Re z^2| + |Im z^2| i + c
+    Buffalo,
+    /// Newton's method on the scene's polynomial, colored by root
+    Newton,
+    /// z -> z - a p(z)/p'(z) + c on the scene's polynomial
+    Nova,
+}
+
+/// Encoding of the rendered image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    Bmp,
+    Tiff,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::Tiff => "tiff",
+        }
+    }
+
+    /// Guess the format from a file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match image::ImageFormat::from_path(path).ok()? {
+            image::ImageFormat::Png => Some(OutputFormat::Png),
+            image::ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
+            image::ImageFormat::Bmp => Some(OutputFormat::Bmp),
+            image::ImageFormat::Tiff => Some(OutputFormat::Tiff),
+            _ => None,
+        }
+    }
+
+    pub fn image_format(self) -> image::ImageFormat {
+        match self {
+            OutputFormat::Png => image::ImageFormat::Png,
+            OutputFormat::Jpeg => image::ImageFormat::Jpeg,
+            OutputFormat::Bmp => image::ImageFormat::Bmp,
+            OutputFormat::Tiff => image::ImageFormat::Tiff,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImageSpec {
+    pub width: usize,
+    pub height: usize,
+    pub format: OutputFormat,
+}
+
+impl Default for ImageSpec {
+    fn default() -> Self {
+        Self {
+            width: 2560,
+            height: 1440,
+            format: OutputFormat::Png,
+        }
+    }
+}
+
+/// When to iterate pixels as perturbations of a high-precision reference
+/// orbit instead of directly in `f64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Perturbation {
+    /// Only once pixels are too close together for `f64`
+    Auto,
+    Always,
+    Never,
+}
+
+/// Number type for the per-pixel deltas of a perturbation render
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Deltas {
+    /// Picked from the zoom depth
+    Auto,
+    F64,
+    /// `f64` scaled by a fixed power of two
+    Rescaled,
+    /// Extended-exponent floats
+    Floatexp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IterationSpec {
+    pub max_iterations: usize,
+    /// Escape radius, a point has escaped once |z| exceeds it
+    pub bailout: f64,
+    #[serde(default = "IterationSpec::default_perturbation")]
+    pub perturbation: Perturbation,
+    #[serde(default = "IterationSpec::default_deltas")]
+    pub deltas: Deltas,
+}
+
+impl IterationSpec {
+    /// Largest bailout whose square still fits the integer part of the
+    /// reference orbit's fixed-point numbers
+    pub const MAX_BAILOUT: f64 = 1e9;
+
+    fn default_perturbation() -> Perturbation {
+        Perturbation::Auto
+    }
+
+    fn default_deltas() -> Deltas {
+        Deltas::Auto
+    }
+}
+
+impl Default for IterationSpec {
+    fn default() -> Self {
+        Self {
+            max_iterations: 1000,
+            bailout: 256.0,
+            perturbation: Self::default_perturbation(),
+            deltas: Self::default_deltas(),
+        }
+    }
+}
+
+/// The constant c of a Julia set. Every pixel starts the iteration at its
+/// own z, rather than at z = 0 with c taken from the pixel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JuliaSpec {
+    pub x: BigFixed,
+    pub y: BigFixed,
+}
+
+impl FromStr for JuliaSpec {
+    type Err = String;
+
+    /// Parse "x,y", each part with as many digits as needed
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (x, y) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected a point as \"x,y\", got \"{}\"", s))?;
+        Ok(Self {
+            x: x.trim().parse()?,
+            y: y.trim().parse()?,
+        })
+    }
+}
+
+/// Everything needed to reproduce a render
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scene {
+    pub version: u32,
+    pub formula: Formula,
+    /// Exponent n of the multibrot formula, any real number above 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power: Option<f64>,
+    #[serde(default)]
+    pub image: ImageSpec,
+    #[serde(default)]
+    pub viewport: Viewport,
+    #[serde(default)]
+    pub iteration: IterationSpec,
+    #[serde(default)]
+    pub palette: PaletteSpec,
+    /// Render the Julia set for this constant instead of the Mandelbrot set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub julia: Option<JuliaSpec>,
+    /// Polynomial of the newton and nova formulas
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub newton: Option<NewtonSpec>,
+    /// Render the density of escaping orbits instead of escape times
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buddhabrot: Option<BuddhabrotSpec>,
+    /// Color by the estimated distance to the set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<DistanceSpec>,
+    /// Color by how close orbits come to a shape
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trap: Option<TrapSpec>,
+    /// Color by a statistic averaged over the orbit
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub average: Option<AverageSpec>,
+    /// Color points that never escape by their attracting cycle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interior: Option<InteriorSpec>,
+    /// Average several samples per pixel
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub antialias: Option<AntialiasSpec>,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        Self {
+            version: SCENE_VERSION,
+            formula: Formula::Mandelbrot,
+            power: None,
+            image: ImageSpec::default(),
+            viewport: Viewport::default(),
+            iteration: IterationSpec::default(),
+            palette: PaletteSpec::default(),
+            julia: None,
+            newton: None,
+            buddhabrot: None,
+            distance: None,
+            trap: None,
+            average: None,
+            interior: None,
+            antialias: None,
+        }
+    }
+}
+
+/// On-disk encoding of a scene, picked by file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneFormat {
+    Toml,
+    Json,
+}
+
+impl SceneFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Ok(SceneFormat::Toml),
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Ok(SceneFormat::Json),
+            _ => Err(Error::Scene(format!(
+                "{}: scene files must end in .toml or .json",
+                path.display()
+            ))),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            SceneFormat::Toml => "toml",
+            SceneFormat::Json => "json",
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, text: &str) -> std::result::Result<T, String> {
+        match self {
+            SceneFormat::Toml => toml::from_str(text).map_err(|err| err.to_string()),
+            SceneFormat::Json => serde_json::from_str(text).map_err(|err| err.to_string()),
+        }
+    }
+
+    /// The text `parse` reads back as `value`
+    pub fn write<T: Serialize>(self, value: &T) -> std::result::Result<String, String> {
+        match self {
+            SceneFormat::Toml => toml::to_string_pretty(value).map_err(|err| err.to_string()),
+            SceneFormat::Json => serde_json::to_string_pretty(value)
+                .map(|text| text + "\n")
+                .map_err(|err| err.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u32,
+}
+
+impl Scene {
+    pub fn load(path: &Path) -> Result<Self> {
+        let format = SceneFormat::from_path(path)?;
+        let text = fs::read_to_string(path)?;
+
+        // Check the version on its own first, so that files written by newer
+        // builds report the mismatch rather than an unknown field
+        let probe: VersionProbe = format.parse(&text).map_err(|err| scene_error(path, err))?;
+        if probe.version != SCENE_VERSION {
+            return Err(scene_error(
+                path,
+                format!(
+                    "unsupported scene version {}, this build reads version {}",
+                    probe.version, SCENE_VERSION
+                ),
+            ));
+        }
+
+        let mut scene: Scene = format.parse(&text).map_err(|err| scene_error(path, err))?;
+        if let Some(dir) = path.parent() {
+            for file in scene.files_mut() {
+                *file = dir.join(&*file);
+            }
+        }
+
+        scene
+            .validate()
+            .map_err(|err| scene_error(path, err.to_string()))?;
+        Ok(scene)
+    }
+
+    pub fn save(&self, path: &Path, format: SceneFormat) -> Result<()> {
+        fs::write(path, self.to_text(path, format)?)?;
+        Ok(())
+    }
+
+    /// The scene as `save` would write it to `path`
+    pub fn to_text(&self, path: &Path, format: SceneFormat) -> Result<String> {
+        // Keep referenced files reachable from wherever the scene is written
+        let mut scene = self.clone();
+        if let Some(dir) = path.parent() {
+            for file in scene.files_mut() {
+                *file = relative_to(file, dir);
+            }
+        }
+        format.write(&scene).map_err(|err| scene_error(path, err))
+    }
+
+    /// Paths of the files the scene reads, relative to the scene file when
+    /// stored
+    fn files_mut(&mut self) -> impl Iterator<Item = &mut PathBuf> {
+        let gradient = self
+            .palette
+            .gradient
+            .as_mut()
+            .map(|gradient| &mut gradient.file);
+        let trap = self.trap.as_mut().and_then(|trap| trap.image.as_mut());
+        gradient.into_iter().chain(trap)
+    }
+
+    /// Check that the scene describes a renderable image
+    pub fn validate(&self) -> Result<()> {
+        let (width, height) = (self.image.width, self.image.height);
+        if width == 0 || height == 0 {
+            return Err(invalid(format!(
+                "image size must be at least 1 x 1, got {} x {}",
+                width, height
+            )));
+        }
+        if width
+            .checked_mul(height)
+            .and_then(|p| p.checked_mul(4))
+            .is_none()
+            || width > u32::MAX as usize
+            || height > u32::MAX as usize
+        {
+            return Err(invalid(format!(
+                "image size {} x {} is too large",
+                width, height
+            )));
+        }
+        if self.iteration.max_iterations == 0 {
+            return Err(invalid("max iterations must be at least 1".to_string()));
+        }
+        if !(self.iteration.bailout >= 2.0 && self.iteration.bailout <= IterationSpec::MAX_BAILOUT)
+        {
+            return Err(invalid(format!(
+                "bailout radius must be between 2 and {:e}, got {}",
+                IterationSpec::MAX_BAILOUT,
+                self.iteration.bailout
+            )));
+        }
+        if self.viewport.zoom <= FloatExp::ZERO {
+            return Err(invalid(format!(
+                "zoom must be a positive number, got {}",
+                self.viewport.zoom
+            )));
+        }
+        if !self.viewport.rotation.is_finite() {
+            return Err(invalid(format!(
+                "rotation must be a number of degrees, got {}",
+                self.viewport.rotation
+            )));
+        }
+        if !self.palette.offset.is_finite() {
+            return Err(invalid(format!(
+                "palette offset must be a number, got {}",
+                self.palette.offset
+            )));
+        }
+        match (self.formula, self.power) {
+            (Formula::Multibrot, None) => {
+                return Err(invalid("the multibrot formula needs a power".to_string()))
+            }
+            (Formula::Multibrot, Some(power)) if !(power > 1.0 && power.is_finite()) => {
+                return Err(invalid(format!(
+                    "multibrot power must be a number above 1, got {}",
+                    power
+                )))
+            }
+            (Formula::Multibrot, Some(_)) | (_, None) => {}
+            (formula, Some(_)) => {
+                return Err(invalid(format!(
+                    "only the multibrot formula takes a power, not {:?}",
+                    formula
+                )))
+            }
+        }
+        match (self.formula, &self.newton) {
+            (Formula::Newton | Formula::Nova, None) => {
+                return Err(invalid(format!(
+                    "the {:?} formula needs a polynomial",
+                    self.formula
+                )))
+            }
+            (Formula::Newton | Formula::Nova, Some(newton)) => {
+                if newton.polynomial.degree() < 2 {
+                    return Err(invalid(format!(
+                        "polynomial {} must be of degree 2 or more",
+                        newton.polynomial
+                    )));
+                }
+                if !(newton.relaxation.is_finite() && newton.relaxation != 0.0) {
+                    return Err(invalid(format!(
+                        "relaxation must be a nonzero number, got {}",
+                        newton.relaxation
+                    )));
+                }
+                if !(0.0..=1.0).contains(&newton.shading) {
+                    return Err(invalid(format!(
+                        "shading must be between 0 and 1, got {}",
+                        newton.shading
+                    )));
+                }
+            }
+            (_, None) => {}
+            (formula, Some(_)) => {
+                return Err(invalid(format!(
+                    "only the newton and nova formulas take a polynomial, not {:?}",
+                    formula
+                )))
+            }
+        }
+        if self.formula == Formula::Newton && self.julia.is_some() {
+            return Err(invalid(
+                "the Newton formula has no Julia sets, try nova".to_string(),
+            ));
+        }
+        if let Some(buddhabrot) = &self.buddhabrot {
+            if matches!(self.formula, Formula::Newton | Formula::Nova) || self.julia.is_some() {
+                return Err(invalid(
+                    "the buddhabrot needs an escape-time formula without a Julia constant"
+                        .to_string(),
+                ));
+            }
+            if self.iteration.perturbation == Perturbation::Always {
+                return Err(invalid(
+                    "the buddhabrot cannot be rendered by perturbation".to_string(),
+                ));
+            }
+            if buddhabrot.samples == 0 {
+                return Err(invalid("buddhabrot samples must be at least 1".to_string()));
+            }
+            if buddhabrot
+                .nebulabrot
+                .is_some_and(|limits| limits.contains(&0))
+            {
+                return Err(invalid(
+                    "nebulabrot iteration limits must be at least 1".to_string(),
+                ));
+            }
+            if !(buddhabrot.gamma > 0.0 && buddhabrot.gamma.is_finite()) {
+                return Err(invalid(format!(
+                    "buddhabrot gamma must be a positive number, got {}",
+                    buddhabrot.gamma
+                )));
+            }
+        }
+        if let Some(distance) = &self.distance {
+            if !matches!(self.formula, Formula::Mandelbrot | Formula::Multibrot) {
+                return Err(invalid(format!(
+                    "distance estimation needs the mandelbrot or multibrot formula, not {:?}",
+                    self.formula
+                )));
+            }
+            if self.buddhabrot.is_some() {
+                return Err(invalid(
+                    "the buddhabrot has no distance estimate".to_string(),
+                ));
+            }
+            if self.iteration.perturbation == Perturbation::Always {
+                return Err(invalid(
+                    "distance estimation cannot be rendered by perturbation".to_string(),
+                ));
+            }
+            if !(distance.thickness > 0.0 && distance.thickness.is_finite()) {
+                return Err(invalid(format!(
+                    "distance thickness must be a positive number of pixels, got {}",
+                    distance.thickness
+                )));
+            }
+        }
+        if let Some(trap) = &self.trap {
+            if matches!(self.formula, Formula::Newton | Formula::Nova) {
+                return Err(invalid(format!(
+                    "orbit traps need an escape-time formula, not {:?}",
+                    self.formula
+                )));
+            }
+            if self.buddhabrot.is_some() || self.distance.is_some() {
+                return Err(invalid(
+                    "orbit traps cannot be combined with the buddhabrot or distance coloring"
+                        .to_string(),
+                ));
+            }
+            if self.iteration.perturbation == Perturbation::Always {
+                return Err(invalid(
+                    "orbit traps cannot be rendered by perturbation".to_string(),
+                ));
+            }
+            if !(trap.size > 0.0 && trap.size.is_finite()) {
+                return Err(invalid(format!(
+                    "trap size must be a positive number, got {}",
+                    trap.size
+                )));
+            }
+            if !(trap.x.is_finite() && trap.y.is_finite()) {
+                return Err(invalid("trap center must be finite".to_string()));
+            }
+            match (trap.shape, trap.angle) {
+                (TrapShape::Line, Some(angle)) if !angle.is_finite() => {
+                    return Err(invalid(format!("trap angle must be finite, got {}", angle)))
+                }
+                (TrapShape::Line, _) | (_, None) => {}
+                (shape, Some(_)) => {
+                    return Err(invalid(format!(
+                        "only line traps take an angle, not {:?}",
+                        shape
+                    )))
+                }
+            }
+            match (trap.shape, trap.radius) {
+                (TrapShape::Circle, Some(radius)) if !(radius > 0.0 && radius.is_finite()) => {
+                    return Err(invalid(format!(
+                        "trap radius must be a positive number, got {}",
+                        radius
+                    )))
+                }
+                (TrapShape::Circle, _) | (_, None) => {}
+                (shape, Some(_)) => {
+                    return Err(invalid(format!(
+                        "only circle traps take a radius, not {:?}",
+                        shape
+                    )))
+                }
+            }
+            match (trap.shape, &trap.image) {
+                (TrapShape::Image, Some(_)) => {}
+                (TrapShape::Image, None) => {
+                    return Err(invalid("image traps need an image file".to_string()))
+                }
+                (_, None) => {}
+                (shape, Some(_)) => {
+                    return Err(invalid(format!(
+                        "only image traps take an image file, not {:?}",
+                        shape
+                    )))
+                }
+            }
+        }
+        if let Some(average) = &self.average {
+            if matches!(self.formula, Formula::Newton | Formula::Nova) {
+                return Err(invalid(format!(
+                    "average coloring needs an escape-time formula, not {:?}",
+                    self.formula
+                )));
+            }
+            if self.buddhabrot.is_some() || self.distance.is_some() || self.trap.is_some() {
+                return Err(invalid(
+                    "average coloring cannot be combined with the buddhabrot, distance or trap coloring"
+                        .to_string(),
+                ));
+            }
+            if self.iteration.perturbation == Perturbation::Always {
+                return Err(invalid(
+                    "average coloring cannot be rendered by perturbation".to_string(),
+                ));
+            }
+            match (average.method, average.density) {
+                (AverageMethod::Stripe, Some(density))
+                    if !(density > 0.0 && density.is_finite()) =>
+                {
+                    return Err(invalid(format!(
+                        "stripe density must be a positive number, got {}",
+                        density
+                    )))
+                }
+                (AverageMethod::Stripe, _) | (_, None) => {}
+                (method, Some(_)) => {
+                    return Err(invalid(format!(
+                        "only the stripe average takes a density, not {:?}",
+                        method
+                    )))
+                }
+            }
+        }
+        if let Some(interior) = &self.interior {
+            if !matches!(self.formula, Formula::Mandelbrot | Formula::Multibrot) {
+                return Err(invalid(format!(
+                    "interior coloring needs the mandelbrot or multibrot formula, not {:?}",
+                    self.formula
+                )));
+            }
+            if self.buddhabrot.is_some() || self.trap.is_some() {
+                return Err(invalid(
+                    "interior coloring cannot be combined with the buddhabrot or trap coloring"
+                        .to_string(),
+                ));
+            }
+            if self.iteration.perturbation == Perturbation::Always {
+                return Err(invalid(
+                    "interior coloring cannot be rendered by perturbation".to_string(),
+                ));
+            }
+            if interior.mode == InteriorMode::Distance && self.julia.is_some() {
+                return Err(invalid(
+                    "interior distance is only defined for the Mandelbrot set, not Julia sets"
+                        .to_string(),
+                ));
+            }
+            match (interior.mode, interior.falloff) {
+                (InteriorMode::Distance, Some(falloff))
+                    if !(falloff > 0.0 && falloff.is_finite()) =>
+                {
+                    return Err(invalid(format!(
+                        "interior falloff must be a positive number of pixels, got {}",
+                        falloff
+                    )))
+                }
+                (InteriorMode::Distance, _) | (_, None) => {}
+                (mode, Some(_)) => {
+                    return Err(invalid(format!(
+                        "only the interior distance takes a falloff, not {:?}",
+                        mode
+                    )))
+                }
+            }
+        }
+        if let Some(antialias) = &self.antialias {
+            if !(1..=MAX_SUPERSAMPLE_GRID).contains(&antialias.grid) {
+                return Err(invalid(format!(
+                    "supersampling grid must be between 1 and {}, got {}",
+                    MAX_SUPERSAMPLE_GRID, antialias.grid
+                )));
+            }
+            if self.buddhabrot.is_some() {
+                return Err(invalid(
+                    "the buddhabrot accumulates its own samples and cannot be supersampled"
+                        .to_string(),
+                ));
+            }
+            match antialias.adaptive {
+                Some(threshold) if !(threshold > 0.0 && threshold < 1.0) => {
+                    return Err(invalid(format!(
+                        "adaptive supersampling threshold must be between 0 and 1, got {}",
+                        threshold
+                    )))
+                }
+                _ => {}
+            }
+        }
+        if self.formula != Formula::Mandelbrot
+            && self.iteration.perturbation == Perturbation::Always
+        {
+            return Err(invalid(format!(
+                "perturbation is only available for the Mandelbrot formula, not {:?}",
+                self.formula
+            )));
+        }
+        if (self.iteration.perturbation == Perturbation::Never
+            || self.formula != Formula::Mandelbrot
+            || self.buddhabrot.is_some()
+            || self.distance.is_some()
+            || self.trap.is_some()
+            || self.average.is_some()
+            || self.interior.is_some())
+            && self.viewport.finest_step(width, height).to_f64() < self.viewport.f64_spacing()
+        {
+            return Err(invalid(format!(
+                "zoom {} is too deep to render without perturbation, its pixels are closer \
+                 together than f64 coordinates around the center",
+                self.viewport.zoom
+            )));
+        }
+        match &self.palette.gradient {
+            Some(gradient) if gradient.name.is_some() && !gradient.is_collection() => {
+                return Err(invalid(format!(
+                    "gradient name is only used with .ugr and .gradient files, not {}",
+                    gradient.file.display()
+                )));
+            }
+            None if self.palette.stops.len() < 2 => {
+                return Err(invalid(format!(
+                    "palette needs at least 2 color stops or a gradient file, got {} stops",
+                    self.palette.stops.len()
+                )));
+            }
+            _ => {}
+        }
+        if self.palette.root == 0 {
+            return Err(invalid("palette root must be at least 1".to_string()));
+        }
+        for stop in &self.palette.stops {
+            if let Some(position) = stop.position {
+                if !(0.0..=1.0).contains(&position) {
+                    return Err(invalid(format!(
+                        "palette stop position must be between 0 and 1, got {}",
+                        position
+                    )));
+                }
+            }
+            if let Easing::Power(power) = stop.easing {
+                if !(power > 0.0 && power.is_finite()) {
+                    return Err(invalid(format!(
+                        "palette easing power must be positive, got {}",
+                        power
+                    )));
+                }
+            }
+        }
+        if stop_positions(&self.palette.stops)
+            .windows(2)
+            .any(|pair| pair[1] < pair[0])
+        {
+            return Err(invalid(
+                "palette stop positions must not decrease".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::InvalidArgument(message)
+}
+
+/// `path` relative to the directory `base`, or unchanged when either cannot
+/// be resolved
+fn relative_to(path: &Path, base: &Path) -> PathBuf {
+    let base = if base.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        base
+    };
+    let (Ok(path), Ok(base)) = (path.canonicalize(), base.canonicalize()) else {
+        return path.to_path_buf();
+    };
+    let path_components: Vec<Component> = path.components().collect();
+    let base_components: Vec<Component> = base.components().collect();
+    let common = path_components
+        .iter()
+        .zip(&base_components)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return path;
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..base_components.len() {
+        relative.push("..");
+    }
+    relative.extend(&path_components[common..]);
+    relative
+}
+
+fn scene_error(path: &Path, message: String) -> Error {
+    Error::Scene(format!("{}: {}", path.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gradient::GradientFile, test_util::scratch};
+
+    fn deep_scene() -> Scene {
+        let mut scene = Scene::default();
+        scene.viewport.center_x = "-1.7497219297423385717894931877024199184312546843\
+            1975987045218306457230437812634729827489233"
+            .parse()
+            .unwrap();
+        scene.viewport.center_y = "-0.0000000000000000000000000000001937499739\
+            4217403427823897412193"
+            .parse()
+            .unwrap();
+        scene.viewport.zoom = FloatExp::from_f64(3.1e40);
+        scene.viewport.rotation = 12.5;
+        scene.julia = Some("0.285,-0.01".parse().unwrap());
+        scene.palette.offset = 0.375;
+        scene.antialias = Some(AntialiasSpec {
+            adaptive: Some(0.05),
+            ..AntialiasSpec::new(4)
+        });
+        scene
+    }
+
+    #[test]
+    fn saved_scenes_load_back_unchanged() {
+        let dir = scratch("round-trip");
+        let scene = deep_scene();
+        for format in [SceneFormat::Toml, SceneFormat::Json] {
+            let path = dir.join("scene").with_extension(format.extension());
+            scene.save(&path, format).unwrap();
+            assert_eq!(Scene::load(&path).unwrap(), scene, "{:?}", format);
+            // Saving what was loaded writes the same text again
+            let text = fs::read_to_string(&path).unwrap();
+            assert_eq!(scene.to_text(&path, format).unwrap(), text);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_versions_and_fields_are_rejected() {
+        let dir = scratch("rejected");
+        let path = dir.join("scene.toml");
+        let text = Scene::default().to_text(&path, SceneFormat::Toml).unwrap();
+
+        let newer = text.replace("version = 1", "version = 2");
+        assert_ne!(newer, text);
+        fs::write(&path, newer).unwrap();
+        let err = Scene::load(&path).unwrap_err().to_string();
+        assert!(err.contains("unsupported scene version 2"), "{}", err);
+
+        let unknown = text.replace("[image]", "[image]\ndepth = 16");
+        fs::write(&path, unknown).unwrap();
+        let err = Scene::load(&path).unwrap_err().to_string();
+        assert!(err.contains("unknown field `depth`"), "{}", err);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn f64_only_modes_stop_where_pixels_collapse() {
+        let mut scene = Scene::default();
+        scene.image.width = 1000;
+        scene.viewport.center_x = "-0.75".parse().unwrap();
+        scene.viewport.center_y = "0.1".parse().unwrap();
+        // Pixels 3.5e-16 apart, about twice the spacing of f64 around 0.75
+        scene.viewport.zoom = FloatExp::from_f64(1e13);
+        scene.distance = Some(DistanceSpec::default());
+        scene.validate().unwrap();
+
+        scene.viewport.zoom = FloatExp::from_f64(1e14);
+        let err = scene.validate().unwrap_err().to_string();
+        assert!(
+            err.contains("too deep to render without perturbation"),
+            "{}",
+            err
+        );
+        scene.iteration.perturbation = Perturbation::Never;
+        scene.distance = None;
+        assert!(scene.validate().is_err());
+
+        // Plain Mandelbrot renders switch to perturbation instead
+        scene.iteration.perturbation = Perturbation::Auto;
+        scene.validate().unwrap();
+    }
+
+    #[test]
+    fn referenced_files_stay_reachable_from_the_scene() {
+        let dir = scratch("files");
+        fs::create_dir_all(dir.join("scenes")).unwrap();
+        fs::create_dir_all(dir.join("art")).unwrap();
+        let gradient = dir.join("art").join("fire.ggr");
+        let texture = dir.join("art").join("leaf.png");
+        fs::write(&gradient, "").unwrap();
+        fs::write(&texture, "").unwrap();
+
+        let mut scene = Scene::default();
+        scene.palette.stops.clear();
+        scene.palette.gradient = Some(GradientFile {
+            file: gradient.clone(),
+            name: None,
+        });
+        scene.trap = Some(TrapSpec {
+            image: Some(texture.clone()),
+            ..TrapSpec::new(TrapShape::Image)
+        });
+
+        let path = dir.join("scenes").join("leaf.json");
+        let text = scene.to_text(&path, SceneFormat::Json).unwrap();
+        let stored: Scene = SceneFormat::Json.parse(&text).unwrap();
+        assert_eq!(
+            stored.palette.gradient.unwrap().file,
+            Path::new("..").join("art").join("fire.ggr")
+        );
+        assert_eq!(
+            stored.trap.unwrap().image.unwrap(),
+            Path::new("..").join("art").join("leaf.png")
+        );
+
+        // Loading resolves them against the scene's directory again
+        fs::write(&path, text).unwrap();
+        let loaded = Scene::load(&path).unwrap();
+        let file = loaded.palette.gradient.unwrap().file;
+        assert_eq!(
+            file.canonicalize().unwrap(),
+            gradient.canonicalize().unwrap()
+        );
+        let image = loaded.trap.unwrap().image.unwrap();
+        assert_eq!(
+            image.canonicalize().unwrap(),
+            texture.canonicalize().unwrap()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+//! Vectorized escape-time kernels. Every lane runs exactly the same floating
+//! point operations as `mandelbrot_calculate_point`, so the results match the
+//! scalar kernel bit for bit. The periodicity check is likewise the scalar
+//! one: all lanes still iterating have taken the same number of steps, so
+//! they share Brent's window.
+
+use std::arch::x86_64::*;
+
+use crate::kernel::{smooth, EscapeParams};
+
+/// Only call once `Kernel::Avx2.is_supported()` has returned true
+pub fn calculate_points_avx2(
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    periodicity: Option<f64>,
+    out: &mut [f64],
+) -> u64 {
+    calculate_lanes::<4>(x0, y0, params, out, |x0, y0, params| {
+        // Safety: the kernel is only selected when the CPU supports AVX2
+        unsafe { iterate_avx2(x0, y0, params, periodicity) }
+    })
+}
+
+/// Only call once `Kernel::Avx512.is_supported()` has returned true
+pub fn calculate_points_avx512(
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    periodicity: Option<f64>,
+    out: &mut [f64],
+) -> u64 {
+    calculate_lanes::<8>(x0, y0, params, out, |x0, y0, params| {
+        // Safety: the kernel is only selected when the CPU supports AVX-512F
+        unsafe { iterate_avx512(x0, y0, params, periodicity) }
+    })
+}
+
+/// Feed the points through `iterate` in groups of N, padding the last group
+/// by repeating its first point. `iterate` also returns a bit mask of the
+/// lanes caught in a cycle, whose count is returned.
+fn calculate_lanes<const N: usize>(
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    out: &mut [f64],
+    iterate: impl Fn(&[f64; N], &[f64; N], &EscapeParams) -> ([f64; N], u32),
+) -> u64 {
+    let mut periodic = 0;
+    for ((x0, y0), out) in x0.chunks(N).zip(y0.chunks(N)).zip(out.chunks_mut(N)) {
+        let mut xs = [x0[0]; N];
+        let mut ys = [y0[0]; N];
+        xs[..x0.len()].copy_from_slice(x0);
+        ys[..y0.len()].copy_from_slice(y0);
+
+        let (iterations, cycled) = iterate(&xs, &ys, params);
+        out.copy_from_slice(&iterations[..out.len()]);
+        // Padding lanes repeat a point that is already counted
+        periodic += (cycled & ((1 << out.len()) - 1)).count_ones() as u64;
+    }
+    periodic
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn iterate_avx2(
+    x0: &[f64; 4],
+    y0: &[f64; 4],
+    params: &EscapeParams,
+    periodicity: Option<f64>,
+) -> ([f64; 4], u32) {
+    let (mut x, mut y, cx, cy) = match params.julia {
+        Some((cx, cy)) => (
+            _mm256_loadu_pd(x0.as_ptr()),
+            _mm256_loadu_pd(y0.as_ptr()),
+            _mm256_set1_pd(cx),
+            _mm256_set1_pd(cy),
+        ),
+        None => (
+            _mm256_setzero_pd(),
+            _mm256_setzero_pd(),
+            _mm256_loadu_pd(x0.as_ptr()),
+            _mm256_loadu_pd(y0.as_ptr()),
+        ),
+    };
+    let bailout_sqr = _mm256_set1_pd(params.bailout_sqr);
+    let max_iterations = _mm256_set1_pd(params.max_iterations);
+    let one = _mm256_set1_pd(1.0);
+    let two = _mm256_set1_pd(2.0);
+    let tolerance_sqr = _mm256_set1_pd(periodicity.unwrap_or(0.0));
+
+    let mut iteration = _mm256_setzero_pd();
+    let mut cycled = _mm256_setzero_pd();
+    let (mut saved_x, mut saved_y) = (x, y);
+    let (mut steps, mut window) = (0u64, 1u64);
+
+    loop {
+        let xx = _mm256_mul_pd(x, x);
+        let yy = _mm256_mul_pd(y, y);
+        let active = _mm256_andnot_pd(
+            cycled,
+            _mm256_and_pd(
+                _mm256_cmp_pd::<_CMP_LE_OQ>(_mm256_add_pd(xx, yy), bailout_sqr),
+                _mm256_cmp_pd::<_CMP_LT_OQ>(iteration, max_iterations),
+            ),
+        );
+        if _mm256_movemask_pd(active) == 0 {
+            break;
+        }
+
+        let xtemp = _mm256_add_pd(_mm256_sub_pd(xx, yy), cx);
+        let ytemp = _mm256_add_pd(_mm256_mul_pd(_mm256_mul_pd(two, x), y), cy);
+        x = _mm256_blendv_pd(x, xtemp, active);
+        y = _mm256_blendv_pd(y, ytemp, active);
+        iteration = _mm256_add_pd(iteration, _mm256_and_pd(active, one));
+
+        if periodicity.is_some() {
+            steps += 1;
+            let dx = _mm256_sub_pd(x, saved_x);
+            let dy = _mm256_sub_pd(y, saved_y);
+            let near = _mm256_cmp_pd::<_CMP_LT_OQ>(
+                _mm256_add_pd(_mm256_mul_pd(dx, dx), _mm256_mul_pd(dy, dy)),
+                tolerance_sqr,
+            );
+            let inside = _mm256_cmp_pd::<_CMP_LE_OQ>(
+                _mm256_add_pd(_mm256_mul_pd(x, x), _mm256_mul_pd(y, y)),
+                bailout_sqr,
+            );
+            cycled = _mm256_or_pd(cycled, _mm256_and_pd(active, _mm256_and_pd(near, inside)));
+            if steps == window {
+                saved_x = x;
+                saved_y = y;
+                steps = 0;
+                window *= 2;
+            }
+        }
+    }
+    iteration = _mm256_blendv_pd(iteration, max_iterations, cycled);
+
+    let mut xs = [0.0; 4];
+    let mut ys = [0.0; 4];
+    let mut iterations = [0.0; 4];
+    _mm256_storeu_pd(xs.as_mut_ptr(), x);
+    _mm256_storeu_pd(ys.as_mut_ptr(), y);
+    _mm256_storeu_pd(iterations.as_mut_ptr(), iteration);
+
+    for lane in 0..4 {
+        iterations[lane] = smooth(iterations[lane], xs[lane], ys[lane], 2.0, params);
+    }
+    (iterations, _mm256_movemask_pd(cycled) as u32)
+}
+
+#[target_feature(enable = "avx512f")]
+unsafe fn iterate_avx512(
+    x0: &[f64; 8],
+    y0: &[f64; 8],
+    params: &EscapeParams,
+    periodicity: Option<f64>,
+) -> ([f64; 8], u32) {
+    let (mut x, mut y, cx, cy) = match params.julia {
+        Some((cx, cy)) => (
+            _mm512_loadu_pd(x0.as_ptr()),
+            _mm512_loadu_pd(y0.as_ptr()),
+            _mm512_set1_pd(cx),
+            _mm512_set1_pd(cy),
+        ),
+        None => (
+            _mm512_setzero_pd(),
+            _mm512_setzero_pd(),
+            _mm512_loadu_pd(x0.as_ptr()),
+            _mm512_loadu_pd(y0.as_ptr()),
+        ),
+    };
+    let bailout_sqr = _mm512_set1_pd(params.bailout_sqr);
+    let max_iterations = _mm512_set1_pd(params.max_iterations);
+    let one = _mm512_set1_pd(1.0);
+    let two = _mm512_set1_pd(2.0);
+    let tolerance_sqr = _mm512_set1_pd(periodicity.unwrap_or(0.0));
+
+    let mut iteration = _mm512_setzero_pd();
+    let mut cycled: __mmask8 = 0;
+    let (mut saved_x, mut saved_y) = (x, y);
+    let (mut steps, mut window) = (0u64, 1u64);
+
+    loop {
+        let xx = _mm512_mul_pd(x, x);
+        let yy = _mm512_mul_pd(y, y);
+        let active = _mm512_cmp_pd_mask::<_CMP_LE_OQ>(_mm512_add_pd(xx, yy), bailout_sqr)
+            & _mm512_cmp_pd_mask::<_CMP_LT_OQ>(iteration, max_iterations)
+            & !cycled;
+        if active == 0 {
+            break;
+        }
+
+        let xtemp = _mm512_add_pd(_mm512_sub_pd(xx, yy), cx);
+        let ytemp = _mm512_add_pd(_mm512_mul_pd(_mm512_mul_pd(two, x), y), cy);
+        x = _mm512_mask_mov_pd(x, active, xtemp);
+        y = _mm512_mask_mov_pd(y, active, ytemp);
+        iteration = _mm512_mask_add_pd(iteration, active, iteration, one);
+
+        if periodicity.is_some() {
+            steps += 1;
+            let dx = _mm512_sub_pd(x, saved_x);
+            let dy = _mm512_sub_pd(y, saved_y);
+            let near = _mm512_cmp_pd_mask::<_CMP_LT_OQ>(
+                _mm512_add_pd(_mm512_mul_pd(dx, dx), _mm512_mul_pd(dy, dy)),
+                tolerance_sqr,
+            );
+            let inside = _mm512_cmp_pd_mask::<_CMP_LE_OQ>(
+                _mm512_add_pd(_mm512_mul_pd(x, x), _mm512_mul_pd(y, y)),
+                bailout_sqr,
+            );
+            cycled |= active & near & inside;
+            if steps == window {
+                saved_x = x;
+                saved_y = y;
+                steps = 0;
+                window *= 2;
+            }
+        }
+    }
+    iteration = _mm512_mask_mov_pd(iteration, cycled, max_iterations);
+
+    let mut xs = [0.0; 8];
+    let mut ys = [0.0; 8];
+    let mut iterations = [0.0; 8];
+    _mm512_storeu_pd(xs.as_mut_ptr(), x);
+    _mm512_storeu_pd(ys.as_mut_ptr(), y);
+    _mm512_storeu_pd(iterations.as_mut_ptr(), iteration);
+
+    for lane in 0..8 {
+        iterations[lane] = smooth(iterations[lane], xs[lane], ys[lane], 2.0, params);
+    }
+    (iterations, cycled as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kernel::{mandelbrot_calculate_point, Kernel};
+
+    /// A grid over the whole set plus a strip along the boundary, where
+    /// neighbouring lanes escape at very different iterations
+    fn sample_points() -> (Vec<f64>, Vec<f64>) {
+        let mut xs = Vec::new();
+        let mut ys = Vec::new();
+        for j in 0..61 {
+            for i in 0..83 {
+                xs.push(-2.2 + 3.0 * i as f64 / 82.0);
+                ys.push(-1.3 + 2.6 * j as f64 / 60.0);
+            }
+        }
+        for i in 0..101 {
+            xs.push(-0.75 + 0.01 * i as f64 / 100.0);
+            ys.push(0.1 + 0.0005 * i as f64);
+        }
+        (xs, ys)
+    }
+
+    fn assert_matches_scalar(kernel: Kernel) {
+        if !kernel.is_supported() {
+            eprintln!("skipping {:?}, not supported by this CPU", kernel);
+            return;
+        }
+
+        let (xs, ys) = sample_points();
+        let cases = [
+            (1.0, 2.0, None),
+            (100.0, 2.0, None),
+            (1000.0, 256.0, None),
+            (1000.0, 256.0, Some((-0.8, 0.156))),
+        ];
+        for &(max_iterations, bailout, julia) in &cases {
+            let params = EscapeParams {
+                max_iterations,
+                bailout_sqr: bailout * bailout,
+                julia,
+            };
+            let mut out = vec![0.0; xs.len()];
+            kernel.calculate_points(&xs, &ys, &params, None, &mut out);
+            let mut periodic = vec![0.0; xs.len()];
+            let caught = kernel.calculate_points(&xs, &ys, &params, Some(1e-20), &mut periodic);
+            let mut scalar = vec![0.0; xs.len()];
+            let expected_caught =
+                Kernel::Scalar.calculate_points(&xs, &ys, &params, Some(1e-20), &mut scalar);
+            assert_eq!(caught, expected_caught, "{:?} periodic orbits", kernel);
+
+            for (i, (&x0, &y0)) in xs.iter().zip(&ys).enumerate() {
+                assert_eq!(periodic[i].to_bits(), out[i].to_bits());
+                let expected = mandelbrot_calculate_point(x0, y0, &params);
+                assert_eq!(
+                    out[i].to_bits(),
+                    expected.to_bits(),
+                    "{:?} at ({}, {}) with {} iterations: {} != {}",
+                    kernel,
+                    x0,
+                    y0,
+                    max_iterations,
+                    out[i],
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn avx2_matches_scalar() {
+        assert_matches_scalar(Kernel::Avx2);
+    }
+
+    #[test]
+    fn avx512_matches_scalar() {
+        assert_matches_scalar(Kernel::Avx512);
+    }
+}
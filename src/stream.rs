@@ -0,0 +1,513 @@
+//! Images written a band of rows at a time, for renders too large to hold
+//! in memory. PNGs go out as a single deflate stream cut into IDAT chunks.
+//! TIFFs are LZW compressed a strip at a time and their directory is
+//! written at the end, switching to BigTIFF when the file outgrows 32-bit offsets.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use deflate::{write::ZlibEncoder, Compression};
+use rayon::prelude::*;
+use weezl::{encode::Encoder, BitOrder};
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    png::{self, write_chunk},
+    scene::OutputFormat,
+};
+
+/// Rows per band when none is asked for, about 4 million pixels' worth
+pub fn default_band_rows(width: usize) -> usize {
+    ((1 << 22) / width.max(1)).max(1)
+}
+
+/// Peak resident memory of the process so far in bytes, where the system
+/// reports it
+pub fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line
+        .trim_start_matches("VmHWM:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// An image being written, see `ImageStream::create`
+pub struct ImageStream {
+    width: usize,
+    height: usize,
+    written: usize,
+    output: Output,
+}
+
+enum Output {
+    Png {
+        encoder: Box<ZlibEncoder<IdatWriter>>,
+        /// Last scanline written, which the next one is filtered against
+        previous: Vec<u8>,
+    },
+    Tiff(TiffWriter),
+}
+
+impl ImageStream {
+    /// Start an 8-bit RGB image at `path`
+    pub fn create(path: &Path, format: OutputFormat, width: usize, height: usize) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidArgument(format!(
+                "cannot write an empty {} x {} image",
+                width, height
+            )));
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+        let output = match format {
+            OutputFormat::Png => {
+                if width > i32::MAX as usize / 3 || height > i32::MAX as usize {
+                    return Err(Error::InvalidArgument(format!(
+                        "{} x {} is too large for a PNG",
+                        width, height
+                    )));
+                }
+                png::write_header(&mut writer, width, height)?;
+                Output::Png {
+                    encoder: Box::new(ZlibEncoder::new(
+                        IdatWriter::new(writer),
+                        Compression::Default,
+                    )),
+                    previous: vec![0; width * 3],
+                }
+            }
+            OutputFormat::Tiff => Output::Tiff(TiffWriter::new(writer, width, height)?),
+            format => {
+                return Err(Error::InvalidArgument(format!(
+                    "streamed images are written as PNG or TIFF, not {:?}",
+                    format
+                )))
+            }
+        };
+        Ok(Self {
+            width,
+            height,
+            written: 0,
+            output,
+        })
+    }
+
+    /// Append whole rows of pixels below the ones already written
+    pub fn write_rows(&mut self, colors: &[Color]) -> Result<()> {
+        assert_eq!(colors.len() % self.width, 0, "partial row");
+        let rows = colors.len() / self.width;
+        assert!(self.written + rows <= self.height, "too many rows");
+
+        let rgb: Vec<u8> = colors
+            .iter()
+            .flat_map(|color| [color.red, color.green, color.blue])
+            .collect();
+        match &mut self.output {
+            Output::Png { encoder, previous } => {
+                let mut filtered = Vec::with_capacity(rgb.len() + rows);
+                for row in rgb.chunks_exact(self.width * 3) {
+                    png::filter_row(row, previous, &mut filtered);
+                    previous.copy_from_slice(row);
+                }
+                encoder.write_all(&filtered)?;
+            }
+            Output::Tiff(writer) => writer.write_rows(&rgb)?,
+        }
+        self.written += rows;
+        Ok(())
+    }
+
+    /// Close the image once every row is written. Returns the name of the
+    /// file format, which for TIFFs tells whether it came out as BigTIFF.
+    pub fn finish(self) -> Result<&'static str> {
+        if self.written != self.height {
+            return Err(Error::InvalidArgument(format!(
+                "the image was started with {} row(s), but {} were written",
+                self.height, self.written
+            )));
+        }
+        match self.output {
+            Output::Png { encoder, .. } => {
+                encoder.finish()?.finish()?;
+                Ok("PNG")
+            }
+            Output::Tiff(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Collects the zlib stream of a PNG into IDAT chunks of a fixed size
+struct IdatWriter {
+    writer: BufWriter<File>,
+    buffer: Vec<u8>,
+}
+
+impl IdatWriter {
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    fn new(writer: BufWriter<File>) -> Self {
+        Self {
+            writer,
+            buffer: Vec::with_capacity(Self::CHUNK_SIZE),
+        }
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            write_chunk(&mut self.writer, b"IDAT", &self.buffer)?;
+        }
+        write_chunk(&mut self.writer, b"IEND", &[])?;
+        self.writer.flush()
+    }
+}
+
+impl Write for IdatWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let taken = data.len().min(Self::CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..taken]);
+        if self.buffer.len() == Self::CHUNK_SIZE {
+            write_chunk(&mut self.writer, b"IDAT", &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Room kept at the start of a TIFF for the header, which is 8 bytes in
+/// classic TIFF and 16 in BigTIFF
+const TIFF_HEADER_SIZE: u64 = 16;
+
+/// Strips hold about this many bytes before compression
+const TIFF_STRIP_SIZE: usize = 1 << 20;
+
+/// TIFF field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const LONG8: u16 = 16;
+
+/// Writes the strips of a TIFF as their rows come in and the directory
+/// that points to them at the end
+struct TiffWriter {
+    writer: BufWriter<File>,
+    width: usize,
+    height: usize,
+    rows_per_strip: usize,
+    /// Rows that do not fill a strip yet
+    pending: Vec<u8>,
+    /// Where each strip was written, and its compressed length
+    strips: Vec<(u64, u64)>,
+    /// Bytes written so far
+    position: u64,
+}
+
+impl TiffWriter {
+    fn new(mut writer: BufWriter<File>, width: usize, height: usize) -> Result<Self> {
+        if width > u32::MAX as usize || height > u32::MAX as usize {
+            return Err(Error::InvalidArgument(format!(
+                "{} x {} is too large for a TIFF",
+                width, height
+            )));
+        }
+        writer.write_all(&[0; TIFF_HEADER_SIZE as usize])?;
+        Ok(Self {
+            writer,
+            width,
+            height,
+            rows_per_strip: (TIFF_STRIP_SIZE / (width * 3)).clamp(1, height),
+            pending: Vec::new(),
+            strips: Vec::new(),
+            position: TIFF_HEADER_SIZE,
+        })
+    }
+
+    fn write_rows(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let strip_size = self.rows_per_strip * self.width * 3;
+        self.pending.extend_from_slice(rgb);
+        let full = self.pending.len() / strip_size * strip_size;
+        let pending = std::mem::take(&mut self.pending);
+        self.write_strips(&pending[..full])?;
+        self.pending = pending[full..].to_vec();
+        Ok(())
+    }
+
+    /// Compress whole strips in parallel and write them in order
+    fn write_strips(&mut self, rgb: &[u8]) -> io::Result<()> {
+        let stride = self.width * 3;
+        let compressed: Vec<Vec<u8>> = rgb
+            .par_chunks(self.rows_per_strip * stride)
+            .map(|strip| {
+                // Horizontal differencing, which the predictor tag undoes
+                let mut predicted = strip.to_vec();
+                for row in predicted.chunks_exact_mut(stride) {
+                    for i in (3..stride).rev() {
+                        row[i] = row[i].wrapping_sub(row[i - 3]);
+                    }
+                }
+                Encoder::with_tiff_size_switch(BitOrder::Msb, 8)
+                    .encode(&predicted)
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+            })
+            .collect::<io::Result<_>>()?;
+        for data in compressed {
+            self.writer.write_all(&data)?;
+            self.strips.push((self.position, data.len() as u64));
+            self.position += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<&'static str> {
+        let pending = std::mem::take(&mut self.pending);
+        self.write_strips(&pending)?;
+        let big = self.needs_bigtiff();
+        self.write_directory(big)
+    }
+
+    /// Everything past the strips is small next to them, so a file whose
+    /// strips end well short of 4 GiB fits classic TIFF
+    fn needs_bigtiff(&self) -> bool {
+        self.position + self.strips.len() as u64 * 8 + 4096 > u32::MAX as u64
+    }
+
+    /// Write the directory after the strips, with 64-bit offsets when `big`,
+    /// and the header that points to it
+    fn write_directory(mut self, big: bool) -> Result<&'static str> {
+        let (offsets, counts): (Vec<u64>, Vec<u64>) = self.strips.iter().copied().unzip();
+        let strip_type = if big { LONG8 } else { LONG };
+        let entries: [(u16, u16, Vec<u64>); 11] = [
+            (256, LONG, vec![self.width as u64]),
+            (257, LONG, vec![self.height as u64]),
+            // Bits per sample
+            (258, SHORT, vec![8, 8, 8]),
+            // Compression: LZW, which more readers take than deflate
+            (259, SHORT, vec![5]),
+            // Photometric interpretation: RGB
+            (262, SHORT, vec![2]),
+            (273, strip_type, offsets),
+            // Samples per pixel
+            (277, SHORT, vec![3]),
+            (278, LONG, vec![self.rows_per_strip as u64]),
+            (279, strip_type, counts),
+            // Planar configuration: interleaved
+            (284, SHORT, vec![1]),
+            // Predictor: horizontal differencing
+            (317, SHORT, vec![2]),
+        ];
+
+        // Values too long to sit in their entry go ahead of the directory
+        let inline = if big { 8 } else { 4 };
+        let mut fields = Vec::with_capacity(entries.len());
+        for (tag, kind, values) in entries {
+            let mut bytes = Vec::new();
+            for value in &values {
+                match kind {
+                    SHORT => bytes.extend_from_slice(&(*value as u16).to_le_bytes()),
+                    LONG => bytes.extend_from_slice(&(*value as u32).to_le_bytes()),
+                    _ => bytes.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+            if bytes.len() <= inline {
+                bytes.resize(inline, 0);
+                fields.push((tag, kind, values.len() as u64, bytes));
+            } else {
+                // Values start on a word boundary
+                if self.position % 2 == 1 {
+                    self.writer.write_all(&[0])?;
+                    self.position += 1;
+                }
+                self.writer.write_all(&bytes)?;
+                let offset = self.position;
+                self.position += bytes.len() as u64;
+                let bytes = if big {
+                    offset.to_le_bytes().to_vec()
+                } else {
+                    (offset as u32).to_le_bytes().to_vec()
+                };
+                fields.push((tag, kind, values.len() as u64, bytes));
+            }
+        }
+
+        if self.position % 2 == 1 {
+            self.writer.write_all(&[0])?;
+            self.position += 1;
+        }
+        let directory = self.position;
+        if big {
+            self.writer
+                .write_all(&(fields.len() as u64).to_le_bytes())?;
+        } else {
+            self.writer
+                .write_all(&(fields.len() as u16).to_le_bytes())?;
+        }
+        for (tag, kind, count, bytes) in fields {
+            self.writer.write_all(&tag.to_le_bytes())?;
+            self.writer.write_all(&kind.to_le_bytes())?;
+            if big {
+                self.writer.write_all(&count.to_le_bytes())?;
+            } else {
+                self.writer.write_all(&(count as u32).to_le_bytes())?;
+            }
+            self.writer.write_all(&bytes)?;
+        }
+        // No further directories
+        self.writer.write_all(&[0; 8][..inline])?;
+
+        let mut header = b"II".to_vec();
+        if big {
+            header.extend_from_slice(&43u16.to_le_bytes());
+            // Size of offsets, and a reserved zero
+            header.extend_from_slice(&8u16.to_le_bytes());
+            header.extend_from_slice(&0u16.to_le_bytes());
+            header.extend_from_slice(&directory.to_le_bytes());
+        } else {
+            header.extend_from_slice(&42u16.to_le_bytes());
+            header.extend_from_slice(&(directory as u32).to_le_bytes());
+        }
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+        Ok(if big { "BigTIFF" } else { "TIFF" })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::scratch;
+    use std::convert::TryInto;
+    use weezl::decode::Decoder;
+
+    const WIDTH: usize = 13;
+    const HEIGHT: usize = 17;
+
+    fn pixels() -> Vec<Color> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (x, y) = (i % WIDTH, i / WIDTH);
+                Color::new((x * 19) as u8, (y * 15) as u8, (x * y * 7 + 3) as u8)
+            })
+            .collect()
+    }
+
+    /// Write `colors` in bands of 5, 1, 7, ... rows, so bands straddle strips
+    fn write_bands(write: &mut dyn FnMut(&[Color]) -> Result<()>, colors: &[Color]) {
+        let mut row = 0;
+        for &rows in [5, 1, 7].iter().cycle() {
+            if row == HEIGHT {
+                break;
+            }
+            let rows = rows.min(HEIGHT - row);
+            write(&colors[row * WIDTH..(row + rows) * WIDTH]).unwrap();
+            row += rows;
+        }
+    }
+
+    fn assert_decodes_to(path: &Path, colors: &[Color]) {
+        let image = image::open(path).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (WIDTH as u32, HEIGHT as u32));
+        for (pixel, color) in image.pixels().zip(colors) {
+            assert_eq!(pixel.0, [color.red, color.green, color.blue]);
+        }
+    }
+
+    #[test]
+    fn streamed_images_decode_to_their_pixels() {
+        let dir = scratch("stream");
+        let colors = pixels();
+        for (format, name) in [(OutputFormat::Png, "PNG"), (OutputFormat::Tiff, "TIFF")] {
+            let path = dir.join(format!("image.{}", name.to_lowercase()));
+            let mut stream = ImageStream::create(&path, format, WIDTH, HEIGHT).unwrap();
+            if let Output::Tiff(writer) = &mut stream.output {
+                writer.rows_per_strip = 4;
+            }
+            write_bands(&mut |band| stream.write_rows(band), &colors);
+            assert_eq!(stream.finish().unwrap(), name);
+            assert_decodes_to(&path, &colors);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bigtiff_directories_point_at_every_strip() {
+        let dir = scratch("bigtiff");
+        let path = dir.join("image.tiff");
+        let colors = pixels();
+        let mut writer =
+            TiffWriter::new(BufWriter::new(File::create(&path).unwrap()), WIDTH, HEIGHT).unwrap();
+        writer.rows_per_strip = 4;
+        assert!(!writer.needs_bigtiff());
+        write_bands(
+            &mut |band: &[Color]| {
+                let rgb: Vec<u8> = band
+                    .iter()
+                    .flat_map(|color| [color.red, color.green, color.blue])
+                    .collect();
+                Ok(writer.write_rows(&rgb)?)
+            },
+            &colors,
+        );
+        let pending = std::mem::take(&mut writer.pending);
+        writer.write_strips(&pending).unwrap();
+        assert_eq!(writer.write_directory(true).unwrap(), "BigTIFF");
+
+        let file = fs::read(&path).unwrap();
+        let u16_at = |at: usize| u16::from_le_bytes(file[at..at + 2].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(file[at..at + 8].try_into().unwrap());
+        assert_eq!(&file[..2], b"II");
+        assert_eq!((u16_at(2), u16_at(4), u16_at(6)), (43, 8, 0));
+        let directory = u64_at(8) as usize;
+        let entries = u64_at(directory) as usize;
+        let field = |tag: u16| {
+            (0..entries)
+                .map(|i| directory + 8 + i * 20)
+                .find(|&at| u16_at(at) == tag)
+                .map(|at| (u16_at(at + 2), u64_at(at + 4), at + 12))
+                .unwrap()
+        };
+        assert_eq!(field(259).0, SHORT);
+        assert_eq!(u16_at(field(259).2), 5);
+
+        let strips = HEIGHT.div_ceil(4);
+        let values = |tag: u16| {
+            let (kind, count, at) = field(tag);
+            assert_eq!((kind, count), (LONG8, strips as u64));
+            let start = u64_at(at) as usize;
+            (0..strips)
+                .map(|i| u64_at(start + i * 8) as usize)
+                .collect::<Vec<_>>()
+        };
+        let stride = WIDTH * 3;
+        let mut rgb = Vec::new();
+        for (offset, count) in values(273).into_iter().zip(values(279)) {
+            let mut strip = Decoder::with_tiff_size_switch(BitOrder::Msb, 8)
+                .decode(&file[offset..offset + count])
+                .unwrap();
+            for row in strip.chunks_exact_mut(stride) {
+                for i in 3..stride {
+                    row[i] = row[i].wrapping_add(row[i - 3]);
+                }
+            }
+            rgb.extend(strip);
+        }
+        let expected: Vec<u8> = colors
+            .iter()
+            .flat_map(|color| [color.red, color.green, color.blue])
+            .collect();
+        assert_eq!(rgb, expected);
+
+        assert_decodes_to(&path, &colors);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,11 @@
+//! Fixtures shared by the tests of several modules
+
+use std::{fs, path::PathBuf};
+
+/// A fresh directory for one test's files, which the test removes when done
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mandelbrot-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
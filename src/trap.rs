@@ -0,0 +1,420 @@
+//! Orbit traps: coloring by how close the orbit of a point comes to a shape
+//! in the complex plane, instead of by how long it takes to escape.
+
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    color::Color,
+    error::Result,
+    fractal::{self, Fractal, Visitor},
+    kernel::EscapeParams,
+    palette::Palette,
+    scene::Formula,
+};
+
+/// Shape that orbits are measured against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TrapShape {
+    Point,
+    /// Line through the center at the trap's angle
+    Line,
+    /// Horizontal and vertical lines through the center. Centered on 0 with
+    /// a small size this gives Pickover stalks.
+    Cross,
+    /// Circle around the center with the trap's radius
+    Circle,
+    /// Texture laid on the plane, colored by the first orbit point that lands
+    /// on an opaque pixel of it
+    Image,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrapSpec {
+    pub shape: TrapShape,
+    /// Center of the shape, a point on the line
+    #[serde(default)]
+    pub x: f64,
+    #[serde(default)]
+    pub y: f64,
+    /// Distance from the shape at which the palette runs out. For image
+    /// traps, the width of the texture in the plane.
+    #[serde(default = "TrapSpec::default_size")]
+    pub size: f64,
+    /// Angle of a line trap in degrees from the real axis
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub angle: Option<f64>,
+    /// Radius of a circle trap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f64>,
+    /// Texture of an image trap. Relative paths are resolved against the
+    /// directory of the scene file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<PathBuf>,
+}
+
+impl TrapSpec {
+    pub fn new(shape: TrapShape) -> Self {
+        Self {
+            shape,
+            x: 0.0,
+            y: 0.0,
+            size: Self::default_size(),
+            angle: None,
+            radius: None,
+            image: None,
+        }
+    }
+
+    fn default_size() -> f64 {
+        0.25
+    }
+}
+
+/// A trap ready to measure orbits, with its texture loaded
+pub enum Trap {
+    Point(f64, f64),
+    /// Unit normal and the normal's offset from the origin
+    Line {
+        nx: f64,
+        ny: f64,
+        offset: f64,
+    },
+    Cross(f64, f64),
+    Circle {
+        x: f64,
+        y: f64,
+        radius: f64,
+    },
+    Image {
+        texture: image::RgbaImage,
+        /// Top left corner of the texture in the plane
+        left: f64,
+        top: f64,
+        /// Width and height of a texture pixel in the plane
+        texel: f64,
+    },
+}
+
+impl Trap {
+    pub fn new(spec: &TrapSpec) -> Result<Self> {
+        let (x, y) = (spec.x, spec.y);
+        Ok(match spec.shape {
+            TrapShape::Point => Trap::Point(x, y),
+            TrapShape::Line => {
+                let angle = spec.angle.unwrap_or(0.0).to_radians();
+                let (nx, ny) = (-angle.sin(), angle.cos());
+                Trap::Line {
+                    nx,
+                    ny,
+                    offset: nx * x + ny * y,
+                }
+            }
+            TrapShape::Cross => Trap::Cross(x, y),
+            TrapShape::Circle => Trap::Circle {
+                x,
+                y,
+                radius: spec.radius.unwrap_or(1.0),
+            },
+            TrapShape::Image => {
+                let path = spec.image.as_ref().expect("image traps are validated");
+                let texture = image::open(path)?.to_rgba8();
+                let texel = spec.size / texture.width() as f64;
+                Trap::Image {
+                    left: x - spec.size / 2.0,
+                    top: y + texel * texture.height() as f64 / 2.0,
+                    texel,
+                    texture,
+                }
+            }
+        })
+    }
+
+    /// Distance from z to the shape
+    fn distance(&self, (zx, zy): (f64, f64)) -> f64 {
+        match *self {
+            Trap::Point(x, y) => (zx - x).hypot(zy - y),
+            Trap::Line { nx, ny, offset } => (nx * zx + ny * zy - offset).abs(),
+            Trap::Cross(x, y) => (zx - x).abs().min((zy - y).abs()),
+            Trap::Circle { x, y, radius } => ((zx - x).hypot(zy - y) - radius).abs(),
+            Trap::Image { .. } => f64::INFINITY,
+        }
+    }
+
+    /// Opaque texture pixel under z, if any
+    fn texel(&self, (zx, zy): (f64, f64)) -> Option<Color> {
+        let Trap::Image {
+            texture,
+            left,
+            top,
+            texel,
+        } = self
+        else {
+            return None;
+        };
+        let u = (zx - left) / texel;
+        let v = (top - zy) / texel;
+        if !(u >= 0.0 && v >= 0.0 && u < texture.width() as f64 && v < texture.height() as f64) {
+            return None;
+        }
+        let [r, g, b, a] = texture.get_pixel(u as u32, v as u32).0;
+        (a >= 0x80).then(|| Color::new(r, g, b))
+    }
+}
+
+/// Color a run of points by their orbits against the trap. Distance traps
+/// color every point, including those that never escape. Image traps fall
+/// back to the palette's iteration coloring for orbits that miss the
+/// texture.
+#[allow(clippy::too_many_arguments)]
+pub fn color_points(
+    formula: Formula,
+    power: f64,
+    x0: &[f64],
+    y0: &[f64],
+    params: &EscapeParams,
+    trap: &Trap,
+    size: f64,
+    palette: &Palette,
+    out: &mut Vec<Color>,
+) {
+    struct Run<'a> {
+        x0: &'a [f64],
+        y0: &'a [f64],
+        params: &'a EscapeParams,
+        trap: &'a Trap,
+        size: f64,
+        palette: &'a Palette,
+        out: &'a mut Vec<Color>,
+    }
+
+    impl Visitor for Run<'_> {
+        type Output = ();
+
+        fn visit<F: Fractal + Sync>(self, fractal: &F) {
+            let Run {
+                x0,
+                y0,
+                params,
+                trap,
+                size,
+                palette,
+                out,
+            } = self;
+            for (&x0, &y0) in x0.iter().zip(y0) {
+                if let Trap::Image { .. } = trap {
+                    let mut hit = None;
+                    let iteration = fractal::calculate_orbit(fractal, x0, y0, params, |_, z| {
+                        if hit.is_none() && !fractal.escaped(z, params.bailout_sqr) {
+                            hit = trap.texel(z);
+                        }
+                    });
+                    out.push(hit.unwrap_or_else(|| palette.color(iteration)));
+                } else {
+                    let mut nearest = f64::INFINITY;
+                    fractal::calculate_orbit(fractal, x0, y0, params, |_, z| {
+                        if !fractal.escaped(z, params.bailout_sqr) {
+                            nearest = nearest.min(trap.distance(z));
+                        }
+                    });
+                    // Orbits that touch the shape take the end of the palette
+                    let closeness = 1.0 - (nearest / size).min(1.0);
+                    out.push(palette.ramp(closeness as f32));
+                }
+            }
+        }
+    }
+
+    fractal::visit(
+        formula,
+        power,
+        Run {
+            x0,
+            y0,
+            params,
+            trap,
+            size,
+            palette,
+            out,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fractal::Mandelbrot, palette::PaletteSpec, test_util::scratch};
+
+    const PARAMS: EscapeParams = EscapeParams {
+        max_iterations: 100.0,
+        bailout_sqr: 256.0 * 256.0,
+        julia: None,
+    };
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-12,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    /// 2 x 2 texture: opaque red, green just below the alpha threshold,
+    /// blue just at it and opaque white
+    fn texture() -> image::RgbaImage {
+        image::RgbaImage::from_raw(
+            2,
+            2,
+            vec![
+                255, 0, 0, 255, 0, 255, 0, 0x7f, //
+                0, 0, 255, 0x80, 255, 255, 255, 255,
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn distances_to_each_shape() {
+        let point = Trap::new(&TrapSpec {
+            x: 1.0,
+            y: -1.0,
+            ..TrapSpec::new(TrapShape::Point)
+        })
+        .unwrap();
+        assert_close(point.distance((4.0, 3.0)), 5.0);
+
+        // The line y = x, through (1, 1) at 45 degrees
+        let line = Trap::new(&TrapSpec {
+            x: 1.0,
+            y: 1.0,
+            angle: Some(45.0),
+            ..TrapSpec::new(TrapShape::Line)
+        })
+        .unwrap();
+        let Trap::Line { nx, ny, offset } = line else {
+            panic!("not a line");
+        };
+        assert_close(nx.hypot(ny), 1.0);
+        assert_close(offset, 0.0);
+        assert_close(line.distance((3.0, 3.0)), 0.0);
+        assert_close(line.distance((-2.0, -2.0)), 0.0);
+        assert_close(line.distance((2.0, 0.0)), 2.0_f64.sqrt());
+        assert_close(line.distance((0.0, 2.0)), 2.0_f64.sqrt());
+
+        // A horizontal line keeps its offset from the origin
+        let horizontal = Trap::new(&TrapSpec {
+            y: 0.5,
+            ..TrapSpec::new(TrapShape::Line)
+        })
+        .unwrap();
+        assert_close(horizontal.distance((7.0, -1.0)), 1.5);
+
+        let cross = Trap::new(&TrapSpec {
+            x: 1.0,
+            y: 1.0,
+            ..TrapSpec::new(TrapShape::Cross)
+        })
+        .unwrap();
+        assert_close(cross.distance((1.25, 3.0)), 0.25);
+        assert_close(cross.distance((-3.0, 0.5)), 0.5);
+
+        let circle = Trap::new(&TrapSpec {
+            radius: Some(2.0),
+            ..TrapSpec::new(TrapShape::Circle)
+        })
+        .unwrap();
+        assert_close(circle.distance((0.0, 0.0)), 2.0);
+        assert_close(circle.distance((0.0, -2.0)), 0.0);
+        assert_close(circle.distance((3.0, 4.0)), 3.0);
+    }
+
+    #[test]
+    fn texels_under_the_orbit() {
+        let dir = scratch("trap");
+        let path = dir.join("texture.png");
+        texture().save(&path).unwrap();
+
+        // Two units wide around the origin, so each texel is a unit square
+        let trap = Trap::new(&TrapSpec {
+            size: 2.0,
+            image: Some(path),
+            ..TrapSpec::new(TrapShape::Image)
+        })
+        .unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(trap.distance((0.0, 0.0)), f64::INFINITY);
+
+        let red = Some(Color::new(255, 0, 0));
+        assert_eq!(trap.texel((-0.5, 0.5)), red);
+        assert_eq!(trap.texel((0.5, 0.5)), None);
+        assert_eq!(trap.texel((-0.5, -0.5)), Some(Color::new(0, 0, 255)));
+        assert_eq!(trap.texel((0.5, -0.5)), Some(Color::new(255, 255, 255)));
+
+        // The top left edges are inside, the bottom right ones are not
+        assert_eq!(trap.texel((-1.0, 1.0)), red);
+        assert_eq!(trap.texel((-1.0001, 0.5)), None);
+        assert_eq!(trap.texel((-0.5, 1.0001)), None);
+        assert_eq!(trap.texel((1.0, -0.5)), None);
+        assert_eq!(trap.texel((-0.5, -1.0)), None);
+        assert_eq!(trap.texel((f64::NAN, 0.0)), None);
+    }
+
+    #[test]
+    fn orbits_that_miss_the_image_take_the_palette() {
+        let palette = Palette::generate(&PaletteSpec::default(), 100).unwrap();
+        let x0 = [-0.5, 0.3, 3.0];
+        let y0 = [0.5, 0.6, 3.0];
+        let run = |trap: &Trap| {
+            let mut out = Vec::new();
+            color_points(
+                Formula::Mandelbrot,
+                2.0,
+                &x0,
+                &y0,
+                &PARAMS,
+                trap,
+                1.0,
+                &palette,
+                &mut out,
+            );
+            out
+        };
+
+        // Far from every orbit
+        let away = Trap::Image {
+            texture: texture(),
+            left: 1000.0,
+            top: 1000.0,
+            texel: 1.0,
+        };
+        let expected: Vec<Color> = x0
+            .iter()
+            .zip(&y0)
+            .map(|(&x, &y)| palette.color(fractal::calculate_point(&Mandelbrot, x, y, &PARAMS)))
+            .collect();
+        assert_eq!(run(&away), expected);
+
+        // Around the origin, where the first step of every orbit lands on c.
+        // The second point starts on the transparent texel and the last one
+        // escapes without coming near the texture.
+        let around = Trap::Image {
+            texture: texture(),
+            left: -1.0,
+            top: 1.0,
+            texel: 1.0,
+        };
+        let colors = run(&around);
+        assert_eq!(colors[0], Color::new(255, 0, 0));
+        assert_ne!(colors[1], Color::new(0, 255, 0));
+        assert_eq!(colors[2], expected[2]);
+
+        // Distance traps color every orbit by how close it came
+        let point = Trap::Point(-0.5, 0.5);
+        assert_eq!(run(&point)[0], palette.ramp(1.0));
+    }
+}
@@ -0,0 +1,507 @@
+//! Encoders that take animation frames one at a time, so that no more than
+//! the current frame is held in memory
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use color_quant::NeuQuant;
+
+use crate::{
+    error::{Error, Result},
+    png::{self, filter_scanlines, write_chunk},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VideoFormat {
+    /// Animated PNG, lossless
+    Apng,
+    /// Animated GIF with a 256 color palette per frame
+    Gif,
+    /// YUV4MPEG2 stream in 4:4:4, which ffmpeg and most encoders read
+    /// directly
+    Y4m,
+    /// Bare 8-bit RGB frames one after another, without any header
+    Raw,
+}
+
+impl VideoFormat {
+    /// Guess the format from a file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "apng" | "png" => Some(VideoFormat::Apng),
+            "gif" => Some(VideoFormat::Gif),
+            "y4m" => Some(VideoFormat::Y4m),
+            "rgb" | "raw" => Some(VideoFormat::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// Settings shared by every frame of a video
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoSpec {
+    pub format: VideoFormat,
+    pub width: usize,
+    pub height: usize,
+    /// Number of frames that will be written, stored up front by APNG
+    pub frames: usize,
+    pub fps: u32,
+    /// Spread the error of GIF palette colors over the neighbouring pixels
+    pub dither: bool,
+}
+
+impl VideoSpec {
+    /// Refuse sizes and rates the format cannot describe
+    fn check(&self) -> Result<()> {
+        if self.fps == 0 || self.fps as usize > MAX_DIMENSION {
+            return Err(Error::InvalidArgument(format!(
+                "frame rate must be between 1 and {}, got {}",
+                MAX_DIMENSION, self.fps
+            )));
+        }
+        if self.format == VideoFormat::Gif
+            && (self.width > MAX_DIMENSION || self.height > MAX_DIMENSION)
+        {
+            return Err(Error::InvalidArgument(format!(
+                "GIF frames are at most {0} x {0} pixels, got {1} x {2}",
+                MAX_DIMENSION, self.width, self.height
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// A video being written, see `VideoEncoder::create`
+pub struct VideoEncoder {
+    spec: VideoSpec,
+    written: usize,
+    output: Output,
+}
+
+enum Output {
+    Apng {
+        writer: Box<dyn Write>,
+        /// Shared counter of the fcTL and fdAT chunks
+        sequence: u32,
+    },
+    Gif(gif::Encoder<Box<dyn Write>>),
+    Y4m(Box<dyn Write>),
+    Raw(Box<dyn Write>),
+}
+
+/// Largest GIF frame and APNG frame rate the formats can describe
+const MAX_DIMENSION: usize = u16::MAX as usize;
+
+impl VideoEncoder {
+    /// Start a video at `path`, or on stdout when the path is `-`
+    pub fn create(path: &Path, spec: VideoSpec) -> Result<Self> {
+        spec.check()?;
+        let writer: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(BufWriter::new(io::stdout()))
+        } else {
+            Box::new(BufWriter::new(File::create(path)?))
+        };
+        Self::new(writer, spec)
+    }
+
+    /// Start a video on `writer`, with a spec that passed `VideoSpec::check`
+    fn new(mut writer: Box<dyn Write>, spec: VideoSpec) -> Result<Self> {
+        let output = match spec.format {
+            VideoFormat::Apng => {
+                png::write_header(&mut writer, spec.width, spec.height)?;
+                let mut control = Vec::with_capacity(8);
+                control.extend_from_slice(&(spec.frames as u32).to_be_bytes());
+                // Loop forever
+                control.extend_from_slice(&0u32.to_be_bytes());
+                write_chunk(&mut writer, b"acTL", &control)?;
+                Output::Apng {
+                    writer,
+                    sequence: 0,
+                }
+            }
+            VideoFormat::Gif => {
+                let mut encoder =
+                    gif::Encoder::new(writer, spec.width as u16, spec.height as u16, &[])
+                        .map_err(gif_error)?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(gif_error)?;
+                Output::Gif(encoder)
+            }
+            VideoFormat::Y4m => {
+                writeln!(
+                    writer,
+                    "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444 XCOLORRANGE=LIMITED",
+                    spec.width, spec.height, spec.fps
+                )?;
+                Output::Y4m(writer)
+            }
+            VideoFormat::Raw => Output::Raw(writer),
+        };
+        Ok(Self {
+            spec,
+            written: 0,
+            output,
+        })
+    }
+
+    /// Append a frame given as RGBA bytes, the alpha channel is dropped
+    pub fn write_frame(&mut self, rgba: &[u8]) -> Result<()> {
+        let VideoSpec {
+            width,
+            height,
+            fps,
+            dither,
+            ..
+        } = self.spec;
+        assert_eq!(rgba.len(), width * height * 4, "frame size changed");
+        if self.written == self.spec.frames && self.spec.format == VideoFormat::Apng {
+            return Err(Error::Video(format!(
+                "the animated PNG was started with {} frame(s)",
+                self.spec.frames
+            )));
+        }
+
+        match &mut self.output {
+            Output::Apng { writer, sequence } => {
+                let mut control = Vec::with_capacity(26);
+                control.extend_from_slice(&sequence.to_be_bytes());
+                control.extend_from_slice(&(width as u32).to_be_bytes());
+                control.extend_from_slice(&(height as u32).to_be_bytes());
+                // Offset of the frame within the canvas
+                control.extend_from_slice(&[0; 8]);
+                control.extend_from_slice(&1u16.to_be_bytes());
+                control.extend_from_slice(&(fps as u16).to_be_bytes());
+                // Keep nothing of the previous frame, and replace it whole
+                control.extend_from_slice(&[0, 0]);
+                write_chunk(writer, b"fcTL", &control)?;
+                *sequence += 1;
+
+                let data = deflate::deflate_bytes_zlib(&filter_scanlines(rgba, width));
+                if self.written == 0 {
+                    // The first frame doubles as the still image
+                    write_chunk(writer, b"IDAT", &data)?;
+                } else {
+                    let mut frame = Vec::with_capacity(data.len() + 4);
+                    frame.extend_from_slice(&sequence.to_be_bytes());
+                    frame.extend_from_slice(&data);
+                    write_chunk(writer, b"fdAT", &frame)?;
+                    *sequence += 1;
+                }
+            }
+            Output::Gif(encoder) => {
+                let (palette, indices) = quantize(rgba, width, dither);
+                let frame = gif::Frame {
+                    width: width as u16,
+                    height: height as u16,
+                    // In hundredths of a second, the finest GIF allows
+                    delay: (100.0 / fps as f64).round().max(1.0) as u16,
+                    palette: Some(palette),
+                    buffer: Cow::Owned(indices),
+                    ..gif::Frame::default()
+                };
+                encoder.write_frame(&frame).map_err(gif_error)?;
+            }
+            Output::Y4m(writer) => {
+                writer.write_all(b"FRAME\n")?;
+                writer.write_all(&to_ycbcr(rgba))?;
+            }
+            Output::Raw(writer) => {
+                let rgb: Vec<u8> = rgba
+                    .chunks_exact(4)
+                    .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+                    .collect();
+                writer.write_all(&rgb)?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    /// Close the video once every frame is written
+    pub fn finish(self) -> Result<()> {
+        let mut writer = match self.output {
+            Output::Apng { mut writer, .. } => {
+                if self.written != self.spec.frames {
+                    return Err(Error::Video(format!(
+                        "the animated PNG was started with {} frame(s), but {} were written",
+                        self.spec.frames, self.written
+                    )));
+                }
+                write_chunk(&mut writer, b"IEND", &[])?;
+                writer
+            }
+            Output::Gif(encoder) => encoder.into_inner()?,
+            Output::Y4m(writer) | Output::Raw(writer) => writer,
+        };
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn gif_error(err: gif::EncodingError) -> Error {
+    Error::Video(format!("failed to encode GIF: {}", err))
+}
+
+/// A 256 color palette for the frame and the palette index of each pixel.
+/// With dithering, Floyd–Steinberg carries each pixel's error over to the
+/// pixels right of and below it, which hides the banding of smooth
+/// gradients.
+fn quantize(rgba: &[u8], width: usize, dither: bool) -> (Vec<u8>, Vec<u8>) {
+    // Look at every 10th pixel, quick and close to the full quality
+    let quant = NeuQuant::new(10, 256, rgba);
+    let palette = quant.color_map_rgb();
+    let pixels = rgba.len() / 4;
+    if !dither {
+        let indices = rgba
+            .chunks_exact(4)
+            .map(|pixel| quant.index_of(pixel) as u8)
+            .collect();
+        return (palette, indices);
+    }
+
+    let mut indices = Vec::with_capacity(pixels);
+    // Error carried into the current and next row, with a pixel of padding
+    // at both ends
+    let mut current = vec![[0.0f32; 3]; width + 2];
+    let mut next = vec![[0.0f32; 3]; width + 2];
+    for row in rgba.chunks_exact(width * 4) {
+        for (x, pixel) in row.chunks_exact(4).enumerate() {
+            let wanted = [0, 1, 2].map(|i| (pixel[i] as f32 + current[x + 1][i]).clamp(0.0, 255.0));
+            let rounded = [wanted[0] as u8, wanted[1] as u8, wanted[2] as u8, 0xFF];
+            let index = quant.index_of(&rounded);
+            indices.push(index as u8);
+            let chosen = &palette[index * 3..index * 3 + 3];
+            for i in 0..3 {
+                let error = wanted[i] - chosen[i] as f32;
+                current[x + 2][i] += error * 7.0 / 16.0;
+                next[x][i] += error * 3.0 / 16.0;
+                next[x + 1][i] += error * 5.0 / 16.0;
+                next[x + 2][i] += error * 1.0 / 16.0;
+            }
+        }
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|error| *error = [0.0; 3]);
+    }
+    (palette, indices)
+}
+
+/// Planar Y, Cb and Cr in the limited range of BT.601
+fn to_ycbcr(rgba: &[u8]) -> Vec<u8> {
+    let pixels = rgba.len() / 4;
+    let mut planes = vec![0; pixels * 3];
+    let (luma, chroma) = planes.split_at_mut(pixels);
+    let (blue, red) = chroma.split_at_mut(pixels);
+    for (i, pixel) in rgba.chunks_exact(4).enumerate() {
+        let [r, g, b] = [0, 1, 2].map(|c| pixel[c] as f64 / 255.0);
+        luma[i] = (16.0 + 65.481 * r + 128.553 * g + 24.966 * b).round() as u8;
+        blue[i] = (128.0 - 37.797 * r - 74.203 * g + 112.0 * b).round() as u8;
+        red[i] = (128.0 + 112.0 * r - 93.786 * g - 18.214 * b).round() as u8;
+    }
+    planes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::RefCell, convert::TryInto, rc::Rc};
+
+    /// A writer whose bytes the test can still read once the encoder owns it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn encoder(
+        format: VideoFormat,
+        width: usize,
+        height: usize,
+        frames: usize,
+    ) -> (VideoEncoder, Shared) {
+        let shared = Shared::default();
+        let spec = VideoSpec {
+            format,
+            width,
+            height,
+            frames,
+            fps: 30,
+            dither: false,
+        };
+        (
+            VideoEncoder::new(Box::new(shared.clone()), spec).unwrap(),
+            shared,
+        )
+    }
+
+    /// A 3 x 2 frame, different for each `seed`
+    fn frame(seed: u8) -> Vec<u8> {
+        (0..6u8)
+            .flat_map(|i| {
+                [
+                    seed.wrapping_mul(40).wrapping_add(i * 7),
+                    i * 30,
+                    255 - seed,
+                    0xFF,
+                ]
+            })
+            .collect()
+    }
+
+    /// The chunks of a PNG after its signature, with their CRCs checked
+    fn chunks(data: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        assert_eq!(&data[..8], b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut at = 8;
+        while at < data.len() {
+            let length = u32::from_be_bytes(data[at..at + 4].try_into().unwrap()) as usize;
+            let kind: [u8; 4] = data[at + 4..at + 8].try_into().unwrap();
+            let body = data[at + 8..at + 8 + length].to_vec();
+            let crc =
+                u32::from_be_bytes(data[at + 8 + length..at + 12 + length].try_into().unwrap());
+            assert_eq!(crc, crc32fast::hash(&data[at + 4..at + 8 + length]));
+            chunks.push((kind, body));
+            at += 12 + length;
+        }
+        chunks
+    }
+
+    #[test]
+    fn animated_pngs_number_their_frames() {
+        let frames: Vec<Vec<u8>> = (0..3).map(frame).collect();
+        let (mut video, shared) = encoder(VideoFormat::Apng, 3, 2, 3);
+        for rgba in &frames {
+            video.write_frame(rgba).unwrap();
+        }
+        video.finish().unwrap();
+
+        let data = shared.0.borrow().clone();
+        let chunks = chunks(&data);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(
+            kinds,
+            [
+                &b"IHDR"[..],
+                b"acTL",
+                b"fcTL",
+                b"IDAT",
+                b"fcTL",
+                b"fdAT",
+                b"fcTL",
+                b"fdAT",
+                b"IEND"
+            ]
+        );
+        let (_, control) = &chunks[1];
+        assert_eq!(control[..], [0, 0, 0, 3, 0, 0, 0, 0]);
+
+        // fcTL and fdAT count up together, and IDAT has no number
+        let be = |bytes: &[u8]| u32::from_be_bytes(bytes[..4].try_into().unwrap());
+        let numbers: Vec<u32> = chunks
+            .iter()
+            .filter(|(kind, _)| kind == b"fcTL" || kind == b"fdAT")
+            .map(|(_, body)| be(body))
+            .collect();
+        assert_eq!(numbers, [0, 1, 2, 3, 4]);
+
+        // Each frame's data, put in a still PNG by itself, decodes to it
+        let header = &chunks[0].1;
+        let images = chunks.iter().filter_map(|(kind, body)| match kind {
+            b"IDAT" => Some(&body[..]),
+            b"fdAT" => Some(&body[4..]),
+            _ => None,
+        });
+        for (image, rgba) in images.zip(&frames) {
+            let mut still = b"\x89PNG\r\n\x1a\n".to_vec();
+            write_chunk(&mut still, b"IHDR", header).unwrap();
+            write_chunk(&mut still, b"IDAT", image).unwrap();
+            write_chunk(&mut still, b"IEND", &[]).unwrap();
+            let decoded = image::load_from_memory(&still).unwrap().to_rgba8();
+            assert_eq!(decoded.into_raw(), *rgba);
+        }
+    }
+
+    #[test]
+    fn animated_pngs_hold_the_frames_they_announce() {
+        let (mut video, _) = encoder(VideoFormat::Apng, 3, 2, 2);
+        video.write_frame(&frame(0)).unwrap();
+        assert!(matches!(video.finish(), Err(Error::Video(_))));
+
+        let (mut video, _) = encoder(VideoFormat::Apng, 3, 2, 1);
+        video.write_frame(&frame(0)).unwrap();
+        assert!(matches!(video.write_frame(&frame(1)), Err(Error::Video(_))));
+    }
+
+    #[test]
+    fn y4m_frames_are_limited_range_ycbcr() {
+        let (mut video, shared) = encoder(VideoFormat::Y4m, 3, 1, 2);
+        let rgba = [0, 0, 0, 0xFF, 255, 255, 255, 0xFF, 255, 0, 0, 0xFF];
+        video.write_frame(&rgba).unwrap();
+        video.write_frame(&rgba).unwrap();
+        video.finish().unwrap();
+
+        let header = b"YUV4MPEG2 W3 H1 F30:1 Ip A1:1 C444 XCOLORRANGE=LIMITED\n";
+        // Black, white and red in the Y plane, then Cb, then Cr
+        let planes = [16, 235, 81, 128, 128, 90, 128, 128, 240];
+        let mut expected = header.to_vec();
+        for _ in 0..2 {
+            expected.extend_from_slice(b"FRAME\n");
+            expected.extend_from_slice(&planes);
+        }
+        assert_eq!(*shared.0.borrow(), expected);
+    }
+
+    #[test]
+    fn dithering_keeps_the_average_color() {
+        // A gentle ramp with far more shades than the palette has room for
+        // next to a patch of other colors
+        let (width, height) = (256, 64);
+        let mut rgba = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                let shade = (x / 4 + 64) as u8;
+                let pixel = if y < 8 {
+                    [(x * 7) as u8, (y * 31) as u8, (x * 13) as u8, 0xFF]
+                } else {
+                    [shade, shade, shade, 0xFF]
+                };
+                rgba.extend_from_slice(&pixel);
+            }
+        }
+        let (palette, indices) = quantize(&rgba, width, true);
+        assert_eq!(palette.len(), 256 * 3);
+        // Averaged over a block the dithered ramp stays within a shade
+        for block in (0..width).step_by(16) {
+            let (mut wanted, mut got) = (0.0, 0.0);
+            for y in 16..height {
+                for x in block..block + 16 {
+                    let index = indices[y * width + x] as usize;
+                    wanted += rgba[(y * width + x) * 4] as f64;
+                    got += palette[index * 3] as f64;
+                }
+            }
+            let count = (16 * (height - 16)) as f64;
+            assert!(
+                (wanted - got).abs() / count < 1.0,
+                "block {}: {} vs {}",
+                block,
+                wanted / count,
+                got / count
+            );
+        }
+    }
+}
@@ -0,0 +1,398 @@
+use std::f64::consts::{LN_2, TAU};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{bignum::BigFixed, floatexp::FloatExp, perturbation::GUARD_BITS};
+
+/// Region of the complex plane mapped onto the output image. The center is
+/// kept at arbitrary precision and the zoom as an extended-exponent float,
+/// both written as strings in scene files when `f64` cannot hold them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Viewport {
+    pub center_x: BigFixed,
+    pub center_y: BigFixed,
+    pub zoom: FloatExp,
+    /// Turn of the view about its center in degrees, counterclockwise in
+    /// the complex plane
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rotation: f64,
+    #[serde(default, skip_serializing_if = "Mapping::is_cartesian")]
+    pub mapping: Mapping,
+}
+
+fn is_zero(value: &f64) -> bool {
+    *value == 0.0
+}
+
+/// How image pixels are laid over the complex plane
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Mapping {
+    /// Rows and columns along the imaginary and real axes
+    #[default]
+    Cartesian,
+    /// Log-polar around the center: columns go once round the circle and
+    /// each row shrinks the radius by the width of a pixel, so one tall
+    /// strip holds every zoom level from the view down to its bottom row
+    Exponential,
+}
+
+impl Mapping {
+    fn is_cartesian(&self) -> bool {
+        *self == Mapping::Cartesian
+    }
+}
+
+impl Viewport {
+    /// Width of the real axis that is visible at zoom 1
+    pub const BASE_SPAN: f64 = 3.5;
+
+    /// Distance between neighbouring pixels in the complex plane. With the
+    /// exponential mapping this is the width of the view divided by the
+    /// image width, the pixels themselves shrink row by row.
+    pub fn step(&self, width: usize) -> FloatExp {
+        FloatExp::from_f64(Self::BASE_SPAN / width as f64).div(self.zoom)
+    }
+
+    /// Distance between neighbouring `f64` numbers around the center. Pixel
+    /// steps below it put several pixels on the same `f64` point.
+    pub fn f64_spacing(&self) -> f64 {
+        let magnitude = self
+            .center_x
+            .to_f64()
+            .abs()
+            .max(self.center_y.to_f64().abs());
+        (magnitude * f64::EPSILON).max(f64::MIN_POSITIVE)
+    }
+
+    /// Smallest distance between neighbouring pixels anywhere in the image
+    pub fn finest_step(&self, width: usize, height: usize) -> FloatExp {
+        match self.mapping {
+            Mapping::Cartesian => self.step(width),
+            Mapping::Exponential => self
+                .radius()
+                .mul(FloatExp::exp2(
+                    -ring_turn(width) * height.saturating_sub(1) as f64 / LN_2,
+                ))
+                .mul_f64(ring_turn(width)),
+        }
+    }
+
+    /// Radius of the top row of an exponential map: the width of the view,
+    /// which takes in the corners of any landscape frame at this zoom
+    pub fn radius(&self) -> FloatExp {
+        FloatExp::from_f64(Self::BASE_SPAN).div(self.zoom)
+    }
+
+    /// A view of the disk |z| <= 2, which holds every Julia set and the
+    /// interesting part of the root-finding fractals
+    pub fn centered() -> Self {
+        Self {
+            center_x: BigFixed::zero(1),
+            center_y: BigFixed::zero(1),
+            zoom: FloatExp::from_f64(0.875),
+            rotation: 0.0,
+            mapping: Mapping::Cartesian,
+        }
+    }
+
+    /// Fraction limbs needed to place any pixel of an image this size
+    /// exactly
+    pub fn frac_limbs(&self, width: usize, height: usize) -> usize {
+        BigFixed::limbs_for_step(self.finest_step(width, height), GUARD_BITS)
+            .max(self.center_x.frac_limbs())
+            .max(self.center_y.frac_limbs())
+    }
+
+    /// Full precision coordinates of the top-left corner of pixel (x, y)
+    pub fn pixel(&self, width: usize, height: usize, x: usize, y: usize) -> (BigFixed, BigFixed) {
+        let frac_limbs = self.frac_limbs(width, height);
+        let (dx, dy) = self.offsets(width, height).point(x, y);
+        (
+            self.center_x
+                .with_frac_limbs(frac_limbs)
+                .add(&BigFixed::from_floatexp(dx, frac_limbs)),
+            self.center_y
+                .with_frac_limbs(frac_limbs)
+                .add(&BigFixed::from_floatexp(dy, frac_limbs)),
+        )
+    }
+
+    /// Map the viewport onto an image of the given size. Pixels are square,
+    /// so the imaginary span follows from the aspect ratio.
+    pub fn bounds(&self, width: usize, height: usize) -> Bounds {
+        let step = self.step(width).to_f64();
+        let (center_x, center_y) = (self.center_x.to_f64(), self.center_y.to_f64());
+        let mut bounds = Bounds {
+            min_x: center_x - step * width as f64 / 2.0,
+            min_y: center_y - step * height as f64 / 2.0,
+            step,
+            rotation: self.turn().map(|(cos, sin)| Rotation {
+                center_x,
+                center_y,
+                cos,
+                sin,
+            }),
+            polar: None,
+            top: 0.0,
+        };
+        if self.mapping == Mapping::Exponential {
+            let radius = self.radius().to_f64();
+            bounds.min_x = center_x - radius;
+            bounds.min_y = center_y - radius;
+            bounds.rotation = None;
+            bounds.polar = Some(Polar {
+                center_x,
+                center_y,
+                radius,
+                angle: self.rotation.to_radians(),
+                turn: ring_turn(width),
+            });
+        }
+        bounds
+    }
+
+    /// Cosine and sine of the rotation, unless the view is upright
+    fn turn(&self) -> Option<(f64, f64)> {
+        if self.rotation == 0.0 {
+            return None;
+        }
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        Some((cos, sin))
+    }
+
+    /// Like `bounds`, but mapping pixels to their offset from the center
+    /// rather than to absolute coordinates. Keeps full precision at zooms
+    /// where the absolute coordinates would round together.
+    pub fn offsets(&self, width: usize, height: usize) -> Offsets {
+        Offsets {
+            step: self.step(width),
+            half_width: width as f64 / 2.0,
+            half_height: height as f64 / 2.0,
+            turn: self.turn(),
+            polar: (self.mapping == Mapping::Exponential).then(|| PolarOffsets {
+                radius: self.radius(),
+                angle: self.rotation.to_radians(),
+                turn: ring_turn(width),
+            }),
+            top: 0.0,
+            center: (FloatExp::ZERO, FloatExp::ZERO),
+        }
+    }
+}
+
+/// Angle between neighbouring columns of an exponential map, which is also
+/// the log of the radius ratio between neighbouring rows
+fn ring_turn(width: usize) -> f64 {
+    TAU / width as f64
+}
+
+impl Default for Viewport {
+    /// The view of the original hard-coded renderer: x in (-2.5, 1) and
+    /// y in (-1, 1), offset by (-3.5, -2.5) and zoomed in 7 times
+    fn default() -> Self {
+        Self {
+            center_x: "-0.6071428571428571".parse().unwrap(),
+            center_y: "-0.35714285714285715".parse().unwrap(),
+            zoom: FloatExp::from_f64(7.0),
+            rotation: 0.0,
+            mapping: Mapping::Cartesian,
+        }
+    }
+}
+
+/// Pixel to complex plane mapping for a fixed image size
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bounds {
+    /// Corner of the view before it is rotated
+    pub min_x: f64,
+    pub min_y: f64,
+    /// Distance between neighbouring pixels in the complex plane
+    pub step: f64,
+    rotation: Option<Rotation>,
+    /// Set for the exponential mapping, which ignores the corner and step
+    polar: Option<Polar>,
+    /// Row of the full image that row 0 stands for, see `rows_from`
+    top: f64,
+}
+
+/// Turn of the view about its center
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rotation {
+    center_x: f64,
+    center_y: f64,
+    cos: f64,
+    sin: f64,
+}
+
+/// Log-polar layout of an exponential map
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Polar {
+    center_x: f64,
+    center_y: f64,
+    /// Radius of the top row
+    radius: f64,
+    /// Angle of the first column in radians
+    angle: f64,
+    /// See `ring_turn`
+    turn: f64,
+}
+
+impl Polar {
+    /// Distance from the center of row y
+    fn radius(&self, y: f64) -> f64 {
+        self.radius * (-self.turn * y).exp()
+    }
+}
+
+impl Bounds {
+    /// The same mapping for a band of the image that starts at row `top`,
+    /// so that its rows land exactly where they do in the full image
+    pub fn rows_from(self, top: usize) -> Self {
+        Self {
+            top: top as f64,
+            ..self
+        }
+    }
+
+    /// Coordinates of the top-left corner of pixel (x, y)
+    pub fn point(&self, x: usize, y: usize) -> (f64, f64) {
+        self.point_at(x, y, (0.0, 0.0))
+    }
+
+    /// Like `point`, moved by a fraction of a pixel. The pixel's own row
+    /// is placed before the fraction is added, so a band lands on exactly
+    /// the same points as the full image.
+    pub fn point_at(&self, x: usize, y: usize, (dx, dy): (f64, f64)) -> (f64, f64) {
+        let (x, y) = (x as f64 + dx, (y as f64 + self.top) + dy);
+        if let Some(polar) = &self.polar {
+            let (sin, cos) = (polar.angle + polar.turn * x).sin_cos();
+            let radius = polar.radius(y);
+            return (polar.center_x + radius * cos, polar.center_y + radius * sin);
+        }
+        let (px, py) = (self.min_x + self.step * x, self.min_y + self.step * y);
+        match self.rotation {
+            None => (px, py),
+            Some(Rotation {
+                center_x,
+                center_y,
+                cos,
+                sin,
+            }) => {
+                let (dx, dy) = (px - center_x, py - center_y);
+                (
+                    center_x + cos * dx - sin * dy,
+                    center_y + sin * dx + cos * dy,
+                )
+            }
+        }
+    }
+
+    /// Width of the pixels in row y
+    pub fn pixel_size(&self, y: usize) -> f64 {
+        match &self.polar {
+            Some(polar) => polar.radius(y as f64 + self.top) * polar.turn,
+            None => self.step,
+        }
+    }
+
+    /// Pixel coordinates of the point (x, y), the inverse of `point`
+    pub fn locate(&self, x: f64, y: f64) -> (f64, f64) {
+        if let Some(polar) = &self.polar {
+            let (dx, dy) = (x - polar.center_x, y - polar.center_y);
+            let angle = (dy.atan2(dx) - polar.angle).rem_euclid(TAU);
+            let radius = (dx * dx + dy * dy).sqrt();
+            return (
+                angle / polar.turn,
+                (polar.radius / radius).ln() / polar.turn - self.top,
+            );
+        }
+        let (x, y) = match self.rotation {
+            None => (x, y),
+            Some(Rotation {
+                center_x,
+                center_y,
+                cos,
+                sin,
+            }) => {
+                let (dx, dy) = (x - center_x, y - center_y);
+                (
+                    center_x + cos * dx + sin * dy,
+                    center_y - sin * dx + cos * dy,
+                )
+            }
+        };
+        (
+            (x - self.min_x) / self.step,
+            (y - self.min_y) / self.step - self.top,
+        )
+    }
+}
+
+/// Pixel to offset-from-center mapping, see `Viewport::offsets`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Offsets {
+    pub step: FloatExp,
+    half_width: f64,
+    half_height: f64,
+    /// Cosine and sine of the view's rotation
+    turn: Option<(f64, f64)>,
+    polar: Option<PolarOffsets>,
+    /// See `Bounds::top`
+    top: f64,
+    /// Offset of the center from the point the offsets are measured from
+    center: (FloatExp, FloatExp),
+}
+
+/// Like `Polar`, with the radius kept as an extended-exponent float, since
+/// the rows of a deep strip shrink far past the range of `f64`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PolarOffsets {
+    radius: FloatExp,
+    angle: f64,
+    turn: f64,
+}
+
+impl Offsets {
+    /// See `Bounds::rows_from`
+    pub fn rows_from(self, top: usize) -> Self {
+        Self {
+            top: top as f64,
+            ..self
+        }
+    }
+
+    /// Offsets from another point than the center, `center` away from it
+    pub fn with_center_at(self, center: (FloatExp, FloatExp)) -> Self {
+        Self { center, ..self }
+    }
+
+    /// Offset of the top-left corner of pixel (x, y) from the center
+    pub fn point(&self, x: usize, y: usize) -> (FloatExp, FloatExp) {
+        self.point_at(x, y, (0.0, 0.0))
+    }
+
+    /// Like `point`, moved by a fraction of a pixel. The pixel's own row
+    /// is placed before the fraction is added, so a band lands on exactly
+    /// the same points as the full image.
+    pub fn point_at(&self, x: usize, y: usize, (dx, dy): (f64, f64)) -> (FloatExp, FloatExp) {
+        let (x, y) = (x as f64 + dx, (y as f64 + self.top) + dy);
+        let (px, py) = if let Some(polar) = &self.polar {
+            let (sin, cos) = (polar.angle + polar.turn * x).sin_cos();
+            let radius = polar.radius.mul(FloatExp::exp2(-polar.turn * y / LN_2));
+            (radius.mul_f64(cos), radius.mul_f64(sin))
+        } else {
+            let (dx, dy) = (x - self.half_width, y - self.half_height);
+            match self.turn {
+                None => (self.step.mul_f64(dx), self.step.mul_f64(dy)),
+                Some((cos, sin)) => (
+                    self.step.mul_f64(cos * dx - sin * dy),
+                    self.step.mul_f64(sin * dx + cos * dy),
+                ),
+            }
+        };
+        (px.add(self.center.0), py.add(self.center.1))
+    }
+}